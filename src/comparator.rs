@@ -1,5 +1,450 @@
 use ::slice::Slice;
+use std::cmp::{self, Ordering};
+use std::collections::HashMap;
+use std::sync::Arc;
 
-pub trait SliceComparator {
+/// A SliceComparator object provides a total order across slices that
+/// are used as keys in an sstable or a database.  A SliceComparator
+/// implementation must be thread-safe since rubbledb may invoke its
+/// methods concurrently from multiple threads.
+pub trait SliceComparator: Send + Sync {
+    /// Three-way comparison.  Returns value:
+    ///   < 0 iff "a" < "b",
+    ///   == 0 iff "a" == "b",
+    ///   > 0 iff "a" > "b"
     fn compare(&self, a: Slice, b: Slice) -> i32;
+
+    /// The name of the comparator.  Used to check for comparator
+    /// mismatches (i.e., a DB created with one comparator is accessed
+    /// using a different comparator), and recorded in the properties of
+    /// every table.
+    ///
+    /// The client of this package should switch to a new name whenever
+    /// the comparator implementation changes in a way that will cause
+    /// the relative ordering of any two keys to change.
+    ///
+    /// Names starting with "leveldb." are reserved and should not be used
+    /// by any clients of this package.
+    fn name(&self) -> &str;
+
+    // Advanced functions: these are used to reduce the space requirements
+    // for internal data structures like index blocks.
+
+    /// If *start < limit, changes *start to a short string in [start,limit).
+    /// Simple comparator implementations may return with *start unchanged,
+    /// i.e., an implementation of this method that does nothing is correct.
+    ///
+    /// The default implementation shortens *start bytewise, and keeps
+    /// the result only if it still lies in [start,limit) according to
+    /// compare().
+    fn find_shortest_separator(&self, start: &mut Vec<u8>, limit: Slice)
+    {
+        if let Some(separator) = bytewise_separator(start, limit) {
+            if self.compare(start, &separator) <= 0 && self.compare(&separator, limit) < 0 {
+                *start = separator;
+            }
+        }
+    }
+
+    /// Changes *key to a short string >= *key.
+    /// Simple comparator implementations may return with *key unchanged,
+    /// i.e., an implementation of this method that does nothing is correct.
+    ///
+    /// The default implementation shortens *key bytewise, and keeps the
+    /// result only if it is still >= *key according to compare().
+    fn find_short_successor(&self, key: &mut Vec<u8>)
+    {
+        if let Some(successor) = bytewise_successor(key) {
+            if self.compare(&successor, key) >= 0 {
+                *key = successor;
+            }
+        }
+    }
+}
+
+/// Return a short bytewise separator in [start,limit), or None if
+/// "start" cannot be shortened.
+fn bytewise_separator(start: Slice, limit: Slice) -> Option<Vec<u8>>
+{
+    // Find length of common prefix
+    let min_length = cmp::min(start.len(), limit.len());
+    let mut diff_index = 0;
+    while diff_index < min_length && start[diff_index] == limit[diff_index] {
+        diff_index += 1;
+    }
+
+    if diff_index >= min_length {
+        // Do not shorten if one string is a prefix of the other
+        return None;
+    }
+
+    let diff_byte = start[diff_index];
+    if diff_byte < 0xff && diff_byte + 1 < limit[diff_index] {
+        let mut separator = start[..diff_index + 1].to_vec();
+        separator[diff_index] += 1;
+        return Some(separator);
+    }
+    None
+}
+
+/// Return a short bytewise successor of "key", or None if "key" cannot
+/// be shortened.
+fn bytewise_successor(key: Slice) -> Option<Vec<u8>>
+{
+    // Find first character that can be incremented
+    for (i, &byte) in key.iter().enumerate() {
+        if byte != 0xff {
+            let mut successor = key[..i + 1].to_vec();
+            successor[i] += 1;
+            return Some(successor);
+        }
+    }
+    // *key is a run of 0xffs.  Leave it alone.
+    None
+}
+
+impl<C: SliceComparator + ?Sized> SliceComparator for &C {
+    fn compare(&self, a: Slice, b: Slice) -> i32
+    {
+        (**self).compare(a, b)
+    }
+
+    fn name(&self) -> &str
+    {
+        (**self).name()
+    }
+
+    fn find_shortest_separator(&self, start: &mut Vec<u8>, limit: Slice)
+    {
+        (**self).find_shortest_separator(start, limit)
+    }
+
+    fn find_short_successor(&self, key: &mut Vec<u8>)
+    {
+        (**self).find_short_successor(key)
+    }
+}
+
+impl<C: SliceComparator + ?Sized> SliceComparator for Arc<C> {
+    fn compare(&self, a: Slice, b: Slice) -> i32
+    {
+        (**self).compare(a, b)
+    }
+
+    fn name(&self) -> &str
+    {
+        (**self).name()
+    }
+
+    fn find_shortest_separator(&self, start: &mut Vec<u8>, limit: Slice)
+    {
+        (**self).find_shortest_separator(start, limit)
+    }
+
+    fn find_short_successor(&self, key: &mut Vec<u8>)
+    {
+        (**self).find_short_successor(key)
+    }
+}
+
+/// A comparator that uses lexicographic byte-wise ordering.  This is
+/// the default comparator used by Options.
+#[derive(Clone, Copy)]
+pub struct BytewiseComparator;
+
+impl SliceComparator for BytewiseComparator {
+    fn compare(&self, a: Slice, b: Slice) -> i32
+    {
+        match a.cmp(b) {
+            Ordering::Less => -1,
+            Ordering::Equal => 0,
+            Ordering::Greater => 1,
+        }
+    }
+
+    fn name(&self) -> &str
+    {
+        "leveldb.BytewiseComparator"
+    }
+
+    fn find_shortest_separator(&self, start: &mut Vec<u8>, limit: Slice)
+    {
+        if let Some(separator) = bytewise_separator(start, limit) {
+            assert!(self.compare(&separator, limit) < 0);
+            *start = separator;
+        }
+    }
+
+    fn find_short_successor(&self, key: &mut Vec<u8>)
+    {
+        if let Some(successor) = bytewise_successor(key) {
+            *key = successor;
+        }
+    }
+}
+
+/// A comparator that orders keys bytewise in reverse, so that iterating
+/// a table forwards yields its keys from largest to smallest.
+#[derive(Clone, Copy)]
+pub struct ReverseBytewiseComparator;
+
+impl SliceComparator for ReverseBytewiseComparator {
+    fn compare(&self, a: Slice, b: Slice) -> i32
+    {
+        BytewiseComparator.compare(b, a)
+    }
+
+    fn name(&self) -> &str
+    {
+        "rocksdb.ReverseBytewiseComparator"
+    }
+}
+
+/// A comparator for keys that are unsigned 64-bit integers encoded as
+/// 8 big-endian bytes, ordered numerically.  Keys are never shortened,
+/// so that every key stays 8 bytes long.
+///
+/// Keys are ordered by length first, so keys of any other length do
+/// not interleave with the 8-byte keys: shorter keys sort before all
+/// of them and longer keys after.  Keys of the same length are ordered
+/// bytewise, which for 8-byte keys is their numeric order.  This
+/// differs from BytewiseComparator, which would put b"\x01" after
+/// every 8-byte key that starts with a zero byte.
+#[derive(Clone, Copy)]
+pub struct BigEndianU64Comparator;
+
+impl SliceComparator for BigEndianU64Comparator {
+    fn compare(&self, a: Slice, b: Slice) -> i32
+    {
+        match a.len().cmp(&b.len()).then_with(|| a.cmp(b)) {
+            Ordering::Less => -1,
+            Ordering::Equal => 0,
+            Ordering::Greater => 1,
+        }
+    }
+
+    fn name(&self) -> &str
+    {
+        "rubbledb.BigEndianU64Comparator"
+    }
+
+    fn find_shortest_separator(&self, start: &mut Vec<u8>, limit: Slice) {}
+
+    fn find_short_successor(&self, key: &mut Vec<u8>) {}
+}
+
+/// A set of comparators keyed by name.  Since the name of the
+/// comparator a table was written with is recorded in its properties,
+/// a registry lets readers find the comparator that orders the keys of
+/// an existing table or database.
+pub struct ComparatorRegistry {
+    comparators: HashMap<String, Arc<dyn SliceComparator>>,
+}
+
+impl ComparatorRegistry {
+    /// Create a registry holding the builtin comparators.
+    pub fn new() -> ComparatorRegistry
+    {
+        let mut registry = ComparatorRegistry::empty();
+        registry.register(Arc::new(BytewiseComparator));
+        registry.register(Arc::new(ReverseBytewiseComparator));
+        registry.register(Arc::new(BigEndianU64Comparator));
+        registry
+    }
+
+    /// Create a registry without any comparators.
+    pub fn empty() -> ComparatorRegistry
+    {
+        ComparatorRegistry {
+            comparators: HashMap::new(),
+        }
+    }
+
+    /// Register "comparator" under its name, replacing any comparator
+    /// previously registered under that name.
+    pub fn register(&mut self, comparator: Arc<dyn SliceComparator>)
+    {
+        self.comparators.insert(comparator.name().to_string(), comparator);
+    }
+
+    /// Return the comparator named "name", if one is registered.
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn SliceComparator>>
+    {
+        self.comparators.get(name)
+    }
+}
+
+impl Default for ComparatorRegistry {
+    fn default() -> ComparatorRegistry
+    {
+        ComparatorRegistry::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bytewise_separator, bytewise_successor, BigEndianU64Comparator,
+                BytewiseComparator, ComparatorRegistry, ReverseBytewiseComparator,
+                SliceComparator};
+
+    fn encode(value: u64) -> Vec<u8>
+    {
+        value.to_be_bytes().to_vec()
+    }
+
+    #[test]
+    fn separator()
+    {
+        assert_eq!(bytewise_separator(b"abcd", b"abzz"), Some(b"abd".to_vec()));
+        assert_eq!(bytewise_separator(b"the quick brown fox", b"the who"),
+                   Some(b"the r".to_vec()));
+
+        // One string is a prefix of the other
+        assert_eq!(bytewise_separator(b"abc", b"abcdef"), None);
+        assert_eq!(bytewise_separator(b"abcdef", b"abc"), None);
+        assert_eq!(bytewise_separator(b"abc", b"abc"), None);
+        assert_eq!(bytewise_separator(b"", b"abc"), None);
+
+        // Adjacent bytes leave no room for a shorter separator
+        assert_eq!(bytewise_separator(b"abc1", b"abc2"), None);
+        assert_eq!(bytewise_separator(b"abc1xyz", b"abc2"), None);
+        assert_eq!(bytewise_separator(b"a\xff\xff", b"b"), None);
+
+        // A shared 0xff prefix is kept and the first differing byte incremented
+        assert_eq!(bytewise_separator(b"\xff\x01", b"\xff\x03"), Some(b"\xff\x02".to_vec()));
+        assert_eq!(bytewise_separator(b"\x00\x00", b"\x00\x02"), Some(b"\x00\x01".to_vec()));
+    }
+
+    #[test]
+    fn successor()
+    {
+        assert_eq!(bytewise_successor(b"abc"), Some(b"b".to_vec()));
+        assert_eq!(bytewise_successor(b"\xff\xffabc"), Some(b"\xff\xffb".to_vec()));
+        assert_eq!(bytewise_successor(b"\x00"), Some(b"\x01".to_vec()));
+        assert_eq!(bytewise_successor(b"\xff\xfe\xff"), Some(b"\xff\xff".to_vec()));
+
+        // Runs of 0xff and empty keys are left alone
+        assert_eq!(bytewise_successor(b"\xff"), None);
+        assert_eq!(bytewise_successor(b"\xff\xff\xff"), None);
+        assert_eq!(bytewise_successor(b""), None);
+    }
+
+    #[test]
+    fn bytewise_shortening()
+    {
+        let comparator = BytewiseComparator;
+        let mut start = b"helloworld".to_vec();
+        comparator.find_shortest_separator(&mut start, b"hellozzz");
+        assert_eq!(start, b"hellox");
+
+        let mut start = b"hello".to_vec();
+        comparator.find_shortest_separator(&mut start, b"helloworld");
+        assert_eq!(start, b"hello");
+
+        let mut key = b"\xff\xffhello".to_vec();
+        comparator.find_short_successor(&mut key);
+        assert_eq!(key, b"\xff\xffi");
+        let mut key = b"\xff\xff".to_vec();
+        comparator.find_short_successor(&mut key);
+        assert_eq!(key, b"\xff\xff");
+    }
+
+    /// A comparator that only overrides compare() and name() gets the
+    /// default shortening, checked against its own order.
+    struct ReverseComparator;
+
+    impl SliceComparator for ReverseComparator {
+        fn compare(&self, a: &[u8], b: &[u8]) -> i32
+        {
+            -BytewiseComparator.compare(a, b)
+        }
+
+        fn name(&self) -> &str
+        {
+            "test.ReverseComparator"
+        }
+    }
+
+    #[test]
+    fn default_shortening_respects_order()
+    {
+        let comparator = ReverseComparator;
+        // Bytewise shortening would produce keys out of order
+        let mut start = b"abz".to_vec();
+        comparator.find_shortest_separator(&mut start, b"abc");
+        assert_eq!(start, b"abz");
+        let mut key = b"abc".to_vec();
+        comparator.find_short_successor(&mut key);
+        assert_eq!(key, b"abc");
+    }
+
+    #[test]
+    fn big_endian_u64()
+    {
+        let comparator = BigEndianU64Comparator;
+        let values = [0, 1, 255, 256, 1 << 32, u64::MAX - 1, u64::MAX];
+        for (i, &a) in values.iter().enumerate() {
+            for (j, &b) in values.iter().enumerate() {
+                let r = comparator.compare(&encode(a), &encode(b));
+                assert_eq!(r.signum(), (i as i32 - j as i32).signum(), "{} vs {}", a, b);
+            }
+        }
+
+        let mut key = encode(1 << 32);
+        comparator.find_shortest_separator(&mut key, &encode(u64::MAX));
+        assert_eq!(key, encode(1 << 32));
+        comparator.find_short_successor(&mut key);
+        assert_eq!(key, encode(1 << 32));
+    }
+
+    /// Keys that are not 8 bytes long sort before or after the 8-byte
+    /// keys by their length, and bytewise among keys of their length.
+    #[test]
+    fn big_endian_u64_bad_keys()
+    {
+        let comparator = BigEndianU64Comparator;
+        let keys: [&[u8]; 8] = [b"", b"\x00", b"\x01", b"\xff", b"\x00\x00",
+                                b"\x00\x00\x00\x00\x00\x00\x00\x01",
+                                b"\x00\x00\x00\x00\x00\x00\x00\x00\x00",
+                                b"\x01\x00\x00\x00\x00\x00\x00\x00\x00"];
+        for (i, &a) in keys.iter().enumerate() {
+            for (j, &b) in keys.iter().enumerate() {
+                let r = comparator.compare(a, b);
+                assert_eq!(r.signum(), (i as i32 - j as i32).signum(), "{:?} vs {:?}", a, b);
+            }
+        }
+
+        // Unlike a bytewise compare, short keys stay ahead of 8-byte keys
+        let one = encode(1);
+        assert!(comparator.compare(b"\x01", &one) < 0);
+        assert!(BytewiseComparator.compare(b"\x01", &one) > 0);
+    }
+
+    #[test]
+    fn reverse_bytewise()
+    {
+        let comparator = ReverseBytewiseComparator;
+        assert!(comparator.compare(b"a", b"b") > 0);
+        assert!(comparator.compare(b"b", b"a") < 0);
+        assert!(comparator.compare(b"ab", b"a") < 0);
+        assert_eq!(comparator.compare(b"a", b"a"), 0);
+
+        // Shortened keys must stay in order
+        let mut start = b"abcdef".to_vec();
+        comparator.find_shortest_separator(&mut start, b"abc");
+        assert!(comparator.compare(b"abcdef", &start) <= 0);
+        assert!(comparator.compare(&start, b"abc") < 0);
+    }
+
+    #[test]
+    fn registry()
+    {
+        let registry = ComparatorRegistry::new();
+        for name in &["leveldb.BytewiseComparator", "rocksdb.ReverseBytewiseComparator",
+                      "rubbledb.BigEndianU64Comparator"] {
+            assert_eq!(registry.get(name).unwrap().name(), *name);
+        }
+        assert!(registry.get("unknown").is_none());
+        assert!(ComparatorRegistry::empty().get("leveldb.BytewiseComparator").is_none());
+    }
 }
@@ -0,0 +1,571 @@
+//! The format of the keys the DB stores internally.  Every user key is
+//! stored as an internal key: the user key followed by an 8-byte tag
+//! holding the sequence number of the write and the type of the entry.
+
+use ::comparator::SliceComparator;
+use ::filter_policy::FilterPolicy;
+use ::slice::Slice;
+use ::util::coding;
+use std::fmt;
+use std::sync::Arc;
+
+pub type SequenceNumber = u64;
+
+/// We leave eight bits empty at the bottom so a type and sequence#
+/// can be packed together into 64-bits.
+pub const MAX_SEQUENCE_NUMBER: SequenceNumber = (1 << 56) - 1;
+
+/// Value types encoded as the last component of internal keys.
+/// DO NOT CHANGE THESE ENUM VALUES: they are embedded in the on-disk
+/// data structures.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueType {
+    Deletion = 0x0,
+    Value = 0x1,
+}
+
+/// VALUE_TYPE_FOR_SEEK defines the ValueType that should be passed when
+/// constructing a key for seeking to a particular sequence number
+/// (since we sort sequence numbers in decreasing order and the value
+/// type is embedded as the low 8 bits in the sequence number in
+/// internal keys, we need to use the highest-numbered ValueType, not
+/// the lowest).
+pub const VALUE_TYPE_FOR_SEEK: ValueType = ValueType::Value;
+
+impl ValueType {
+    pub fn from_u8(value_type: u8) -> Option<ValueType>
+    {
+        match value_type {
+            0x0 => Some(ValueType::Deletion),
+            0x1 => Some(ValueType::Value),
+            _ => None,
+        }
+    }
+}
+
+/// Size of the tag that follows the user key in an internal key
+pub const TAG_SIZE: usize = 8;
+
+/// Prefix of the name of every InternalKeyComparator, which goes on
+/// with the name of the user comparator it wraps, so that a table
+/// opened with another user comparator is caught.  Tables built with a
+/// comparator named this way hold internal keys.
+pub const INTERNAL_KEY_COMPARATOR_NAME_PREFIX: &str = "leveldb.InternalKeyComparator:";
+
+/// An internal key split into its parts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ParsedInternalKey<'a> {
+    pub user_key: Slice<'a>,
+    pub sequence: SequenceNumber,
+    pub value_type: ValueType,
+}
+
+impl<'a> ParsedInternalKey<'a> {
+    pub fn new(user_key: Slice<'a>, sequence: SequenceNumber, value_type: ValueType)
+               -> ParsedInternalKey<'a>
+    {
+        ParsedInternalKey {
+            user_key,
+            sequence,
+            value_type,
+        }
+    }
+
+    /// Return the length of the encoding of this key.
+    pub fn encoding_length(&self) -> usize
+    {
+        self.user_key.len() + TAG_SIZE
+    }
+}
+
+impl<'a> fmt::Display for ParsedInternalKey<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "'{}' @ {} : {}", String::from_utf8_lossy(self.user_key), self.sequence,
+               self.value_type as u8)
+    }
+}
+
+/// Attempt to parse an internal key from "internal_key".  Returns None
+/// if the key is malformed.
+pub fn parse_internal_key(internal_key: Slice) -> Option<ParsedInternalKey>
+{
+    if internal_key.len() < TAG_SIZE {
+        return None;
+    }
+    let num = extract_tag(internal_key);
+    ValueType::from_u8((num & 0xff) as u8).map(|value_type| {
+        ParsedInternalKey::new(extract_user_key(internal_key), num >> 8, value_type)
+    })
+}
+
+pub fn pack_sequence_and_type(seq: SequenceNumber, value_type: ValueType) -> u64
+{
+    assert!(seq <= MAX_SEQUENCE_NUMBER);
+    (seq << 8) | value_type as u64
+}
+
+/// Append the internal key for "user_key", "seq" and "value_type" to
+/// "result".
+pub fn append_internal_key(result: &mut Vec<u8>, user_key: Slice, seq: SequenceNumber,
+                           value_type: ValueType)
+{
+    result.extend_from_slice(user_key);
+    coding::put_fixed64(result, pack_sequence_and_type(seq, value_type));
+}
+
+/// Returns the user key portion of an internal key.
+pub fn extract_user_key(internal_key: Slice) -> Slice
+{
+    assert!(internal_key.len() >= TAG_SIZE);
+    &internal_key[..internal_key.len() - TAG_SIZE]
+}
+
+/// Returns the tag (sequence number and value type) of an internal key.
+pub fn extract_tag(internal_key: Slice) -> u64
+{
+    assert!(internal_key.len() >= TAG_SIZE);
+    coding::decode_fixed64(&internal_key[internal_key.len() - TAG_SIZE..])
+}
+
+/// A comparator for internal keys that uses a specified comparator for
+/// the user key portion and breaks ties by decreasing sequence number.
+#[derive(Clone)]
+pub struct InternalKeyComparator {
+    user_comparator: Arc<dyn SliceComparator>,
+    name: String,
+}
+
+impl InternalKeyComparator {
+    pub fn new(user_comparator: Arc<dyn SliceComparator>) -> InternalKeyComparator
+    {
+        InternalKeyComparator {
+            name: format!("{}{}", INTERNAL_KEY_COMPARATOR_NAME_PREFIX, user_comparator.name()),
+            user_comparator,
+        }
+    }
+
+    pub fn user_comparator(&self) -> &Arc<dyn SliceComparator>
+    {
+        &self.user_comparator
+    }
+
+    pub fn compare_keys(&self, a: &InternalKey, b: &InternalKey) -> i32
+    {
+        self.compare(a.encode(), b.encode())
+    }
+}
+
+impl SliceComparator for InternalKeyComparator {
+    fn compare(&self, akey: Slice, bkey: Slice) -> i32
+    {
+        // Order by:
+        //    increasing user key (according to user-supplied comparator)
+        //    decreasing sequence number
+        //    decreasing type (though sequence# should be enough to disambiguate)
+        let r = self.user_comparator.compare(extract_user_key(akey), extract_user_key(bkey));
+        if r != 0 {
+            return r;
+        }
+        let anum = extract_tag(akey);
+        let bnum = extract_tag(bkey);
+        if anum > bnum {
+            -1
+        } else if anum < bnum {
+            1
+        } else {
+            0
+        }
+    }
+
+    fn name(&self) -> &str
+    {
+        &self.name
+    }
+
+    fn find_shortest_separator(&self, start: &mut Vec<u8>, limit: Slice)
+    {
+        // Attempt to shorten the user portion of the key
+        let user_start = extract_user_key(start).to_vec();
+        let user_limit = extract_user_key(limit);
+        let mut tmp = user_start.clone();
+        self.user_comparator.find_shortest_separator(&mut tmp, user_limit);
+        if tmp.len() < user_start.len() && self.user_comparator.compare(&user_start, &tmp) < 0 {
+            // User key has become shorter physically, but larger logically.
+            // Tack on the earliest possible number to the shortened user key.
+            coding::put_fixed64(&mut tmp,
+                                pack_sequence_and_type(MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK));
+            assert!(self.compare(start, &tmp) < 0);
+            assert!(self.compare(&tmp, limit) < 0);
+            *start = tmp;
+        }
+    }
+
+    fn find_short_successor(&self, key: &mut Vec<u8>)
+    {
+        let user_key = extract_user_key(key).to_vec();
+        let mut tmp = user_key.clone();
+        self.user_comparator.find_short_successor(&mut tmp);
+        if tmp.len() < user_key.len() && self.user_comparator.compare(&user_key, &tmp) < 0 {
+            // User key has become shorter physically, but larger logically.
+            // Tack on the earliest possible number to the shortened user key.
+            coding::put_fixed64(&mut tmp,
+                                pack_sequence_and_type(MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK));
+            assert!(self.compare(key, &tmp) < 0);
+            *key = tmp;
+        }
+    }
+}
+
+/// Filter policy wrapper that converts from internal keys to user keys,
+/// so that the filters of a table of internal keys hold user keys and
+/// match lookups of any sequence number.  Prefixes are already taken
+/// from user keys, so they are passed through as they are.
+pub struct InternalFilterPolicy {
+    user_policy: Arc<dyn FilterPolicy>,
+}
+
+impl InternalFilterPolicy {
+    pub fn new(user_policy: Arc<dyn FilterPolicy>) -> InternalFilterPolicy
+    {
+        InternalFilterPolicy {
+            user_policy,
+        }
+    }
+}
+
+impl FilterPolicy for InternalFilterPolicy {
+    fn name(&self) -> &str
+    {
+        self.user_policy.name()
+    }
+
+    fn create_filter(&self, keys: &[Slice], dst: &mut Vec<u8>)
+    {
+        let user_keys: Vec<Slice> = keys.iter().map(|key| extract_user_key(key)).collect();
+        self.user_policy.create_filter(&user_keys, dst);
+    }
+
+    fn key_may_match(&self, key: Slice, filter: Slice) -> bool
+    {
+        self.user_policy.key_may_match(extract_user_key(key), filter)
+    }
+
+    fn create_filter_with_prefixes(&self, keys: &[Slice], prefixes: &[Slice], dst: &mut Vec<u8>)
+    {
+        let user_keys: Vec<Slice> = keys.iter().map(|key| extract_user_key(key)).collect();
+        self.user_policy.create_filter_with_prefixes(&user_keys, prefixes, dst);
+    }
+
+    fn prefix_may_match(&self, prefix: Slice, filter: Slice) -> bool
+    {
+        self.user_policy.prefix_may_match(prefix, filter)
+    }
+}
+
+/// Code above the table layer should keep internal keys wrapped inside
+/// the following type instead of plain byte vectors so that we do not
+/// incorrectly use byte vector comparisons instead of an
+/// InternalKeyComparator.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InternalKey {
+    rep: Vec<u8>,
+}
+
+impl InternalKey {
+    /// Leave rep as empty to indicate it is invalid
+    pub fn empty() -> InternalKey
+    {
+        InternalKey {
+            rep: vec![],
+        }
+    }
+
+    pub fn new(user_key: Slice, seq: SequenceNumber, value_type: ValueType) -> InternalKey
+    {
+        let mut rep = Vec::with_capacity(user_key.len() + TAG_SIZE);
+        append_internal_key(&mut rep, user_key, seq, value_type);
+        InternalKey {
+            rep,
+        }
+    }
+
+    /// Replace the key with the encoded internal key "s".  Returns false
+    /// if "s" is empty.
+    pub fn decode_from(&mut self, s: Slice) -> bool
+    {
+        self.rep.clear();
+        self.rep.extend_from_slice(s);
+        !self.rep.is_empty()
+    }
+
+    pub fn encode(&self) -> Slice<'_>
+    {
+        assert!(!self.rep.is_empty());
+        &self.rep
+    }
+
+    pub fn user_key(&self) -> Slice<'_>
+    {
+        extract_user_key(&self.rep)
+    }
+
+    pub fn set_from(&mut self, p: &ParsedInternalKey)
+    {
+        self.rep.clear();
+        append_internal_key(&mut self.rep, p.user_key, p.sequence, p.value_type);
+    }
+
+    pub fn clear(&mut self)
+    {
+        self.rep.clear();
+    }
+}
+
+impl fmt::Display for InternalKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match parse_internal_key(&self.rep) {
+            Some(parsed) => write!(f, "{}", parsed),
+            None => write!(f, "(bad){}", String::from_utf8_lossy(&self.rep)),
+        }
+    }
+}
+
+/// A helper class useful for looking up a key in a memtable or a table
+/// of internal keys.
+pub struct LookupKey {
+    /// We construct a byte array of the form:
+    ///    klength  varint32               <-- start
+    ///    userkey  char[klength]          <-- kstart
+    ///    tag      uint64
+    ///                                    <-- end
+    /// The array is a suitable MemTable key.
+    /// The suffix starting with "userkey" can be used as an InternalKey.
+    data: Vec<u8>,
+    kstart: usize,
+}
+
+impl LookupKey {
+    /// Initialize for looking up user_key at a snapshot with the
+    /// specified sequence number.
+    pub fn new(user_key: Slice, sequence: SequenceNumber) -> LookupKey
+    {
+        let internal_key_size = user_key.len() + TAG_SIZE;
+        let mut data = Vec::with_capacity(internal_key_size + 5);
+        let kstart = coding::put_varint32(&mut data, internal_key_size as u32);
+        append_internal_key(&mut data, user_key, sequence, VALUE_TYPE_FOR_SEEK);
+        LookupKey {
+            data,
+            kstart,
+        }
+    }
+
+    /// Return a key suitable for lookup in a MemTable.
+    pub fn memtable_key(&self) -> Slice<'_>
+    {
+        &self.data
+    }
+
+    /// Return an internal key (suitable for passing to an internal
+    /// iterator)
+    pub fn internal_key(&self) -> Slice<'_>
+    {
+        &self.data[self.kstart..]
+    }
+
+    /// Return the user key
+    pub fn user_key(&self) -> Slice<'_>
+    {
+        &self.data[self.kstart..self.data.len() - TAG_SIZE]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::comparator::BytewiseComparator;
+
+    fn ikey(user_key: &[u8], seq: SequenceNumber, value_type: ValueType) -> Vec<u8>
+    {
+        let mut encoded = vec![];
+        append_internal_key(&mut encoded, user_key, seq, value_type);
+        encoded
+    }
+
+    fn comparator() -> InternalKeyComparator
+    {
+        InternalKeyComparator::new(Arc::new(BytewiseComparator))
+    }
+
+    fn shorten(start: Vec<u8>, limit: Vec<u8>) -> Vec<u8>
+    {
+        let mut result = start;
+        comparator().find_shortest_separator(&mut result, &limit);
+        result
+    }
+
+    fn short_successor(key: Vec<u8>) -> Vec<u8>
+    {
+        let mut result = key;
+        comparator().find_short_successor(&mut result);
+        result
+    }
+
+    fn test_key(user_key: &[u8], seq: SequenceNumber, value_type: ValueType)
+    {
+        let encoded = ikey(user_key, seq, value_type);
+        let decoded = parse_internal_key(&encoded).unwrap();
+        assert_eq!(decoded, ParsedInternalKey::new(user_key, seq, value_type));
+        assert_eq!(decoded.encoding_length(), encoded.len());
+        assert_eq!(extract_user_key(&encoded), user_key);
+        assert_eq!(extract_tag(&encoded), pack_sequence_and_type(seq, value_type));
+
+        let internal_key = InternalKey::new(user_key, seq, value_type);
+        assert_eq!(internal_key.encode(), &encoded[..]);
+        assert_eq!(internal_key.user_key(), user_key);
+    }
+
+    #[test]
+    fn internal_key_encode_decode()
+    {
+        let keys: [&[u8]; 4] = [b"", b"k", b"hello", b"longggggggggggggggggggggg"];
+        let seqs = [1, 2, 3, (1 << 8) - 1, 1 << 8, (1 << 8) + 1, (1 << 16) - 1, 1 << 16,
+                    (1 << 16) + 1, (1 << 32) - 1, 1 << 32, (1 << 32) + 1, MAX_SEQUENCE_NUMBER];
+        for key in &keys {
+            for &seq in &seqs {
+                test_key(key, seq, ValueType::Value);
+            }
+        }
+        test_key(b"hello", 1, ValueType::Deletion);
+        assert!(parse_internal_key(b"bar").is_none());
+    }
+
+    #[test]
+    fn bad_value_type()
+    {
+        let mut encoded = ikey(b"foo", 100, ValueType::Value);
+        let last = encoded.len() - TAG_SIZE;
+        encoded[last] = 0x7f;
+        assert!(parse_internal_key(&encoded).is_none());
+        let mut internal_key = InternalKey::empty();
+        assert!(internal_key.decode_from(&encoded));
+        assert!(internal_key.to_string().starts_with("(bad)"));
+    }
+
+    /// Internal keys are ordered by increasing user key, then by
+    /// decreasing sequence number, then by decreasing value type.
+    #[test]
+    fn internal_key_ordering()
+    {
+        let ordered = [
+            ikey(b"a", 100, ValueType::Value),
+            ikey(b"a", 100, ValueType::Deletion),
+            ikey(b"a", 99, ValueType::Value),
+            ikey(b"a", 0, ValueType::Deletion),
+            ikey(b"aa", MAX_SEQUENCE_NUMBER, ValueType::Value),
+            ikey(b"aa", 1, ValueType::Value),
+            ikey(b"b", 200, ValueType::Value),
+        ];
+        let comparator = comparator();
+        for (i, a) in ordered.iter().enumerate() {
+            for (j, b) in ordered.iter().enumerate() {
+                let r = comparator.compare(a, b);
+                assert_eq!(r.signum(), (i as i32 - j as i32).signum(), "{} vs {}", i, j);
+            }
+        }
+        let a = InternalKey::new(b"a", 2, ValueType::Value);
+        let b = InternalKey::new(b"a", 1, ValueType::Value);
+        assert!(comparator.compare_keys(&a, &b) < 0);
+        assert_eq!(comparator.name(), "leveldb.InternalKeyComparator:leveldb.BytewiseComparator");
+    }
+
+    #[test]
+    fn internal_key_short_separator()
+    {
+        // When user keys are same
+        assert_eq!(shorten(ikey(b"foo", 100, ValueType::Value), ikey(b"foo", 99, ValueType::Value)),
+                   ikey(b"foo", 100, ValueType::Value));
+        assert_eq!(shorten(ikey(b"foo", 100, ValueType::Value), ikey(b"foo", 101, ValueType::Value)),
+                   ikey(b"foo", 100, ValueType::Value));
+        assert_eq!(shorten(ikey(b"foo", 100, ValueType::Value), ikey(b"foo", 100, ValueType::Value)),
+                   ikey(b"foo", 100, ValueType::Value));
+        assert_eq!(shorten(ikey(b"foo", 100, ValueType::Value),
+                           ikey(b"foo", 100, ValueType::Deletion)),
+                   ikey(b"foo", 100, ValueType::Value));
+
+        // When user keys are misordered
+        assert_eq!(shorten(ikey(b"foo", 100, ValueType::Value), ikey(b"bar", 99, ValueType::Value)),
+                   ikey(b"foo", 100, ValueType::Value));
+
+        // When user keys are different, but correctly ordered
+        assert_eq!(shorten(ikey(b"foo", 100, ValueType::Value),
+                           ikey(b"hello", 200, ValueType::Value)),
+                   ikey(b"g", MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK));
+
+        // When start user key is prefix of limit user key
+        assert_eq!(shorten(ikey(b"foo", 100, ValueType::Value),
+                           ikey(b"foobar", 200, ValueType::Value)),
+                   ikey(b"foo", 100, ValueType::Value));
+
+        // When limit user key is prefix of start user key
+        assert_eq!(shorten(ikey(b"foobar", 100, ValueType::Value),
+                           ikey(b"foo", 200, ValueType::Value)),
+                   ikey(b"foobar", 100, ValueType::Value));
+    }
+
+    #[test]
+    fn internal_key_shortest_successor()
+    {
+        assert_eq!(short_successor(ikey(b"foo", 100, ValueType::Value)),
+                   ikey(b"g", MAX_SEQUENCE_NUMBER, VALUE_TYPE_FOR_SEEK));
+        assert_eq!(short_successor(ikey(b"\xff\xff", 100, ValueType::Value)),
+                   ikey(b"\xff\xff", 100, ValueType::Value));
+    }
+
+    /// Shortened keys stay valid internal keys that keep their place in
+    /// the order.
+    #[test]
+    fn shortened_keys_are_internal_keys()
+    {
+        let comparator = comparator();
+        let keys = [ikey(b"abc", 5, ValueType::Value), ikey(b"abd", 100, ValueType::Deletion),
+                    ikey(b"abzzz", 7, ValueType::Value), ikey(b"b", 1, ValueType::Value),
+                    ikey(b"zz", 3, ValueType::Deletion)];
+        for pair in keys.windows(2) {
+            let separator = shorten(pair[0].clone(), pair[1].clone());
+            assert!(parse_internal_key(&separator).is_some());
+            assert!(comparator.compare(&pair[0], &separator) <= 0);
+            assert!(comparator.compare(&separator, &pair[1]) < 0);
+        }
+        for key in &keys {
+            let successor = short_successor(key.clone());
+            assert!(parse_internal_key(&successor).is_some());
+            assert!(comparator.compare(key, &successor) <= 0);
+        }
+    }
+
+    #[test]
+    fn lookup_key()
+    {
+        for &(user_key, seq) in &[(&b""[..], 0), (b"foo", 100), (&[b'x'; 200][..], 1 << 40)] {
+            let lookup = LookupKey::new(user_key, seq);
+            let internal_key = ikey(user_key, seq, VALUE_TYPE_FOR_SEEK);
+            assert_eq!(lookup.user_key(), user_key);
+            assert_eq!(lookup.internal_key(), &internal_key[..]);
+
+            // The memtable key is the internal key prefixed by its length
+            let (rest, len) = coding::get_varint32(lookup.memtable_key()).unwrap();
+            assert_eq!(len as usize, internal_key.len());
+            assert_eq!(rest, &internal_key[..]);
+        }
+
+        // A lookup sorts before every entry of its user key that it can
+        // see, and after every entry it cannot
+        let comparator = comparator();
+        let lookup = LookupKey::new(b"foo", 100);
+        assert!(comparator.compare(lookup.internal_key(), &ikey(b"foo", 100, ValueType::Value)) <= 0);
+        assert!(comparator.compare(lookup.internal_key(), &ikey(b"foo", 99, ValueType::Value)) < 0);
+        assert!(comparator.compare(lookup.internal_key(), &ikey(b"foo", 101, ValueType::Value)) > 0);
+    }
+}
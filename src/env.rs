@@ -0,0 +1,42 @@
+//! The environment rubbledb uses to access the file system.  For now
+//! this only covers the random-access files tables are read from.
+
+use ::errors::RubbleResult;
+use memmap::Mmap;
+use std::fs::File;
+use std::io::{Cursor, Read, Seek};
+
+/// A file abstraction for randomly reading the contents of a file.
+///
+/// Besides being read through Read + Seek, a file may offer a
+/// read-only memory map of its contents.  Tables opened with
+/// Options::allow_mmap_reads serve blocks straight out of the mapping
+/// when one is available, and fall back to reading otherwise.
+pub trait RandomAccessFile: Read + Seek {
+    /// Map the whole file into memory, or return None if this kind of
+    /// file cannot be mapped.
+    fn mmap(&self) -> RubbleResult<Option<Mmap>>
+    {
+        Ok(None)
+    }
+}
+
+impl RandomAccessFile for File {
+    fn mmap(&self) -> RubbleResult<Option<Mmap>>
+    {
+        // The mapping is only sound as long as the file is not
+        // modified while it is mapped.  Table files are immutable once
+        // written, so this holds for every file a table is read from.
+        let mapping = unsafe { Mmap::map(self)? };
+        Ok(Some(mapping))
+    }
+}
+
+impl RandomAccessFile for &File {
+    fn mmap(&self) -> RubbleResult<Option<Mmap>>
+    {
+        (**self).mmap()
+    }
+}
+
+impl<T: AsRef<[u8]>> RandomAccessFile for Cursor<T> {}
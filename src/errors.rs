@@ -31,3 +31,14 @@ quick_error! {
         }
     }
 }
+
+impl From<RubbleError> for Status {
+    fn from(err: RubbleError) -> Status
+    {
+        match err {
+            RubbleError::Status(status) => status,
+            RubbleError::Io(err) => Status::IOError(format!("{}", err)),
+            err => Status::Corruption(format!("{}", err)),
+        }
+    }
+}
@@ -44,7 +44,7 @@ pub fn log_file_name(name: &str, number: u64) -> String
 pub fn table_file_name(name: &str, number: u64) -> String
 {
     assert!(number > 0);
-    return make_file_name(name, number, "ldb");
+    make_file_name(name, number, "ldb")
 }
 
 
@@ -121,12 +121,12 @@ fn parse_file_name(fname: &str) -> RubbleResult<FileNameDetails>
         },
         name if name.starts_with("MANIFEST-") => {
             FileNameDetails {
-                number: try!(util::coding::parse_u64(&fname["MANIFEST-".len()..])).number,
+                number: util::coding::parse_u64(&fname["MANIFEST-".len()..])?.number,
                 file_type: FileType::DescriptorFile,
             }
         }
         _ => {
-            let parsed = try!(util::coding::parse_u64(fname));
+            let parsed = util::coding::parse_u64(fname)?;
             let suffix = &fname[parsed.offset..];
             FileNameDetails {
                 number: parsed.number,
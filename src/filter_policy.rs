@@ -0,0 +1,51 @@
+use ::slice::Slice;
+
+/// A database can be configured with a custom FilterPolicy object.
+/// This object is responsible for creating a small filter from a set
+/// of keys.  These filters are stored in rubbledb and are consulted
+/// automatically by rubbledb to decide whether or not to read some
+/// information from disk. In many cases, a filter can cut down the
+/// number of disk seeks form a handful to a single disk seek per
+/// DB::Get() call.
+///
+/// Most people will want to use the builtin bloom filter support (see
+/// util::bloom::BloomFilterPolicy).
+pub trait FilterPolicy: Send + Sync {
+    /// Return the name of this policy.  Note that if the filter encoding
+    /// changes in an incompatible way, the name returned by this method
+    /// must be changed.  Otherwise, old incompatible filters may be
+    /// passed to methods of this type.
+    fn name(&self) -> &str;
+
+    /// keys contains a list of keys (potentially with duplicates)
+    /// that are ordered according to the user supplied comparator.
+    /// Append a filter that summarizes keys to "dst".
+    ///
+    /// Warning: do not change the initial contents of "dst".  Instead,
+    /// append the newly constructed filter to "dst".
+    fn create_filter(&self, keys: &[Slice], dst: &mut Vec<u8>);
+
+    /// "filter" contains the data appended by a preceding call to
+    /// create_filter() on this policy.  This method must return true if
+    /// the key was in the list of keys passed to create_filter().
+    /// This method may return true or false if the key was not on the
+    /// list, but it should aim to return false with a high probability.
+    fn key_may_match(&self, key: Slice, filter: Slice) -> bool;
+
+    /// Like create_filter(), but the filter must also match "prefixes",
+    /// the prefixes Options::prefix_extractor extracted from the user
+    /// keys of the table.  Prefixes are never internal keys, even in a
+    /// table of internal keys.  The default adds them to the keys.
+    fn create_filter_with_prefixes(&self, keys: &[Slice], prefixes: &[Slice], dst: &mut Vec<u8>)
+    {
+        let entries: Vec<Slice> = keys.iter().chain(prefixes).cloned().collect();
+        self.create_filter(&entries, dst);
+    }
+
+    /// Like key_may_match(), for a prefix passed to
+    /// create_filter_with_prefixes().
+    fn prefix_may_match(&self, prefix: Slice, filter: Slice) -> bool
+    {
+        self.key_may_match(prefix, filter)
+    }
+}
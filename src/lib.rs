@@ -6,10 +6,17 @@
 #[macro_use] extern crate quick_error;
 extern crate regex;
 extern crate byteorder;
-extern crate snappy;
+extern crate snap;
 extern crate itertools;
+extern crate memmap;
+extern crate lz4;
+extern crate zstd;
 
+pub mod env;
 pub mod filename;
+pub mod log_format;
+pub mod log_reader;
+pub mod log_writer;
 pub mod errors;
 pub mod status;
 pub mod slice;
@@ -17,4 +24,10 @@ pub mod util;
 pub mod table;
 pub mod port;
 pub mod comparator;
+pub mod filter_policy;
+pub mod slice_transform;
 pub mod options;
+pub mod dbformat;
+pub mod skiplist;
+pub mod memtable;
+pub mod write_batch;
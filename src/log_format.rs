@@ -0,0 +1,41 @@
+//! Log format information shared by reader and writer.
+//!
+//! The log file contents are a sequence of 32KB blocks.  The only
+//! exception is that the tail of the file may contain a partial block.
+//!
+//! Each block consists of a sequence of records:
+//!     block := record* trailer?
+//!     record :=
+//!         checksum: uint32     // crc32c of type and data[] ; little-endian
+//!         length: uint16       // little-endian
+//!         type: uint8          // One of FULL, FIRST, MIDDLE, LAST
+//!         data: uint8[length]
+//!
+//! A record never starts within the last six bytes of a block (since it
+//! won't fit).  Any leftover bytes here form the trailer, which must
+//! consist entirely of zero bytes and must be skipped by readers.
+//!
+//! A user record that does not fit in the rest of its block is split
+//! into fragments: a FIRST record, any number of MIDDLE records and a
+//! LAST record.  A user record that fits is stored as a single FULL
+//! record.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordType {
+    /// Zero is reserved for preallocated files
+    ZeroType = 0,
+
+    FullType = 1,
+
+    /// For fragments
+    FirstType = 2,
+    MiddleType = 3,
+    LastType = 4,
+}
+
+pub const MAX_RECORD_TYPE: u8 = RecordType::LastType as u8;
+
+pub const BLOCK_SIZE: usize = 32768;
+
+/// Header is checksum (4 bytes), length (2 bytes), type (1 byte).
+pub const HEADER_SIZE: usize = 4 + 2 + 1;
@@ -0,0 +1,650 @@
+use ::errors::RubbleResult;
+use ::filename;
+use ::log_format::{RecordType, BLOCK_SIZE, HEADER_SIZE};
+use ::slice::Slice;
+use ::status::Status;
+use ::util::{coding, crc32c};
+use std::fs::File;
+use std::io::{self, Read};
+
+/// Interface for reporting errors.
+pub trait Reporter {
+    /// Some corruption was detected.  "bytes" is the approximate number
+    /// of bytes dropped due to the corruption.
+    fn corruption(&mut self, bytes: usize, status: &Status);
+}
+
+/// Extend record types with the following special values
+enum PhysicalRecord {
+    Record(u8),
+    /// Returned whenever we find an invalid physical record.
+    /// Currently there are three situations in which this happens:
+    /// * The record has an invalid CRC (read_physical_record reports a drop)
+    /// * The record is a 0-length record (No drop is reported)
+    /// * The record is below constructor's initial_offset (No drop is reported)
+    BadRecord,
+    Eof,
+}
+
+/// Reads the records of a log file (see log_format.rs for the format),
+/// reassembling the ones that were split into fragments.
+pub struct Reader<R: Read> {
+    file: R,
+    reporter: Option<Box<dyn Reporter>>,
+    checksum: bool,
+    /// The last block read from the file
+    backing_store: Vec<u8>,
+    /// Offset in backing_store of the data not yet parsed
+    buffer_start: usize,
+    /// Last read() indicated EOF by returning < BLOCK_SIZE
+    eof: bool,
+
+    /// Offset of the last record returned by read_record.
+    last_record_offset: u64,
+    /// Offset of the first location past the end of buffer.
+    end_of_buffer_offset: u64,
+
+    /// Offset at which to start looking for the first record to return
+    initial_offset: u64,
+
+    /// True if we are resynchronizing after a seek (initial_offset > 0).
+    /// In particular, a run of MiddleType and LastType records can be
+    /// silently skipped in this mode
+    resyncing: bool,
+
+    /// The record returned by the last read_record()
+    record: Vec<u8>,
+}
+
+impl<R: Read> Reader<R> {
+    /// Create a reader that will return log records from "file".
+    ///
+    /// If "reporter" is set, it is notified whenever some data is
+    /// dropped due to a detected corruption.
+    ///
+    /// If "checksum" is true, verify checksums if available.
+    ///
+    /// The Reader will start reading at the first record located at
+    /// physical position >= initial_offset within the file.
+    pub fn new(file: R, reporter: Option<Box<dyn Reporter>>, checksum: bool, initial_offset: u64)
+               -> Reader<R>
+    {
+        Reader {
+            file,
+            reporter,
+            checksum,
+            backing_store: Vec::with_capacity(BLOCK_SIZE),
+            buffer_start: 0,
+            eof: false,
+            last_record_offset: 0,
+            end_of_buffer_offset: 0,
+            initial_offset,
+            resyncing: initial_offset > 0,
+            record: vec![],
+        }
+    }
+
+    /// Read the next record.  Returns None at the end of the input.
+    /// The returned record is only valid until the next read_record()
+    /// call on this reader.
+    pub fn read_record(&mut self) -> Option<Slice<'_>>
+    {
+        if self.last_record_offset < self.initial_offset && !self.skip_to_initial_block() {
+            return None;
+        }
+
+        self.record.clear();
+        let mut in_fragmented_record = false;
+        // Record offset of the logical record that we're reading
+        // 0 is a dummy value to make compilers happy
+        let mut prospective_record_offset = 0;
+
+        loop {
+            let (record_type, fragment_start, fragment_len) = self.read_physical_record();
+
+            if self.resyncing {
+                match record_type {
+                    PhysicalRecord::Record(t) if t == RecordType::MiddleType as u8 => continue,
+                    PhysicalRecord::Record(t) if t == RecordType::LastType as u8 => {
+                        self.resyncing = false;
+                        continue;
+                    },
+                    _ => self.resyncing = false,
+                }
+            }
+
+            let fragment = fragment_start..fragment_start + fragment_len;
+            match record_type {
+                PhysicalRecord::Record(t) if t == RecordType::FullType as u8 => {
+                    if in_fragmented_record && !self.record.is_empty() {
+                        // Handle bug in earlier versions of log::Writer
+                        // where it could emit an empty FirstType record
+                        // at the tail end of a block followed by a
+                        // FullType or FirstType record at the beginning
+                        // of the next block.
+                        let bytes = self.record.len();
+                        self.report_corruption(bytes, "partial record without end(1)");
+                    }
+                    prospective_record_offset = self.physical_record_offset(fragment_len);
+                    self.record.clear();
+                    self.record.extend_from_slice(&self.backing_store[fragment]);
+                    self.last_record_offset = prospective_record_offset;
+                    return Some(&self.record);
+                },
+
+                PhysicalRecord::Record(t) if t == RecordType::FirstType as u8 => {
+                    if in_fragmented_record && !self.record.is_empty() {
+                        // Handle bug in earlier versions of log::Writer
+                        // (see comment above)
+                        let bytes = self.record.len();
+                        self.report_corruption(bytes, "partial record without end(2)");
+                    }
+                    prospective_record_offset = self.physical_record_offset(fragment_len);
+                    self.record.clear();
+                    self.record.extend_from_slice(&self.backing_store[fragment]);
+                    in_fragmented_record = true;
+                },
+
+                PhysicalRecord::Record(t) if t == RecordType::MiddleType as u8 => {
+                    if !in_fragmented_record {
+                        self.report_corruption(fragment_len, "missing start of fragmented record(1)");
+                    } else {
+                        self.record.extend_from_slice(&self.backing_store[fragment]);
+                    }
+                },
+
+                PhysicalRecord::Record(t) if t == RecordType::LastType as u8 => {
+                    if !in_fragmented_record {
+                        self.report_corruption(fragment_len, "missing start of fragmented record(2)");
+                    } else {
+                        self.record.extend_from_slice(&self.backing_store[fragment]);
+                        self.last_record_offset = prospective_record_offset;
+                        return Some(&self.record);
+                    }
+                },
+
+                PhysicalRecord::Eof => {
+                    if in_fragmented_record {
+                        // This can be caused by the writer dying
+                        // immediately after writing a physical record
+                        // but before completing the next; don't treat it
+                        // as a corruption, just ignore the entire
+                        // logical record.
+                        self.record.clear();
+                    }
+                    return None;
+                },
+
+                PhysicalRecord::BadRecord => {
+                    if in_fragmented_record {
+                        let bytes = self.record.len();
+                        self.report_corruption(bytes, "error in middle of record");
+                        in_fragmented_record = false;
+                        self.record.clear();
+                    }
+                },
+
+                PhysicalRecord::Record(t) => {
+                    let bytes = fragment_len + if in_fragmented_record { self.record.len() } else { 0 };
+                    self.report_corruption(bytes, &format!("unknown record type {}", t));
+                    in_fragmented_record = false;
+                    self.record.clear();
+                },
+            }
+        }
+    }
+
+    /// Returns the physical offset of the last record returned by
+    /// read_record.
+    ///
+    /// Undefined before the first call to read_record.
+    pub fn last_record_offset(&self) -> u64
+    {
+        self.last_record_offset
+    }
+
+    /// Returns the offset of the physical record read_physical_record
+    /// just returned, given the length of its payload.  Only meaningful
+    /// after it returned a record: it may have only had an empty trailer
+    /// remaining in its internal buffer, or have hit the end of a short
+    /// file, in which case the buffer holds less than a header.
+    fn physical_record_offset(&self, fragment_len: usize) -> u64
+    {
+        self.end_of_buffer_offset
+            - self.buffer_len() as u64 - HEADER_SIZE as u64 - fragment_len as u64
+    }
+
+    fn buffer_len(&self) -> usize
+    {
+        self.backing_store.len() - self.buffer_start
+    }
+
+    fn clear_buffer(&mut self)
+    {
+        self.backing_store.clear();
+        self.buffer_start = 0;
+    }
+
+    /// Skips all blocks that are completely before "initial_offset".
+    ///
+    /// Returns true on success.  Handles reporting.
+    fn skip_to_initial_block(&mut self) -> bool
+    {
+        let offset_in_block = self.initial_offset % BLOCK_SIZE as u64;
+        let mut block_start_location = self.initial_offset - offset_in_block;
+
+        // Don't search a block if we'd be in the trailer
+        if offset_in_block > (BLOCK_SIZE - 6) as u64 {
+            block_start_location += BLOCK_SIZE as u64;
+        }
+
+        self.end_of_buffer_offset = block_start_location;
+
+        // Skip to start of first block that can contain the initial record
+        if block_start_location > 0 {
+            let skipped = io::copy(&mut (&mut self.file).take(block_start_location), &mut io::sink());
+            if let Err(err) = skipped {
+                let status = Status::IOError(format!("{}", err));
+                self.report_drop(block_start_location as usize, &status);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Fill the buffer with the next block of the file, or what is left
+    /// of the file if that is less than a block.
+    fn read_block(&mut self) -> RubbleResult<()>
+    {
+        self.clear_buffer();
+        self.backing_store.resize(BLOCK_SIZE, 0);
+        let mut filled = 0;
+        while filled < BLOCK_SIZE {
+            match self.file.read(&mut self.backing_store[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+                Err(err) => {
+                    self.clear_buffer();
+                    return Err(err.into());
+                },
+            }
+        }
+        self.backing_store.truncate(filled);
+        Ok(())
+    }
+
+    /// Return the type of the next physical record, and the offset and
+    /// length of its payload in backing_store.
+    fn read_physical_record(&mut self) -> (PhysicalRecord, usize, usize)
+    {
+        loop {
+            if self.buffer_len() < HEADER_SIZE {
+                if !self.eof {
+                    // Last read was a full read, so this is a trailer to skip
+                    let read = self.read_block();
+                    self.end_of_buffer_offset += self.buffer_len() as u64;
+                    if let Err(err) = read {
+                        self.clear_buffer();
+                        self.report_drop(BLOCK_SIZE, &err.into());
+                        self.eof = true;
+                        return (PhysicalRecord::Eof, 0, 0);
+                    } else if self.buffer_len() < BLOCK_SIZE {
+                        self.eof = true;
+                    }
+                    continue;
+                } else {
+                    // Note that if buffer is non-empty, we have a
+                    // truncated header at the end of the file, which can
+                    // be caused by the writer crashing in the middle of
+                    // writing the header.  Instead of considering this an
+                    // error, just report EOF.
+                    self.clear_buffer();
+                    return (PhysicalRecord::Eof, 0, 0);
+                }
+            }
+
+            // Parse the header
+            let header_start = self.buffer_start;
+            let header = &self.backing_store[header_start..];
+            let a = header[4] as usize;
+            let b = header[5] as usize;
+            let record_type = header[6];
+            let length = a | (b << 8);
+            if HEADER_SIZE + length > header.len() {
+                let drop_size = header.len();
+                self.clear_buffer();
+                if !self.eof {
+                    self.report_corruption(drop_size, "bad record length");
+                    return (PhysicalRecord::BadRecord, 0, 0);
+                }
+                // If the end of the file has been reached without
+                // reading |length| bytes of payload, assume the writer
+                // died in the middle of writing the record.  Don't
+                // report a corruption.
+                return (PhysicalRecord::Eof, 0, 0);
+            }
+
+            if record_type == RecordType::ZeroType as u8 && length == 0 {
+                // Skip zero length record without reporting any drops
+                // since such records are produced by the mmap based
+                // writing code in env_posix.cc that preallocates file
+                // regions.
+                self.clear_buffer();
+                return (PhysicalRecord::BadRecord, 0, 0);
+            }
+
+            // Check crc
+            if self.checksum {
+                let expected_crc = crc32c::unmask(coding::decode_fixed32(header));
+                let actual_crc = crc32c::value(&header[6..HEADER_SIZE + length]);
+                if actual_crc != expected_crc {
+                    // Drop the rest of the buffer since "length" itself
+                    // may have been corrupted and if we trust it, we
+                    // could find some fragment of a real log record that
+                    // just happens to look like a valid log record.
+                    let drop_size = header.len();
+                    self.clear_buffer();
+                    self.report_corruption(drop_size, "checksum mismatch");
+                    return (PhysicalRecord::BadRecord, 0, 0);
+                }
+            }
+
+            self.buffer_start += HEADER_SIZE + length;
+
+            // Skip physical record that started before initial_offset
+            let record_start = self.buffer_len() + HEADER_SIZE + length;
+            if self.end_of_buffer_offset - (record_start as u64) < self.initial_offset {
+                return (PhysicalRecord::BadRecord, 0, 0);
+            }
+
+            return (PhysicalRecord::Record(record_type), header_start + HEADER_SIZE, length);
+        }
+    }
+
+    /// Reports dropped bytes to the reporter.
+    fn report_corruption(&mut self, bytes: usize, reason: &str)
+    {
+        self.report_drop(bytes, &Status::Corruption(reason.into()));
+    }
+
+    fn report_drop(&mut self, bytes: usize, reason: &Status)
+    {
+        let buffer_len = self.buffer_len() as u64;
+        if let Some(ref mut reporter) = self.reporter {
+            if self.end_of_buffer_offset.saturating_sub(buffer_len + bytes as u64) >= self.initial_offset {
+                reporter.corruption(bytes, reason);
+            }
+        }
+    }
+}
+
+impl Reader<File> {
+    /// Open the log file numbered "number" in the db named "dbname"
+    /// and return a reader over its records (see Reader::new).
+    pub fn open(dbname: &str, number: u64, reporter: Option<Box<dyn Reporter>>, checksum: bool)
+                -> RubbleResult<Reader<File>>
+    {
+        let file = File::open(filename::log_file_name(dbname, number))?;
+        Ok(Reader::new(file, reporter, checksum, 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Reader, Reporter};
+    use ::log_format::{RecordType, BLOCK_SIZE, HEADER_SIZE};
+    use ::log_writer::Writer;
+    use ::status::Status;
+    use ::util::{coding, crc32c};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Dropped bytes and the reasons reported for them
+    #[derive(Default)]
+    struct Drops {
+        bytes: usize,
+        messages: String,
+    }
+
+    struct ReportCollector(Rc<RefCell<Drops>>);
+
+    impl Reporter for ReportCollector {
+        fn corruption(&mut self, bytes: usize, status: &Status)
+        {
+            let mut drops = self.0.borrow_mut();
+            drops.bytes += bytes;
+            drops.messages.push_str(&format!("{:?}", status));
+        }
+    }
+
+    /// Construct a string of the specified length made out of the
+    /// supplied partial string.
+    fn big_string(partial: &str, n: usize) -> Vec<u8>
+    {
+        partial.bytes().cycle().take(n).collect()
+    }
+
+    /// Construct a string from a number
+    fn number_string(n: usize) -> Vec<u8>
+    {
+        format!("{}.", n).into_bytes()
+    }
+
+    fn write(records: &[&[u8]]) -> Vec<u8>
+    {
+        let mut file = vec![];
+        {
+            let mut writer = Writer::new(&mut file);
+            for record in records {
+                writer.add_record(record).unwrap();
+            }
+        }
+        file
+    }
+
+    /// Read every record of "file", along with the bytes dropped while
+    /// reading it.
+    fn read_all(file: &[u8], initial_offset: u64) -> (Vec<Vec<u8>>, Rc<RefCell<Drops>>)
+    {
+        let drops = Rc::new(RefCell::new(Drops::default()));
+        let reporter = Box::new(ReportCollector(drops.clone()));
+        let mut reader = Reader::new(file, Some(reporter), true, initial_offset);
+        let mut records = vec![];
+        while let Some(record) = reader.read_record() {
+            records.push(record.to_vec());
+        }
+        // The end of the input stays the end
+        assert!(reader.read_record().is_none());
+        (records, drops)
+    }
+
+    /// Overwrite the type of the record at "offset" and fix its checksum
+    fn set_record_type(file: &mut [u8], offset: usize, record_type: u8)
+    {
+        file[offset + 6] = record_type;
+        fix_checksum(file, offset);
+    }
+
+    fn fix_checksum(file: &mut [u8], offset: usize)
+    {
+        let len = file[offset + 4] as usize | (file[offset + 5] as usize) << 8;
+        let crc = crc32c::value(&file[offset + 6..offset + HEADER_SIZE + len]);
+        coding::encode_fixed32(&mut file[offset..], crc32c::mask(crc));
+    }
+
+    #[test]
+    fn empty()
+    {
+        let (records, drops) = read_all(&[], 0);
+        assert!(records.is_empty());
+        assert_eq!(drops.borrow().bytes, 0);
+    }
+
+    #[test]
+    fn read_write()
+    {
+        let written: [&[u8]; 4] = [b"foo", b"bar", b"", b"xxxx"];
+        let (records, drops) = read_all(&write(&written), 0);
+        assert_eq!(records, written);
+        assert_eq!(drops.borrow().bytes, 0);
+    }
+
+    #[test]
+    fn many_blocks()
+    {
+        let written: Vec<Vec<u8>> = (0..100000).map(number_string).collect();
+        let file = write(&written.iter().map(|r| &r[..]).collect::<Vec<_>>());
+        assert!(file.len() > 10 * BLOCK_SIZE);
+        let (records, drops) = read_all(&file, 0);
+        assert_eq!(records, written);
+        assert_eq!(drops.borrow().bytes, 0);
+    }
+
+    #[test]
+    fn fragmentation()
+    {
+        let written = [b"small".to_vec(), big_string("medium", 50000),
+                       big_string("large", 100000)];
+        let file = write(&written.iter().map(|r| &r[..]).collect::<Vec<_>>());
+        let (records, drops) = read_all(&file, 0);
+        assert_eq!(records, written);
+        assert_eq!(drops.borrow().bytes, 0);
+    }
+
+    /// Records that leave less than a header at the end of a block
+    /// make the writer pad the block with a trailer.
+    #[test]
+    fn trailers()
+    {
+        for &trailer in &[HEADER_SIZE, HEADER_SIZE - 1, 0] {
+            let first = big_string("foo", BLOCK_SIZE - HEADER_SIZE - trailer);
+            let file = write(&[&first, b"", b"bar"]);
+            let (records, drops) = read_all(&file, 0);
+            assert_eq!(records, [first, vec![], b"bar".to_vec()]);
+            assert_eq!(drops.borrow().bytes, 0);
+        }
+    }
+
+    #[test]
+    fn record_offsets()
+    {
+        let written = [b"small".to_vec(), big_string("medium", 50000), b"".to_vec()];
+        let file = write(&written.iter().map(|r| &r[..]).collect::<Vec<_>>());
+        let mut reader = Reader::new(&file[..], None, true, 0);
+        let mut offsets = vec![];
+        while reader.read_record().is_some() {
+            offsets.push(reader.last_record_offset());
+        }
+        let medium_end = HEADER_SIZE + 5 + 2 * HEADER_SIZE + 50000;
+        assert_eq!(offsets, [0, (HEADER_SIZE + 5) as u64, medium_end as u64]);
+
+        // Reading from an offset skips the records that start before it
+        let (records, _) = read_all(&file, 1);
+        assert_eq!(records, &written[1..]);
+        let (records, _) = read_all(&file, medium_end as u64);
+        assert_eq!(records, &written[2..]);
+        let (records, _) = read_all(&file, medium_end as u64 + 1);
+        assert!(records.is_empty());
+    }
+
+    /// A log cut short by a crash holds less than a header, or a header
+    /// without its payload.  Neither is a corruption.
+    #[test]
+    fn truncated()
+    {
+        let file = write(&[b"foo"]);
+        for len in 0..file.len() {
+            let (records, drops) = read_all(&file[..len], 0);
+            assert!(records.is_empty(), "length {}", len);
+            assert_eq!(drops.borrow().bytes, 0);
+        }
+
+        let file = write(&[b"foo", b"bar"]);
+        let (records, drops) = read_all(&file[..file.len() - 1], 0);
+        assert_eq!(records, [b"foo"]);
+        assert_eq!(drops.borrow().bytes, 0);
+    }
+
+    #[test]
+    fn bad_checksum()
+    {
+        let mut file = write(&[b"foo"]);
+        file[0] ^= 1;
+        let (records, drops) = read_all(&file, 0);
+        assert!(records.is_empty());
+        assert_eq!(drops.borrow().bytes, 10);
+        assert!(drops.borrow().messages.contains("checksum mismatch"));
+
+        // Without checksums, the record is read regardless
+        let mut reader = Reader::new(&file[..], None, false, 0);
+        assert_eq!(reader.read_record(), Some(&b"foo"[..]));
+    }
+
+    #[test]
+    fn bad_record_type()
+    {
+        let mut file = write(&[b"foo"]);
+        set_record_type(&mut file, 0, 100);
+        let (records, drops) = read_all(&file, 0);
+        assert!(records.is_empty());
+        assert_eq!(drops.borrow().bytes, 3);
+        assert!(drops.borrow().messages.contains("unknown record type"));
+    }
+
+    #[test]
+    fn bad_length()
+    {
+        let payload = BLOCK_SIZE - HEADER_SIZE;
+        let mut file = write(&[&big_string("bar", payload), b"foo"]);
+        // The first record fills a block: claiming one more byte drops it
+        file[4] += 1;
+        let (records, drops) = read_all(&file, 0);
+        assert_eq!(records, [b"foo"]);
+        assert_eq!(drops.borrow().bytes, BLOCK_SIZE);
+        assert!(drops.borrow().messages.contains("bad record length"));
+    }
+
+    #[test]
+    fn unexpected_fragments()
+    {
+        for &(record_type, message) in &[(RecordType::MiddleType, "missing start"),
+                                         (RecordType::LastType, "missing start"),
+                                         (RecordType::FirstType, "partial record without end")] {
+            let mut file = write(&[b"foo", b"bar"]);
+            set_record_type(&mut file, 0, record_type as u8);
+            let (records, drops) = read_all(&file, 0);
+            assert_eq!(records, [b"bar"]);
+            assert_eq!(drops.borrow().bytes, 3);
+            assert!(drops.borrow().messages.contains(message));
+        }
+
+        // A fragmented record cut short by the end of the file is dropped
+        // silently
+        let mut file = write(&[b"foo"]);
+        set_record_type(&mut file, 0, RecordType::FirstType as u8);
+        let (records, drops) = read_all(&file, 0);
+        assert!(records.is_empty());
+        assert_eq!(drops.borrow().bytes, 0);
+    }
+
+    #[test]
+    fn error_joins_records()
+    {
+        // Consider two fragmented records: first(R1) last(R1) first(R2)
+        // last(R2) where the middle two fragments disappear.  We do not
+        // want first(R1),last(R2) to get joined and returned as a valid
+        // record.
+        let mut file = write(&[&big_string("foo", BLOCK_SIZE), &big_string("bar", BLOCK_SIZE),
+                               b"correct"]);
+        // Wipe the middle block
+        for byte in &mut file[BLOCK_SIZE..2 * BLOCK_SIZE] {
+            *byte = b'x';
+        }
+        let (records, drops) = read_all(&file, 0);
+        assert_eq!(records, [b"correct"]);
+        let dropped = drops.borrow().bytes;
+        assert!(dropped <= 2 * BLOCK_SIZE + 100);
+        assert!(dropped >= 2 * BLOCK_SIZE);
+    }
+}
@@ -0,0 +1,127 @@
+use ::errors::RubbleResult;
+use ::filename;
+use ::log_format::{RecordType, BLOCK_SIZE, HEADER_SIZE, MAX_RECORD_TYPE};
+use ::slice::Slice;
+use ::util::{coding, crc32c};
+use std::cmp;
+use std::fs::File;
+use std::io::Write;
+
+/// Appends records to a log file (see log_format.rs for the format).
+pub struct Writer<W: Write> {
+    dest: W,
+    /// Current offset in block
+    block_offset: usize,
+
+    /// crc32c values for all supported record types.  These are
+    /// pre-computed to reduce the overhead of computing the crc of the
+    /// record type stored in the header.
+    type_crc: [u32; MAX_RECORD_TYPE as usize + 1],
+}
+
+impl<W: Write> Writer<W> {
+    /// Create a writer that will append data to "dest".
+    /// "dest" must be initially empty.
+    pub fn new(dest: W) -> Writer<W>
+    {
+        Self::with_dest_length(dest, 0)
+    }
+
+    /// Create a writer that will append data to "dest".
+    /// "dest" must have initial length "dest_length".
+    pub fn with_dest_length(dest: W, dest_length: u64) -> Writer<W>
+    {
+        let mut type_crc = [0; MAX_RECORD_TYPE as usize + 1];
+        for (record_type, crc) in type_crc.iter_mut().enumerate() {
+            *crc = crc32c::value(&[record_type as u8]);
+        }
+        Writer {
+            dest,
+            block_offset: (dest_length % BLOCK_SIZE as u64) as usize,
+            type_crc,
+        }
+    }
+
+    pub fn add_record(&mut self, slice: Slice) -> RubbleResult<()>
+    {
+        let mut ptr = slice;
+
+        // Fragment the record if necessary and emit it.  Note that if
+        // slice is empty, we still want to iterate once to emit a single
+        // zero-length record
+        let mut begin = true;
+        loop {
+            let leftover = BLOCK_SIZE - self.block_offset;
+            if leftover < HEADER_SIZE {
+                // Switch to a new block
+                if leftover > 0 {
+                    // Fill the trailer with zeroes
+                    self.dest.write_all(&[0; HEADER_SIZE - 1][..leftover])?;
+                }
+                self.block_offset = 0;
+            }
+
+            // Invariant: we never leave < HEADER_SIZE bytes in a block.
+            assert!(BLOCK_SIZE - self.block_offset >= HEADER_SIZE);
+
+            let avail = BLOCK_SIZE - self.block_offset - HEADER_SIZE;
+            let fragment_length = cmp::min(ptr.len(), avail);
+
+            let end = ptr.len() == fragment_length;
+            let record_type = match (begin, end) {
+                (true, true) => RecordType::FullType,
+                (true, false) => RecordType::FirstType,
+                (false, true) => RecordType::LastType,
+                (false, false) => RecordType::MiddleType,
+            };
+
+            self.emit_physical_record(record_type, &ptr[..fragment_length])?;
+            ptr = &ptr[fragment_length..];
+            begin = false;
+            if ptr.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn emit_physical_record(&mut self, record_type: RecordType, data: Slice) -> RubbleResult<()>
+    {
+        let n = data.len();
+        assert!(n <= 0xffff);  // Must fit in two bytes
+        assert!(self.block_offset + HEADER_SIZE + n <= BLOCK_SIZE);
+
+        // Format the header
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+
+        // Compute the crc of the record type and the payload.
+        let crc = crc32c::extend(self.type_crc[record_type as usize], data);
+        coding::put_fixed32(&mut header, crc32c::mask(crc));
+        header.push((n & 0xff) as u8);
+        header.push((n >> 8) as u8);
+        header.push(record_type as u8);
+
+        // Write the header and the payload
+        self.dest.write_all(&header)?;
+        self.dest.write_all(data)?;
+        self.dest.flush()?;
+        self.block_offset += HEADER_SIZE + n;
+        Ok(())
+    }
+}
+
+impl Writer<File> {
+    /// Create the log file numbered "number" in the db named "dbname",
+    /// and return a writer that appends to it.
+    pub fn create(dbname: &str, number: u64) -> RubbleResult<Writer<File>>
+    {
+        let file = File::create(filename::log_file_name(dbname, number))?;
+        Ok(Writer::new(file))
+    }
+
+    /// Sync the contents of the log file to stable storage.
+    pub fn sync(&mut self) -> RubbleResult<()>
+    {
+        self.dest.sync_data()?;
+        Ok(())
+    }
+}
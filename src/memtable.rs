@@ -0,0 +1,351 @@
+//! A MemTable holds the most recent writes to the DB in memory, sorted
+//! by internal key, until they are written out to a table.
+//!
+//! Each write is stored in the memtable's skiplist as a single entry:
+//!     key_size:  varint32 of internal_key.len()
+//!     key bytes: char[internal_key.len()]
+//!     value_size: varint32 of value.len()
+//!     value bytes: char[value.len()]
+//!
+//! where the internal key is the user key followed by its tag, the
+//! packed sequence number and value type of the write.
+
+use ::comparator::SliceComparator;
+use ::dbformat::{self, InternalKeyComparator, LookupKey, ParsedInternalKey, SequenceNumber, ValueType};
+use ::errors::RubbleResult;
+use ::options::Options;
+use ::skiplist::{SkipList, SkipListIterator};
+use ::slice::Slice;
+use ::status::Status;
+use ::table::iterator::RubbleIterator;
+use ::util::coding;
+
+/// Returns the length-prefixed slice at the start of "data", which must
+/// be a memtable entry or a key built by encode_key.
+fn get_length_prefixed_slice(data: Slice) -> Slice
+{
+    coding::get_length_prefixed_slice(data).expect("malformed memtable entry").1
+}
+
+/// Returns the internal key and the value of memtable entry "entry".
+fn decode_entry(entry: Slice) -> (Slice, Slice)
+{
+    let (rest, internal_key) = coding::get_length_prefixed_slice(entry)
+        .expect("malformed memtable entry");
+    (internal_key, get_length_prefixed_slice(rest))
+}
+
+/// Encode a suitable internal key target for "target" and return it.
+/// Uses "scratch" as scratch space, and the returned slice will point
+/// into this scratch space.
+fn encode_key<'a>(scratch: &'a mut Vec<u8>, target: Slice) -> Slice<'a>
+{
+    scratch.clear();
+    coding::put_length_prefixed_slice(scratch, target);
+    scratch
+}
+
+/// Format an entry as described at the top of this file.
+fn encode_entry(seq: SequenceNumber, value_type: ValueType, key: Slice, value: Slice) -> Vec<u8>
+{
+    let internal_key_size = key.len() + dbformat::TAG_SIZE;
+    let encoded_len = coding::varint_length(internal_key_size as u64) + internal_key_size
+        + coding::varint_length(value.len() as u64) + value.len();
+    let mut buf = Vec::with_capacity(encoded_len);
+    coding::put_varint32(&mut buf, internal_key_size as u32);
+    dbformat::append_internal_key(&mut buf, key, seq, value_type);
+    coding::put_length_prefixed_slice(&mut buf, value);
+    assert!(buf.len() == encoded_len);
+    buf
+}
+
+/// Orders memtable entries by their internal keys.
+struct KeyComparator {
+    comparator: InternalKeyComparator,
+}
+
+impl SliceComparator for KeyComparator {
+    fn compare(&self, a: Slice, b: Slice) -> i32
+    {
+        // Internal keys are encoded as length-prefixed strings.
+        let a = get_length_prefixed_slice(a);
+        let b = get_length_prefixed_slice(b);
+        self.comparator.compare(a, b)
+    }
+
+    fn name(&self) -> &str
+    {
+        self.comparator.name()
+    }
+}
+
+pub struct MemTable {
+    comparator: InternalKeyComparator,
+    table: SkipList<KeyComparator>,
+}
+
+impl MemTable {
+    /// MemTables order their entries by internal key, as ordered by
+    /// "comparator".
+    pub fn new(comparator: InternalKeyComparator) -> MemTable
+    {
+        MemTable {
+            comparator: comparator.clone(),
+            table: SkipList::new(KeyComparator { comparator }),
+        }
+    }
+
+    /// Returns an estimate of the number of bytes of data in use by this
+    /// data structure.
+    pub fn approximate_memory_usage(&self) -> usize
+    {
+        self.table.memory_usage()
+    }
+
+    /// Returns true once the memtable has grown past
+    /// options.write_buffer_size, at which point it should be written
+    /// out to a table and replaced with an empty memtable.
+    pub fn exceeds_write_buffer_size(&self, options: &Options) -> bool
+    {
+        self.approximate_memory_usage() > options.write_buffer_size
+    }
+
+    /// Return an iterator that yields the contents of the memtable.
+    ///
+    /// The keys returned by this iterator are internal keys, so it may be
+    /// merged with iterators over tables of internal keys using an
+    /// InternalKeyComparator.
+    pub fn iter(&self) -> MemTableIterator<'_>
+    {
+        MemTableIterator {
+            iter: self.table.iter(),
+            tmp: vec![],
+            status: Status::Ok,
+        }
+    }
+
+    /// Add an entry into memtable that maps key to value at the
+    /// specified sequence number and with the specified type.
+    /// Typically value will be empty if value_type == Deletion.
+    pub fn add(&mut self, seq: SequenceNumber, value_type: ValueType, key: Slice, value: Slice)
+    {
+        let entry = encode_entry(seq, value_type, key, value);
+        self.table.insert(&entry);
+    }
+
+    /// Like add(), but may be called by several threads at once, while
+    /// other threads read the memtable.
+    pub fn add_concurrently(&self, seq: SequenceNumber, value_type: ValueType, key: Slice,
+                            value: Slice)
+    {
+        let entry = encode_entry(seq, value_type, key, value);
+        self.table.insert_concurrently(&entry);
+    }
+
+    /// If memtable contains a value for key, returns Some(Ok(value)).
+    /// If memtable contains a deletion for key, returns
+    /// Some(Err(NotFound)).  Else returns None.  Only entries written at
+    /// or before the sequence number of "key" are considered.
+    pub fn get(&self, key: &LookupKey) -> Option<RubbleResult<Vec<u8>>>
+    {
+        let mut iter = self.table.iter();
+        iter.seek(key.memtable_key());
+        if !iter.is_valid() {
+            return None;
+        }
+
+        // entry format is:
+        //    klength  varint32
+        //    userkey  char[klength-8]
+        //    tag      uint64
+        //    vlength  varint32
+        //    value    char[vlength]
+        // Check that it belongs to same user key.  We do not check the
+        // sequence number since the seek() call above should have
+        // skipped all entries with overly large sequence numbers.
+        let (internal_key, value) = decode_entry(iter.key());
+        let user_comparator = self.comparator.user_comparator();
+        if user_comparator.compare(dbformat::extract_user_key(internal_key), key.user_key()) != 0 {
+            return None;
+        }
+        match dbformat::parse_internal_key(internal_key) {
+            Some(ParsedInternalKey { value_type: ValueType::Value, .. }) => Some(Ok(value.to_vec())),
+            Some(ParsedInternalKey { value_type: ValueType::Deletion, .. }) => {
+                Some(Err(Status::NotFound("key deleted in memtable".into()).into()))
+            },
+            None => Some(Err(Status::Corruption("unknown value type in memtable".into()).into())),
+        }
+    }
+}
+
+pub struct MemTableIterator<'a> {
+    iter: SkipListIterator<'a, KeyComparator>,
+    /// For passing to encode_key
+    tmp: Vec<u8>,
+    status: Status,
+}
+
+impl<'a> RubbleIterator for MemTableIterator<'a> {
+    fn is_valid(&self) -> bool
+    {
+        self.iter.is_valid()
+    }
+
+    fn seek(&mut self, k: Slice)
+    {
+        self.iter.seek(encode_key(&mut self.tmp, k))
+    }
+
+    fn seek_to_first(&mut self)
+    {
+        self.iter.seek_to_first()
+    }
+
+    fn seek_to_last(&mut self)
+    {
+        self.iter.seek_to_last()
+    }
+
+    fn step(&mut self)
+    {
+        self.iter.step()
+    }
+
+    fn prev(&mut self)
+    {
+        self.iter.prev()
+    }
+
+    fn key(&self) -> Slice<'_>
+    {
+        get_length_prefixed_slice(self.iter.key())
+    }
+
+    fn value(&self) -> Slice<'_>
+    {
+        decode_entry(self.iter.key()).1
+    }
+
+    fn status(&self) -> &Status
+    {
+        &self.status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemTable;
+    use ::comparator::BytewiseComparator;
+    use ::dbformat::{self, InternalKeyComparator, LookupKey, ValueType};
+    use ::options::Options;
+    use ::status::Status;
+    use ::table::iterator::RubbleIterator;
+    use std::sync::Arc;
+
+    fn memtable() -> MemTable
+    {
+        MemTable::new(InternalKeyComparator::new(Arc::new(BytewiseComparator)))
+    }
+
+    fn internal_key(key: &[u8], seq: u64, value_type: ValueType) -> Vec<u8>
+    {
+        let mut internal_key = vec![];
+        dbformat::append_internal_key(&mut internal_key, key, seq, value_type);
+        internal_key
+    }
+
+    #[test]
+    fn get()
+    {
+        let mut mem = memtable();
+        mem.add(1, ValueType::Value, b"foo", b"v1");
+        mem.add(2, ValueType::Value, b"bar", b"b1");
+        mem.add(3, ValueType::Value, b"foo", b"v3");
+        mem.add(4, ValueType::Deletion, b"foo", b"");
+        mem.add(5, ValueType::Value, b"", b"empty key");
+
+        // The latest entry at or before the sequence number wins
+        assert_eq!(mem.get(&LookupKey::new(b"foo", 1)).unwrap().unwrap(), b"v1");
+        assert_eq!(mem.get(&LookupKey::new(b"foo", 2)).unwrap().unwrap(), b"v1");
+        assert_eq!(mem.get(&LookupKey::new(b"foo", 3)).unwrap().unwrap(), b"v3");
+        assert_eq!(mem.get(&LookupKey::new(b"bar", 100)).unwrap().unwrap(), b"b1");
+        assert_eq!(mem.get(&LookupKey::new(b"", 5)).unwrap().unwrap(), b"empty key");
+
+        // Deletions are found as NotFound
+        let status: Status = mem.get(&LookupKey::new(b"foo", 4)).unwrap().unwrap_err().into();
+        assert_eq!(status, Status::NotFound("key deleted in memtable".into()));
+        let status: Status = mem.get(&LookupKey::new(b"foo", 100)).unwrap().unwrap_err().into();
+        assert_eq!(status, Status::NotFound("key deleted in memtable".into()));
+
+        // Misses: keys never written, or written after the sequence
+        assert!(mem.get(&LookupKey::new(b"foo", 0)).is_none());
+        assert!(mem.get(&LookupKey::new(b"bar", 1)).is_none());
+        assert!(mem.get(&LookupKey::new(b"baz", 100)).is_none());
+        assert!(mem.get(&LookupKey::new(b"fo", 100)).is_none());
+        assert!(mem.get(&LookupKey::new(b"fooo", 100)).is_none());
+        assert!(mem.get(&LookupKey::new(b"", 4)).is_none());
+    }
+
+    #[test]
+    fn iterate()
+    {
+        let mut mem = memtable();
+        mem.add(1, ValueType::Value, b"b", b"b1");
+        mem.add(2, ValueType::Value, b"a", b"a2");
+        mem.add(3, ValueType::Deletion, b"b", b"");
+        mem.add(4, ValueType::Value, b"c", b"c4");
+
+        // Ordered by user key, then by decreasing sequence number
+        let expected = [(internal_key(b"a", 2, ValueType::Value), &b"a2"[..]),
+                        (internal_key(b"b", 3, ValueType::Deletion), &b""[..]),
+                        (internal_key(b"b", 1, ValueType::Value), &b"b1"[..]),
+                        (internal_key(b"c", 4, ValueType::Value), &b"c4"[..])];
+        let mut iter = mem.iter();
+        iter.seek_to_first();
+        for (key, value) in &expected {
+            assert!(iter.is_valid());
+            assert_eq!(iter.key(), &key[..]);
+            assert_eq!(iter.value(), *value);
+            iter.step();
+        }
+        assert!(!iter.is_valid());
+
+        iter.seek_to_last();
+        for (key, _) in expected.iter().rev() {
+            assert_eq!(iter.key(), &key[..]);
+            iter.prev();
+        }
+        assert!(!iter.is_valid());
+
+        // Seeks take internal keys
+        iter.seek(&internal_key(b"b", 2, ValueType::Value));
+        assert_eq!(iter.key(), &expected[2].0[..]);
+        iter.seek(&internal_key(b"bb", 100, ValueType::Value));
+        assert_eq!(iter.key(), &expected[3].0[..]);
+        iter.seek(&internal_key(b"d", 100, ValueType::Value));
+        assert!(!iter.is_valid());
+        assert!(iter.status().is_ok());
+    }
+
+    #[test]
+    fn approximate_memory_usage()
+    {
+        let mut mem = memtable();
+        let mut options = Options::new();
+        options.write_buffer_size = 64 << 10;
+        let empty_usage = mem.approximate_memory_usage();
+
+        let value = vec![b'x'; 100];
+        let mut usage = empty_usage;
+        let mut seq = 1;
+        while !mem.exceeds_write_buffer_size(&options) {
+            mem.add(seq, ValueType::Value, format!("key{:06}", seq).as_bytes(), &value);
+            assert!(mem.approximate_memory_usage() >= usage);
+            usage = mem.approximate_memory_usage();
+            seq += 1;
+        }
+        // Every entry holds its key and value
+        assert!(usage - empty_usage >= (seq - 1) as usize * (9 + 100));
+        assert!(usage > options.write_buffer_size);
+    }
+}
@@ -1,12 +1,66 @@
+use ::comparator::{SliceComparator, BytewiseComparator};
+use ::filter_policy::FilterPolicy;
+use ::slice_transform::SliceTransform;
+use ::table::block::OwnedBlock;
+use ::table::table_properties::TablePropertiesCollectorFactory;
+use ::util::cache::Cache;
+use ::util::compression::CodecRegistry;
+use std::cmp;
+use std::sync::Arc;
+
 /// DB contents are stored in a set of blocks, each of which holds a
 /// sequence of key,value pairs.  Each block may be compressed before
 /// being stored in a file.  The following enum describes which
 /// compression method (if any) is used to compress a block.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum CompressionType {
     /// NOTE: do not change the values of existing entries, as these are
     /// part of the persistent format on disk.
     NoCompression     = 0x0,
     SnappyCompression = 0x1,
+    Lz4Compression    = 0x4,
+    ZstdCompression   = 0x7,
+}
+
+impl CompressionType {
+    /// The name of the compression type, as recorded in table properties.
+    pub fn name(&self) -> &'static str
+    {
+        match *self {
+            CompressionType::NoCompression => "NoCompression",
+            CompressionType::SnappyCompression => "Snappy",
+            CompressionType::Lz4Compression => "LZ4",
+            CompressionType::ZstdCompression => "ZSTD",
+        }
+    }
+}
+
+/// The kind of index a table is built with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IndexType {
+    /// A single index block holding an entry for every data block.  The
+    /// whole index is read into memory when the table is opened.
+    BinarySearch,
+    /// An index split into partitions of about metadata_block_size
+    /// bytes, plus a top-level index over the partitions.  Only the
+    /// top-level index is read when the table is opened; the partitions
+    /// are read through the block cache as they are needed.
+    TwoLevelIndexSearch,
+}
+
+/// The kind of index data blocks are built with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DataBlockIndexType {
+    /// Keys are found by binary search over the restart points of the
+    /// block.
+    BinarySearch,
+    /// Blocks also get a hash index from their keys to their restart
+    /// intervals, so point lookups skip the binary search.  Blocks with
+    /// too many restart points for the hash index fall back to binary
+    /// search.
+    /// Not supported for tables of internal keys, which are built with
+    /// BinarySearch instead (see Options::data_block_index_type).
+    BinaryAndHash,
 }
 
 
@@ -21,7 +75,10 @@ pub struct Options {
     /// REQUIRES: The client must ensure that the comparator supplied
     /// here has the same name and orders keys *exactly* the same as the
     /// comparator provided to previous open calls on the same DB.
-    /// const Comparator* comparator;
+    /// Tables record the name of the comparator they were written with,
+    /// and fail to open with a comparator of another name (see
+    /// comparator::ComparatorRegistry to find comparators by name).
+    pub comparator: Arc<dyn SliceComparator>,
 
     /// If true, the database will be created if it is missing.
     /// Default: false
@@ -72,13 +129,24 @@ pub struct Options {
     /// Default: 1000
     pub max_open_files: usize,
 
-    // /// Control over blocks (user data is stored in a set of blocks, and
-    // /// a block is the unit of reading from disk).
-    //
-    // /// If non-NULL, use the specified cache for blocks.
-    // /// If NULL, leveldb will automatically create and use an 8MB internal cache.
-    // /// Default: NULL
-    pub block_cache: Option<()>,
+    /// If true, table files are memory mapped and uncompressed blocks
+    /// are read straight out of the mapping instead of being copied.
+    /// Such blocks are not inserted into block_cache, since that would
+    /// only duplicate the mapped data.  Files that cannot be mapped are
+    /// read as usual.
+    ///
+    /// Default: false
+    pub allow_mmap_reads: bool,
+
+    /// -------------------
+    /// Control over blocks (user data is stored in a set of blocks, and
+    /// a block is the unit of reading from disk).
+
+    /// If set, use the specified cache for blocks.  The cache may be
+    /// shared between tables; each table keys its blocks by its own
+    /// cache id and the block offset.
+    /// Default: None
+    pub block_cache: Option<Arc<Cache<OwnedBlock>>>,
 
     /// Approximate size of user data packed per block.  Note that the
     /// block size specified here corresponds to uncompressed data.  The
@@ -95,6 +163,42 @@ pub struct Options {
     /// Default: 16
     pub block_restart_interval: u16,
 
+    /// The kind of index data blocks are built with.  Blocks of either
+    /// kind can be read regardless of this setting.  Tables ordered by
+    /// an InternalKeyComparator are always built with
+    /// DataBlockIndexType::BinarySearch.
+    ///
+    /// Default: DataBlockIndexType::BinarySearch
+    pub data_block_index_type: DataBlockIndexType,
+
+    /// With DataBlockIndexType::BinaryAndHash, the average number of
+    /// keys per bucket of the hash index.  Lower ratios use more space
+    /// for fewer hash collisions.
+    ///
+    /// Default: 0.75
+    pub data_block_hash_table_util_ratio: f64,
+
+    /// The kind of index tables are built with.  Tables of either kind
+    /// can be read regardless of this setting.
+    ///
+    /// Default: IndexType::BinarySearch
+    pub index_type: IndexType,
+
+    /// Approximate size of each partition of a partitioned index or
+    /// filter (see IndexType::TwoLevelIndexSearch and partition_filters).
+    ///
+    /// Default: 4K
+    pub metadata_block_size: usize,
+
+    /// If true and index_type is IndexType::TwoLevelIndexSearch, the
+    /// filter is partitioned along with the index: each index partition
+    /// gets its own filter over the keys of its data blocks, and only
+    /// the filter partitions a read needs are loaded.  Ignored for other
+    /// index types.
+    ///
+    /// Default: false
+    pub partition_filters: bool,
+
     /// Compress blocks using the specified compression algorithm.  This
     /// parameter can be changed dynamically.
     ///
@@ -109,20 +213,84 @@ pub struct Options {
     /// worth switching to kNoCompression.  Even if the input data is
     /// incompressible, the kSnappyCompression implementation will
     /// efficiently detect that and will switch to uncompressed mode.
+    ///
+    /// Whatever the codec, a block whose compressed form does not save
+    /// at least 12.5% of its size is stored uncompressed.
     pub compression: CompressionType,
 
+    /// Different levels may use different compression types.  If this
+    /// is non-empty, tables written for level L use
+    /// compression_per_level[L] instead of compression; levels past the
+    /// end of the list use its last entry.  A typical setup leaves the
+    /// small, frequently rewritten upper levels uncompressed and
+    /// compresses the lower levels.
+    ///
+    /// Default: empty
+    pub compression_per_level: Vec<CompressionType>,
+
+    /// If set, tables written for the bottommost level use this
+    /// compression type, overriding compression and
+    /// compression_per_level.  The bottommost level holds most of the
+    /// data and is rewritten least often, so it is usually worth
+    /// compressing more strongly.
+    ///
+    /// Default: None
+    pub bottommost_compression: Option<CompressionType>,
+
+    /// Codec-specific level passed to the compression codec: 1-12 for
+    /// Lz4Compression (which then uses LZ4HC) and 1-22 for
+    /// ZstdCompression.  The valid ranges differ, so each codec clamps
+    /// the level to its own range, and Snappy ignores it.  None selects
+    /// the codec's default level.
+    ///
+    /// Default: None
+    pub compression_level: Option<i32>,
+
+    /// The codecs used to compress blocks when writing tables and to
+    /// uncompress them when reading, keyed by the block type byte.
+    /// Custom codecs may be registered to read and write additional
+    /// block types.
+    ///
+    /// Default: a registry holding the codecs for every CompressionType
+    pub compression_codecs: Arc<CodecRegistry>,
+
     /// EXPERIMENTAL: If true, append to existing MANIFEST and log files
     /// when a database is opened.  This can significantly speed up open.
     ///
     /// Default: currently false, but may become true later.
     pub reuse_logs: bool,
 
-    /// If non-NULL, use the specified filter policy to reduce disk reads.
-    /// Many applications will benefit from passing the result of
-    /// NewBloomFilterPolicy() here.
+    /// If set, use the specified filter policy to reduce disk reads.
+    /// Many applications will benefit from passing a
+    /// util::bloom::BloomFilterPolicy here.
+    ///
+    /// Default: None
+    pub filter_policy: Option<Arc<dyn FilterPolicy>>,
+
+    /// If set, the filters of tables also hold the prefix this transform
+    /// extracts from every key in its domain.  Seeks to a key with a
+    /// prefix then consult the filter, and skip tables that hold no keys
+    /// with that prefix (see ReadOptions::total_order_seek).
+    ///
+    /// Default: None
+    pub prefix_extractor: Option<Arc<dyn SliceTransform>>,
+
+    /// If true, the filters of tables hold every key.  Setting this to
+    /// false along with a prefix_extractor makes filters smaller when
+    /// reads are mostly prefix seeks rather than point lookups.  LevelDB
+    /// does not use the filters of tables written with it false.
     ///
-    /// Default: NULL
-    pub filter_policy: Option<()> // Option<FilterPolicy>
+    /// Default: true
+    pub whole_key_filtering: bool,
+
+    /// Factories for the collectors of user-defined table properties.
+    /// Each table that is built gets a collector from every factory;
+    /// the collectors see every key/value added to the table and the
+    /// properties they produce are stored in the table's properties
+    /// block (see Table::properties()).
+    ///
+    /// Default: empty
+    pub table_properties_collector_factories: Vec<Arc<dyn TablePropertiesCollectorFactory>>,
 }
 
 /// Options to control the behavior of a database (passed to DB::Open)
@@ -130,6 +298,7 @@ impl Options {
     pub fn new() -> Options
     {
         Options {
+            comparator: Arc::new(BytewiseComparator),
             create_if_missing: false,
             error_if_exists: false,
             paranoid_checks: false,
@@ -137,17 +306,53 @@ impl Options {
             // Logger* info_log;
             write_buffer_size: 4194304,
             max_open_files: 1000,
+            allow_mmap_reads: false,
             block_cache: None,
             block_size: 4096,
             block_restart_interval: 16,
+            data_block_index_type: DataBlockIndexType::BinarySearch,
+            data_block_hash_table_util_ratio: 0.75,
+            index_type: IndexType::BinarySearch,
+            metadata_block_size: 4096,
+            partition_filters: false,
             compression: CompressionType::SnappyCompression,
+            compression_per_level: vec![],
+            bottommost_compression: None,
+            compression_level: None,
+            compression_codecs: Arc::new(CodecRegistry::new()),
             reuse_logs: false,
             filter_policy: None,
+            prefix_extractor: None,
+            whole_key_filtering: true,
+            table_properties_collector_factories: vec![],
+        }
+    }
+
+    /// Return the compression type to use for a table written to
+    /// "level", which is the bottommost level iff "bottommost".
+    pub fn compression_for_level(&self, level: usize, bottommost: bool) -> CompressionType
+    {
+        if bottommost {
+            if let Some(compression) = self.bottommost_compression {
+                return compression;
+            }
+        }
+        match self.compression_per_level.len() {
+            0 => self.compression,
+            n => self.compression_per_level[cmp::min(level, n - 1)],
         }
     }
 }
 
+impl Default for Options {
+    fn default() -> Options
+    {
+        Options::new()
+    }
+}
+
 /// Options that control read operations
+#[derive(Clone)]
 pub struct ReadOptions {
     /// If true, all data read from underlying storage will be
     /// verified against corresponding checksums.
@@ -159,6 +364,15 @@ pub struct ReadOptions {
     /// Default: true
     pub fill_cache: bool,
 
+    /// If false and Options::prefix_extractor is set, iterators seek in
+    /// prefix mode: a seek to a key with a prefix may leave the iterator
+    /// invalid when the filter shows that no keys with that prefix
+    /// follow the key, and iterating past the keys with that prefix
+    /// gives no meaningful results.  Set to true to seek in the total
+    /// order of the keys regardless of prefixes.
+    /// Default: false
+    pub total_order_seek: bool,
+
     // /// If "snapshot" is non-NULL, read as of the supplied snapshot
     // /// (which must belong to the DB that is being read and which must
     // /// not have been released).  If "snapshot" is NULL, use an implicit
@@ -173,11 +387,19 @@ impl ReadOptions {
         ReadOptions{
             verify_checksums: false,
             fill_cache: true,
+            total_order_seek: false,
             // snapshot: None,
         }
     }
 }
 
+impl Default for ReadOptions {
+    fn default() -> ReadOptions
+    {
+        ReadOptions::new()
+    }
+}
+
 /// Options that control write operations
 pub struct WriteOptions {
     /// If true, the write will be flushed from the operating system
@@ -199,6 +421,13 @@ pub struct WriteOptions {
     pub sync: bool,
 }
 
+impl Default for WriteOptions {
+    fn default() -> WriteOptions
+    {
+        WriteOptions::new()
+    }
+}
+
 impl WriteOptions {
     pub fn new() -> WriteOptions {
         WriteOptions {
@@ -206,3 +435,45 @@ impl WriteOptions {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{CompressionType, Options};
+
+    #[test]
+    fn compression_for_level()
+    {
+        let mut options = Options::new();
+        options.compression = CompressionType::ZstdCompression;
+        // Without a per-level list every level uses compression
+        for level in 0..7 {
+            assert_eq!(options.compression_for_level(level, false), CompressionType::ZstdCompression);
+        }
+
+        options.compression_per_level = vec![CompressionType::NoCompression,
+                                             CompressionType::NoCompression,
+                                             CompressionType::SnappyCompression,
+                                             CompressionType::Lz4Compression];
+        assert_eq!(options.compression_for_level(0, false), CompressionType::NoCompression);
+        assert_eq!(options.compression_for_level(1, false), CompressionType::NoCompression);
+        assert_eq!(options.compression_for_level(2, false), CompressionType::SnappyCompression);
+        assert_eq!(options.compression_for_level(3, false), CompressionType::Lz4Compression);
+        // Levels past the end use the last entry
+        assert_eq!(options.compression_for_level(4, false), CompressionType::Lz4Compression);
+        assert_eq!(options.compression_for_level(100, false), CompressionType::Lz4Compression);
+        // Without bottommost_compression the bottommost level is no
+        // different from the others
+        assert_eq!(options.compression_for_level(6, true), CompressionType::Lz4Compression);
+        assert_eq!(options.compression_for_level(1, true), CompressionType::NoCompression);
+
+        options.bottommost_compression = Some(CompressionType::ZstdCompression);
+        assert_eq!(options.compression_for_level(6, true), CompressionType::ZstdCompression);
+        assert_eq!(options.compression_for_level(1, true), CompressionType::ZstdCompression);
+        assert_eq!(options.compression_for_level(6, false), CompressionType::Lz4Compression);
+
+        options.compression_per_level = vec![];
+        options.compression = CompressionType::SnappyCompression;
+        assert_eq!(options.compression_for_level(3, true), CompressionType::ZstdCompression);
+        assert_eq!(options.compression_for_level(3, false), CompressionType::SnappyCompression);
+    }
+}
@@ -0,0 +1,696 @@
+//! A sorted set of keys, kept in a skiplist whose nodes and keys live
+//! in an Arena.  Nodes are never deleted: they are freed all at once
+//! when the skiplist is dropped.
+//!
+//! Thread safety
+//! -------------
+//!
+//! A single writer may insert() through a mutable borrow of the list.
+//! Alternatively, any number of writers may insert_concurrently()
+//! through shared borrows, while any number of readers search and
+//! iterate the list.  Readers never take a lock: they only rely on
+//! acquire-loads of the links between nodes.  Writers do not either:
+//! they allocate nodes from a ConcurrentArena, pick their heights with
+//! a generator of their own thread, and link them in with
+//! compare-and-swaps.
+//!
+//! Invariants:
+//!
+//! (1) Allocated nodes are never deleted until the SkipList is
+//! dropped.  This is trivially guaranteed by the code since we never
+//! delete any skip list nodes.
+//!
+//! (2) The contents of a Node except for the next pointers are
+//! immutable after the Node has been linked into the SkipList.
+//! Only the insert methods modify the list, and they are careful to
+//! initialize a node and use release-stores (or compare-and-swaps) to
+//! publish the nodes in one or more lists.
+//!
+//! (3) A node is linked into the lists from the bottom up, so a node
+//! that is reachable at some level is always reachable at level 0.
+//! Concurrent inserts link a node into each level with a
+//! compare-and-swap that only succeeds if the links around the node
+//! have not changed since the insert found them, and otherwise search
+//! that level again.
+
+use ::comparator::SliceComparator;
+use ::slice::Slice;
+use ::util::arena::ConcurrentArena;
+use ::util::random::Random;
+use std::cell::RefCell;
+use std::mem;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+
+const MAX_HEIGHT: usize = 12;
+
+#[repr(C)]
+struct Node {
+    key: *const u8,
+    key_len: usize,
+    /// Array of length equal to the node height.  next[0] is lowest
+    /// level link.  The rest of the array is allocated past the end of
+    /// the node.
+    next: [AtomicPtr<Node>; 1],
+}
+
+impl Node {
+    fn key<'a>(node: *const Node) -> Slice<'a>
+    {
+        unsafe { slice::from_raw_parts((*node).key, (*node).key_len) }
+    }
+
+    /// The n'th link of "node".
+    ///
+    /// REQUIRES: n is less than the height of the node
+    fn link<'a>(node: *const Node, n: usize) -> &'a AtomicPtr<Node>
+    {
+        unsafe { &*(ptr::addr_of!((*node).next) as *const AtomicPtr<Node>).add(n) }
+    }
+
+    /// Accessors/mutators for links.  Wrapped in methods so we can add
+    /// the appropriate barriers as necessary.
+    fn next(node: *const Node, n: usize) -> *mut Node
+    {
+        // Use an 'acquire load' so that we observe a fully initialized
+        // version of the returned Node.
+        Node::link(node, n).load(Ordering::Acquire)
+    }
+
+    fn set_next(node: *const Node, n: usize, x: *mut Node)
+    {
+        // Use a 'release store' so that anybody who reads through this
+        // pointer observes a fully initialized version of the inserted
+        // node.
+        Node::link(node, n).store(x, Ordering::Release)
+    }
+
+    /// No-barrier variants that can be safely used in a few locations.
+    fn no_barrier_next(node: *const Node, n: usize) -> *mut Node
+    {
+        Node::link(node, n).load(Ordering::Relaxed)
+    }
+
+    fn no_barrier_set_next(node: *const Node, n: usize, x: *mut Node)
+    {
+        Node::link(node, n).store(x, Ordering::Relaxed)
+    }
+
+    /// Link "x" in place of "expected" as the n'th link of "node".
+    /// Returns false, leaving the link alone, if the link no longer
+    /// points to "expected".
+    fn cas_next(node: *const Node, n: usize, expected: *mut Node, x: *mut Node) -> bool
+    {
+        Node::link(node, n)
+            .compare_exchange(expected, x, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    }
+}
+
+thread_local! {
+    /// Picks the height of the nodes a thread inserts.  Each thread has
+    /// its own generator, so concurrent inserts share no state to
+    /// allocate a node.
+    static HEIGHT_RND: RefCell<Random> =
+        RefCell::new(Random::new(NEXT_HEIGHT_SEED.fetch_add(0x9e3779b9, Ordering::Relaxed)));
+}
+
+/// Seed of the height generator of the next thread to insert a node
+static NEXT_HEIGHT_SEED: AtomicU32 = AtomicU32::new(0xdeadbeef);
+
+fn random_height() -> usize
+{
+    // Increase height with probability 1 in BRANCHING
+    const BRANCHING: u32 = 4;
+    HEIGHT_RND.with(|rnd| {
+        let mut rnd = rnd.borrow_mut();
+        let mut height = 1;
+        while height < MAX_HEIGHT && rnd.one_in(BRANCHING) {
+            height += 1;
+        }
+        assert!(height > 0);
+        assert!(height <= MAX_HEIGHT);
+        height
+    })
+}
+
+pub struct SkipList<C: SliceComparator> {
+    /// Immutable after construction
+    comparator: C,
+    /// Arena used for allocations of nodes and keys.  Inserts allocate
+    /// from it without taking a lock.
+    arena: ConcurrentArena,
+
+    head: *mut Node,
+
+    /// Modified only by the insert methods.  Read racily by readers,
+    /// but stale values are ok.
+    max_height: AtomicUsize,  // Height of the entire list
+}
+
+// Nodes are only modified through the atomic links between them (see
+// the invariants above), so the list may be shared between threads.
+unsafe impl<C: SliceComparator> Send for SkipList<C> {}
+unsafe impl<C: SliceComparator> Sync for SkipList<C> {}
+
+impl<C: SliceComparator> SkipList<C> {
+    /// Create a new SkipList that will use "comparator" for comparing
+    /// keys.
+    pub fn new(comparator: C) -> SkipList<C>
+    {
+        let arena = ConcurrentArena::new();
+        let head = SkipList::<C>::new_node_of_height(&arena, &[], MAX_HEIGHT);
+        SkipList {
+            comparator,
+            arena,
+            head,
+            max_height: AtomicUsize::new(1),
+        }
+    }
+
+    /// Insert a copy of "key" into the list.
+    ///
+    /// REQUIRES: nothing that compares equal to key is currently in the
+    /// list.
+    pub fn insert(&mut self, key: Slice)
+    {
+        let mut prev = [ptr::null_mut(); MAX_HEIGHT];
+        let x = self.find_greater_or_equal(key, Some(&mut prev));
+
+        // Our data structure does not allow duplicate insertion
+        assert!(x.is_null() || !self.equal(key, Node::key(x)));
+
+        let (x, height) = self.new_node(key);
+        let max_height = self.get_max_height();
+        if height > max_height {
+            for link in prev.iter_mut().take(height).skip(max_height) {
+                *link = self.head;
+            }
+            // It is ok to mutate max_height without any synchronization
+            // with concurrent readers.  A concurrent reader that observes
+            // the new value of max_height will see either the old value
+            // of new level pointers from head (null), or a new value set
+            // in the loop below.  In the former case the reader will
+            // immediately drop to the next level since null sorts after
+            // all keys.  In the latter case the reader will use the new
+            // node.
+            self.max_height.store(height, Ordering::Relaxed);
+        }
+
+        for (i, link) in prev.iter().enumerate().take(height) {
+            // no_barrier_set_next() suffices since we will add a barrier
+            // when we publish a pointer to "x" in prev[i].
+            Node::no_barrier_set_next(x, i, Node::no_barrier_next(*link, i));
+            Node::set_next(*link, i, x);
+        }
+    }
+
+    /// Insert a copy of "key" into the list, like insert(), but while
+    /// other threads may be inserting too.
+    ///
+    /// REQUIRES: nothing that compares equal to key is currently in the
+    /// list, or is being inserted concurrently.
+    pub fn insert_concurrently(&self, key: Slice)
+    {
+        let (x, height) = self.new_node(key);
+
+        // Raise max_height if needed.  Readers that see the new height
+        // before the node is linked in find null links from head at
+        // the new levels, which sort after all keys.
+        let mut max_height = self.get_max_height();
+        while height > max_height {
+            match self.max_height.compare_exchange_weak(max_height, height, Ordering::Relaxed,
+                                                        Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(current) => max_height = current,
+            }
+        }
+
+        // Find the nodes the new node goes between at each of its levels,
+        // searching each level from where the search of the level above
+        // left off.
+        let mut prev = [self.head; MAX_HEIGHT];
+        let mut next = [ptr::null_mut(); MAX_HEIGHT];
+        let mut before = self.head;
+        for level in (0..height).rev() {
+            let (p, n) = self.find_splice_for_level(key, before, level);
+            prev[level] = p;
+            next[level] = n;
+            before = p;
+        }
+
+        // Link the node in from the bottom up, so that it is always
+        // reachable at level 0 when it is reachable at all.
+        for level in 0..height {
+            loop {
+                // Our data structure does not allow duplicate insertion
+                assert!(level > 0 || next[0].is_null() || !self.equal(key, Node::key(next[0])));
+
+                Node::no_barrier_set_next(x, level, next[level]);
+                if Node::cas_next(prev[level], level, next[level], x) {
+                    break;
+                }
+                // Another insert changed the links around the node at this
+                // level.  Search it again, starting from prev, which still
+                // sorts before key.
+                let (p, n) = self.find_splice_for_level(key, prev[level], level);
+                prev[level] = p;
+                next[level] = n;
+            }
+        }
+    }
+
+    /// Returns true iff an entry that compares equal to key is in the
+    /// list.
+    pub fn contains(&self, key: Slice) -> bool
+    {
+        let x = self.find_greater_or_equal(key, None);
+        !x.is_null() && self.equal(key, Node::key(x))
+    }
+
+    /// Returns an estimate of the memory used by the nodes and keys of
+    /// the list.
+    pub fn memory_usage(&self) -> usize
+    {
+        self.arena.memory_usage()
+    }
+
+    /// Returns an iterator over the contents of the list.  The iterator
+    /// is initially not valid.
+    pub fn iter(&self) -> SkipListIterator<'_, C>
+    {
+        SkipListIterator {
+            list: self,
+            node: ptr::null(),
+        }
+    }
+
+    /// Allocate a node of a random height holding a copy of "key".
+    /// Returns the node and its height.
+    fn new_node(&self, key: Slice) -> (*mut Node, usize)
+    {
+        let height = random_height();
+        (SkipList::<C>::new_node_of_height(&self.arena, key, height), height)
+    }
+
+    fn new_node_of_height(arena: &ConcurrentArena, key: Slice, height: usize) -> *mut Node
+    {
+        let links_size = mem::size_of::<Node>() + mem::size_of::<AtomicPtr<Node>>() * (height - 1);
+        let memory = arena.allocate(links_size + key.len());
+        unsafe {
+            let key_copy = memory.add(links_size);
+            ptr::copy_nonoverlapping(key.as_ptr(), key_copy, key.len());
+            let node = memory as *mut Node;
+            ptr::addr_of_mut!((*node).key).write(key_copy);
+            ptr::addr_of_mut!((*node).key_len).write(key.len());
+            let links = ptr::addr_of_mut!((*node).next) as *mut AtomicPtr<Node>;
+            for i in 0..height {
+                links.add(i).write(AtomicPtr::new(ptr::null_mut()));
+            }
+            node
+        }
+    }
+
+    fn get_max_height(&self) -> usize
+    {
+        self.max_height.load(Ordering::Relaxed)
+    }
+
+    fn equal(&self, a: Slice, b: Slice) -> bool
+    {
+        self.comparator.compare(a, b) == 0
+    }
+
+    /// Return true if key is greater than the data stored in "n"
+    fn key_is_after_node(&self, key: Slice, n: *const Node) -> bool
+    {
+        // null n is considered infinite
+        !n.is_null() && self.comparator.compare(Node::key(n), key) < 0
+    }
+
+    /// Return the nodes that "key" goes between at "level", searching
+    /// forward from "before".
+    ///
+    /// REQUIRES: before is head or a node whose key is less than key
+    fn find_splice_for_level(&self, key: Slice, mut before: *mut Node, level: usize)
+                             -> (*mut Node, *mut Node)
+    {
+        loop {
+            let after = Node::next(before, level);
+            if self.key_is_after_node(key, after) {
+                before = after;
+            } else {
+                return (before, after);
+            }
+        }
+    }
+
+    /// Return the earliest node that comes at or after key.
+    /// Return null if there is no such node.
+    ///
+    /// If prev is set, fills prev[level] with pointer to previous node
+    /// at "level" for every level in [0..max_height-1].
+    fn find_greater_or_equal(&self, key: Slice, mut prev: Option<&mut [*mut Node; MAX_HEIGHT]>)
+                             -> *mut Node
+    {
+        let mut x = self.head;
+        let mut level = self.get_max_height() - 1;
+        loop {
+            let next = Node::next(x, level);
+            if self.key_is_after_node(key, next) {
+                // Keep searching in this list
+                x = next;
+            } else {
+                if let Some(ref mut prev) = prev {
+                    prev[level] = x;
+                }
+                if level == 0 {
+                    return next;
+                } else {
+                    // Switch to next list
+                    level -= 1;
+                }
+            }
+        }
+    }
+
+    /// Return the latest node with a key < key.
+    /// Return head if there is no such node.
+    fn find_less_than(&self, key: Slice) -> *mut Node
+    {
+        let mut x = self.head;
+        let mut level = self.get_max_height() - 1;
+        loop {
+            assert!(x == self.head || self.comparator.compare(Node::key(x), key) < 0);
+            let next = Node::next(x, level);
+            if next.is_null() || self.comparator.compare(Node::key(next), key) >= 0 {
+                if level == 0 {
+                    return x;
+                } else {
+                    // Switch to next list
+                    level -= 1;
+                }
+            } else {
+                x = next;
+            }
+        }
+    }
+
+    /// Return the last node in the list.
+    /// Return head if list is empty.
+    fn find_last(&self) -> *mut Node
+    {
+        let mut x = self.head;
+        let mut level = self.get_max_height() - 1;
+        loop {
+            let next = Node::next(x, level);
+            if next.is_null() {
+                if level == 0 {
+                    return x;
+                } else {
+                    // Switch to next list
+                    level -= 1;
+                }
+            } else {
+                x = next;
+            }
+        }
+    }
+}
+
+/// Iteration over the contents of a skip list
+pub struct SkipListIterator<'a, C: SliceComparator + 'a> {
+    list: &'a SkipList<C>,
+    node: *const Node,
+}
+
+impl<'a, C: SliceComparator> SkipListIterator<'a, C> {
+    /// Returns true iff the iterator is positioned at a valid node.
+    pub fn is_valid(&self) -> bool
+    {
+        !self.node.is_null()
+    }
+
+    /// Returns the key at the current position.
+    ///
+    /// REQUIRES: is_valid()
+    pub fn key(&self) -> Slice<'a>
+    {
+        assert!(self.is_valid());
+        Node::key(self.node)
+    }
+
+    /// Advances to the next position.
+    ///
+    /// REQUIRES: is_valid()
+    pub fn step(&mut self)
+    {
+        assert!(self.is_valid());
+        self.node = Node::next(self.node, 0);
+    }
+
+    /// Advances to the previous position.
+    ///
+    /// REQUIRES: is_valid()
+    pub fn prev(&mut self)
+    {
+        // Instead of using explicit "prev" links, we just search for the
+        // last node that falls before key.
+        assert!(self.is_valid());
+        self.node = self.list.find_less_than(self.key());
+        if self.node == self.list.head {
+            self.node = ptr::null();
+        }
+    }
+
+    /// Advance to the first entry with a key >= target
+    pub fn seek(&mut self, target: Slice)
+    {
+        self.node = self.list.find_greater_or_equal(target, None);
+    }
+
+    /// Position at the first entry in list.
+    /// Final state of iterator is is_valid() iff list is not empty.
+    pub fn seek_to_first(&mut self)
+    {
+        self.node = Node::next(self.list.head, 0);
+    }
+
+    /// Position at the last entry in list.
+    /// Final state of iterator is is_valid() iff list is not empty.
+    pub fn seek_to_last(&mut self)
+    {
+        self.node = self.list.find_last();
+        if self.node == self.list.head {
+            self.node = ptr::null();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SkipList;
+    use ::comparator::BytewiseComparator;
+    use ::util::coding;
+    use ::util::random::Random;
+    use std::collections::{BTreeSet, HashSet};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+
+    fn encode(key: u64) -> Vec<u8>
+    {
+        key.to_be_bytes().to_vec()
+    }
+
+    #[test]
+    fn empty()
+    {
+        let list = SkipList::new(BytewiseComparator);
+        assert!(!list.contains(&encode(10)));
+
+        let mut iter = list.iter();
+        assert!(!iter.is_valid());
+        iter.seek_to_first();
+        assert!(!iter.is_valid());
+        iter.seek(&encode(100));
+        assert!(!iter.is_valid());
+        iter.seek_to_last();
+        assert!(!iter.is_valid());
+    }
+
+    #[test]
+    fn insert_and_lookup()
+    {
+        const N: usize = 2000;
+        const R: u32 = 5000;
+        let mut rnd = Random::new(1000);
+        let mut keys = BTreeSet::new();
+        let mut list = SkipList::new(BytewiseComparator);
+        for _ in 0..N {
+            let key = rnd.uniform(R) as u64;
+            if keys.insert(key) {
+                list.insert(&encode(key));
+            }
+        }
+
+        for i in 0..R as u64 {
+            assert_eq!(list.contains(&encode(i)), keys.contains(&i), "key {}", i);
+        }
+
+        // Simple iterator tests
+        let mut iter = list.iter();
+        iter.seek(&encode(0));
+        assert_eq!(iter.key(), &encode(*keys.iter().next().unwrap())[..]);
+        iter.seek_to_first();
+        assert_eq!(iter.key(), &encode(*keys.iter().next().unwrap())[..]);
+        iter.seek_to_last();
+        assert_eq!(iter.key(), &encode(*keys.iter().next_back().unwrap())[..]);
+
+        // Forward iteration test
+        for i in 0..R as u64 {
+            let mut iter = list.iter();
+            iter.seek(&encode(i));
+
+            // Compare against model iterator
+            let mut model = keys.range(i..);
+            for _ in 0..3 {
+                match model.next() {
+                    Some(&key) => {
+                        assert!(iter.is_valid());
+                        assert_eq!(iter.key(), &encode(key)[..]);
+                        iter.step();
+                    },
+                    None => {
+                        assert!(!iter.is_valid());
+                        break;
+                    },
+                }
+            }
+        }
+
+        // Backward iteration test
+        let mut iter = list.iter();
+        iter.seek_to_last();
+        for &key in keys.iter().rev() {
+            assert!(iter.is_valid());
+            assert_eq!(iter.key(), &encode(key)[..]);
+            iter.prev();
+        }
+        assert!(!iter.is_valid());
+    }
+
+    #[test]
+    fn memory_usage_grows()
+    {
+        let mut list = SkipList::new(BytewiseComparator);
+        let mut usage = list.memory_usage();
+        for i in 0..1000 {
+            list.insert(&encode(i));
+            assert!(list.memory_usage() >= usage);
+            usage = list.memory_usage();
+        }
+        assert!(usage > 1000 * 8);
+    }
+
+    const NUM_WRITERS: usize = 4;
+    const NUM_READERS: usize = 2;
+    const KEYS_PER_WRITER: usize = 5000;
+
+    /// The i'th key inserted by "writer".  Multiplying by an odd
+    /// constant scatters the keys of each writer across the whole list
+    /// while keeping them distinct.
+    fn make_key(writer: usize, i: usize) -> Vec<u8>
+    {
+        let scattered = (i as u32).wrapping_mul(2654435761);
+        let mut key = scattered.to_be_bytes().to_vec();
+        coding::put_fixed32(&mut key, writer as u32);
+        key
+    }
+
+    /// Several writers insert into the list at once while readers
+    /// iterate it.  Every pass of a reader must see the keys in
+    /// strictly increasing order, and must see every key whose insert
+    /// had finished before the pass started.
+    #[test]
+    fn concurrent_inserts_with_readers()
+    {
+        let list = Arc::new(SkipList::new(BytewiseComparator));
+        let inserted: Arc<Vec<AtomicUsize>> =
+            Arc::new((0..NUM_WRITERS).map(|_| AtomicUsize::new(0)).collect());
+        let done = Arc::new(AtomicBool::new(false));
+
+        let readers: Vec<_> = (0..NUM_READERS).map(|_| {
+            let list = list.clone();
+            let inserted = inserted.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut passes = 0;
+                loop {
+                    let finished = done.load(Ordering::Acquire);
+                    let visible: Vec<usize> =
+                        inserted.iter().map(|n| n.load(Ordering::Acquire)).collect();
+
+                    let mut seen = HashSet::new();
+                    let mut last: Option<Vec<u8>> = None;
+                    let mut iter = list.iter();
+                    iter.seek_to_first();
+                    while iter.is_valid() {
+                        let key = iter.key().to_vec();
+                        if let Some(ref last) = last {
+                            assert!(*last < key, "keys out of order");
+                        }
+                        seen.insert(key.clone());
+                        last = Some(key);
+                        iter.step();
+                    }
+
+                    for (writer, &n) in visible.iter().enumerate() {
+                        for i in 0..n {
+                            assert!(seen.contains(&make_key(writer, i)),
+                                    "key {} of writer {} not visible", i, writer);
+                        }
+                    }
+                    passes += 1;
+                    if finished {
+                        return passes;
+                    }
+                }
+            })
+        }).collect();
+
+        let writers: Vec<_> = (0..NUM_WRITERS).map(|writer| {
+            let list = list.clone();
+            let inserted = inserted.clone();
+            thread::spawn(move || {
+                for i in 0..KEYS_PER_WRITER {
+                    list.insert_concurrently(&make_key(writer, i));
+                    inserted[writer].store(i + 1, Ordering::Release);
+                }
+            })
+        }).collect();
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::Release);
+        for reader in readers {
+            assert!(reader.join().unwrap() > 0);
+        }
+
+        let mut count = 0;
+        let mut iter = list.iter();
+        iter.seek_to_first();
+        while iter.is_valid() {
+            count += 1;
+            iter.step();
+        }
+        assert_eq!(count, NUM_WRITERS * KEYS_PER_WRITER);
+        for writer in 0..NUM_WRITERS {
+            for i in 0..KEYS_PER_WRITER {
+                assert!(list.contains(&make_key(writer, i)));
+            }
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use ::slice::Slice;
+use std::cmp;
+
+/// A SliceTransform extracts a prefix from keys.  When set as
+/// Options::prefix_extractor, filters hold the prefixes of the keys of
+/// a table as well as (or instead of) the keys themselves, so seeks to
+/// a prefix can skip tables that hold no keys with that prefix.
+///
+/// A SliceTransform implementation must be thread-safe since it may be
+/// used by several tables at once.
+pub trait SliceTransform: Send + Sync {
+    /// Return the name of this transform.  It is recorded in the
+    /// properties of every table, and the prefixes in the filter of a
+    /// table are only used if it matches the name of the transform the
+    /// table is read with.  The name must change if the transform
+    /// changes in a way that maps any key to a different prefix.
+    fn name(&self) -> &str;
+
+    /// Extract the prefix of "key".
+    ///
+    /// REQUIRES: in_domain(key)
+    fn transform<'a>(&self, key: Slice<'a>) -> Slice<'a>;
+
+    /// Return true iff "key" has a prefix.  Keys outside the domain are
+    /// only filtered by whole key.
+    fn in_domain(&self, key: Slice) -> bool;
+}
+
+/// A transform that extracts the first "prefix_len" bytes of keys.
+/// Keys shorter than that have no prefix.
+pub struct FixedPrefixTransform {
+    prefix_len: usize,
+    name: String,
+}
+
+impl FixedPrefixTransform {
+    pub fn new(prefix_len: usize) -> FixedPrefixTransform
+    {
+        FixedPrefixTransform {
+            prefix_len,
+            name: format!("rocksdb.FixedPrefix.{}", prefix_len),
+        }
+    }
+}
+
+impl SliceTransform for FixedPrefixTransform {
+    fn name(&self) -> &str
+    {
+        &self.name
+    }
+
+    fn transform<'a>(&self, key: Slice<'a>) -> Slice<'a>
+    {
+        assert!(self.in_domain(key));
+        &key[..self.prefix_len]
+    }
+
+    fn in_domain(&self, key: Slice) -> bool
+    {
+        key.len() >= self.prefix_len
+    }
+}
+
+/// A transform that extracts the first "cap_len" bytes of keys, or the
+/// whole key if it is shorter than that.
+pub struct CappedPrefixTransform {
+    cap_len: usize,
+    name: String,
+}
+
+impl CappedPrefixTransform {
+    pub fn new(cap_len: usize) -> CappedPrefixTransform
+    {
+        CappedPrefixTransform {
+            cap_len,
+            name: format!("rocksdb.CappedPrefix.{}", cap_len),
+        }
+    }
+}
+
+impl SliceTransform for CappedPrefixTransform {
+    fn name(&self) -> &str
+    {
+        &self.name
+    }
+
+    fn transform<'a>(&self, key: Slice<'a>) -> Slice<'a>
+    {
+        &key[..cmp::min(key.len(), self.cap_len)]
+    }
+
+    fn in_domain(&self, key: Slice) -> bool
+    {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CappedPrefixTransform, FixedPrefixTransform, SliceTransform};
+
+    #[test]
+    fn fixed_prefix()
+    {
+        let transform = FixedPrefixTransform::new(3);
+        assert_eq!(transform.name(), "rocksdb.FixedPrefix.3");
+        assert!(transform.in_domain(b"abc"));
+        assert!(transform.in_domain(b"abcdef"));
+        assert!(!transform.in_domain(b"ab"));
+        assert!(!transform.in_domain(b""));
+        assert_eq!(transform.transform(b"abc"), b"abc");
+        assert_eq!(transform.transform(b"abcdef"), b"abc");
+        assert_eq!(transform.transform(b"\x00\xff\x00\xff"), b"\x00\xff\x00");
+    }
+
+    #[test]
+    fn capped_prefix()
+    {
+        let transform = CappedPrefixTransform::new(3);
+        assert_eq!(transform.name(), "rocksdb.CappedPrefix.3");
+        assert!(transform.in_domain(b""));
+        assert!(transform.in_domain(b"abcdef"));
+        assert_eq!(transform.transform(b""), b"");
+        assert_eq!(transform.transform(b"ab"), b"ab");
+        assert_eq!(transform.transform(b"abcdef"), b"abc");
+    }
+}
@@ -1,7 +1,7 @@
 use std::error;
 use std::fmt;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Status {
     Ok,
     NotFound(String),
@@ -11,6 +11,14 @@ pub enum Status {
     IOError(String),
 }
 
+impl Status {
+    /// Returns true iff the status indicates success.
+    pub fn is_ok(&self) -> bool
+    {
+        *self == Status::Ok
+    }
+}
+
 impl fmt::Display for Status {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Status error: {:?}", self)
@@ -21,15 +29,15 @@ impl error::Error for Status {
     fn description(&self) -> &str {
         match *self {
             Status::Ok => "no error",
-            Status::NotFound(ref s) => &*s,
-            Status::Corruption(ref s) => &*s,
-            Status::NotSupported(ref s) => &*s,
-            Status::InvalidArgument(ref s) => &*s,
-            Status::IOError(ref s) => &*s,
+            Status::NotFound(ref s) => s,
+            Status::Corruption(ref s) => s,
+            Status::NotSupported(ref s) => s,
+            Status::InvalidArgument(ref s) => s,
+            Status::IOError(ref s) => s,
         }
     }
 
-    fn cause(&self) -> Option<&error::Error> {
+    fn cause(&self) -> Option<&dyn error::Error> {
         None
     }
 }
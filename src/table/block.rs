@@ -3,8 +3,13 @@ use ::errors::RubbleResult;
 use ::util::coding;
 use ::status::Status;
 use ::comparator::SliceComparator;
+use ::table::data_block_hash_index::{self, HASH_INDEX_FLAG, NO_ENTRY, COLLISION};
+use ::table::format::BlockContents;
+use ::table::iterator::RubbleIterator;
 use std::mem;
+use std::ops::{Bound, Deref};
 use std::str;
+use std::sync::Arc;
 
 pub struct OwnedBlock {
     data: Vec<u8>,
@@ -18,7 +23,7 @@ pub struct SliceBlock<'a> {
 
 pub trait Block {
     fn get_size(&self) -> usize;
-    fn data(&self) -> Slice;
+    fn data(&self) -> Slice<'_>;
     fn restart_offset(&self) -> usize;
 
     fn iter<'a, T: SliceComparator>(&'a self, comparator: T) -> BlockIterator<'a, T>;
@@ -27,30 +32,19 @@ pub trait Block {
     {
         assert!(data.len() >= mem::size_of::<u32>());
         let offset = data.len() - mem::size_of::<u32>();
-        coding::decode_fixed32(&data[offset..]) as usize
+        (coding::decode_fixed32(&data[offset..]) & !HASH_INDEX_FLAG) as usize
     }
 
     fn iter_slice<'a, T: SliceComparator>(&'a self, comparator: T, slice: Slice<'a>) -> BlockIterator<'a, T>
     {
-        if self.get_size() < mem::size_of::<u32>() {
-            BlockIterator::new(comparator, &[], 0, 0)
-                .with_status(Status::Corruption("bad block contents".into()))
-        } else {
-            let num_restarts = Self::num_restarts(slice);
-            if num_restarts == 0 {
-                BlockIterator::new(comparator, &[], 0, 0)
-            } else {
-                let restart_offset = self.restart_offset();
-                BlockIterator::new(comparator, slice, restart_offset, num_restarts)
-            }
-        }
+        BlockIterator::for_block(comparator, BlockData::Borrowed(slice), self.restart_offset())
     }
 
 }
 
 impl Block for OwnedBlock {
     fn get_size(&self) -> usize { self.data.len() }
-    fn data(&self) -> Slice { &self.data }
+    fn data(&self) -> Slice<'_> { &self.data }
     fn restart_offset(&self) -> usize { self.restart_offset }
 
     fn iter<'a, T: SliceComparator>(&'a self, comparator: T) -> BlockIterator<'a, T>
@@ -62,7 +56,7 @@ impl Block for OwnedBlock {
 
 impl<'a> Block for SliceBlock<'a> {
     fn get_size(&self) -> usize { self.data.len() }
-    fn data(&self) -> Slice { self.data }
+    fn data(&self) -> Slice<'_> { self.data }
     fn restart_offset(&self) -> usize { self.restart_offset }
 
     fn iter<'i, T: SliceComparator>(&'i self, comparator: T) -> BlockIterator<'i, T>
@@ -72,115 +66,251 @@ impl<'a> Block for SliceBlock<'a> {
 }
 
 
+/// Validate the restart array at the end of "data" and return the
+/// offset at which it begins.
+fn parse_restart_offset(data: Slice) -> RubbleResult<usize>
+{
+    let sizeof_u32 = mem::size_of::<u32>();
+    if data.len() < sizeof_u32 {
+        return Err(Status::Corruption("bad block contents".into()).into())
+    }
+
+    // The restart array ends where the hash index (if any) begins
+    let mut restarts_end = data.len() - sizeof_u32;
+    if let Some(hash_index_len) = hash_index_len(data)? {
+        restarts_end -= hash_index_len;
+    }
+
+    let max_restarts_allowed = restarts_end / sizeof_u32;
+    let num_restarts = OwnedBlock::num_restarts(data);
+
+    if num_restarts > max_restarts_allowed {
+        return Err("The size is too small for num_restarts()".into())
+    }
+
+    Ok(restarts_end - num_restarts * sizeof_u32)
+}
+
+/// Return the length of the hash index of the block held in "data",
+/// or None if the block has no hash index.
+fn hash_index_len(data: Slice) -> RubbleResult<Option<usize>>
+{
+    let sizeof_u16 = mem::size_of::<u16>();
+    let sizeof_u32 = mem::size_of::<u32>();
+    if coding::decode_fixed32(&data[data.len() - sizeof_u32..]) & HASH_INDEX_FLAG == 0 {
+        return Ok(None);
+    }
+    let index_end = data.len() - sizeof_u32;
+    if index_end < sizeof_u16 {
+        return Err(Status::Corruption("bad block hash index".into()).into())
+    }
+    let num_buckets = coding::decode_fixed16(&data[index_end - sizeof_u16..]) as usize;
+    if num_buckets + sizeof_u16 > index_end {
+        return Err(Status::Corruption("bad block hash index".into()).into())
+    }
+    Ok(Some(num_buckets + sizeof_u16))
+}
+
 impl OwnedBlock {
-    pub fn new(contents: Slice) -> RubbleResult<OwnedBlock>
+    pub fn new(contents: BlockContents) -> RubbleResult<OwnedBlock>
     {
-        let sizeof_u32 = mem::size_of::<u32>();
-        let max_restarts_allowed = (contents.len() - sizeof_u32) / sizeof_u32;
-        let num_restarts = Self::num_restarts(contents);
+        let restart_offset = parse_restart_offset(&contents.data)?;
+        Ok(OwnedBlock {
+            data: contents.data.into_owned(),
+            restart_offset,
+        })
+    }
 
-        if num_restarts > max_restarts_allowed {
-            return Err("The size is too small for num_restarts()".into())
+    /// Wrap "contents" that are not in the block format (e.g. a filter
+    /// partition) so that they may share the block cache with blocks.
+    /// Such a block is only read through data(): it has no entries to
+    /// iterate.
+    pub fn raw(contents: BlockContents) -> OwnedBlock
+    {
+        let data = contents.data.into_owned();
+        OwnedBlock {
+            restart_offset: data.len(),
+            data,
         }
+    }
 
-        Ok(OwnedBlock {
-            data: contents.to_vec(),
-            restart_offset: contents.len() - (1 + num_restarts) * sizeof_u32,
-        })
+    pub fn size(&self) -> usize { self.data.len() }
+
+    /// Like iter(), but the returned iterator shares ownership of the
+    /// block rather than borrowing it, so it may outlive the caller's
+    /// reference (e.g. a data block read on behalf of a table iterator).
+    pub fn iter_shared<'a, T: SliceComparator>(self: Arc<Self>, comparator: T) -> BlockIterator<'a, T>
+    {
+        let restart_offset = self.restart_offset;
+        BlockIterator::for_block(comparator, BlockData::Shared(self), restart_offset)
     }
 }
 
 impl<'a> SliceBlock<'a> {
-    fn get_size(&self) -> usize { self.data.len() }
+    /// Initialize the block with "data", which must outlive the block
+    /// and every iterator over it (e.g. a region of a memory-mapped
+    /// file).
+    pub fn new(data: Slice<'a>) -> RubbleResult<SliceBlock<'a>>
+    {
+        let restart_offset = parse_restart_offset(data)?;
+        Ok(SliceBlock {
+            data,
+            restart_offset,
+        })
+    }
+
+    /// Like iter(), but the returned iterator borrows the underlying
+    /// data rather than the block, so it may outlive the block itself.
+    pub fn iter_borrowed<T: SliceComparator>(self, comparator: T) -> BlockIterator<'a, T>
+    {
+        BlockIterator::for_block(comparator, BlockData::Borrowed(self.data), self.restart_offset)
+    }
+}
+
+/// The bytes a BlockIterator walks over: either borrowed from a block
+/// that outlives the iterator, or a block the iterator shares ownership
+/// of.
+#[derive(Clone)]
+enum BlockData<'a> {
+    Borrowed(Slice<'a>),
+    Shared(Arc<OwnedBlock>),
 }
 
-struct DecodedEntry<'a> {
-    new_slice: Slice<'a>,
+impl<'a> Deref for BlockData<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8]
+    {
+        match *self {
+            BlockData::Borrowed(slice) => slice,
+            BlockData::Shared(ref block) => &block.data,
+        }
+    }
+}
+
+struct DecodedEntry {
+    /// Offset of the key delta, relative to the start of the entry
+    key_offset: usize,
     shared: u32,
     non_shared: u32,
     value_length: u32,
 }
 
 /// Helper routine: decode the next block entry starting at "p",
-/// storing the number of shared key bytes, non_shared key bytes,
-/// and the length of the value in "*shared", "*non_shared", and
-/// "*value_length", respectively.  Will not dereference past "limit".
+/// returning the number of shared key bytes, non_shared key bytes,
+/// and the length of the value.  Will not read past the end of "p".
 ///
-/// If any errors are detected, returns NULL.  Otherwise, returns a
-/// pointer to the key delta (just past the three decoded values).
+/// If any errors are detected, returns an error.  Otherwise, the
+/// returned key_offset is the position of the key delta (just past
+/// the three decoded values).
 fn decode_entry(mut p: &[u8]) -> RubbleResult<DecodedEntry>
 {
     if p.len() < 3 {
         return Err("Entry missing header!".into())
     };
 
-    let mut cur = 0;
+    let entry_len = p.len();
     let mut shared = p[0] as u32;
     let mut non_shared = p[1] as u32;
     let mut value_length = p[2] as u32;
 
     if (shared | non_shared | value_length) < 128 {
         // Fast path: all three values are encoded in one byte each
-        cur += 3;
+        p = &p[3..];
 
     } else {
-        let fallback = try!(coding::get_varint32_ptr_fallback(p));
+        let fallback = coding::get_varint32_ptr_fallback(p)?;
         p = fallback.slice;
         shared = fallback.value;
-        let fallback = try!(coding::get_varint32_ptr_fallback(p));
+        let fallback = coding::get_varint32_ptr_fallback(p)?;
         p = fallback.slice;
         non_shared = fallback.value;
-        let fallback = try!(coding::get_varint32_ptr_fallback(p));
+        let fallback = coding::get_varint32_ptr_fallback(p)?;
         p = fallback.slice;
         value_length = fallback.value;
     }
 
-    let new_slice = &p[cur..];
-
-    if new_slice.len() < (non_shared + value_length) as usize {
+    if p.len() < (non_shared + value_length) as usize {
         return Err("bad block?".into());
     }
 
-    return Ok(DecodedEntry {
-        new_slice: new_slice,
-        shared: shared,
-        non_shared: non_shared,
-        value_length: value_length,
-    });
+    Ok(DecodedEntry {
+        key_offset: entry_len - p.len(),
+        shared,
+        non_shared,
+        value_length,
+    })
 }
 
+#[derive(Clone)]
 pub struct BlockIterator<'a, T: SliceComparator> {
     comparator: T,
-    data: Slice<'a>,
+    data: BlockData<'a>,
     value_offset: usize,
     value_len: usize,
     restarts: usize,
     num_restarts: usize,
     current: usize,
     restart_index: usize,
-    key: String,
+    key: Vec<u8>,
     status: Status,
+    /// Offset of the hash index, if the block has one
+    hash_index: Option<usize>,
 }
 
 impl<'a, T: SliceComparator> BlockIterator<'a, T> {
     pub fn new(comparator: T, data: Slice<'a>, restarts: usize, num_restarts: usize)
                -> BlockIterator<'a, T>
     {
-        assert!(num_restarts > 0);
+        Self::with_data(comparator, BlockData::Borrowed(data), restarts, num_restarts)
+    }
+
+    fn with_data(comparator: T, data: BlockData<'a>, restarts: usize, num_restarts: usize)
+                 -> BlockIterator<'a, T>
+    {
+        // The hash index, if any, lies between the restart array and
+        // num_restarts
+        let hash_index_offset = restarts + num_restarts * mem::size_of::<u32>();
+        let hash_index = match data.len() >= mem::size_of::<u32>() {
+            true => match hash_index_len(&data) {
+                Ok(Some(len)) if hash_index_offset + len + mem::size_of::<u32>() == data.len() =>
+                    Some(hash_index_offset),
+                _ => None,
+            },
+            false => None,
+        };
         BlockIterator::<'a, T> {
-            key: String::new(),
+            hash_index,
+            key: vec![],
             status: Status::Ok,
-            value_offset: 0,
+            value_offset: restarts,
             value_len: 0,
-            comparator: comparator,
-            data: data,
-            restarts: restarts,
-            num_restarts: num_restarts,
+            comparator,
+            data,
+            restarts,
+            num_restarts,
             current: restarts,
             restart_index: num_restarts,
         }
     }
 
+    /// Returns an iterator over the block held in "data", whose restart
+    /// array begins at "restart_offset".
+    fn for_block(comparator: T, data: BlockData<'a>, restart_offset: usize) -> BlockIterator<'a, T>
+    {
+        if data.len() < mem::size_of::<u32>() {
+            BlockIterator::new(comparator, &[], 0, 0)
+                .with_status(Status::Corruption("bad block contents".into()))
+        } else {
+            let num_restarts = OwnedBlock::num_restarts(&data);
+            if num_restarts == 0 {
+                BlockIterator::new(comparator, &[], 0, 0)
+            } else {
+                BlockIterator::with_data(comparator, data, restart_offset, num_restarts)
+            }
+        }
+    }
+
     fn with_status(mut self, status: Status) -> BlockIterator<'a, T>
     {
         self.status = status;
@@ -207,12 +337,13 @@ impl<'a, T: SliceComparator> BlockIterator<'a, T> {
 
     pub fn seek_to_restart_point(&mut self, index: usize)
     {
-        self.key = String::new();
+        self.key.clear();
         self.restart_index = index;
         // current_ will be fixed by ParseNextKey();
 
         // ParseNextKey() starts at the end of value_, so set value_ accordingly
         self.value_offset = self.get_restart_point(index);
+        self.value_len = 0;
     }
 
     pub fn is_valid(&self) -> bool
@@ -224,12 +355,12 @@ impl<'a, T: SliceComparator> BlockIterator<'a, T> {
         &self.status
     }
 
-    pub fn key(&self) -> String {
+    pub fn key(&self) -> Slice<'_> {
         assert!(self.is_valid());
-        self.key.clone()
+        &self.key
     }
 
-    pub fn value(&self) -> Slice {
+    pub fn value(&self) -> Slice<'_> {
         assert!(self.is_valid());
         &self.data[self.value_offset..self.value_offset+self.value_len]
     }
@@ -254,22 +385,32 @@ impl<'a, T: SliceComparator> BlockIterator<'a, T> {
             }
             self.restart_index -= 1;
         }
+
+        self.seek_to_restart_point(self.restart_index);
+        // Loop until end of current entry hits the start of original entry
+        while self.parse_next_key() && self.next_entry_offset() < original {
+            // Keep skipping
+        }
     }
 
     pub fn seek(&mut self, target: Slice)
     {
+        if self.num_restarts == 0 {
+            return;
+        }
+
         // Binary search in restart array to find the last restart point
         // with a key < target
         let mut left = 0;
         let mut right = self.num_restarts - 1;
 
         while left < right {
-            let mid = (left + right + 1) / 2;
+            let mid = (left + right).div_ceil(2);
             let region_offset = self.get_restart_point(mid);
 
             // let shared, non_shared, value_length;
 
-            let entry = match decode_entry(&self.data[region_offset as usize..]) {
+            let entry = match decode_entry(&self.data[region_offset..self.restarts]) {
                 Err(_) => return self.corruption_error(),
                 Ok(key) => key,
             };
@@ -278,7 +419,8 @@ impl<'a, T: SliceComparator> BlockIterator<'a, T> {
                 return self.corruption_error()
             }
 
-            let mid_key = entry.new_slice;
+            let key_offset = region_offset + entry.key_offset;
+            let mid_key = &self.data[key_offset..key_offset + entry.non_shared as usize];
 
             if self.compare(mid_key, target) < 0 {
                 // Key at "mid" is smaller than "target".  Therefore all
@@ -299,19 +441,60 @@ impl<'a, T: SliceComparator> BlockIterator<'a, T> {
             if !self.parse_next_key() {
                 return;
             }
-            if self.compare(self.key.as_bytes(), target) >= 0 {
+            if self.compare(&self.key, target) >= 0 {
                 return;
             }
         }
 
     }
 
+    /// Like seek(), but meant for point lookups of "target": if the
+    /// block has a hash index, it is used to go straight to the restart
+    /// interval that holds "target" instead of binary searching the
+    /// restart array.  Leaves the iterator invalid if the hash index
+    /// shows that the block does not hold "target".  If the block holds
+    /// "target", the iterator is left at it; otherwise it may be left at
+    /// any entry after "target", not necessarily the first one.
+    pub fn seek_for_get(&mut self, target: Slice)
+    {
+        let restart_index = match self.hash_index {
+            Some(offset) => {
+                let index_end = self.data.len() - mem::size_of::<u32>();
+                data_block_hash_index::lookup(&self.data[offset..index_end], target)
+            },
+            None => COLLISION,
+        };
+
+        match restart_index {
+            COLLISION => self.seek(target),
+            NO_ENTRY => {
+                // The block does not hold "target"
+                self.current = self.restarts;
+                self.restart_index = self.num_restarts;
+            },
+            restart_index if restart_index as usize >= self.num_restarts => self.corruption_error(),
+            restart_index => {
+                // Linear search (within restart block) for first key >= target
+                self.seek_to_restart_point(restart_index as usize);
+                while self.parse_next_key() && self.compare(&self.key, target) < 0 {
+                    // Keep skipping
+                }
+            },
+        }
+    }
+
     pub fn seek_to_first(&mut self) {
+        if self.num_restarts == 0 {
+            return;
+        }
         self.seek_to_restart_point(0);
         self.parse_next_key();
     }
 
     pub fn seek_to_last(&mut self) {
+        if self.num_restarts == 0 {
+            return;
+        }
         let n_restarts = self.num_restarts - 1;
         self.seek_to_restart_point(n_restarts);
         while self.parse_next_key() && self.next_entry_offset() < self.restarts {
@@ -323,22 +506,21 @@ impl<'a, T: SliceComparator> BlockIterator<'a, T> {
         self.current = self.restarts;
         self.restart_index = self.num_restarts;
         self.status = Status::Corruption("bad entry in block".into());
-        self.key = String::new();
+        self.key.clear();
     }
 
     fn parse_next_key(&mut self) -> bool {
         self.current = self.next_entry_offset();
-        let p = &self.data[self.current..];
 
-        if p.len() == 0 {
+        if self.current >= self.restarts {
             // No more entries to return.  Mark as invalid.
             self.current = self.restarts;
             self.restart_index = self.num_restarts;
             return false;
         }
 
-        let entry = match decode_entry(p) {
-            Ok(p) => p,
+        let entry = match decode_entry(&self.data[self.current..self.restarts]) {
+            Ok(entry) => entry,
             _ => {
                 self.corruption_error();
                 return false;
@@ -350,11 +532,14 @@ impl<'a, T: SliceComparator> BlockIterator<'a, T> {
             return false;
         }
 
-        self.key = str::from_utf8(&entry.new_slice[..entry.non_shared as usize])
-            .expect("Invalid UTF-8 key")
-            .to_owned();
+        // The key is the first "shared" bytes of the previous key
+        // followed by the delta stored in this entry.
+        let key_offset = self.current + entry.key_offset;
+        let non_shared = entry.non_shared as usize;
+        self.key.truncate(entry.shared as usize);
+        self.key.extend_from_slice(&self.data[key_offset..key_offset + non_shared]);
 
-        self.value_offset = entry.non_shared as usize;
+        self.value_offset = key_offset + non_shared;
         self.value_len = entry.value_length as usize;
 
         while self.restart_index + 1 < self.num_restarts
@@ -367,23 +552,598 @@ impl<'a, T: SliceComparator> BlockIterator<'a, T> {
     }
 }
 
+impl<'a, T: SliceComparator> RubbleIterator for BlockIterator<'a, T> {
+    fn is_valid(&self) -> bool { BlockIterator::is_valid(self) }
+    fn seek_to_first(&mut self) { BlockIterator::seek_to_first(self) }
+    fn seek_to_last(&mut self) { BlockIterator::seek_to_last(self) }
+    fn seek(&mut self, target: Slice) { BlockIterator::seek(self, target) }
+    fn step(&mut self) { BlockIterator::step(self) }
+    fn prev(&mut self) { BlockIterator::prev(self) }
+    fn key(&self) -> Slice<'_> { BlockIterator::key(self) }
+    fn value(&self) -> Slice<'_> { BlockIterator::value(self) }
+    fn status(&self) -> &Status { BlockIterator::status(self) }
+}
+
+/// A key/value pair copied out of a block.
+#[derive(Clone, Debug, PartialEq)]
 pub struct KVEntry {
-    key: String,
+    key: Vec<u8>,
     value: Vec<u8>,
 }
 
-impl<'a, T: SliceComparator> Iterator for BlockIterator<'a, T> {
-    // we will be counting with usize
+impl KVEntry {
+    pub fn key(&self) -> Slice<'_> { &self.key }
+    pub fn value(&self) -> Slice<'_> { &self.value }
+}
+
+impl<'a, T: SliceComparator + Clone> BlockIterator<'a, T> {
+    fn entry(&self) -> KVEntry
+    {
+        KVEntry {
+            key: self.key.clone(),
+            value: self.value().to_vec(),
+        }
+    }
+
+    /// Returns an iterator over the entries of the block whose keys
+    /// fall within the given bounds, in either direction.
+    pub fn range(mut self, start: Bound<Slice>, end: Bound<Slice>) -> BlockEntries<'a, T>
+    {
+        let mut back = self.clone();
+
+        match start {
+            Bound::Included(start) => self.seek(start),
+            Bound::Excluded(start) => {
+                self.seek(start);
+                if self.is_valid() && self.compare(&self.key, start) == 0 {
+                    self.step();
+                }
+            },
+            Bound::Unbounded => self.seek_to_first(),
+        }
+
+        match end {
+            Bound::Included(end) => {
+                back.seek(end);
+                if !back.is_valid() {
+                    back.seek_to_last();
+                } else if back.compare(&back.key, end) > 0 {
+                    back.prev();
+                }
+            },
+            Bound::Excluded(end) => {
+                back.seek(end);
+                if back.is_valid() {
+                    back.prev();
+                } else {
+                    back.seek_to_last();
+                }
+            },
+            Bound::Unbounded => back.seek_to_last(),
+        }
+
+        // The range is empty if either end ran off the block or the
+        // ends crossed over.
+        let done = !self.is_valid() || !back.is_valid() || self.current > back.current;
+        BlockEntries {
+            front: self,
+            back,
+            done,
+        }
+    }
+
+    /// Returns an iterator over the entries of the block with keys in
+    /// [start, end).
+    pub fn range_between(self, start: Slice, end: Slice) -> BlockEntries<'a, T>
+    {
+        self.range(Bound::Included(start), Bound::Excluded(end))
+    }
+
+    /// Returns an iterator over the entries of the block with keys at
+    /// or past start.
+    pub fn range_from(self, start: Slice) -> BlockEntries<'a, T>
+    {
+        self.range(Bound::Included(start), Bound::Unbounded)
+    }
+}
+
+/// An iterator over a contiguous run of entries in a block, which may
+/// be consumed from either end.  Returned by BlockIterator::range()
+/// and by iterating over a BlockIterator with a for loop.
+pub struct BlockEntries<'a, T: SliceComparator> {
+    /// Positioned at the next entry yielded by next()
+    front: BlockIterator<'a, T>,
+    /// Positioned at the next entry yielded by next_back()
+    back: BlockIterator<'a, T>,
+    /// Set once front and back have passed each other
+    done: bool,
+}
+
+impl<'a, T: SliceComparator + Clone> Iterator for BlockEntries<'a, T> {
     type Item = KVEntry;
 
-    fn next(&mut self) -> Option<KVEntry> {
-        self.step();
-        match self.num_restarts {
-            0 => None,
-            _ => Some(KVEntry {
-                key: self.key(),
-                value: self.value().to_vec(),
+    fn next(&mut self) -> Option<KVEntry>
+    {
+        if self.done || !self.front.is_valid() {
+            return None;
+        }
+        let entry = self.front.entry();
+        if self.front.current == self.back.current {
+            self.done = true;
+        } else {
+            self.front.step();
+        }
+        Some(entry)
+    }
+}
+
+impl<'a, T: SliceComparator + Clone> DoubleEndedIterator for BlockEntries<'a, T> {
+    fn next_back(&mut self) -> Option<KVEntry>
+    {
+        if self.done || !self.back.is_valid() {
+            return None;
+        }
+        let entry = self.back.entry();
+        if self.front.current == self.back.current {
+            self.done = true;
+        } else {
+            self.back.prev();
+        }
+        Some(entry)
+    }
+}
+
+impl<'a, T: SliceComparator + Clone> IntoIterator for BlockIterator<'a, T> {
+    type Item = KVEntry;
+    type IntoIter = BlockEntries<'a, T>;
+
+    /// Iterates over every entry of the block, regardless of where the
+    /// iterator is currently positioned.
+    fn into_iter(self) -> BlockEntries<'a, T>
+    {
+        self.range(Bound::Unbounded, Bound::Unbounded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Block, OwnedBlock, SliceBlock};
+    use ::comparator::BytewiseComparator;
+    use ::table::block_builder::{self, BlockBuilder};
+    use ::table::data_block_hash_index::{HASH_INDEX_FLAG, MAX_RESTART_SUPPORTED, COLLISION,
+                                         NO_ENTRY};
+    use ::table::format::BlockContents;
+    use ::table::iterator::RubbleIterator;
+    use ::util::coding;
+    use std::collections::Bound;
+    use std::sync::Arc;
+
+    type Entry = (Vec<u8>, Vec<u8>);
+
+    fn entries(num_keys: usize) -> Vec<Entry>
+    {
+        (0..num_keys).map(|i| {
+            (format!("key{:06}", i * 2).into_bytes(), format!("value{}", i).into_bytes())
+        }).collect()
+    }
+
+    fn build_block(entries: &[Entry], restart_interval: usize) -> OwnedBlock
+    {
+        let mut builder = BlockBuilder::new(block_builder::Options {
+            block_restart_interval: restart_interval,
+            comparator: Arc::new(BytewiseComparator),
+            hash_index_util_ratio: None,
+        });
+        for (key, value) in entries {
+            builder.add(key, value);
+        }
+        let mut contents = BlockContents::new();
+        contents.data = builder.finish().to_vec().into();
+        OwnedBlock::new(contents).unwrap()
+    }
+
+    #[test]
+    fn binary_keys_and_values()
+    {
+        // Keys share prefixes across restart points, hold embedded
+        // zeros and 0xff bytes, and are not valid UTF-8.
+        let mut keys: Vec<Vec<u8>> = vec![
+            b"\x00".to_vec(),
+            b"\x00\x00".to_vec(),
+            b"\x00\x00\x00".to_vec(),
+            b"\x00\xff".to_vec(),
+            b"a\x00b".to_vec(),
+            b"a\x00c".to_vec(),
+            b"a\x00c\x00".to_vec(),
+            b"a\xc3\x28".to_vec(),
+            b"a\xc3\x28\xff".to_vec(),
+            b"\x80\x80".to_vec(),
+            b"\xfe\xff\xff".to_vec(),
+            b"\xff".to_vec(),
+            b"\xff\x00".to_vec(),
+            b"\xff\xff".to_vec(),
+            b"\xff\xff\xff\xff".to_vec(),
+        ];
+        keys.sort();
+        let entries: Vec<Entry> = keys.iter().enumerate().map(|(i, key)| {
+            let mut value = vec![0xff, 0, i as u8];
+            value.extend_from_slice(key);
+            (key.clone(), value)
+        }).collect();
+
+        for &restart_interval in &[1, 2, 3, 16] {
+            let block = build_block(&entries, restart_interval);
+            let mut iter = block.iter(BytewiseComparator);
+
+            iter.seek_to_first();
+            for (key, value) in &entries {
+                assert!(iter.is_valid());
+                assert_eq!(iter.key(), &key[..]);
+                assert_eq!(iter.value(), &value[..]);
+                iter.step();
+            }
+            assert!(!iter.is_valid());
+
+            for (key, value) in &entries {
+                iter.seek(key);
+                assert!(iter.is_valid());
+                assert_eq!(iter.key(), &key[..]);
+                assert_eq!(iter.value(), &value[..]);
+            }
+
+            // Seeking between keys lands on the next one
+            iter.seek(b"\x00\x01");
+            assert_eq!(iter.key(), b"\x00\xff");
+            iter.seek(b"\xff\x00\x00");
+            assert_eq!(iter.key(), b"\xff\xff");
+            iter.seek(b"\xff\xff\xff\xff\x00");
+            assert!(!iter.is_valid());
+            assert!(iter.status().is_ok());
+        }
+    }
+
+    #[test]
+    fn prev_across_restart_points()
+    {
+        let entries = entries(20);
+        for &restart_interval in &[1, 3, 16, 32] {
+            let block = build_block(&entries, restart_interval);
+            let mut iter = block.iter(BytewiseComparator);
+
+            iter.seek_to_last();
+            for (key, value) in entries.iter().rev() {
+                assert!(iter.is_valid());
+                assert_eq!(iter.key(), &key[..]);
+                assert_eq!(iter.value(), &value[..]);
+                iter.prev();
+            }
+            assert!(!iter.is_valid());
+
+            // Step back from every entry, and forward again
+            for i in 1..entries.len() {
+                iter.seek(&entries[i].0);
+                iter.prev();
+                assert_eq!(iter.key(), &entries[i - 1].0[..]);
+                iter.step();
+                assert_eq!(iter.key(), &entries[i].0[..]);
+            }
+
+            iter.seek_to_first();
+            iter.prev();
+            assert!(!iter.is_valid());
+            assert!(iter.status().is_ok());
+        }
+    }
+
+    #[test]
+    fn double_ended()
+    {
+        let entries = entries(20);
+        let block = build_block(&entries, 3);
+
+        let forward: Vec<Entry> = block.iter(BytewiseComparator).into_iter()
+            .map(|e| (e.key().to_vec(), e.value().to_vec())).collect();
+        assert_eq!(forward, entries);
+
+        let backward: Vec<Entry> = block.iter(BytewiseComparator).into_iter().rev()
+            .map(|e| (e.key().to_vec(), e.value().to_vec())).collect();
+        let mut reversed = entries.clone();
+        reversed.reverse();
+        assert_eq!(backward, reversed);
+
+        // A for loop starts at the first entry wherever the iterator is
+        let mut iter = block.iter(BytewiseComparator);
+        iter.seek(&entries[10].0);
+        assert_eq!(iter.into_iter().count(), entries.len());
+    }
+
+    #[test]
+    fn ends_meet_in_the_middle()
+    {
+        for num_keys in 1..10 {
+            let entries = entries(num_keys);
+            let block = build_block(&entries, 2);
+
+            // Take from alternate ends until both run dry
+            let mut range = block.iter(BytewiseComparator).into_iter();
+            let mut front = vec![];
+            let mut back = vec![];
+            while let Some(e) = range.next() {
+                front.push(e.key().to_vec());
+                match range.next_back() {
+                    Some(e) => back.push(e.key().to_vec()),
+                    None => break,
+                }
+            }
+            assert!(range.next().is_none());
+            assert!(range.next_back().is_none());
+
+            back.reverse();
+            front.extend(back);
+            let keys: Vec<Vec<u8>> = entries.into_iter().map(|e| e.0).collect();
+            assert_eq!(front, keys);
+        }
+    }
+
+    fn check_range(entries: &[Entry], start: Bound<&[u8]>, end: Bound<&[u8]>)
+    {
+        let in_range = |key: &[u8]| {
+            (match start {
+                Bound::Included(s) => key >= s,
+                Bound::Excluded(s) => key > s,
+                Bound::Unbounded => true,
+            }) && (match end {
+                Bound::Included(e) => key <= e,
+                Bound::Excluded(e) => key < e,
+                Bound::Unbounded => true,
             })
+        };
+        let expected: Vec<Vec<u8>> = entries.iter()
+            .filter(|e| in_range(&e.0)).map(|e| e.0.clone()).collect();
+
+        let block = build_block(entries, 3);
+        let forward: Vec<Vec<u8>> = block.iter(BytewiseComparator).range(start, end)
+            .map(|e| e.key().to_vec()).collect();
+        assert_eq!(forward, expected, "range {:?}..{:?}", start, end);
+
+        let mut backward: Vec<Vec<u8>> = block.iter(BytewiseComparator).range(start, end).rev()
+            .map(|e| e.key().to_vec()).collect();
+        backward.reverse();
+        assert_eq!(backward, expected, "reversed range {:?}..{:?}", start, end);
+    }
+
+    #[test]
+    fn ranges()
+    {
+        let entries = entries(20);
+        // Present keys, keys between them, and keys past either end
+        let mut bounds: Vec<Vec<u8>> = vec![b"".to_vec(), b"a".to_vec(), b"z".to_vec()];
+        for i in 0..20 {
+            bounds.push(format!("key{:06}", i * 2).into_bytes());
+            bounds.push(format!("key{:06}", i * 2 + 1).into_bytes());
+        }
+
+        for start in &bounds {
+            for end in &bounds {
+                let starts = [Bound::Included(&start[..]), Bound::Excluded(&start[..]), Bound::Unbounded];
+                let ends = [Bound::Included(&end[..]), Bound::Excluded(&end[..]), Bound::Unbounded];
+                for &s in &starts {
+                    for &e in &ends {
+                        check_range(&entries, s, e);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn empty_ranges()
+    {
+        let entries = entries(20);
+        let block = build_block(&entries, 3);
+        let key = |i: usize| entries[i].0.clone();
+
+        assert_eq!(block.iter(BytewiseComparator).range_between(&key(5), &key(5)).count(), 0);
+        assert_eq!(block.iter(BytewiseComparator).range_between(&key(6), &key(5)).count(), 0);
+        assert_eq!(block.iter(BytewiseComparator).range_from(b"z").count(), 0);
+        assert_eq!(block.iter(BytewiseComparator)
+                   .range(Bound::Unbounded, Bound::Excluded(&key(0))).next_back().map(|e| e.key().to_vec()),
+                   None);
+        assert_eq!(block.iter(BytewiseComparator)
+                   .range(Bound::Excluded(&key(5)), Bound::Excluded(&key(6))).count(), 0);
+
+        assert_eq!(block.iter(BytewiseComparator).range_between(&key(5), &key(8)).count(), 3);
+        assert_eq!(block.iter(BytewiseComparator).range_from(&key(15)).count(), 5);
+
+        let empty = build_block(&[], 3);
+        assert_eq!(empty.iter(BytewiseComparator).into_iter().count(), 0);
+        assert_eq!(empty.iter(BytewiseComparator).into_iter().next_back().map(|e| e.key().to_vec()),
+                   None);
+        assert_eq!(empty.iter(BytewiseComparator).range_from(b"").count(), 0);
+    }
+
+    const RESTART_INTERVAL: usize = 4;
+
+    fn key(i: usize) -> Vec<u8>
+    {
+        format!("key{:06}", i * 2).into_bytes()
+    }
+
+    fn value(i: usize) -> Vec<u8>
+    {
+        format!("value{}", i).into_bytes()
+    }
+
+    fn build_hashed_block(num_keys: usize, hash_index_util_ratio: Option<f64>) -> Vec<u8>
+    {
+        let mut builder = BlockBuilder::new(block_builder::Options {
+            block_restart_interval: RESTART_INTERVAL,
+            comparator: Arc::new(BytewiseComparator),
+            hash_index_util_ratio,
+        });
+        for i in 0..num_keys {
+            builder.add(&key(i), &value(i));
+        }
+        builder.finish().to_vec()
+    }
+
+    fn num_restarts_field(data: &[u8]) -> u32
+    {
+        coding::decode_fixed32(&data[data.len() - 4..])
+    }
+
+    /// The range of the buckets of the hash index of "data"
+    fn buckets(data: &[u8]) -> ::std::ops::Range<usize>
+    {
+        let index_end = data.len() - 4;
+        let num_buckets = coding::decode_fixed16(&data[index_end - 2..]) as usize;
+        index_end - 2 - num_buckets..index_end - 2
+    }
+
+    /// Check that seek_for_get finds every key of the block, and rules
+    /// out or passes over absent keys.
+    fn check_gets(data: &[u8], num_keys: usize)
+    {
+        let mut iter = SliceBlock::new(data).unwrap().iter_borrowed(BytewiseComparator);
+        for i in 0..num_keys {
+            iter.seek_for_get(&key(i));
+            assert!(iter.is_valid(), "key {}", i);
+            assert_eq!(iter.key(), &key(i)[..]);
+            assert_eq!(iter.value(), &value(i)[..]);
+
+            let absent = format!("key{:06}", i * 2 + 1).into_bytes();
+            iter.seek_for_get(&absent);
+            assert!(!iter.is_valid() || iter.key() > &absent[..]);
+            assert!(iter.status().is_ok());
+        }
+    }
+
+    #[test]
+    fn hash_index_flag()
+    {
+        let num_keys = 100;
+        let data = build_hashed_block(num_keys, Some(0.75));
+        let num_restarts = num_keys.div_ceil(RESTART_INTERVAL);
+        assert_eq!(num_restarts_field(&data), num_restarts as u32 | HASH_INDEX_FLAG);
+
+        let block = SliceBlock::new(&data).unwrap();
+        assert_eq!(SliceBlock::num_restarts(&data), num_restarts);
+        // The restart array ends where the hash index begins
+        assert_eq!(block.restart_offset() + num_restarts * 4, buckets(&data).start);
+
+        let mut iter = block.iter_borrowed(BytewiseComparator);
+        iter.seek_to_first();
+        for i in 0..num_keys {
+            assert_eq!(iter.key(), &key(i)[..]);
+            iter.step();
+        }
+        assert!(!iter.is_valid());
+        iter.seek_to_last();
+        assert_eq!(iter.key(), &key(num_keys - 1)[..]);
+        check_gets(&data, num_keys);
+    }
+
+    #[test]
+    fn no_entry()
+    {
+        let num_keys = 100;
+        let data = build_hashed_block(num_keys, Some(0.75));
+        let mut iter = SliceBlock::new(&data).unwrap().iter_borrowed(BytewiseComparator);
+
+        // Absent keys that hash to empty buckets leave the iterator
+        // invalid without searching the block
+        let mut ruled_out = 0;
+        for i in num_keys..num_keys * 10 {
+            iter.seek_for_get(&key(i));
+            if !iter.is_valid() {
+                ruled_out += 1;
+            }
+            assert!(iter.status().is_ok());
         }
+        assert!(ruled_out > 0);
+
+        // A block whose buckets are all empty holds nothing
+        let mut empty = data.clone();
+        for i in buckets(&data) {
+            empty[i] = NO_ENTRY;
+        }
+        let mut iter = SliceBlock::new(&empty).unwrap().iter_borrowed(BytewiseComparator);
+        iter.seek_for_get(&key(0));
+        assert!(!iter.is_valid());
+        assert!(iter.status().is_ok());
+    }
+
+    #[test]
+    fn collision_falls_back_to_binary_search()
+    {
+        let num_keys = 100;
+        let mut data = build_hashed_block(num_keys, Some(0.75));
+        for i in buckets(&data) {
+            data[i] = COLLISION;
+        }
+        check_gets(&data, num_keys);
+
+        // Many keys per bucket make real collisions
+        let data = build_hashed_block(num_keys, Some(50.0));
+        assert!(buckets(&data).any(|i| data[i] == COLLISION));
+        check_gets(&data, num_keys);
+    }
+
+    #[test]
+    fn bad_restart_index()
+    {
+        let num_keys = 100;
+        let mut data = build_hashed_block(num_keys, Some(0.75));
+        for i in buckets(&data) {
+            data[i] = (MAX_RESTART_SUPPORTED) as u8;
+        }
+        let mut iter = SliceBlock::new(&data).unwrap().iter_borrowed(BytewiseComparator);
+        iter.seek_for_get(&key(0));
+        assert!(!iter.is_valid());
+        assert!(!iter.status().is_ok());
+    }
+
+    #[test]
+    fn too_many_restarts_for_hash_index()
+    {
+        // One restart interval more than a bucket can point to
+        let num_keys = (MAX_RESTART_SUPPORTED + 2) * RESTART_INTERVAL;
+        let data = build_hashed_block(num_keys, Some(0.75));
+        assert_eq!(num_restarts_field(&data) & HASH_INDEX_FLAG, 0);
+        assert_eq!(SliceBlock::num_restarts(&data), MAX_RESTART_SUPPORTED + 2);
+        check_gets(&data, num_keys);
+
+        let num_keys = (MAX_RESTART_SUPPORTED + 1) * RESTART_INTERVAL;
+        let data = build_hashed_block(num_keys, Some(0.75));
+        assert!(num_restarts_field(&data) & HASH_INDEX_FLAG != 0);
+        check_gets(&data, num_keys);
+    }
+
+    #[test]
+    fn block_without_hash_index()
+    {
+        let num_keys = 100;
+        let data = build_hashed_block(num_keys, None);
+        assert_eq!(num_restarts_field(&data) & HASH_INDEX_FLAG, 0);
+        assert_eq!(SliceBlock::new(&data).unwrap().restart_offset(),
+                   data.len() - 4 - num_keys.div_ceil(RESTART_INTERVAL) * 4);
+        check_gets(&data, num_keys);
+
+        let mut iter = SliceBlock::new(&data).unwrap().iter_borrowed(BytewiseComparator);
+        iter.seek(&key(num_keys));
+        assert!(!iter.is_valid());
+    }
+
+    #[test]
+    fn corrupt_hash_index()
+    {
+        let mut data = build_hashed_block(10, Some(0.75));
+        // More buckets than the block has room for
+        let index_end = data.len() - 4;
+        data[index_end - 4] = 0;
+        data[index_end - 3] = 0;
+        data[index_end - 2] = 0xff;
+        data[index_end - 1] = 0xff;
+        assert!(SliceBlock::new(&data).is_err());
     }
 }
@@ -1,40 +1,50 @@
-/// BlockBuilder generates blocks where keys are prefix-compressed:
-///
-/// When we store a key, we drop the prefix shared with the previous
-/// string.  This helps reduce the space requirement significantly.
-/// Furthermore, once every K keys, we do not apply the prefix
-/// compression and store the entire key.  We call this a "restart
-/// point".  The tail end of the block stores the offsets of all of the
-/// restart points, and can be used to do a binary search when looking
-/// for a particular key.  Values are stored as-is (without compression)
-/// immediately following the corresponding key.
-///
-/// An entry for a particular key-value pair has the form:
-///     shared_bytes: varint32
-///     unshared_bytes: varint32
-///     value_length: varint32
-///     key_delta: char[unshared_bytes]
-///     value: char[value_length]
-/// shared_bytes == 0 for restart points.
-///
-/// The trailer of the block has the form:
-///     restarts: uint32[num_restarts]
-///     num_restarts: uint32
-/// restarts[i] contains the offset within the block of the ith restart point.
+//! BlockBuilder generates blocks where keys are prefix-compressed:
+//!
+//! When we store a key, we drop the prefix shared with the previous
+//! string.  This helps reduce the space requirement significantly.
+//! Furthermore, once every K keys, we do not apply the prefix
+//! compression and store the entire key.  We call this a "restart
+//! point".  The tail end of the block stores the offsets of all of the
+//! restart points, and can be used to do a binary search when looking
+//! for a particular key.  Values are stored as-is (without compression)
+//! immediately following the corresponding key.
+//!
+//! An entry for a particular key-value pair has the form:
+//!     shared_bytes: varint32
+//!     unshared_bytes: varint32
+//!     value_length: varint32
+//!     key_delta: char[unshared_bytes]
+//!     value: char[value_length]
+//! shared_bytes == 0 for restart points.
+//!
+//! The trailer of the block has the form:
+//!     restarts: uint32[num_restarts]
+//!     num_restarts: uint32
+//! restarts[i] contains the offset within the block of the ith restart point.
+//!
+//! If Options::hash_index_util_ratio is set, a hash index is inserted
+//! between the restart array and num_restarts (see
+//! data_block_hash_index.rs).
 
 use ::comparator::SliceComparator;
 use ::slice::Slice;
+use ::table::data_block_hash_index::{DataBlockHashIndexBuilder, HASH_INDEX_FLAG};
 use ::util::coding;
 use std::mem;
 use std::cmp;
+use std::sync::Arc;
 
+#[derive(Clone)]
 pub struct Options {
     pub block_restart_interval: usize,
-    pub comparator: Box<SliceComparator>,
+    pub comparator: Arc<dyn SliceComparator>,
+    /// If set, the block gets a hash index from its keys to their
+    /// restart intervals, with about this many keys per bucket.
+    pub hash_index_util_ratio: Option<f64>,
 }
 
-pub struct BlockBuilder<'a> {
-    options: &'a Options,
+pub struct BlockBuilder {
+    options: Options,
 
     buffer: Vec<u8>,
 
@@ -48,9 +58,12 @@ pub struct BlockBuilder<'a> {
     finished: bool,
 
     last_key: Vec<u8>,
+
+    /// Builder of the hash index, if the block gets one
+    hash_index: Option<DataBlockHashIndexBuilder>,
 }
 
-impl<'a> BlockBuilder<'a> {
+impl BlockBuilder {
     /// Reset the contents as if the BlockBuilder was just constructed.
     pub fn reset(&mut self)
     {
@@ -59,19 +72,23 @@ impl<'a> BlockBuilder<'a> {
         self.counter = 0;
         self.finished = false;
         self.last_key = vec![];
+        if let Some(ref mut hash_index) = self.hash_index {
+            hash_index.reset();
+        }
     }
 
     /// Return true iff no entries have been added since the last Reset()
     pub fn empty(&self) -> bool {
-        self.buffer.len() == 0
+        self.buffer.is_empty()
     }
 
-    pub fn new(options: &'a Options) -> BlockBuilder
+    pub fn new(options: Options) -> BlockBuilder
     {
         assert!(options.block_restart_interval >= 1);
         BlockBuilder {
+            hash_index: options.hash_index_util_ratio.map(DataBlockHashIndexBuilder::new),
             buffer: vec![],
-            options: options,
+            options,
             counter: 0,
             finished: false,
             restarts: vec![0],
@@ -87,27 +104,37 @@ impl<'a> BlockBuilder<'a> {
             + self.restarts.len() * mem::size_of::<u32>()
         // Restart array length
             + mem::size_of::<u32>()
+        // Hash index
+            + self.hash_index.as_ref().map_or(0, |hash_index| hash_index.estimate_size())
     }
 
-    pub fn finish(&mut self) -> Slice
+    pub fn finish(&mut self) -> Slice<'_>
     {
         // Append restart array
         for restart in &self.restarts {
-            coding::put_fixed32(&mut self.buffer, *restart as u32);
+            coding::put_fixed32(&mut self.buffer, *restart);
+        }
+        let mut num_restarts = self.restarts.len() as u32;
+        match self.hash_index {
+            Some(ref hash_index) if hash_index.valid() => {
+                hash_index.finish(&mut self.buffer);
+                num_restarts |= HASH_INDEX_FLAG;
+            },
+            _ => {},
         }
-        coding::put_fixed32(&mut self.buffer, self.restarts.len() as u32);
+        coding::put_fixed32(&mut self.buffer, num_restarts);
         self.finished = true;
         self.buffer.as_slice()
     }
 
     /// REQUIRES: finish() has not been called since the last call to Reset().
     /// REQUIRES: key is larger than any previously added key
-    fn add(&mut self, key: Slice, value: Slice)
+    pub fn add(&mut self, key: Slice, value: Slice)
     {
         let last_key_piece = &*self.last_key.clone();
         assert!(!self.finished);
         assert!(self.counter <= self.options.block_restart_interval);
-        assert!(self.buffer.len() == 0 // No values yet?
+        assert!(self.buffer.is_empty() // No values yet?
                || self.options.comparator.compare(key, last_key_piece) > 0);
 
         let mut shared = 0;
@@ -126,16 +153,75 @@ impl<'a> BlockBuilder<'a> {
         let non_shared = key.len() - shared;
 
         // Add "<shared><non_shared><value_size>" to buffer_
-        coding::put_fixed32(&mut self.buffer, shared as u32);
-        coding::put_fixed32(&mut self.buffer, non_shared as u32);
-        coding::put_fixed32(&mut self.buffer, value.len() as u32);
+        coding::put_varint32(&mut self.buffer, shared as u32);
+        coding::put_varint32(&mut self.buffer, non_shared as u32);
+        coding::put_varint32(&mut self.buffer, value.len() as u32);
 
         // Add string delta to buffer_ followed by value
-        self.buffer.extend_from_slice(&key[shared..non_shared].to_vec());
-        self.buffer.extend_from_slice(&value);
+        self.buffer.extend_from_slice(&key[shared..]);
+        self.buffer.extend_from_slice(value);
+
+        if let Some(ref mut hash_index) = self.hash_index {
+            hash_index.add(key, self.restarts.len() - 1);
+        }
 
         // Update state
         self.last_key = key.to_vec();
         self.counter += 1;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockBuilder, Options};
+    use ::comparator::BytewiseComparator;
+    use ::table::block::SliceBlock;
+    use std::sync::Arc;
+
+    fn options() -> Options
+    {
+        Options {
+            block_restart_interval: 16,
+            comparator: Arc::new(BytewiseComparator),
+            hash_index_util_ratio: None,
+        }
+    }
+
+    /// Entries whose lengths need 3- and 4-byte varint headers must
+    /// read back intact.
+    #[test]
+    fn large_entries_round_trip()
+    {
+        let sizes = [(1 << 14) - 1, 1 << 14, 1 << 15, (1 << 21) - 1, 1 << 21, 1 << 22];
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = sizes.iter().enumerate().map(|(i, &size)| {
+            let mut key = vec![b'k'; size];
+            key.push(b'0' + i as u8);
+            (key, vec![b'a' + i as u8; size])
+        }).collect();
+        let mut sorted = entries.clone();
+        sorted.sort();
+
+        let mut builder = BlockBuilder::new(options());
+        for (key, value) in &sorted {
+            builder.add(key, value);
+        }
+        let data = builder.finish().to_vec();
+
+        let mut iter = SliceBlock::new(&data).unwrap().iter_borrowed(BytewiseComparator);
+        iter.seek_to_first();
+        for (key, value) in &sorted {
+            assert!(iter.is_valid());
+            assert_eq!(iter.key(), &key[..]);
+            assert_eq!(iter.value(), &value[..]);
+            iter.step();
+        }
+        assert!(!iter.is_valid());
+        assert!(iter.status().is_ok());
+
+        for (key, value) in &entries {
+            iter.seek(key);
+            assert!(iter.is_valid());
+            assert_eq!(iter.value(), &value[..]);
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! A data block may carry a hash index that maps the hash of each of
+//! its keys to the restart interval holding the key, so point lookups
+//! can go straight to the right restart interval instead of binary
+//! searching the restart array.
+//!
+//! The hash index sits between the restart array and num_restarts:
+//!     restarts: uint32[num_restarts]
+//!     buckets: uint8[num_buckets]
+//!     num_buckets: uint16
+//!     num_restarts: uint32          // with HASH_INDEX_FLAG set
+//!
+//! Each bucket holds the index of the restart interval of the keys
+//! hashed to it, NO_ENTRY if no key hashed to it, or COLLISION if keys
+//! of several restart intervals did.  Since restart indexes are stored
+//! in a byte, blocks with more than MAX_RESTART_SUPPORTED restart
+//! points are written without a hash index.
+//!
+//! Blocks without a hash index do not have HASH_INDEX_FLAG set, and
+//! keep the plain block format.
+
+use ::slice::Slice;
+use ::util::{coding, hash};
+use std::cmp;
+use std::mem;
+
+/// Set in the num_restarts field of blocks that carry a hash index
+pub const HASH_INDEX_FLAG: u32 = 1 << 31;
+
+/// Bucket value for buckets no key hashed to
+pub const NO_ENTRY: u8 = 255;
+
+/// Bucket value for buckets keys of several restart intervals hashed to
+pub const COLLISION: u8 = 254;
+
+/// Largest restart index a bucket can hold
+pub const MAX_RESTART_SUPPORTED: usize = 253;
+
+const HASH_SEED: u32 = 397;
+
+fn key_hash(key: Slice) -> u32
+{
+    hash::hash(key, HASH_SEED)
+}
+
+/// Builds the hash index of a data block as keys are added to it.
+pub struct DataBlockHashIndexBuilder {
+    /// Average number of keys per bucket
+    util_ratio: f64,
+    /// The hash and restart index of each key added
+    entries: Vec<(u32, u8)>,
+    /// False once a key past MAX_RESTART_SUPPORTED has been added
+    valid: bool,
+}
+
+impl DataBlockHashIndexBuilder {
+    pub fn new(util_ratio: f64) -> DataBlockHashIndexBuilder
+    {
+        assert!(util_ratio > 0.0);
+        DataBlockHashIndexBuilder {
+            util_ratio,
+            entries: vec![],
+            valid: true,
+        }
+    }
+
+    pub fn add(&mut self, key: Slice, restart_index: usize)
+    {
+        if restart_index > MAX_RESTART_SUPPORTED {
+            self.valid = false;
+            return;
+        }
+        self.entries.push((key_hash(key), restart_index as u8));
+    }
+
+    /// Returns true iff the block can carry a hash index.
+    pub fn valid(&self) -> bool
+    {
+        self.valid
+    }
+
+    fn num_buckets(&self) -> usize
+    {
+        let num_buckets = (self.entries.len() as f64 / self.util_ratio) as usize;
+        // An odd number of buckets spreads the keys better
+        cmp::min(cmp::max(num_buckets, 1) | 1, u16::MAX as usize)
+    }
+
+    /// Size of the hash index finish() would append right now.
+    pub fn estimate_size(&self) -> usize
+    {
+        if !self.valid {
+            return 0;
+        }
+        self.num_buckets() + mem::size_of::<u16>()
+    }
+
+    /// Append the hash index to "buffer".
+    ///
+    /// REQUIRES: valid()
+    pub fn finish(&self, buffer: &mut Vec<u8>)
+    {
+        assert!(self.valid);
+        let num_buckets = self.num_buckets();
+        let mut buckets = vec![NO_ENTRY; num_buckets];
+        for &(hash, restart_index) in &self.entries {
+            let bucket = &mut buckets[hash as usize % num_buckets];
+            if *bucket == NO_ENTRY {
+                *bucket = restart_index;
+            } else if *bucket != restart_index {
+                *bucket = COLLISION;
+            }
+        }
+        buffer.extend_from_slice(&buckets);
+        coding::put_fixed16(buffer, num_buckets as u16);
+    }
+
+    pub fn reset(&mut self)
+    {
+        self.entries.clear();
+        self.valid = true;
+    }
+}
+
+/// Look up "key" in "index", the buckets of a hash index followed by
+/// its num_buckets.  Returns the restart index of the interval that may
+/// hold the key, NO_ENTRY if the block does not hold the key, or
+/// COLLISION if the index cannot tell.
+pub fn lookup(index: Slice, key: Slice) -> u8
+{
+    let num_buckets = index.len() - mem::size_of::<u16>();
+    if num_buckets == 0 {
+        return COLLISION;
+    }
+    index[key_hash(key) as usize % num_buckets]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{key_hash, lookup, DataBlockHashIndexBuilder, COLLISION, MAX_RESTART_SUPPORTED,
+                NO_ENTRY};
+    use ::util::coding;
+
+    fn finish(builder: &DataBlockHashIndexBuilder) -> Vec<u8>
+    {
+        let mut index = vec![];
+        builder.finish(&mut index);
+        assert_eq!(index.len(), builder.estimate_size());
+        index
+    }
+
+    #[test]
+    fn lookup_keys()
+    {
+        let mut builder = DataBlockHashIndexBuilder::new(0.75);
+        let keys: Vec<Vec<u8>> = (0..100).map(|i| format!("key{:03}", i).into_bytes()).collect();
+        for (i, key) in keys.iter().enumerate() {
+            builder.add(key, i / 4);
+        }
+        assert!(builder.valid());
+        let index = finish(&builder);
+        let num_buckets = coding::decode_fixed16(&index[index.len() - 2..]) as usize;
+        assert_eq!(num_buckets, index.len() - 2);
+        assert_eq!(num_buckets % 2, 1);
+
+        // Every key finds its restart interval, unless its bucket is
+        // shared with keys of another interval
+        let mut found = 0;
+        for (i, key) in keys.iter().enumerate() {
+            match lookup(&index, key) {
+                COLLISION => {},
+                restart_index => {
+                    assert_eq!(restart_index as usize, i / 4);
+                    found += 1;
+                },
+            }
+        }
+        assert!(found > 0);
+
+        // Keys hashed to buckets no key was added to are ruled out
+        let mut ruled_out = 0;
+        for i in 100..1000 {
+            let key = format!("key{:03}", i).into_bytes();
+            if lookup(&index, &key) == NO_ENTRY {
+                assert!(index[key_hash(&key) as usize % num_buckets] == NO_ENTRY);
+                ruled_out += 1;
+            }
+        }
+        assert!(ruled_out > 0);
+    }
+
+    #[test]
+    fn collision()
+    {
+        // With a single bucket, keys of two restart intervals collide
+        let mut builder = DataBlockHashIndexBuilder::new(1000.0);
+        builder.add(b"a", 0);
+        builder.add(b"b", 0);
+        let index = finish(&builder);
+        assert_eq!(index.len(), 1 + 2);
+        assert_eq!(lookup(&index, b"a"), 0);
+        assert_eq!(lookup(&index, b"anything"), 0);
+
+        builder.add(b"c", 1);
+        let index = finish(&builder);
+        assert_eq!(lookup(&index, b"a"), COLLISION);
+        assert_eq!(lookup(&index, b"c"), COLLISION);
+    }
+
+    #[test]
+    fn empty_index()
+    {
+        let builder = DataBlockHashIndexBuilder::new(0.75);
+        let index = finish(&builder);
+        assert_eq!(lookup(&index, b"a"), NO_ENTRY);
+
+        // An index without buckets cannot tell
+        assert_eq!(lookup(&[0, 0], b"a"), COLLISION);
+    }
+
+    #[test]
+    fn too_many_restarts()
+    {
+        let mut builder = DataBlockHashIndexBuilder::new(0.75);
+        builder.add(b"a", MAX_RESTART_SUPPORTED);
+        assert!(builder.valid());
+        builder.add(b"b", MAX_RESTART_SUPPORTED + 1);
+        assert!(!builder.valid());
+        assert_eq!(builder.estimate_size(), 0);
+
+        builder.reset();
+        assert!(builder.valid());
+    }
+}
@@ -0,0 +1,523 @@
+//! A filter block is stored near the end of a Table file.  It contains
+//! filters (e.g., bloom filters) for all data blocks in the table
+//! combined into a single filter block.
+//!
+//! The filter block has the form:
+//!     [filter 0]
+//!     [filter 1]
+//!     ...
+//!     [filter N-1]
+//!     [offset of filter 0]                  : 4 bytes
+//!     ...
+//!     [offset of filter N-1]                : 4 bytes
+//!     [offset of beginning of offset array] : 4 bytes
+//!     lg(base)                              : 1 byte
+//!
+//! Filter i holds the keys of every data block whose file offset lies
+//! in [i*base ... (i+1)*base-1].
+
+use ::filter_policy::FilterPolicy;
+use ::slice::Slice;
+use ::util::coding;
+use std::sync::Arc;
+
+/// Generate new filter every 2KB of data
+const FILTER_BASE_LG: u8 = 11;
+const FILTER_BASE: u64 = 1 << FILTER_BASE_LG;
+
+/// A FilterBlockBuilder is used to construct all of the filters for a
+/// particular Table.  It generates a single string which is stored as
+/// a special block in the Table.
+///
+/// The sequence of calls to FilterBlockBuilder must match the regexp:
+///      (start_block (add_key | add_prefix)*)* finish
+pub struct FilterBlockBuilder {
+    policy: Arc<dyn FilterPolicy>,
+    /// Flattened key contents
+    keys: Vec<u8>,
+    /// Starting index in keys of each key
+    start: Vec<usize>,
+    /// Flattened prefix contents
+    prefixes: Vec<u8>,
+    /// Starting index in prefixes of each prefix
+    prefix_start: Vec<usize>,
+    /// The last prefix added to the current filter
+    last_prefix: Option<Vec<u8>>,
+    /// Filter data computed so far
+    result: Vec<u8>,
+    filter_offsets: Vec<u32>,
+}
+
+impl FilterBlockBuilder {
+    pub fn new(policy: Arc<dyn FilterPolicy>) -> FilterBlockBuilder
+    {
+        FilterBlockBuilder {
+            policy,
+            keys: vec![],
+            start: vec![],
+            prefixes: vec![],
+            prefix_start: vec![],
+            last_prefix: None,
+            result: vec![],
+            filter_offsets: vec![],
+        }
+    }
+
+    pub fn start_block(&mut self, block_offset: u64)
+    {
+        let filter_index = (block_offset / FILTER_BASE) as usize;
+        assert!(filter_index >= self.filter_offsets.len());
+        while filter_index > self.filter_offsets.len() {
+            self.generate_filter();
+        }
+    }
+
+    pub fn add_key(&mut self, key: Slice)
+    {
+        self.start.push(self.keys.len());
+        self.keys.extend_from_slice(key);
+    }
+
+    /// Add "prefix", a prefix extracted from a user key, unless it is
+    /// the last prefix added to the current filter: consecutive keys
+    /// often share theirs.
+    pub fn add_prefix(&mut self, prefix: Slice)
+    {
+        if self.last_prefix.as_ref().is_some_and(|last| &last[..] == prefix) {
+            return;
+        }
+        self.prefix_start.push(self.prefixes.len());
+        self.prefixes.extend_from_slice(prefix);
+        self.last_prefix = Some(prefix.to_vec());
+    }
+
+    pub fn finish(&mut self) -> Slice<'_>
+    {
+        if !self.start.is_empty() || !self.prefix_start.is_empty() {
+            self.generate_filter();
+        }
+
+        // Append array of per-filter offsets
+        let array_offset = self.result.len() as u32;
+        for offset in &self.filter_offsets {
+            coding::put_fixed32(&mut self.result, *offset);
+        }
+
+        coding::put_fixed32(&mut self.result, array_offset);
+        self.result.push(FILTER_BASE_LG);  // Save encoding parameter in result
+        &self.result
+    }
+
+    fn generate_filter(&mut self)
+    {
+        self.filter_offsets.push(self.result.len() as u32);
+        self.last_prefix = None;
+        if self.start.is_empty() && self.prefix_start.is_empty() {
+            // Fast path if there are no keys for this filter
+            return;
+        }
+
+        // Make lists of keys and prefixes from flattened structures
+        let keys = unflatten(&self.keys, &self.start);
+        let prefixes = unflatten(&self.prefixes, &self.prefix_start);
+
+        // Generate filter for current set of keys and append to result.
+        self.policy.create_filter_with_prefixes(&keys, &prefixes, &mut self.result);
+
+        self.keys.clear();
+        self.start.clear();
+        self.prefixes.clear();
+        self.prefix_start.clear();
+    }
+}
+
+/// Split "flattened" into the entries starting at the offsets in "start".
+fn unflatten<'a>(flattened: &'a [u8], start: &[usize]) -> Vec<Slice<'a>>
+{
+    start.iter().enumerate().map(|(i, &begin)| {
+        let end = start.get(i + 1).cloned().unwrap_or(flattened.len());
+        &flattened[begin..end]
+    }).collect()
+}
+
+pub struct FilterBlockReader {
+    policy: Arc<dyn FilterPolicy>,
+    /// Filter block contents
+    data: Vec<u8>,
+    /// Offset of the beginning of the offset array (at the block end)
+    offset: usize,
+    /// Number of entries in offset array
+    num: usize,
+    /// Encoding parameter (see FILTER_BASE_LG)
+    base_lg: u8,
+}
+
+impl FilterBlockReader {
+    pub fn new(policy: Arc<dyn FilterPolicy>, contents: Vec<u8>) -> FilterBlockReader
+    {
+        let mut reader = FilterBlockReader {
+            policy,
+            data: vec![],
+            offset: 0,
+            num: 0,
+            base_lg: 0,
+        };
+
+        let n = contents.len();
+        if n < 5 {
+            return reader;  // 1 byte for base_lg and 4 for start of offset array
+        }
+        let base_lg = contents[n - 1];
+        if base_lg >= 64 {
+            return reader;  // Corrupt: block offsets cannot be shifted that far
+        }
+        let last_word = coding::decode_fixed32(&contents[n - 5..]) as usize;
+        if last_word > n - 5 {
+            return reader;
+        }
+        reader.base_lg = base_lg;
+        reader.offset = last_word;
+        reader.num = (n - 5 - last_word) / 4;
+        reader.data = contents;
+        reader
+    }
+
+    pub fn key_may_match(&self, block_offset: u64, key: Slice) -> bool
+    {
+        self.filter_may_match(block_offset, |filter| self.policy.key_may_match(key, filter))
+    }
+
+    /// Like key_may_match(), for a prefix added with add_prefix().
+    pub fn prefix_may_match(&self, block_offset: u64, prefix: Slice) -> bool
+    {
+        self.filter_may_match(block_offset, |filter| self.policy.prefix_may_match(prefix, filter))
+    }
+
+    /// Returns may_match(filter) for the filter of the data block at
+    /// "block_offset", or true if there is no such filter.
+    fn filter_may_match<F>(&self, block_offset: u64, may_match: F) -> bool
+        where F: FnOnce(Slice) -> bool
+    {
+        let index = (block_offset >> self.base_lg) as usize;
+        if index < self.num {
+            let entry = self.offset + index * 4;
+            let start = coding::decode_fixed32(&self.data[entry..]) as usize;
+            let limit = coding::decode_fixed32(&self.data[entry + 4..]) as usize;
+            if start <= limit && limit <= self.offset {
+                return may_match(&self.data[start..limit]);
+            } else if start == limit {
+                // Empty filters do not match any keys
+                return false;
+            }
+        }
+        true  // Errors are treated as potential matches
+    }
+}
+
+/// A PartitionedFilterBlockBuilder builds the filters of a table whose
+/// filter is partitioned along with its index.  Each partition is a
+/// single filter over the keys of the data blocks covered by one index
+/// partition, and is stored as its own block.  A top-level filter index
+/// maps the last key of each partition to the partition's handle.
+///
+/// The sequence of calls to PartitionedFilterBlockBuilder must match
+/// the regexp:
+///      ((add_key | add_prefix)* cut_partition)*
+pub struct PartitionedFilterBlockBuilder {
+    policy: Arc<dyn FilterPolicy>,
+    /// Flattened contents of the keys of the current partition
+    keys: Vec<u8>,
+    /// Starting index in keys of each key
+    start: Vec<usize>,
+    /// Flattened contents of the prefixes of the current partition
+    prefixes: Vec<u8>,
+    /// Starting index in prefixes of each prefix
+    prefix_start: Vec<usize>,
+    /// The last prefix added to the current partition
+    last_prefix: Option<Vec<u8>>,
+    /// The last key and filter of each finished partition
+    partitions: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl PartitionedFilterBlockBuilder {
+    pub fn new(policy: Arc<dyn FilterPolicy>) -> PartitionedFilterBlockBuilder
+    {
+        PartitionedFilterBlockBuilder {
+            policy,
+            keys: vec![],
+            start: vec![],
+            prefixes: vec![],
+            prefix_start: vec![],
+            last_prefix: None,
+            partitions: vec![],
+        }
+    }
+
+    pub fn add_key(&mut self, key: Slice)
+    {
+        self.start.push(self.keys.len());
+        self.keys.extend_from_slice(key);
+    }
+
+    /// Add "prefix", a prefix extracted from a user key, unless it is
+    /// the last prefix added to the current partition.
+    pub fn add_prefix(&mut self, prefix: Slice)
+    {
+        if self.last_prefix.as_ref().is_some_and(|last| &last[..] == prefix) {
+            return;
+        }
+        self.prefix_start.push(self.prefixes.len());
+        self.prefixes.extend_from_slice(prefix);
+        self.last_prefix = Some(prefix.to_vec());
+    }
+
+    /// Finish the current partition with a filter over the keys added
+    /// since the previous partition.  "last_key" is the index key of the
+    /// partition: it is >= every key in the partition.
+    pub fn cut_partition(&mut self, last_key: Slice)
+    {
+        let keys = unflatten(&self.keys, &self.start);
+        let prefixes = unflatten(&self.prefixes, &self.prefix_start);
+
+        let mut filter = vec![];
+        self.policy.create_filter_with_prefixes(&keys, &prefixes, &mut filter);
+        self.partitions.push((last_key.to_vec(), filter));
+
+        self.keys.clear();
+        self.start.clear();
+        self.prefixes.clear();
+        self.prefix_start.clear();
+        self.last_prefix = None;
+    }
+
+    /// The last key and filter of each partition finished so far.
+    pub fn partitions(&self) -> &[(Vec<u8>, Vec<u8>)]
+    {
+        &self.partitions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FilterBlockBuilder, FilterBlockReader, PartitionedFilterBlockBuilder};
+    use ::filter_policy::FilterPolicy;
+    use ::slice::Slice;
+    use ::util::coding;
+    use ::util::hash::hash;
+    use std::sync::Arc;
+
+    /// For testing: emit an array with one hash value per key
+    struct TestHashFilter;
+
+    impl FilterPolicy for TestHashFilter {
+        fn name(&self) -> &str
+        {
+            "TestHashFilter"
+        }
+
+        fn create_filter(&self, keys: &[Slice], dst: &mut Vec<u8>)
+        {
+            for key in keys {
+                coding::put_fixed32(dst, hash(key, 1));
+            }
+        }
+
+        fn key_may_match(&self, key: Slice, filter: Slice) -> bool
+        {
+            let h = hash(key, 1);
+            filter.chunks(4).any(|chunk| chunk.len() == 4 && coding::decode_fixed32(chunk) == h)
+        }
+    }
+
+    fn policy() -> Arc<dyn FilterPolicy>
+    {
+        Arc::new(TestHashFilter)
+    }
+
+    #[test]
+    fn empty_builder()
+    {
+        let mut builder = FilterBlockBuilder::new(policy());
+        let block = builder.finish().to_vec();
+        assert_eq!(block, b"\x00\x00\x00\x00\x0b");
+        let reader = FilterBlockReader::new(policy(), block);
+        assert!(reader.key_may_match(0, b"foo"));
+        assert!(reader.key_may_match(100000, b"foo"));
+    }
+
+    #[test]
+    fn single_chunk()
+    {
+        let mut builder = FilterBlockBuilder::new(policy());
+        builder.start_block(100);
+        builder.add_key(b"foo");
+        builder.add_key(b"bar");
+        builder.add_key(b"box");
+        builder.start_block(200);
+        builder.add_key(b"box");
+        builder.start_block(300);
+        builder.add_key(b"hello");
+        let block = builder.finish().to_vec();
+        let reader = FilterBlockReader::new(policy(), block);
+        assert!(reader.key_may_match(100, b"foo"));
+        assert!(reader.key_may_match(100, b"bar"));
+        assert!(reader.key_may_match(100, b"box"));
+        assert!(reader.key_may_match(100, b"hello"));
+        assert!(reader.key_may_match(100, b"foo"));
+        assert!(!reader.key_may_match(100, b"missing"));
+        assert!(!reader.key_may_match(100, b"other"));
+    }
+
+    #[test]
+    fn multi_chunk()
+    {
+        let mut builder = FilterBlockBuilder::new(policy());
+
+        // First filter
+        builder.start_block(0);
+        builder.add_key(b"foo");
+        builder.start_block(2000);
+        builder.add_key(b"bar");
+
+        // Second filter
+        builder.start_block(3100);
+        builder.add_key(b"box");
+
+        // Third filter is empty
+
+        // Last filter
+        builder.start_block(9000);
+        builder.add_key(b"box");
+        builder.add_key(b"hello");
+
+        let block = builder.finish().to_vec();
+        let reader = FilterBlockReader::new(policy(), block);
+
+        // Check first filter
+        assert!(reader.key_may_match(0, b"foo"));
+        assert!(reader.key_may_match(2000, b"bar"));
+        assert!(!reader.key_may_match(0, b"box"));
+        assert!(!reader.key_may_match(0, b"hello"));
+
+        // Check second filter
+        assert!(reader.key_may_match(3100, b"box"));
+        assert!(!reader.key_may_match(3100, b"foo"));
+        assert!(!reader.key_may_match(3100, b"bar"));
+        assert!(!reader.key_may_match(3100, b"hello"));
+
+        // Check third filter (empty)
+        assert!(!reader.key_may_match(4100, b"foo"));
+        assert!(!reader.key_may_match(4100, b"bar"));
+        assert!(!reader.key_may_match(4100, b"box"));
+        assert!(!reader.key_may_match(4100, b"hello"));
+
+        // Check last filter
+        assert!(reader.key_may_match(9000, b"box"));
+        assert!(reader.key_may_match(9000, b"hello"));
+        assert!(!reader.key_may_match(9000, b"foo"));
+        assert!(!reader.key_may_match(9000, b"bar"));
+    }
+
+    /// Filter i covers the blocks starting in [i*2KB, (i+1)*2KB).
+    #[test]
+    fn filter_index_at_base_boundaries()
+    {
+        let mut builder = FilterBlockBuilder::new(policy());
+        builder.start_block(2047);
+        builder.add_key(b"first");
+        builder.start_block(2048);
+        builder.add_key(b"second");
+        builder.start_block(4095);
+        builder.add_key(b"second too");
+        let block = builder.finish().to_vec();
+        let reader = FilterBlockReader::new(policy(), block);
+
+        assert!(reader.key_may_match(0, b"first"));
+        assert!(reader.key_may_match(2047, b"first"));
+        assert!(!reader.key_may_match(2048, b"first"));
+        assert!(reader.key_may_match(2048, b"second"));
+        assert!(reader.key_may_match(4095, b"second too"));
+        assert!(!reader.key_may_match(2047, b"second"));
+
+        // Offsets past the last filter are treated as potential matches
+        assert!(reader.key_may_match(4096, b"anything"));
+    }
+
+    /// A corrupt base_lg too large to shift block offsets by makes every
+    /// key a potential match instead of panicking.
+    #[test]
+    fn corrupt_base_lg()
+    {
+        let mut builder = FilterBlockBuilder::new(policy());
+        builder.start_block(100);
+        builder.add_key(b"foo");
+        let mut block = builder.finish().to_vec();
+        for &base_lg in &[64u8, 200, 255] {
+            let n = block.len();
+            block[n - 1] = base_lg;
+            let reader = FilterBlockReader::new(policy(), block.clone());
+            assert!(reader.key_may_match(0, b"foo"));
+            assert!(reader.key_may_match(100, b"missing"));
+            assert!(reader.key_may_match(u64::MAX, b"missing"));
+        }
+    }
+
+    #[test]
+    fn partitions()
+    {
+        let mut builder = PartitionedFilterBlockBuilder::new(policy());
+        builder.add_key(b"a");
+        builder.add_key(b"b");
+        builder.cut_partition(b"b");
+        builder.add_key(b"c");
+        builder.cut_partition(b"d");
+
+        let partitions = builder.partitions();
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(&partitions[0].0[..], b"b");
+        assert_eq!(&partitions[1].0[..], b"d");
+        let policy = policy();
+        assert!(policy.key_may_match(b"a", &partitions[0].1));
+        assert!(policy.key_may_match(b"b", &partitions[0].1));
+        assert!(!policy.key_may_match(b"c", &partitions[0].1));
+        assert!(policy.key_may_match(b"c", &partitions[1].1));
+        assert!(!policy.key_may_match(b"a", &partitions[1].1));
+    }
+
+    /// Consecutive equal prefixes are added once per filter, but every
+    /// filter holding keys with a prefix holds the prefix.
+    #[test]
+    fn duplicate_prefixes()
+    {
+        let mut builder = FilterBlockBuilder::new(policy());
+        builder.start_block(0);
+        builder.add_prefix(b"foo");
+        builder.add_key(b"foo1");
+        builder.add_prefix(b"foo");
+        builder.add_key(b"foo2");
+        builder.add_prefix(b"bar");
+        builder.add_prefix(b"foo");
+        builder.start_block(3000);
+        builder.add_prefix(b"foo");
+        let block = builder.finish().to_vec();
+        // Filter 0 holds foo, foo1, foo2, bar and foo, filter 1 holds foo
+        assert_eq!(block.len(), (5 + 1) * 4 + 2 * 4 + 5);
+        let reader = FilterBlockReader::new(policy(), block);
+        assert!(reader.key_may_match(0, b"foo"));
+        assert!(reader.key_may_match(0, b"bar"));
+        assert!(reader.key_may_match(3000, b"foo"));
+        assert!(!reader.key_may_match(3000, b"bar"));
+
+        let mut builder = PartitionedFilterBlockBuilder::new(policy());
+        builder.add_prefix(b"foo");
+        builder.add_prefix(b"foo");
+        builder.cut_partition(b"foo1");
+        builder.add_prefix(b"foo");
+        builder.add_prefix(b"foo");
+        builder.cut_partition(b"foo2");
+        let partitions = builder.partitions();
+        assert_eq!(partitions[0].1.len(), 4);
+        assert_eq!(partitions[1].1.len(), 4);
+        assert!(policy().key_may_match(b"foo", &partitions[1].1));
+    }
+}
@@ -1,10 +1,11 @@
 use ::table::block::Block;
 use ::slice::Slice;
 use ::status::Status;
-use ::util::coding;
+use ::util::{coding, crc32c};
+use ::util::compression::CodecRegistry;
 use ::errors::RubbleResult;
 use ::options::{ReadOptions, CompressionType};
-use snappy;
+use std::borrow::Cow;
 use std::fs::File;
 use std::io::SeekFrom;
 use std::io::prelude::*;
@@ -15,17 +16,39 @@ pub const MAX_ENCODED_LENGTH: usize = 10 + 10;
 /// TABLE_MAGIC_NUMBER was picked by running
 ///    echo http://code.google.com/p/leveldb/ | sha1sum
 /// and taking the leading 64 bits.
-const TABLE_MAGIC_NUMBER: u64 = 0xdb4775248b80fb57;
+pub const TABLE_MAGIC_NUMBER: u64 = 0xdb4775248b80fb57;
+
+/// VERSIONED_TABLE_MAGIC_NUMBER identifies tables with a versioned
+/// footer.  It was picked by running
+///    echo http://github.com/millerjs/rubbledb/ | sha1sum
+/// and taking the leading 64 bits.
+pub const VERSIONED_TABLE_MAGIC_NUMBER: u64 = 0x9cfc74558b09c6e0;
+
+/// Format version of tables with the original LevelDB footer: a single
+/// index block maps the last key of each data block to its handle.
+pub const LEGACY_FORMAT_VERSION: u32 = 0;
+
+/// Format version of tables with a partitioned index: the footer's
+/// index handle points to a top-level index whose entries map the last
+/// key of each index partition to its handle, and each index partition
+/// maps the last key of each of its data blocks to the block's handle.
+pub const PARTITIONED_INDEX_FORMAT_VERSION: u32 = 1;
 
 /// 1-byte type + 32-bit crc
-const BLOCK_TRAILER_SIZE: usize = 5;
+pub const BLOCK_TRAILER_SIZE: usize = 5;
 
 /// Encoded length of a Footer.  Note that the serialization of a
 /// Footer will always occupy exactly this many bytes.  It consists
 /// of two block handles and a magic number.
 pub const ENCODED_LENGTH: usize = 2 * MAX_ENCODED_LENGTH + 8;
 
+/// Encoded length of a Footer with a format version other than
+/// LEGACY_FORMAT_VERSION, which stores the version ahead of the magic
+/// number.  This is the largest encoded length of any Footer.
+pub const VERSIONED_ENCODED_LENGTH: usize = ENCODED_LENGTH + 4;
+
 
+#[derive(Clone, Debug)]
 pub struct BlockHandle {
     pub offset: u64,
     pub size: u64,
@@ -51,6 +74,17 @@ impl BlockHandle {
         self.offset = offset
     }
 
+    /// The size of the stored block
+    pub fn size(&self) -> u64
+    {
+        self.size
+    }
+
+    pub fn set_size(&mut self, size: u64)
+    {
+        self.size = size
+    }
+
     pub fn encode_to(&self, dst: &mut Vec<u8>)
     {
         // Sanity check that all fields have been set
@@ -63,8 +97,8 @@ impl BlockHandle {
     pub fn decode_from<'a>(&mut self, input: Slice<'a>) -> RubbleResult<Slice<'a>>
     {
 
-        let (temp, offset) = try!(coding::get_varint64(input));
-        let (leftover, size) = try!(coding::get_varint64(temp));
+        let (temp, offset) = coding::get_varint64(input)?;
+        let (leftover, size) = coding::get_varint64(temp)?;
         self.offset = offset;
         self.size = size;
         Ok(leftover)
@@ -72,7 +106,33 @@ impl BlockHandle {
 
 }
 
+impl Default for BlockHandle {
+    fn default() -> BlockHandle
+    {
+        BlockHandle::new()
+    }
+}
+
+/// Footer encapsulates the fixed information stored at the tail end of
+/// every table file.
+///
+/// Tables of LEGACY_FORMAT_VERSION end with the LevelDB footer:
+///     metaindex_handle: char[p]      // Block handle for metaindex
+///     index_handle:     char[q]      // Block handle for index
+///     padding:          char[40-p-q] // zeroed bytes to make fixed length
+///     magic:            fixed64      // == TABLE_MAGIC_NUMBER
+///
+/// Tables of any later format version end with:
+///     metaindex_handle: char[p]
+///     index_handle:     char[q]
+///     padding:          char[40-p-q]
+///     format_version:   fixed32
+///     magic:            fixed64      // == VERSIONED_TABLE_MAGIC_NUMBER
+///
+/// The magic number tells the two apart, so readers can always find the
+/// footer by looking at the last 8 bytes of the file.
 pub struct Footer {
+    format_version: u32,
     metaindex_handle: BlockHandle,
     index_handle: BlockHandle,
 }
@@ -82,12 +142,25 @@ impl Footer {
     pub fn new() -> Footer
     {
         Footer {
+            format_version: LEGACY_FORMAT_VERSION,
             metaindex_handle: BlockHandle::new(),
             index_handle: BlockHandle::new(),
         }
     }
 
-    pub fn metaindex_handle<'a>(&'a self) -> &'a BlockHandle
+    /// The format version of the table, which determines how its index
+    /// is laid out.
+    pub fn format_version(&self) -> u32
+    {
+        self.format_version
+    }
+
+    pub fn set_format_version(&mut self, format_version: u32)
+    {
+        self.format_version = format_version
+    }
+
+    pub fn metaindex_handle(&self) -> &BlockHandle
     {
         &self.metaindex_handle
     }
@@ -97,7 +170,7 @@ impl Footer {
         self.metaindex_handle = handle
     }
 
-    pub fn index_handle<'a>(&'a self) -> &'a BlockHandle
+    pub fn index_handle(&self) -> &BlockHandle
     {
         &self.index_handle
     }
@@ -107,19 +180,41 @@ impl Footer {
         self.index_handle = handle
     }
 
+    /// Decode the footer from "input", which must hold (at least) the
+    /// trailing bytes of the table file: ENCODED_LENGTH bytes for legacy
+    /// tables, VERSIONED_ENCODED_LENGTH bytes for other tables.  Any
+    /// bytes before the footer are ignored.
     pub fn decode_from<'a>(&mut self, input: Slice<'a>) -> RubbleResult<Slice<'a>>
     {
-        let magic_slice = &input[(ENCODED_LENGTH - 8)..];
+        if input.len() < ENCODED_LENGTH {
+            return Err(Status::Corruption("truncated sstable footer".into()).into())
+        }
+        let magic_slice = &input[input.len() - 8..];
         let magic_lo = coding::decode_fixed32(magic_slice);
         let magic_hi = coding::decode_fixed32(&magic_slice[4..]);
         let magic = (magic_hi as u64) << 32 | magic_lo as u64;
 
-        if magic != TABLE_MAGIC_NUMBER {
-            return Err(Status::Corruption("not an sstable (bad magic number)".into()).into())
-        }
-
-        try!(self.metaindex_handle.decode_from(input));
-        try!(self.index_handle.decode_from(input));
+        let input = match magic {
+            TABLE_MAGIC_NUMBER => {
+                self.format_version = LEGACY_FORMAT_VERSION;
+                &input[input.len() - ENCODED_LENGTH..]
+            },
+            VERSIONED_TABLE_MAGIC_NUMBER => {
+                if input.len() < VERSIONED_ENCODED_LENGTH {
+                    return Err(Status::Corruption("truncated sstable footer".into()).into())
+                }
+                let version_slice = &input[input.len() - 12..];
+                self.format_version = coding::decode_fixed32(version_slice);
+                if self.format_version != PARTITIONED_INDEX_FORMAT_VERSION {
+                    return Err(Status::Corruption("unknown sstable format version".into()).into())
+                }
+                &input[input.len() - VERSIONED_ENCODED_LENGTH..]
+            },
+            _ => return Err(Status::Corruption("not an sstable (bad magic number)".into()).into()),
+        };
+
+        let input = self.metaindex_handle.decode_from(input)?;
+        self.index_handle.decode_from(input)?;
         // We skip over any leftover data (just padding for now) in "input"
         Ok(&magic_slice[8..])
     }
@@ -129,87 +224,321 @@ impl Footer {
         let original_size = dst.len();
         self.metaindex_handle.encode_to(dst);
         self.index_handle.encode_to(dst);
-        dst.resize(2 * MAX_ENCODED_LENGTH, 0);  // Padding
-        coding::put_fixed32(dst, (TABLE_MAGIC_NUMBER & 0xffffffff) as u32);
-        coding::put_fixed32(dst, (TABLE_MAGIC_NUMBER >> 32) as u32);
-        assert!(dst.len() == original_size + ENCODED_LENGTH);
+        dst.resize(original_size + 2 * MAX_ENCODED_LENGTH, 0);  // Padding
+        if self.format_version == LEGACY_FORMAT_VERSION {
+            coding::put_fixed32(dst, (TABLE_MAGIC_NUMBER & 0xffffffff) as u32);
+            coding::put_fixed32(dst, (TABLE_MAGIC_NUMBER >> 32) as u32);
+            assert!(dst.len() == original_size + ENCODED_LENGTH);
+        } else {
+            coding::put_fixed32(dst, self.format_version);
+            coding::put_fixed32(dst, (VERSIONED_TABLE_MAGIC_NUMBER & 0xffffffff) as u32);
+            coding::put_fixed32(dst, (VERSIONED_TABLE_MAGIC_NUMBER >> 32) as u32);
+            assert!(dst.len() == original_size + VERSIONED_ENCODED_LENGTH);
+        }
     }
 
 }
 
-pub struct BlockContents {
-    /// Actual contents of data
-    pub data: Vec<u8>,
+impl Default for Footer {
+    fn default() -> Footer
+    {
+        Footer::new()
+    }
+}
+
+pub struct BlockContents<'a> {
+    /// Actual contents of data.  Borrowed when the data points into
+    /// memory that outlives the block (e.g. a memory-mapped file).
+    pub data: Cow<'a, [u8]>,
     /// True iff data can be cached
     pub cachable: bool,
 }
 
-impl BlockContents {
-    pub fn new() -> BlockContents
+impl<'a> BlockContents<'a> {
+    pub fn new() -> BlockContents<'a>
     {
         BlockContents {
-            data: vec![],
+            data: Cow::Owned(vec![]),
             cachable: true,
         }
     }
 }
 
-/// TODO allow for stack allocation?
-pub fn read_block<F>(file: &mut F, options: &ReadOptions, handle: &BlockHandle)
-                  -> RubbleResult<BlockContents>
+impl<'a> Default for BlockContents<'a> {
+    fn default() -> BlockContents<'a>
+    {
+        BlockContents::new()
+    }
+}
+
+/// Verify the trailer of "block", the contents of a block followed by
+/// its type/crc trailer, and return the block's compression type.
+fn check_block_trailer(block: Slice, options: &ReadOptions) -> RubbleResult<u8>
+{
+    let n = block.len() - BLOCK_TRAILER_SIZE;
+
+    // Check the crc of the type and the block contents
+    if options.verify_checksums {
+        let crc = crc32c::unmask(coding::decode_fixed32(&block[n + 1..]));
+        let actual = crc32c::value(&block[..n + 1]);
+        if actual != crc {
+            return Err(Status::Corruption("block checksum mismatch".into()).into());
+        }
+    }
+    Ok(block[n])
+}
+
+/// Return the offset just past the trailer of the block identified by
+/// "handle", or a corruption error if the block does not fit in a file
+/// of "file_size" bytes.
+fn block_end(handle: &BlockHandle, file_size: u64) -> RubbleResult<u64>
+{
+    // The handle may come from a corrupt index, so guard against
+    // overflow as well as reads past the end of the file.
+    let end = handle.offset.checked_add(handle.size)
+        .and_then(|end| end.checked_add(BLOCK_TRAILER_SIZE as u64));
+    match end {
+        Some(end) if end <= file_size => Ok(end),
+        _ => Err(Status::Corruption("truncated block read".into()).into()),
+    }
+}
+
+/// Read the block identified by "handle" from "file", which holds
+/// "file_size" bytes.
+pub fn read_block<F>(file: &mut F, file_size: u64, codecs: &CodecRegistry,
+                     options: &ReadOptions, handle: &BlockHandle)
+                     -> RubbleResult<BlockContents<'static>>
     where F: Read + Seek
 {
-    let mut result = BlockContents { data: vec![], cachable: true };
+    // Check the handle before allocating a buffer of its size
+    block_end(handle, file_size)?;
 
     // Read the block contents as well as the type/crc footer.
-    // See table_builder.cc for the code that built this structure.
+    // See table_builder.rs for the code that built this structure.
     let n = handle.size as usize;
-    let mut buff = Vec::<u8>::with_capacity(n + BLOCK_TRAILER_SIZE);
-
-    // Slice contents;
-    try!(file.seek(SeekFrom::Start(handle.offset)));
-    try!(file.read_exact(&mut buff.as_mut_slice()));
-
-    // TODO CHECK CHECKSUMS
-    //
-    //   // Check the crc of the type and the block contents
-    //   const char* data = contents.data();    // Pointer to where Read put the data
-    //   if (options.verify_checksums) {
-    //     const uint32_t crc = crc32c::Unmask(DecodeFixed32(data + n + 1));
-    //     const uint32_t actual = crc32c::Value(data, n + 1);
-    //     if (actual != crc) {
-    //       delete[] buf;
-    //       s = Status::Corruption("block checksum mismatch");
-    //       return s;
-    //     }
-    //   }
-
-    match buff[n] {
+    let mut buff = vec![0; n + BLOCK_TRAILER_SIZE];
+
+    file.seek(SeekFrom::Start(handle.offset))?;
+    file.read_exact(&mut buff)?;
+
+    let data = match check_block_trailer(&buff, options)? {
         x if x == CompressionType::NoCompression as u8 => {
-            // TODO stack allocated implementation?
-            // if (data != buf) {
-            // File implementation gave us pointer to some other data.
-            // Use it directly under the assumption that it will be live
-            // while the file is open.
-            // delete[] buf;
-            // result->data = Slice(data, n);
-            // result->heap_allocated = false;
-            // result->cachable = false;  // Do not double-cache
-            // } else {
-            // result.heap_allocated = true;
-            result.data = buff;
-            result.cachable = true;
+            buff.truncate(n);
+            buff
         },
-        x if x == CompressionType::SnappyCompression as u8 => {
-            let uncompressed = snappy::uncompress(buff.as_slice())
-                .or(Err(Status::Corruption("corrupted compressed block contents".into())));
-            let uncompressed = try!(uncompressed);
+        block_type => codecs.uncompress(block_type, &buff[..n])?,
+    };
 
-            result.data = uncompressed;
-            result.cachable = true;
+    Ok(BlockContents {
+        data: Cow::Owned(data),
+        cachable: true,
+    })
+}
+
+/// Like read_block(), but reads from "file_data", the contents of a
+/// file mapped into memory.  Uncompressed blocks are returned as slices
+/// of the mapping instead of copies, and are not cachable: caching
+/// them would only duplicate memory the mapping already provides.
+pub fn read_block_from_mapping<'a>(file_data: Slice<'a>, codecs: &CodecRegistry,
+                                   options: &ReadOptions, handle: &BlockHandle)
+                                   -> RubbleResult<BlockContents<'a>>
+{
+    let end = block_end(handle, file_data.len() as u64)? as usize;
+    let offset = handle.offset as usize;
+    let n = handle.size as usize;
+    let block = &file_data[offset..end];
+
+    match check_block_trailer(block, options)? {
+        x if x == CompressionType::NoCompression as u8 => {
+            Ok(BlockContents {
+                data: Cow::Borrowed(&block[..n]),
+                cachable: false,  // Do not double-cache
+            })
+        },
+        block_type => {
+            Ok(BlockContents {
+                data: Cow::Owned(codecs.uncompress(block_type, &block[..n])?),
+                cachable: true,
+            })
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_block, read_block_from_mapping, BlockHandle, Footer, BLOCK_TRAILER_SIZE, ENCODED_LENGTH,
+                LEGACY_FORMAT_VERSION, PARTITIONED_INDEX_FORMAT_VERSION, TABLE_MAGIC_NUMBER,
+                VERSIONED_ENCODED_LENGTH, VERSIONED_TABLE_MAGIC_NUMBER};
+    use ::options::{CompressionType, ReadOptions};
+    use ::status::Status;
+    use ::util::{coding, crc32c};
+    use ::util::compression::CodecRegistry;
+    use std::borrow::Cow;
+    use std::io::Cursor;
+
+    /// A file holding a single uncompressed block with a valid trailer
+    fn file_with_block(contents: &[u8]) -> Vec<u8>
+    {
+        let mut file = contents.to_vec();
+        file.push(CompressionType::NoCompression as u8);
+        let crc = crc32c::value(&file);
+        coding::put_fixed32(&mut file, crc32c::mask(crc));
+        file
+    }
+
+    fn handle(offset: u64, size: u64) -> BlockHandle
+    {
+        let mut handle = BlockHandle::new();
+        handle.set_offset(offset);
+        handle.set_size(size);
+        handle
+    }
+
+    #[test]
+    fn read_from_mapping()
+    {
+        let file = file_with_block(b"block contents");
+        let mut options = ReadOptions::new();
+        options.verify_checksums = true;
+        let block = read_block_from_mapping(&file, &CodecRegistry::new(), &options,
+                                            &handle(0, 14)).unwrap();
+        assert_eq!(&block.data[..], b"block contents");
+        assert!(!block.cachable);
+        match block.data {
+            Cow::Borrowed(_) => {},
+            Cow::Owned(_) => panic!("uncompressed block was copied out of the mapping"),
+        }
+    }
+
+    #[test]
+    fn read_past_end_of_mapping()
+    {
+        let file = file_with_block(b"block contents");
+        let codecs = CodecRegistry::new();
+        let options = ReadOptions::new();
+        let len = file.len() as u64;
+        let bad_handles = [
+            handle(1, 14),
+            handle(0, len),
+            handle(len, 0),
+            // These overflow when the trailer size is added
+            handle(u64::MAX, 0),
+            handle(0, u64::MAX),
+            handle(u64::MAX - BLOCK_TRAILER_SIZE as u64 + 1, 0),
+            handle(u64::MAX / 2, u64::MAX / 2 + 1),
+        ];
+        for handle in &bad_handles {
+            let result = read_block_from_mapping(&file, &codecs, &options, handle);
+            assert!(result.is_err(), "read {:?}", handle);
         }
-        _ => return Err(Status::Corruption("Bad block type".into()).into())
     }
 
-    Ok(result)
+    #[test]
+    fn read_from_file()
+    {
+        let file = file_with_block(b"block contents");
+        let mut options = ReadOptions::new();
+        options.verify_checksums = true;
+        let block = read_block(&mut Cursor::new(&file), file.len() as u64, &CodecRegistry::new(),
+                               &options, &handle(0, 14)).unwrap();
+        assert_eq!(&block.data[..], b"block contents");
+        assert!(block.cachable);
+    }
+
+    /// Handles past the end of the file are rejected before a buffer of
+    /// their size is allocated.
+    #[test]
+    fn read_past_end_of_file()
+    {
+        let file = file_with_block(b"block contents");
+        let codecs = CodecRegistry::new();
+        let options = ReadOptions::new();
+        let len = file.len() as u64;
+        let bad_handles = [
+            handle(1, 14),
+            handle(0, len),
+            handle(len, 0),
+            handle(0, 1 << 60),
+            handle(u64::MAX, 0),
+            handle(0, u64::MAX),
+            handle(u64::MAX / 2, u64::MAX / 2 + 1),
+        ];
+        for handle in &bad_handles {
+            let result = read_block(&mut Cursor::new(&file), len, &codecs, &options, handle);
+            let status: Status = result.err().expect("read past the end").into();
+            assert_eq!(status, Status::Corruption("truncated block read".into()));
+        }
+    }
+
+    fn footer(format_version: u32) -> Footer
+    {
+        let mut footer = Footer::new();
+        footer.set_format_version(format_version);
+        footer.set_metaindex_handle(handle(1000, 200));
+        footer.set_index_handle(handle(1205, 1 << 40));
+        footer
+    }
+
+    fn check_decoded(decoded: &Footer, format_version: u32)
+    {
+        assert_eq!(decoded.format_version(), format_version);
+        assert_eq!(decoded.metaindex_handle().offset(), 1000);
+        assert_eq!(decoded.metaindex_handle().size(), 200);
+        assert_eq!(decoded.index_handle().offset(), 1205);
+        assert_eq!(decoded.index_handle().size(), 1 << 40);
+    }
+
+    #[test]
+    fn legacy_footer()
+    {
+        let mut encoded = vec![];
+        footer(LEGACY_FORMAT_VERSION).encode_to(&mut encoded);
+        assert_eq!(encoded.len(), ENCODED_LENGTH);
+        assert_eq!(coding::decode_fixed64(&encoded[ENCODED_LENGTH - 8..]), TABLE_MAGIC_NUMBER);
+
+        let mut decoded = Footer::new();
+        decoded.decode_from(&encoded).unwrap();
+        check_decoded(&decoded, LEGACY_FORMAT_VERSION);
+
+        // Readers pass in enough bytes for any footer, so whatever
+        // precedes a legacy footer is ignored.
+        let mut input = vec![0xff; VERSIONED_ENCODED_LENGTH - ENCODED_LENGTH];
+        input.extend_from_slice(&encoded);
+        let mut decoded = footer(PARTITIONED_INDEX_FORMAT_VERSION);
+        decoded.decode_from(&input).unwrap();
+        check_decoded(&decoded, LEGACY_FORMAT_VERSION);
+    }
+
+    #[test]
+    fn versioned_footer()
+    {
+        let mut encoded = vec![];
+        footer(PARTITIONED_INDEX_FORMAT_VERSION).encode_to(&mut encoded);
+        assert_eq!(encoded.len(), VERSIONED_ENCODED_LENGTH);
+        assert_eq!(coding::decode_fixed64(&encoded[VERSIONED_ENCODED_LENGTH - 8..]),
+                   VERSIONED_TABLE_MAGIC_NUMBER);
+
+        let mut decoded = Footer::new();
+        decoded.decode_from(&encoded).unwrap();
+        check_decoded(&decoded, PARTITIONED_INDEX_FORMAT_VERSION);
+
+        // Too short to hold the version
+        assert!(Footer::new().decode_from(&encoded[4..]).is_err());
+
+        // Unknown versions are rejected
+        let mut unknown = encoded.clone();
+        coding::encode_fixed32(&mut unknown[VERSIONED_ENCODED_LENGTH - 12..], 2);
+        assert!(Footer::new().decode_from(&unknown).is_err());
+    }
+
+    #[test]
+    fn bad_footer()
+    {
+        let mut encoded = vec![];
+        footer(LEGACY_FORMAT_VERSION).encode_to(&mut encoded);
+        assert!(Footer::new().decode_from(&encoded[1..]).is_err());
+        let last = encoded.len() - 1;
+        encoded[last] ^= 1;
+        assert!(Footer::new().decode_from(&encoded).is_err());
+    }
 }
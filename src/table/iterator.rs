@@ -0,0 +1,66 @@
+use ::slice::Slice;
+use ::status::Status;
+
+/// An iterator yields a sequence of key/value pairs from a source.
+/// Unlike std::iter::Iterator it is a cursor: it can be repositioned
+/// with the seek methods and moved in either direction.
+///
+/// The key and value of the current entry are only available while the
+/// iterator is_valid(), and remain valid until it is next moved.
+pub trait RubbleIterator {
+    /// An iterator is either positioned at a key/value pair, or not
+    /// valid.  This method returns true iff the iterator is valid.
+    fn is_valid(&self) -> bool;
+
+    /// Position at the first key in the source.  The iterator is
+    /// is_valid() after this call iff the source is not empty.
+    fn seek_to_first(&mut self);
+
+    /// Position at the last key in the source.  The iterator is
+    /// is_valid() after this call iff the source is not empty.
+    fn seek_to_last(&mut self);
+
+    /// Position at the first key in the source that is at or past
+    /// target.  The iterator is is_valid() after this call iff the
+    /// source contains an entry that comes at or past target.
+    fn seek(&mut self, target: Slice);
+
+    /// Moves to the next entry in the source.  After this call,
+    /// is_valid() is true iff the iterator was not positioned at the
+    /// last entry in the source.
+    ///
+    /// REQUIRES: is_valid()
+    fn step(&mut self);
+
+    /// Moves to the previous entry in the source.  After this call,
+    /// is_valid() is true iff the iterator was not positioned at the
+    /// first entry in the source.
+    ///
+    /// REQUIRES: is_valid()
+    fn prev(&mut self);
+
+    /// Return the key for the current entry.
+    ///
+    /// REQUIRES: is_valid()
+    fn key(&self) -> Slice<'_>;
+
+    /// Return the value for the current entry.
+    ///
+    /// REQUIRES: is_valid()
+    fn value(&self) -> Slice<'_>;
+
+    /// If an error has occurred, return it.  Else return Status::Ok.
+    fn status(&self) -> &Status;
+}
+
+impl<I: RubbleIterator + ?Sized> RubbleIterator for Box<I> {
+    fn is_valid(&self) -> bool { (**self).is_valid() }
+    fn seek_to_first(&mut self) { (**self).seek_to_first() }
+    fn seek_to_last(&mut self) { (**self).seek_to_last() }
+    fn seek(&mut self, target: Slice) { (**self).seek(target) }
+    fn step(&mut self) { (**self).step() }
+    fn prev(&mut self) { (**self).prev() }
+    fn key(&self) -> Slice<'_> { (**self).key() }
+    fn value(&self) -> Slice<'_> { (**self).value() }
+    fn status(&self) -> &Status { (**self).status() }
+}
@@ -0,0 +1,413 @@
+use ::comparator::SliceComparator;
+use ::slice::Slice;
+use ::status::Status;
+use ::table::iterator::RubbleIterator;
+
+/// Which way the merging iterator last moved.  All children are
+/// positioned relative to the current key in that direction.
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    Forward,
+    Reverse,
+}
+
+/// An iterator that provides the union of the data in children[0..n-1].
+/// Takes ownership of the child iterators.
+///
+/// The result does no duplicate suppression.  I.e., if a particular
+/// key is present in K child iterators, it will be yielded K times.
+pub struct MergingIterator<'a, T: SliceComparator> {
+    comparator: T,
+    children: Vec<Box<dyn RubbleIterator + 'a>>,
+    /// Index of the child holding the current entry, if any
+    current: Option<usize>,
+    direction: Direction,
+    status: Status,
+}
+
+impl<'a, T: SliceComparator> MergingIterator<'a, T> {
+    pub fn new(comparator: T, children: Vec<Box<dyn RubbleIterator + 'a>>) -> MergingIterator<'a, T>
+    {
+        MergingIterator {
+            comparator,
+            children,
+            current: None,
+            direction: Direction::Forward,
+            status: Status::Ok,
+        }
+    }
+
+    fn find_smallest(&mut self)
+    {
+        let mut smallest: Option<usize> = None;
+        for (i, child) in self.children.iter().enumerate() {
+            if !child.is_valid() {
+                continue;
+            }
+            smallest = match smallest {
+                Some(s) if self.comparator.compare(child.key(), self.children[s].key()) >= 0 => Some(s),
+                _ => Some(i),
+            };
+        }
+        self.current = smallest;
+    }
+
+    fn find_largest(&mut self)
+    {
+        let mut largest: Option<usize> = None;
+        for (i, child) in self.children.iter().enumerate().rev() {
+            if !child.is_valid() {
+                continue;
+            }
+            largest = match largest {
+                Some(l) if self.comparator.compare(child.key(), self.children[l].key()) <= 0 => Some(l),
+                _ => Some(i),
+            };
+        }
+        self.current = largest;
+    }
+}
+
+impl<'a, T: SliceComparator> RubbleIterator for MergingIterator<'a, T> {
+    fn is_valid(&self) -> bool
+    {
+        self.current.is_some()
+    }
+
+    fn seek_to_first(&mut self)
+    {
+        for child in &mut self.children {
+            child.seek_to_first();
+        }
+        self.find_smallest();
+        self.direction = Direction::Forward;
+    }
+
+    fn seek_to_last(&mut self)
+    {
+        for child in &mut self.children {
+            child.seek_to_last();
+        }
+        self.find_largest();
+        self.direction = Direction::Reverse;
+    }
+
+    fn seek(&mut self, target: Slice)
+    {
+        for child in &mut self.children {
+            child.seek(target);
+        }
+        self.find_smallest();
+        self.direction = Direction::Forward;
+    }
+
+    fn step(&mut self)
+    {
+        let current = self.current.expect("step() on invalid MergingIterator");
+
+        // Ensure that all children are positioned after key().
+        // If we are moving in the forward direction, it is already
+        // true for all of the non-current children since current is
+        // the smallest child and key() == current.key().  Otherwise,
+        // we explicitly position the non-current children.
+        if self.direction != Direction::Forward {
+            let key = self.children[current].key().to_vec();
+            for (i, child) in self.children.iter_mut().enumerate() {
+                if i == current {
+                    continue;
+                }
+                child.seek(&key);
+                if child.is_valid() && self.comparator.compare(&key, child.key()) == 0 {
+                    child.step();
+                }
+            }
+            self.direction = Direction::Forward;
+        }
+
+        self.children[current].step();
+        self.find_smallest();
+    }
+
+    fn prev(&mut self)
+    {
+        let current = self.current.expect("prev() on invalid MergingIterator");
+
+        // Ensure that all children are positioned before key().
+        // If we are moving in the reverse direction, it is already
+        // true for all of the non-current children since current is
+        // the largest child and key() == current.key().  Otherwise,
+        // we explicitly position the non-current children.
+        if self.direction != Direction::Reverse {
+            let key = self.children[current].key().to_vec();
+            for (i, child) in self.children.iter_mut().enumerate() {
+                if i == current {
+                    continue;
+                }
+                child.seek(&key);
+                if child.is_valid() {
+                    // Child is at first entry >= key().  Step back one to be < key()
+                    child.prev();
+                } else {
+                    // Child has no entries >= key().  Position at last entry.
+                    child.seek_to_last();
+                }
+            }
+            self.direction = Direction::Reverse;
+        }
+
+        self.children[current].prev();
+        self.find_largest();
+    }
+
+    fn key(&self) -> Slice<'_>
+    {
+        let current = self.current.expect("key() on invalid MergingIterator");
+        self.children[current].key()
+    }
+
+    fn value(&self) -> Slice<'_>
+    {
+        let current = self.current.expect("value() on invalid MergingIterator");
+        self.children[current].value()
+    }
+
+    fn status(&self) -> &Status
+    {
+        for child in &self.children {
+            if !child.status().is_ok() {
+                return child.status();
+            }
+        }
+        &self.status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MergingIterator;
+    use ::comparator::BytewiseComparator;
+    use ::slice::Slice;
+    use ::status::Status;
+    use ::table::iterator::RubbleIterator;
+
+    type Entry = (Vec<u8>, Vec<u8>);
+
+    /// An iterator over a sorted list of entries
+    struct VecIterator {
+        entries: Vec<Entry>,
+        /// Index of the current entry; entries.len() when not valid
+        index: usize,
+        status: Status,
+    }
+
+    impl VecIterator {
+        fn new(keys: &[&str], tag: &str) -> VecIterator
+        {
+            let entries = keys.iter().map(|k| {
+                (k.as_bytes().to_vec(), format!("{}{}", tag, k).into_bytes())
+            }).collect();
+            VecIterator { entries, index: keys.len(), status: Status::Ok }
+        }
+    }
+
+    impl RubbleIterator for VecIterator {
+        fn is_valid(&self) -> bool { self.index < self.entries.len() }
+        fn seek_to_first(&mut self) { self.index = 0; }
+        fn seek_to_last(&mut self)
+        {
+            self.index = if self.entries.is_empty() { 0 } else { self.entries.len() - 1 };
+        }
+        fn seek(&mut self, target: Slice)
+        {
+            self.index = self.entries.iter().position(|e| &e.0[..] >= target)
+                .unwrap_or(self.entries.len());
+        }
+        fn step(&mut self) { self.index += 1; }
+        fn prev(&mut self)
+        {
+            self.index = if self.index == 0 { self.entries.len() } else { self.index - 1 };
+        }
+        fn key(&self) -> Slice<'_> { &self.entries[self.index].0 }
+        fn value(&self) -> Slice<'_> { &self.entries[self.index].1 }
+        fn status(&self) -> &Status { &self.status }
+    }
+
+    /// Merge the children, returning the iterator and every entry in
+    /// the order the merge should yield them.
+    fn merge(children: &[&[&str]]) -> (MergingIterator<'static, BytewiseComparator>, Vec<Entry>)
+    {
+        let mut expected = vec![];
+        let mut iters: Vec<Box<dyn RubbleIterator>> = vec![];
+        for (i, keys) in children.iter().enumerate() {
+            let child = VecIterator::new(keys, &i.to_string());
+            expected.extend(child.entries.iter().cloned());
+            iters.push(Box::new(child));
+        }
+        // Stable, so equal keys stay in child order
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+        (MergingIterator::new(BytewiseComparator, iters), expected)
+    }
+
+    fn entry(iter: &MergingIterator<BytewiseComparator>) -> Entry
+    {
+        (iter.key().to_vec(), iter.value().to_vec())
+    }
+
+    fn check_scans(children: &[&[&str]])
+    {
+        let (mut iter, expected) = merge(children);
+        assert!(!iter.is_valid());
+
+        iter.seek_to_first();
+        for e in &expected {
+            assert!(iter.is_valid());
+            assert_eq!(&entry(&iter), e);
+            iter.step();
+        }
+        assert!(!iter.is_valid());
+
+        iter.seek_to_last();
+        for e in expected.iter().rev() {
+            assert!(iter.is_valid());
+            assert_eq!(&entry(&iter), e);
+            iter.prev();
+        }
+        assert!(!iter.is_valid());
+        assert!(iter.status().is_ok());
+    }
+
+    #[test]
+    fn empty()
+    {
+        check_scans(&[]);
+        check_scans(&[&[], &[]]);
+
+        let (mut iter, _) = merge(&[&[], &[]]);
+        iter.seek(b"a");
+        assert!(!iter.is_valid());
+    }
+
+    #[test]
+    fn single_child()
+    {
+        check_scans(&[&["a", "b", "c"]]);
+    }
+
+    #[test]
+    fn interleaved()
+    {
+        check_scans(&[&["a", "d", "g"], &["b", "e", "h"], &["c", "f", "i"]]);
+    }
+
+    #[test]
+    fn disjoint_and_empty()
+    {
+        check_scans(&[&[], &["x", "y", "z"], &[], &["a", "b"], &[]]);
+    }
+
+    #[test]
+    fn overlapping()
+    {
+        // Keys present in several children are yielded once per child
+        check_scans(&[&["a", "b", "c", "e"], &["b", "c", "d"], &["c", "e", "f"]]);
+    }
+
+    #[test]
+    fn seek()
+    {
+        let (mut iter, _) = merge(&[&["b", "f", "j"], &[], &["d", "h"], &["l"]]);
+        let cases = [("", "b"), ("b", "b"), ("c", "d"), ("e", "f"), ("g", "h"),
+                     ("h", "h"), ("i", "j"), ("k", "l"), ("l", "l")];
+        for &(target, found) in &cases {
+            iter.seek(target.as_bytes());
+            assert!(iter.is_valid(), "seek({:?})", target);
+            assert_eq!(iter.key(), found.as_bytes());
+        }
+        iter.seek(b"m");
+        assert!(!iter.is_valid());
+
+        // Seeks always leave the iterator moving forward
+        iter.seek_to_last();
+        iter.seek(b"e");
+        iter.step();
+        assert_eq!(iter.key(), b"h");
+    }
+
+    #[test]
+    fn direction_switches()
+    {
+        let children: &[&[&str]] = &[&["a", "d", "g", "j"], &["b", "e", "h"], &[], &["c", "f", "i"]];
+        let (mut iter, expected) = merge(children);
+
+        // next -> prev -> next at every position
+        iter.seek_to_first();
+        for i in 0..expected.len() {
+            assert_eq!(entry(&iter), expected[i]);
+            if i > 0 {
+                iter.prev();
+                assert_eq!(entry(&iter), expected[i - 1]);
+                iter.step();
+                assert_eq!(entry(&iter), expected[i]);
+            }
+            iter.step();
+        }
+        assert!(!iter.is_valid());
+
+        // prev -> next -> prev at every position
+        iter.seek_to_last();
+        for i in (0..expected.len()).rev() {
+            assert_eq!(entry(&iter), expected[i]);
+            if i + 1 < expected.len() {
+                iter.step();
+                assert_eq!(entry(&iter), expected[i + 1]);
+                iter.prev();
+                assert_eq!(entry(&iter), expected[i]);
+            }
+            iter.prev();
+        }
+        assert!(!iter.is_valid());
+
+        // Switching direction after a seek
+        iter.seek(b"e");
+        iter.prev();
+        assert_eq!(iter.key(), b"d");
+        iter.prev();
+        assert_eq!(iter.key(), b"c");
+        iter.step();
+        assert_eq!(iter.key(), b"d");
+        iter.step();
+        assert_eq!(iter.key(), b"e");
+
+        // Stepping back from the first entry, and forward from the last
+        iter.seek_to_first();
+        iter.step();
+        iter.prev();
+        assert_eq!(iter.key(), b"a");
+        iter.prev();
+        assert!(!iter.is_valid());
+        iter.seek_to_last();
+        iter.prev();
+        iter.step();
+        assert_eq!(iter.key(), b"j");
+        iter.step();
+        assert!(!iter.is_valid());
+    }
+
+    #[test]
+    fn direction_switch_with_exhausted_children()
+    {
+        // When switching to reverse, a child with no entries at or past
+        // the current key must be positioned at its last entry.
+        let (mut iter, _) = merge(&[&["a", "b"], &["m", "n"]]);
+        iter.seek(b"m");
+        iter.prev();
+        assert_eq!(iter.key(), b"b");
+        iter.prev();
+        assert_eq!(iter.key(), b"a");
+        iter.step();
+        iter.step();
+        assert_eq!(iter.key(), b"m");
+        iter.step();
+        assert_eq!(iter.key(), b"n");
+    }
+}
@@ -1,4 +1,12 @@
 pub mod block;
+pub mod iterator;
+pub mod merger;
 pub mod block_builder;
+pub mod data_block_hash_index;
+pub mod filter_block;
+pub mod table_builder;
+pub mod two_level_iterator;
+#[allow(clippy::module_inception)]
 pub mod table;
+pub mod table_properties;
 pub mod format;
@@ -1,123 +1,391 @@
+use ::env::RandomAccessFile;
 use ::errors::RubbleResult;
-use ::table::block::{Block, OwnedBlock, BlockIterator};
-use ::comparator::SliceComparator;
+use memmap::Mmap;
+use std::borrow::Cow;
+use ::table::block::{Block, OwnedBlock, SliceBlock, BlockIterator};
+use ::table::two_level_iterator::TwoLevelIterator;
+use ::table::iterator::RubbleIterator;
+use ::comparator::{SliceComparator, BytewiseComparator};
+use ::dbformat;
+use ::filter_policy::FilterPolicy;
+use ::table::filter_block::FilterBlockReader;
 use ::slice::Slice;
 use ::status::Status;
 use ::table::format;
+use ::table::table_properties::{TableProperties, PROPERTIES_BLOCK};
+use ::util::coding;
 use std::fs::File;
 use std::io::SeekFrom;
 use std::io::prelude::*;
-use itertools::Zip;
+use std::sync::{Arc, Mutex};
 use ::table::format::{
     MAX_ENCODED_LENGTH,
     ENCODED_LENGTH,
+    VERSIONED_ENCODED_LENGTH,
+    PARTITIONED_INDEX_FORMAT_VERSION,
     BlockHandle,
     BlockContents,
     Footer,
     read_block,
 };
 use ::options::{
+    IndexType,
     Options,
     ReadOptions,
 };
+use std::cmp;
 
-struct TableCache;
-struct FilterBlockReader;
-
+/// The filter of a table.
+enum TableFilter {
+    /// A filter block holding the filters of all data blocks
+    Block(FilterBlockReader),
+    /// The top-level index of a partitioned filter, which maps the last
+    /// key covered by each filter partition to the partition's handle.
+    /// The partitions themselves are read through the block cache as
+    /// they are needed.
+    Partitioned(Arc<dyn FilterPolicy>, OwnedBlock),
+}
 
+/// What a table looks up in its filter for a key.
+#[derive(Clone, Copy)]
+enum FilterKey<'a> {
+    /// The key itself
+    Key(Slice<'a>),
+    /// The prefix of its user key
+    Prefix(Slice<'a>),
+}
 
-struct TableRep<'a, F: Read + Seek> {
+struct TableRep<'a, F: RandomAccessFile> {
     options: &'a Options,
     status: Status,
-    file: F,
+    file: Mutex<F>,
+    /// Size of the file in bytes
+    file_size: u64,
+    /// The file contents, if the file is memory mapped
+    mapping: Option<Mmap>,
     cache_id: u64,
-    filter: FilterBlockReader,
-    filter_data: Vec<u8>,
-    index_block: BlockContents,
-    // metaindex_handle: &'a BlockHandle,
+    filter: Option<TableFilter>,
+    /// The index block, or the top-level index of a partitioned index
+    index_block: OwnedBlock,
+    index_type: IndexType,
+    metaindex_handle: BlockHandle,
+    /// None if the table has no (readable) properties block
+    properties: Option<TableProperties>,
+    /// True iff the filter holds every key of the table
+    whole_key_filtering: bool,
+    /// True iff the filter holds the prefixes Options::prefix_extractor
+    /// extracts from the keys of the table
+    prefix_filtering: bool,
+    /// True iff the table is ordered by an InternalKeyComparator, so
+    /// prefixes are extracted from the user keys of its keys.
+    internal_keys: bool,
+}
+
+/// A block read through the block cache by Table::read_cached_block().
+enum CachedBlock<'a> {
+    /// The contents of an uncompressed block in the mapped file
+    Mapped(Slice<'a>),
+    /// A block read from the file, which may be shared with the cache
+    Owned(Arc<OwnedBlock>),
+}
+
+/// Iterator over the index block of a table, or over one of its data
+/// blocks.
+pub type TableBlockIterator<'a> = BlockIterator<'a, Arc<dyn SliceComparator>>;
+
+/// Iterator over the index of a table.  For a partitioned index, this
+/// iterates over the entries of all index partitions.
+pub type TableIndexIterator<'a> = Box<dyn RubbleIterator + 'a>;
+
+/// Iterator over the entire contents of a table.
+pub type TableIterator<'a> = TwoLevelIterator<'a, TableIndexIterator<'a>, TableBlockIterator<'a>>;
+
+/// Wraps the index iterator of a table iterator that seeks in prefix
+/// mode.  A seek to a target whose prefix the filter of the table rules
+/// out leaves the index iterator invalid, and so the table iterator
+/// too, without reading any data block.
+struct PrefixSeekIndexIterator<'a> {
+    index_iter: TableIndexIterator<'a>,
+    /// Returns false if the table holds no keys at or past the target
+    /// with the same prefix
+    prefix_may_match: Box<dyn Fn(Slice) -> bool + 'a>,
+    /// The last seek was ruled out by the filter
+    filtered: bool,
+}
+
+impl<'a> RubbleIterator for PrefixSeekIndexIterator<'a> {
+    fn is_valid(&self) -> bool
+    {
+        !self.filtered && self.index_iter.is_valid()
+    }
+
+    fn seek(&mut self, target: Slice)
+    {
+        self.filtered = !(self.prefix_may_match)(target);
+        if !self.filtered {
+            self.index_iter.seek(target);
+        }
+    }
+
+    fn seek_to_first(&mut self)
+    {
+        self.filtered = false;
+        self.index_iter.seek_to_first();
+    }
+
+    fn seek_to_last(&mut self)
+    {
+        self.filtered = false;
+        self.index_iter.seek_to_last();
+    }
+
+    fn step(&mut self)
+    {
+        assert!(self.is_valid());
+        self.index_iter.step();
+    }
+
+    fn prev(&mut self)
+    {
+        assert!(self.is_valid());
+        self.index_iter.prev();
+    }
+
+    fn key(&self) -> Slice<'_>
+    {
+        assert!(self.is_valid());
+        self.index_iter.key()
+    }
+
+    fn value(&self) -> Slice<'_>
+    {
+        assert!(self.is_valid());
+        self.index_iter.value()
+    }
+
+    fn status(&self) -> &Status
+    {
+        self.index_iter.status()
+    }
 }
 
 
 /// A Table is a sorted map from strings to strings.  Tables are
 /// immutable and persistent.  A Table may be safely accessed from
 /// multiple threads without external synchronization.
-pub struct Table<'a, F: Read + Seek> {
+pub struct Table<'a, F: RandomAccessFile> {
     rep: TableRep<'a, F>,
 }
 
 
 impl<'a, F> Table<'a, F>
-    where F: Read + Seek
+    where F: RandomAccessFile
 {
 
-    /// Attempt to open the table that is stored in bytes [0..file_size)
-    /// of "file", and read the metadata entries necessary to allow
-    /// retrieving data from the table.
+    /// Opens the table stored in the first `size` bytes of `file`, and
+    /// reads the footer, index, filter and properties blocks needed to
+    /// look up keys in it.
     ///
-    /// If successful, returns ok and sets "*table" to the newly opened
-    /// table.  The client should delete "*table" when no longer needed.
-    /// If there was an error while initializing the table, sets "*table"
-    /// to NULL and returns a non-ok status.  Does not take ownership of
-    /// "*source", but the client must ensure that "source" remains live
-    /// for the duration of the returned table's lifetime.
-    ///
-    /// *file must remain live while this Table is in use.
-    fn open(options: &'a Options, mut file: F, size: usize) -> RubbleResult<Table<'a, F>>
-        where F: Read + Seek
+    /// Returns an error status if the table is corrupt, or if it was
+    /// built with a comparator other than `options.comparator`.  The
+    /// table takes ownership of `file` and borrows `options` for as long
+    /// as it lives.
+    pub fn open(options: &'a Options, mut file: F, size: usize) -> RubbleResult<Table<'a, F>>
     {
-        if size < ENCODED_LENGTH as usize {
+        if size < ENCODED_LENGTH {
             return Err(Status::Corruption("file is too short to be an sstable".into()).into());
         }
 
-        let mut footer_input = [0; ENCODED_LENGTH];
+        // Read enough to hold the footer of any format version
+        let mut footer_input = vec![0; cmp::min(size, VERSIONED_ENCODED_LENGTH)];
 
-        let footer_offset = -(ENCODED_LENGTH as i64);
-        try!(file.seek(SeekFrom::End(footer_offset)));
-        try!(file.read_exact(&mut footer_input));
+        // The file may extend past "size", so the footer is found from
+        // the start of the file.
+        let footer_offset = (size - footer_input.len()) as u64;
+        file.seek(SeekFrom::Start(footer_offset))?;
+        file.read_exact(&mut footer_input)?;
 
         let mut footer = Footer::new();
-        try!(footer.decode_from(&footer_input));
+        footer.decode_from(&footer_input)?;
 
         let mut opt = ReadOptions::new();
-        let index_block = try!(read_block(&mut file, &opt, footer.index_handle()));
         opt.verify_checksums = options.paranoid_checks;
+        let index_block = read_block(&mut file, size as u64, &options.compression_codecs, &opt,
+                                     footer.index_handle())?;
+        let index_block = OwnedBlock::new(index_block)?;
+        let index_type = match footer.format_version() {
+            PARTITIONED_INDEX_FORMAT_VERSION => IndexType::TwoLevelIndexSearch,
+            _ => IndexType::BinarySearch,
+        };
+
+        let mapping = match options.allow_mmap_reads {
+            true => file.mmap()?,
+            false => None,
+        };
 
-        let cache_id = match options.block_cache.is_some() {
-            true => 0, //options.block_cache->NewId(),
-            false => 0,
+        let cache_id = match options.block_cache {
+            Some(ref cache) => cache.new_id(),
+            None => 0,
         };
 
         // We've successfully read the footer and the index block: we're
         // ready to serve requests.
         let rep = TableRep {
             status: Status::Ok,
-            options: options,
-            file: file,
-            index_block: index_block,
-            cache_id: cache_id,
-            filter_data: vec![],
-            // TODO
-            filter: FilterBlockReader{},
+            options,
+            file: Mutex::new(file),
+            file_size: size as u64,
+            mapping,
+            index_block,
+            index_type,
+            metaindex_handle: footer.metaindex_handle().clone(),
+            cache_id,
+            filter: None,
+            properties: None,
+            whole_key_filtering: true,
+            prefix_filtering: false,
+            internal_keys: options.comparator.name()
+                .starts_with(dbformat::INTERNAL_KEY_COMPARATOR_NAME_PREFIX),
         };
 
         let mut table = Table {
-            rep: rep,
+            rep,
         };
 
-        try!(table.read_meta(&footer));
+        table.read_meta(&footer)?;
+        table.check_comparator()?;
         Ok(table)
     }
 
+    /// Fail if the table records that it was written with a comparator
+    /// other than Options::comparator, since its keys would then be out
+    /// of order.  The name of an InternalKeyComparator includes its user
+    /// comparator, so tables of internal keys are checked too.  Tables
+    /// without properties cannot be checked.
+    fn check_comparator(&self) -> RubbleResult<()>
+    {
+        let expected = self.rep.options.comparator.name();
+        match self.rep.properties {
+            Some(ref properties) if !properties.comparator_name.is_empty()
+                && properties.comparator_name != expected =>
+            {
+                Err(Status::InvalidArgument(format!(
+                    "{} does not match existing comparator {}",
+                    expected, properties.comparator_name)).into())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the properties recorded when the table was built, or None
+    /// if the table has no properties block.
+    pub fn properties(&self) -> Option<&TableProperties>
+    {
+        self.rep.properties.as_ref()
+    }
+
     /// Returns a new iterator over the table contents.
-    /// The result of new_iterator() is initially invalid (caller must
+    /// The result of iter() is initially invalid (caller must
     /// call one of the Seek methods on the iterator before using it).
-    fn iter<'b, T>(&'b self, read_options: &ReadOptions) -> BlockIterator<'b, T>
-        where T: SliceComparator
+    ///
+    /// Unless read_options.total_order_seek is set, the iterator seeks
+    /// in prefix mode (see ReadOptions::total_order_seek).
+    pub fn iter<'b>(&'b self, read_options: &ReadOptions) -> TableIterator<'b>
     {
-        // return NewTwoLevelIterator(
-        //     rep_->index_block->NewIterator(rep_->options.comparator),
-        //     &Table::BlockReader, const_cast<Table*>(this), options);
-        unimplemented!()
+        let mut index_iter = self.index_iter(read_options);
+        if self.rep.prefix_filtering && !read_options.total_order_seek {
+            let options = read_options.clone();
+            index_iter = Box::new(PrefixSeekIndexIterator {
+                index_iter,
+                prefix_may_match: Box::new(move |target| self.prefix_may_match(&options, target)),
+                filtered: false,
+            });
+        }
+        TwoLevelIterator::new(
+            index_iter,
+            Box::new(move |options, index_value| self.block_reader(options, index_value)),
+            read_options)
+    }
+
+    /// Returns a new iterator over the index of the table.  The
+    /// partitions of a partitioned index are read through block_reader()
+    /// as the iterator reaches them, so they are cached like data blocks.
+    fn index_iter<'b>(&'b self, read_options: &ReadOptions) -> TableIndexIterator<'b>
+    {
+        let index_iter = self.rep.index_block.iter(self.rep.options.comparator.clone());
+        match self.rep.index_type {
+            IndexType::BinarySearch => Box::new(index_iter),
+            IndexType::TwoLevelIndexSearch => Box::new(TwoLevelIterator::new(
+                index_iter,
+                Box::new(move |options, index_value| self.block_reader(options, index_value)),
+                read_options)),
+        }
+    }
+
+    /// Convert an index iterator value (i.e., an encoded BlockHandle)
+    /// into an iterator over the contents of the corresponding block.
+    fn block_reader<'b>(&'b self, options: &ReadOptions, index_value: Slice)
+                        -> RubbleResult<TableBlockIterator<'b>>
+    {
+        let mut handle = BlockHandle::new();
+        // We intentionally allow extra stuff in index_value so that we
+        // can add more features in the future.
+        handle.decode_from(index_value)?;
+
+        let comparator = self.rep.options.comparator.clone();
+        match self.read_cached_block(options, &handle, OwnedBlock::new)? {
+            // The block points straight into the mapped file, which
+            // lives as long as the table.
+            CachedBlock::Mapped(data) => Ok(SliceBlock::new(data)?.iter_borrowed(comparator)),
+            CachedBlock::Owned(block) => Ok(block.iter_shared(comparator)),
+        }
+    }
+
+    /// Read the block identified by "handle" through the block cache,
+    /// or straight out of the mapped file if it is uncompressed.
+    /// "new_block" turns the contents of a block read from the file
+    /// into the OwnedBlock that is cached.
+    fn read_cached_block<'b, B>(&'b self, options: &ReadOptions, handle: &BlockHandle,
+                                new_block: B) -> RubbleResult<CachedBlock<'b>>
+        where B: FnOnce(BlockContents) -> RubbleResult<OwnedBlock>
+    {
+        let mut cache_key = Vec::with_capacity(16);
+        if let Some(ref cache) = self.rep.options.block_cache {
+            coding::put_fixed64(&mut cache_key, self.rep.cache_id);
+            coding::put_fixed64(&mut cache_key, handle.offset());
+            if let Some(block) = cache.lookup(&cache_key) {
+                return Ok(CachedBlock::Owned(block));
+            }
+        }
+
+        let contents = self.read_block(options, handle)?;
+        if let Cow::Borrowed(data) = contents.data {
+            return Ok(CachedBlock::Mapped(data));
+        }
+
+        let cachable = contents.cachable;
+        let block = new_block(contents)?;
+        let block = match self.rep.options.block_cache {
+            Some(ref cache) if cachable && options.fill_cache => {
+                let charge = block.size();
+                cache.insert(&cache_key, block, charge)
+            },
+            _ => Arc::new(block),
+        };
+        Ok(CachedBlock::Owned(block))
+    }
+
+    fn read_block<'b>(&'b self, options: &ReadOptions, handle: &BlockHandle)
+                      -> RubbleResult<BlockContents<'b>>
+    {
+        let codecs = &self.rep.options.compression_codecs;
+        match self.rep.mapping {
+            Some(ref mapping) => format::read_block_from_mapping(&mapping[..], codecs, options, handle),
+            None => {
+                let mut file = self.rep.file.lock().unwrap();
+                read_block(&mut *file, self.rep.file_size, codecs, options, handle)
+            },
+        }
     }
 
 
@@ -127,82 +395,259 @@ impl<'a, F> Table<'a, F>
     /// bytes, and so includes effects like compression of the underlying data.
     /// E.g., the approximate offset of the last key in the table will
     /// be close to the file length.
-    fn approximate_offset_of(key: Slice) -> usize
-    {
-
-        // let index_iter = self.rep.index_block.iter(self.rep.op);
-        // Iterator* index_iter =
-        //         rep_->index_block->NewIterator(rep_->options.comparator);
-        //     index_iter->Seek(key);
-        //     uint64_t result;
-        //     if (index_iter->Valid()) {
-        //         BlockHandle handle;
-        //         Slice input = index_iter->value();
-        //         Status s = handle.DecodeFrom(&input);
-        //         if (s.ok()) {
-        //             result = handle.offset();
-        //         } else {
-        //             // Strange: we can't decode the block handle in the index block.
-        //             // We'll just return the offset of the metaindex block, which is
-        //             // close to the whole file size for this case.
-        //             result = rep_->metaindex_handle.offset();
-        //         }
-        //     } else {
-        //         // key is past the last key in the file.  Approximate the offset
-        //         // by returning the offset of the metaindex block (which is
-        //         // right near the end of the file).
-        //         result = rep_->metaindex_handle.offset();
-        //     }
-        //     delete index_iter;
-        //     return result;
-        // }
-        unimplemented!()
-    }
-
-    // /// Calls (*handle_result)(arg, ...) with the entry found after a call
-    // /// to Seek(key).  May not make such a call if filter policy says
-    // /// that key is not present.
-    // fn internal_get(&self, options: &ReadOptions, key: Slice, void* arg,
-    //                 void (*handle_result)(void* arg, const Slice& k, const Slice& v));
+    pub fn approximate_offset_of(&self, key: Slice) -> u64
+    {
+        let mut index_iter = self.index_iter(&ReadOptions::new());
+        index_iter.seek(key);
+        if index_iter.is_valid() {
+            let mut handle = BlockHandle::new();
+            match handle.decode_from(index_iter.value()) {
+                Ok(_) => handle.offset(),
+                // Strange: we can't decode the block handle in the index block.
+                // We'll just return the offset of the metaindex block, which is
+                // close to the whole file size for this case.
+                Err(_) => self.rep.metaindex_handle.offset(),
+            }
+        } else {
+            // key is past the last key in the file.  Approximate the offset
+            // by returning the offset of the metaindex block (which is
+            // right near the end of the file).
+            self.rep.metaindex_handle.offset()
+        }
+    }
+
+    /// Returns the approximate number of file bytes used by the keys in
+    /// the range [start, end).
+    pub fn approximate_size(&self, start: Slice, end: Slice) -> u64
+    {
+        let start_offset = self.approximate_offset_of(start);
+        let end_offset = self.approximate_offset_of(end);
+        end_offset.saturating_sub(start_offset)
+    }
+
+    /// Returns the value stored in the table for "key".  Returns
+    /// Status::NotFound if the table holds no entry for "key".
+    pub fn get(&self, options: &ReadOptions, key: Slice) -> RubbleResult<Vec<u8>>
+    {
+        let comparator = &self.rep.options.comparator;
+        let mut value = None;
+        self.internal_get(options, key, |found_key, found_value| {
+            if comparator.compare(found_key, key) == 0 {
+                value = Some(found_value.to_vec());
+            }
+        })?;
+        value.ok_or_else(|| Status::NotFound("key not found in table".into()).into())
+    }
+
+    /// Calls handle_result(key, value) with the entry found after a call
+    /// to seek(key).  May not make such a call if filter policy or the
+    /// hash index of the data block says that key is not present, and
+    /// if key is not present, the entry may be any entry past key.
+    pub fn internal_get<H>(&self, options: &ReadOptions, key: Slice, mut handle_result: H)
+                           -> RubbleResult<()>
+        where H: FnMut(Slice, Slice)
+    {
+        let filter_key = self.filter_key(key);
+
+        // A partitioned filter is consulted before the index, so that
+        // the index partition need not be read when the key is filtered.
+        if let Some(TableFilter::Partitioned(ref policy, ref filter_index)) = self.rep.filter {
+            let filtered = filter_key.is_some_and(|filter_key| {
+                !self.partitioned_filter_may_match(options, policy, filter_index, key, filter_key)
+            });
+            if filtered {
+                return Ok(());
+            }
+        }
+
+        let mut index_iter = self.index_iter(options);
+        index_iter.seek(key);
+
+        if index_iter.is_valid() {
+            let handle_value = index_iter.value();
+            let mut handle = BlockHandle::new();
+            let filtered = match self.rep.filter {
+                Some(TableFilter::Block(ref filter)) => filter_key.is_some_and(|filter_key| {
+                    handle.decode_from(handle_value).is_ok() && !match filter_key {
+                        FilterKey::Key(key) => filter.key_may_match(handle.offset(), key),
+                        FilterKey::Prefix(prefix) => filter.prefix_may_match(handle.offset(), prefix),
+                    }
+                }),
+                _ => false,
+            };
+
+            if !filtered {
+                let mut block_iter = self.block_reader(options, handle_value)?;
+                block_iter.seek_for_get(key);
+                if block_iter.is_valid() {
+                    handle_result(block_iter.key(), block_iter.value());
+                }
+                if !block_iter.status().is_ok() {
+                    return Err(block_iter.status().clone().into())
+                }
+            }
+        }
+
+        if !index_iter.status().is_ok() {
+            return Err(index_iter.status().clone().into())
+        }
+        Ok(())
+    }
+
+    /// Returns false if the filter shows that the table holds no keys at
+    /// or past "target" with the same prefix as "target", so that a
+    /// prefix seek to "target" finds nothing in this table.  Always
+    /// returns true unless the filter holds the prefixes extracted by
+    /// Options::prefix_extractor and "target" has a prefix.
+    pub fn prefix_may_match(&self, options: &ReadOptions, target: Slice) -> bool
+    {
+        let prefix = match self.filter_prefix(target) {
+            Some(prefix) => prefix,
+            None => return true,
+        };
+        match self.rep.filter {
+            Some(TableFilter::Partitioned(ref policy, ref filter_index)) =>
+                self.partitioned_filter_may_match(options, policy, filter_index, target,
+                                                  FilterKey::Prefix(prefix)),
+            Some(TableFilter::Block(ref filter)) => {
+                // If the first key at or past "target" has the prefix, it
+                // is in the data block seek(target) lands in.
+                let mut index_iter = self.index_iter(options);
+                index_iter.seek(target);
+                if !index_iter.is_valid() {
+                    return true;
+                }
+                let mut handle = BlockHandle::new();
+                handle.decode_from(index_iter.value()).is_err()
+                    || filter.prefix_may_match(handle.offset(), prefix)
+            },
+            None => true,
+        }
+    }
+
+    /// Returns what to look up in the filter for "key": the key itself
+    /// if the filter holds whole keys, or else its prefix if the filter
+    /// holds prefixes.  None if the filter cannot tell whether the table
+    /// holds "key".
+    fn filter_key<'k>(&self, key: Slice<'k>) -> Option<FilterKey<'k>>
+    {
+        if self.rep.whole_key_filtering {
+            return Some(FilterKey::Key(key));
+        }
+        self.filter_prefix(key).map(FilterKey::Prefix)
+    }
+
+    /// Returns the prefix of "key", or of its user key if the table
+    /// holds internal keys, if it has one and the filter holds prefixes.
+    fn filter_prefix<'k>(&self, key: Slice<'k>) -> Option<Slice<'k>>
+    {
+        let user_key = match self.rep.internal_keys {
+            true => dbformat::parse_internal_key(key)?.user_key,
+            false => key,
+        };
+        match self.rep.options.prefix_extractor {
+            Some(ref extractor) if self.rep.prefix_filtering && extractor.in_domain(user_key) =>
+                Some(extractor.transform(user_key)),
+            _ => None,
+        }
+    }
+
+    /// Returns false if the partitioned filter shows that the table holds
+    /// no "filter_key" (a key or a prefix) in the partition that covers
+    /// "target".
+    fn partitioned_filter_may_match(&self, options: &ReadOptions, policy: &Arc<dyn FilterPolicy>,
+                                    filter_index: &OwnedBlock, target: Slice,
+                                    filter_key: FilterKey) -> bool
+    {
+        let mut iter = filter_index.iter(self.rep.options.comparator.clone());
+        iter.seek(target);
+        if !iter.is_valid() {
+            // Past the last partition, or an error: let the index decide
+            return true;
+        }
+        let mut handle = BlockHandle::new();
+        if handle.decode_from(iter.value()).is_err() {
+            return true;
+        }
+        // Filter partitions are read like index partitions, so they are
+        // cached like them too.
+        let may_match = |filter| match filter_key {
+            FilterKey::Key(key) => policy.key_may_match(key, filter),
+            FilterKey::Prefix(prefix) => policy.prefix_may_match(prefix, filter),
+        };
+        match self.read_cached_block(options, &handle, |contents| Ok(OwnedBlock::raw(contents))) {
+            Ok(CachedBlock::Mapped(filter)) => may_match(filter),
+            Ok(CachedBlock::Owned(filter)) => may_match(filter.data()),
+            Err(_) => true,  // Errors are treated as potential matches
+        }
+    }
 
     fn read_meta(&mut self, footer: &Footer) -> RubbleResult<()>
     {
-        // TODO: impl  self.rep.options.filter_policy.is_some()
-        if self.rep.options.filter_policy.is_none() {
-            return Ok(())
-        }
-
-        //   // TODO(sanjay): Skip this if footer.metaindex_handle() size indicates
-        //   // it is an empty block.
-        //   ReadOptions opt;
-        //   if (rep_->options.paranoid_checks) {
-        //     opt.verify_checksums = true;
-        //   }
-        //   BlockContents contents;
-        //   if (!ReadBlock(rep_->file, opt, footer.metaindex_handle(), &contents).ok()) {
-        //     // Do not propagate errors since meta info is not needed for operation
-        //     return;
-        //   }
-        //   Block* meta = new Block(contents);
-
-        //   Iterator* iter = meta->NewIterator(BytewiseComparator());
-        //   std::string key = "filter.";
-        //   key.append(rep_->options.filter_policy->Name());
-        //   iter->Seek(key);
-        //   if (iter->Valid() && iter->key() == Slice(key)) {
-        //     ReadFilter(iter->value());
-        //   }
-        //   delete iter;
-        //   delete meta;
-        // }
+        // TODO(sanjay): Skip this if footer.metaindex_handle() size indicates
+        // it is an empty block.
+        let mut opt = ReadOptions::new();
+        opt.verify_checksums = self.rep.options.paranoid_checks;
+        let meta = match self.read_block(&opt, footer.metaindex_handle()).and_then(OwnedBlock::new) {
+            Ok(meta) => meta,
+            // Do not propagate errors since meta info is not needed for operation
+            Err(_) => return Ok(()),
+        };
+
+        let mut iter = meta.iter(BytewiseComparator);
+        iter.seek(PROPERTIES_BLOCK.as_bytes());
+        if iter.is_valid() && iter.key() == PROPERTIES_BLOCK.as_bytes() {
+            // Tables written without a properties block (e.g. by LevelDB)
+            // work just the same, so this error is not propagated either.
+            let _ = self.read_properties(iter.value());
+        }
+
+        // Tables without properties hold whole keys in their filters,
+        // like LevelDB tables do.
+        if let Some(ref properties) = self.rep.properties {
+            self.rep.whole_key_filtering = properties.whole_key_filtering;
+            self.rep.prefix_filtering = match self.rep.options.prefix_extractor {
+                Some(ref extractor) => properties.prefix_extractor_name == extractor.name(),
+                None => false,
+            };
+        }
+
+        if let Some(ref policy) = self.rep.options.filter_policy {
+            // Without its filter the table still works, just with more
+            // block reads, so these errors are not propagated either.
+            let key = match self.rep.whole_key_filtering {
+                true => format!("filter.{}", policy.name()),
+                false => format!("prefixfilter.{}", policy.name()),
+            };
+            iter.seek(key.as_bytes());
+            if iter.is_valid() && iter.key() == key.as_bytes() {
+                let _ = self.read_filter(policy.clone(), iter.value());
+            }
+            let key = format!("partitionedfilter.{}", policy.name());
+            iter.seek(key.as_bytes());
+            if iter.is_valid() && iter.key() == key.as_bytes() {
+                let _ = self.read_partitioned_filter(policy.clone(), iter.value());
+            }
+        }
+        Ok(())
+    }
+
+    fn read_properties(&mut self, properties_handle_value: Slice) -> RubbleResult<()>
+    {
+        let mut properties_handle = BlockHandle::new();
+        properties_handle.decode_from(properties_handle_value)?;
+
+        let mut opt = ReadOptions::new();
+        opt.verify_checksums = self.rep.options.paranoid_checks;
+        let block = self.read_block(&opt, &properties_handle).and_then(OwnedBlock::new)?;
+        self.rep.properties = Some(TableProperties::decode(&block)?);
         Ok(())
     }
 
-    fn read_filter(&mut self, filter_handle_value: Slice) -> RubbleResult<()>
+    fn read_filter(&mut self, policy: Arc<dyn FilterPolicy>, filter_handle_value: Slice) -> RubbleResult<()>
     {
         let mut filter_handle = BlockHandle::new();
-
-        let next = try!(filter_handle.decode_from(filter_handle_value));
+        filter_handle.decode_from(filter_handle_value)?;
 
         // We might want to unify with ReadBlock() if we start
         // requiring checksum verification in Table::Open.
@@ -211,123 +656,1280 @@ impl<'a, F> Table<'a, F>
             opt.verify_checksums = true;
         }
 
-        // TODO!
-        // let mut file = &mut self.rep.file;
-        // let block = try!(read_block(file, &opt, &filter_handle));
-        // if (block.heap_allocated) {
-        // self.rep.filter_data = block.data;     // Will need to delete later??
-        // }
-        // self.rep.filter = FilterBlockReader::new(self.rep.options.filter_policy, block.data);
+        let block = self.read_block(&opt, &filter_handle)?;
+        self.rep.filter = Some(TableFilter::Block(FilterBlockReader::new(policy, block.data.into_owned())));
+        Ok(())
+    }
+
+    fn read_partitioned_filter(&mut self, policy: Arc<dyn FilterPolicy>, filter_index_handle_value: Slice)
+                               -> RubbleResult<()>
+    {
+        let mut filter_index_handle = BlockHandle::new();
+        filter_index_handle.decode_from(filter_index_handle_value)?;
+
+        let mut opt = ReadOptions::new();
+        opt.verify_checksums = self.rep.options.paranoid_checks;
+        let block = self.read_block(&opt, &filter_index_handle).and_then(OwnedBlock::new)?;
+        self.rep.filter = Some(TableFilter::Partitioned(policy, block));
         Ok(())
     }
 
 }
 
-struct LessThanComparator;
+#[cfg(test)]
+mod tests {
+    use super::{Table, TableFilter};
+    use ::comparator::{BytewiseComparator, ReverseBytewiseComparator, SliceComparator};
+    use ::dbformat::{self, InternalFilterPolicy, InternalKeyComparator, LookupKey, ValueType};
+    use ::env::RandomAccessFile;
+    use ::status::Status;
+    use ::table::block::{Block, OwnedBlock};
+    use ::options::{CompressionType, DataBlockIndexType, IndexType, Options, ReadOptions};
+    use ::slice_transform::FixedPrefixTransform;
+    use ::table::format::{self, BlockHandle, Footer, BLOCK_TRAILER_SIZE, ENCODED_LENGTH,
+                          TABLE_MAGIC_NUMBER, VERSIONED_ENCODED_LENGTH};
+    use ::table::iterator::RubbleIterator;
+    use ::table::table_builder::TableBuilder;
+    use ::table::table_properties::{
+        self,
+        TablePropertiesCollector,
+        TablePropertiesCollectorFactory,
+        UserCollectedProperties,
+        PROPERTIES_BLOCK,
+    };
+    use ::util::bloom::BloomFilterPolicy;
+    use ::util::cache::Cache;
+    use ::util::coding;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{self, Cursor, Read, Seek, SeekFrom};
+    use std::process;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const NUM_KEYS: usize = 1000;
+
+    fn key(i: usize) -> Vec<u8>
+    {
+        format!("key{:06}", i * 2).into_bytes()
+    }
+
+    fn value(i: usize) -> Vec<u8>
+    {
+        format!("value{}", i).repeat(i % 7 + 1).into_bytes()
+    }
 
-impl SliceComparator for LessThanComparator {
-    fn compare(&self, a: Slice, b: Slice) -> i32
+    /// Build a table holding NUM_KEYS entries with small data blocks,
+    /// so that the table spans many blocks.
+    fn build_table(options: &Options) -> Vec<u8>
     {
-        for (bytea, byteb) in Zip::new((a, b)) {
-            if bytea < byteb {
-                return 1
-            } else if bytea < byteb {
-                return -1
+        let mut file = vec![];
+        {
+            let mut builder = TableBuilder::new(options, &mut file);
+            for i in 0..NUM_KEYS {
+                builder.add(&key(i), &value(i)).unwrap();
             }
+            builder.finish().unwrap();
+            assert_eq!(builder.num_entries(), NUM_KEYS as u64);
         }
-        return 0
+        file
     }
-}
 
+    fn options(compression: CompressionType) -> Options
+    {
+        let mut options = Options::new();
+        options.block_size = 256;
+        options.compression = compression;
+        options
+    }
+
+    /// Options for a table with a partitioned index, and partitioned
+    /// bloom filters if "partition_filters" is set.
+    fn partitioned_options(partition_filters: bool) -> Options
+    {
+        let mut options = options(CompressionType::SnappyCompression);
+        options.index_type = IndexType::TwoLevelIndexSearch;
+        options.metadata_block_size = 128;
+        options.partition_filters = partition_filters;
+        options.filter_policy = Some(Arc::new(BloomFilterPolicy::new(10)));
+        options
+    }
+
+    /// Iterate, seek and get every key of a table built by build_table.
+    fn check_contents<F: RandomAccessFile>(table: &Table<F>)
+    {
+        let read_options = ReadOptions::new();
+        let mut iter = table.iter(&read_options);
+        iter.seek_to_first();
+        for i in 0..NUM_KEYS {
+            assert!(iter.is_valid());
+            assert_eq!(iter.key(), &key(i)[..]);
+            assert_eq!(iter.value(), &value(i)[..]);
+            iter.step();
+        }
+        assert!(!iter.is_valid());
+        iter.seek_to_last();
+        for i in (0..NUM_KEYS).rev() {
+            assert_eq!(iter.key(), &key(i)[..]);
+            iter.prev();
+        }
+        assert!(!iter.is_valid());
+        assert!(iter.status().is_ok());
+
+        for i in 0..NUM_KEYS {
+            let between = format!("key{:06}", i * 2 + 1).into_bytes();
+            iter.seek(&between);
+            if i + 1 < NUM_KEYS {
+                assert_eq!(iter.key(), &key(i + 1)[..]);
+            } else {
+                assert!(!iter.is_valid());
+            }
+            assert_eq!(table.get(&read_options, &key(i)).unwrap(), value(i));
+            assert!(table.get(&read_options, &between).is_err());
+        }
+    }
+
+    #[test]
+    fn footer()
+    {
+        let options = options(CompressionType::NoCompression);
+        let file = build_table(&options);
+
+        // LevelDB tables end with the legacy magic number, written as
+        // two fixed32s.
+        let magic = coding::decode_fixed64(&file[file.len() - 8..]);
+        assert_eq!(magic, TABLE_MAGIC_NUMBER);
+
+        let mut footer = Footer::new();
+        footer.decode_from(&file[file.len() - ENCODED_LENGTH..]).unwrap();
+        assert_eq!(footer.format_version(), format::LEGACY_FORMAT_VERSION);
+
+        // The metaindex block is the last block before the footer, and
+        // the index block comes before it.
+        let metaindex = footer.metaindex_handle();
+        let index = footer.index_handle();
+        assert_eq!(metaindex.offset() + metaindex.size() + BLOCK_TRAILER_SIZE as u64,
+                   (file.len() - ENCODED_LENGTH) as u64);
+        assert!(index.offset() + index.size() + (BLOCK_TRAILER_SIZE as u64) <= metaindex.offset());
+
+        let mut encoded = vec![];
+        footer.encode_to(&mut encoded);
+        assert_eq!(&encoded[..], &file[file.len() - ENCODED_LENGTH..]);
+
+        // Spans many data blocks
+        let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+        let mut index_iter = table.index_iter(&ReadOptions::new());
+        let mut blocks = 0;
+        index_iter.seek_to_first();
+        while index_iter.is_valid() {
+            let mut handle = BlockHandle::new();
+            handle.decode_from(index_iter.value()).unwrap();
+            blocks += 1;
+            index_iter.step();
+        }
+        assert!(blocks > 10);
+    }
+
+    #[test]
+    fn open_compressed()
+    {
+        let options = options(CompressionType::SnappyCompression);
+        let file = build_table(&options);
+        assert!(Table::open(&options, Cursor::new(file.clone()), file.len()).is_ok());
+    }
+
+    #[test]
+    fn bad_magic_number()
+    {
+        let options = options(CompressionType::NoCompression);
+        let mut file = build_table(&options);
+        let len = file.len();
+        file[len - 1] ^= 1;
+        assert!(Table::open(&options, Cursor::new(file), len).is_err());
+
+        let file = vec![0; ENCODED_LENGTH - 1];
+        assert!(Table::open(&options, Cursor::new(file), ENCODED_LENGTH - 1).is_err());
+    }
+
+    /// The table is the first "size" bytes of the file, even when more
+    /// follows it.
+    #[test]
+    fn trailing_garbage()
+    {
+        let options = options(CompressionType::NoCompression);
+        let mut file = build_table(&options);
+        let size = file.len();
+        file.extend_from_slice(&[0xff; 100]);
+        let table = Table::open(&options, Cursor::new(file), size).unwrap();
+        let mut iter = table.iter(&ReadOptions::new());
+        iter.seek_to_first();
+        for i in 0..NUM_KEYS {
+            assert!(iter.is_valid());
+            assert_eq!(iter.key(), &key(i)[..]);
+            assert_eq!(iter.value(), &value(i)[..]);
+            iter.step();
+        }
+        assert!(!iter.is_valid());
+        assert!(iter.status().is_ok());
+    }
+
+    #[test]
+    fn empty_table()
+    {
+        let options = options(CompressionType::NoCompression);
+        let mut file = vec![];
+        TableBuilder::new(&options, &mut file).finish().unwrap();
+        let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+        let mut iter = table.iter(&ReadOptions::new());
+        iter.seek_to_first();
+        assert!(!iter.is_valid());
+        iter.seek_to_last();
+        assert!(!iter.is_valid());
+        assert!(table.get(&ReadOptions::new(), b"key").is_err());
+    }
+
+    #[test]
+    fn iterate()
+    {
+        let compressions = [CompressionType::NoCompression, CompressionType::SnappyCompression,
+                            CompressionType::Lz4Compression, CompressionType::ZstdCompression];
+        for &compression in &compressions {
+            let options = options(compression);
+            let file = build_table(&options);
+            let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+            let mut iter = table.iter(&ReadOptions::new());
+            assert!(!iter.is_valid());
 
-// // Convert an index iterator value (i.e., an encoded BlockHandle)
-// // into an iterator over the contents of the corresponding block.
-// Iterator* Table::BlockReader(void* arg,
-//                              const ReadOptions& options,
-//                              const Slice& index_value) {
-//   Table* table = reinterpret_cast<Table*>(arg);
-//   Cache* block_cache = table->rep_->options.block_cache;
-//   Block* block = NULL;
-//   Cache::Handle* cache_handle = NULL;
-
-//   BlockHandle handle;
-//   Slice input = index_value;
-//   Status s = handle.DecodeFrom(&input);
-//   // We intentionally allow extra stuff in index_value so that we
-//   // can add more features in the future.
-
-//   if (s.ok()) {
-//     BlockContents contents;
-//     if (block_cache != NULL) {
-//       char cache_key_buffer[16];
-//       EncodeFixed64(cache_key_buffer, table->rep_->cache_id);
-//       EncodeFixed64(cache_key_buffer+8, handle.offset());
-//       Slice key(cache_key_buffer, sizeof(cache_key_buffer));
-//       cache_handle = block_cache->Lookup(key);
-//       if (cache_handle != NULL) {
-//         block = reinterpret_cast<Block*>(block_cache->Value(cache_handle));
-//       } else {
-//         s = ReadBlock(table->rep_->file, options, handle, &contents);
-//         if (s.ok()) {
-//           block = new Block(contents);
-//           if (contents.cachable && options.fill_cache) {
-//             cache_handle = block_cache->Insert(
-//                 key, block, block->size(), &DeleteCachedBlock);
-//           }
-//         }
-//       }
-//     } else {
-//       s = ReadBlock(table->rep_->file, options, handle, &contents);
-//       if (s.ok()) {
-//         block = new Block(contents);
-//       }
-//     }
-//   }
-
-//   Iterator* iter;
-//   if (block != NULL) {
-//     iter = block->NewIterator(table->rep_->options.comparator);
-//     if (cache_handle == NULL) {
-//       iter->RegisterCleanup(&DeleteBlock, block, NULL);
-//     } else {
-//       iter->RegisterCleanup(&ReleaseBlock, block_cache, cache_handle);
-//     }
-//   } else {
-//     iter = NewErrorIterator(s);
-//   }
-//   return iter;
-// }
-
-// Status Table::InternalGet(const ReadOptions& options, const Slice& k,
-//                           void* arg,
-//                           void (*saver)(void*, const Slice&, const Slice&)) {
-//   Status s;
-//   Iterator* iiter = rep_->index_block->NewIterator(rep_->options.comparator);
-//   iiter->Seek(k);
-//   if (iiter->Valid()) {
-//     Slice handle_value = iiter->value();
-//     FilterBlockReader* filter = rep_->filter;
-//     BlockHandle handle;
-//     if (filter != NULL &&
-//         handle.DecodeFrom(&handle_value).ok() &&
-//         !filter->KeyMayMatch(handle.offset(), k)) {
-//       // Not found
-//     } else {
-//       Iterator* block_iter = BlockReader(this, options, iiter->value());
-//       block_iter->Seek(k);
-//       if (block_iter->Valid()) {
-//         (*saver)(arg, block_iter->key(), block_iter->value());
-//       }
-//       s = block_iter->status();
-//       delete block_iter;
-//     }
-//   }
-//   if (s.ok()) {
-//     s = iiter->status();
-//   }
-//   delete iiter;
-//   return s;
-// }
-
-
-// }  // namespace leveldb
+            iter.seek_to_first();
+            for i in 0..NUM_KEYS {
+                assert!(iter.is_valid());
+                assert_eq!(iter.key(), &key(i)[..]);
+                assert_eq!(iter.value(), &value(i)[..]);
+                iter.step();
+            }
+            assert!(!iter.is_valid());
+
+            iter.seek_to_last();
+            for i in (0..NUM_KEYS).rev() {
+                assert!(iter.is_valid());
+                assert_eq!(iter.key(), &key(i)[..]);
+                assert_eq!(iter.value(), &value(i)[..]);
+                iter.prev();
+            }
+            assert!(!iter.is_valid());
+            assert!(iter.status().is_ok());
+        }
+    }
+
+    #[test]
+    fn seek()
+    {
+        let options = options(CompressionType::SnappyCompression);
+        let file = build_table(&options);
+        let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+        let mut iter = table.iter(&ReadOptions::new());
+
+        for i in 0..NUM_KEYS {
+            iter.seek(&key(i));
+            assert!(iter.is_valid());
+            assert_eq!(iter.key(), &key(i)[..]);
+
+            // Keys are even, so an odd key seeks to the next one
+            let between = format!("key{:06}", i * 2 + 1).into_bytes();
+            iter.seek(&between);
+            if i + 1 < NUM_KEYS {
+                assert!(iter.is_valid());
+                assert_eq!(iter.key(), &key(i + 1)[..]);
+                iter.prev();
+                assert_eq!(iter.key(), &key(i)[..]);
+            } else {
+                assert!(!iter.is_valid());
+            }
+        }
+
+        iter.seek(b"");
+        assert_eq!(iter.key(), &key(0)[..]);
+        iter.seek(b"z");
+        assert!(!iter.is_valid());
+    }
+
+    #[test]
+    fn get()
+    {
+        let options = options(CompressionType::NoCompression);
+        let file = build_table(&options);
+        let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+        let read_options = ReadOptions::new();
+
+        for i in 0..NUM_KEYS {
+            assert_eq!(table.get(&read_options, &key(i)).unwrap(), value(i));
+            let absent = format!("key{:06}", i * 2 + 1).into_bytes();
+            assert!(table.get(&read_options, &absent).is_err());
+        }
+        assert!(table.get(&read_options, b"").is_err());
+        assert!(table.get(&read_options, b"z").is_err());
+
+        // internal_get hands over the entry the seek landed on, which for
+        // an absent key is the next key in the table.
+        let mut found = vec![];
+        table.internal_get(&read_options, b"key000001", |k, v| found.push((k.to_vec(), v.to_vec())))
+            .unwrap();
+        assert_eq!(found, vec![(key(1), value(1))]);
+
+        let mut calls = 0;
+        table.internal_get(&read_options, b"z", |_, _| calls += 1).unwrap();
+        assert_eq!(calls, 0);
+    }
+
+    #[test]
+    fn get_with_filter()
+    {
+        let mut options = options(CompressionType::NoCompression);
+        options.filter_policy = Some(Arc::new(BloomFilterPolicy::new(10)));
+        let file = build_table(&options);
+        let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+        assert!(table.rep.filter.is_some());
+        let read_options = ReadOptions::new();
+
+        for i in 0..NUM_KEYS {
+            assert_eq!(table.get(&read_options, &key(i)).unwrap(), value(i));
+            let absent = format!("key{:06}", i * 2 + 1).into_bytes();
+            assert!(table.get(&read_options, &absent).is_err());
+        }
+    }
+
+    #[test]
+    fn checksum_mismatch()
+    {
+        let mut paranoid = options(CompressionType::NoCompression);
+        paranoid.paranoid_checks = true;
+        let options = options(CompressionType::NoCompression);
+        let mut file = build_table(&options);
+        // The first data block starts at offset 0, so this corrupts
+        // its first entry.
+        file[3] ^= 0x80;
+
+        let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+        let mut read_options = ReadOptions::new();
+        read_options.verify_checksums = true;
+        let status: Status = table.get(&read_options, &key(0)).unwrap_err().into();
+        assert_eq!(status, Status::Corruption("block checksum mismatch".into()));
+
+        // Blocks far from the corruption still read fine
+        assert_eq!(table.get(&read_options, &key(NUM_KEYS - 1)).unwrap(), value(NUM_KEYS - 1));
+
+        // paranoid_checks verifies the index block as the table is
+        // opened.
+        let mut file = build_table(&options);
+        let mut footer = Footer::new();
+        footer.decode_from(&file[file.len() - ENCODED_LENGTH..]).unwrap();
+        let index_offset = footer.index_handle().offset() as usize;
+        file[index_offset + 1] ^= 0x80;
+        let status: Status = Table::open(&paranoid, Cursor::new(file.clone()), file.len())
+            .err().unwrap().into();
+        assert_eq!(status, Status::Corruption("block checksum mismatch".into()));
+    }
+
+    /// A file that counts the reads made from it
+    struct CountingFile {
+        file: Cursor<Vec<u8>>,
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl Read for CountingFile {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+        {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            self.file.read(buf)
+        }
+    }
+
+    impl Seek for CountingFile {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64>
+        {
+            self.file.seek(pos)
+        }
+    }
+
+    impl RandomAccessFile for CountingFile {}
+
+    /// Write "contents" to a new file named after "name" in the
+    /// temporary directory, and return its path.
+    fn temp_file(name: &str, contents: &[u8]) -> ::std::path::PathBuf
+    {
+        let path = env::temp_dir().join(format!("rubbledb-{}-{}.sst", name, process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn block_cache()
+    {
+        let mut options = options(CompressionType::NoCompression);
+        let cache = Arc::new(Cache::new(1 << 20));
+        options.block_cache = Some(cache.clone());
+        let file = build_table(&options);
+        let reads = Arc::new(AtomicUsize::new(0));
+        let counting = CountingFile { file: Cursor::new(file.clone()), reads: reads.clone() };
+        let table = Table::open(&options, counting, file.len()).unwrap();
+
+        // Reads that don't fill the cache go to the file every time
+        let mut no_fill = ReadOptions::new();
+        no_fill.fill_cache = false;
+        let before = reads.load(Ordering::SeqCst);
+        assert_eq!(table.get(&no_fill, &key(0)).unwrap(), value(0));
+        let after_first = reads.load(Ordering::SeqCst);
+        assert!(after_first > before);
+        assert_eq!(cache.total_charge(), 0);
+        assert_eq!(table.get(&no_fill, &key(0)).unwrap(), value(0));
+        assert!(reads.load(Ordering::SeqCst) > after_first);
+
+        // The first normal read caches the block, later reads are
+        // served from the cache.
+        let read_options = ReadOptions::new();
+        assert_eq!(table.get(&read_options, &key(0)).unwrap(), value(0));
+        let charge = cache.total_charge();
+        assert!(charge > 0);
+        let cached_reads = reads.load(Ordering::SeqCst);
+        assert_eq!(table.get(&read_options, &key(0)).unwrap(), value(0));
+        assert_eq!(table.get(&read_options, &key(1)).unwrap(), value(1));
+        assert_eq!(reads.load(Ordering::SeqCst), cached_reads);
+        assert_eq!(cache.total_charge(), charge);
+
+        // Tables sharing a cache don't see each other's blocks
+        let other = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+        assert_eq!(other.get(&read_options, &key(0)).unwrap(), value(0));
+        assert_eq!(cache.total_charge(), 2 * charge);
+    }
+
+    /// Uncompressed blocks are served out of the mapped file and are
+    /// not added to the block cache.  Compressed blocks still are.
+    #[test]
+    fn mmap_reads()
+    {
+        for &compression in &[CompressionType::NoCompression, CompressionType::SnappyCompression] {
+            let mut options = options(compression);
+            let cache = Arc::new(Cache::new(1 << 20));
+            options.block_cache = Some(cache.clone());
+            options.allow_mmap_reads = true;
+            let file = build_table(&options);
+            let path = temp_file(&format!("mmap-{}", compression as u8), &file);
+            let table = Table::open(&options, File::open(&path).unwrap(), file.len()).unwrap();
+            assert!(table.rep.mapping.is_some());
+
+            let read_options = ReadOptions::new();
+            let mut iter = table.iter(&read_options);
+            iter.seek_to_first();
+            for i in 0..NUM_KEYS {
+                assert_eq!(iter.key(), &key(i)[..]);
+                assert_eq!(iter.value(), &value(i)[..]);
+                assert_eq!(table.get(&read_options, &key(i)).unwrap(), value(i));
+                iter.step();
+            }
+            assert!(!iter.is_valid());
+            assert!(iter.status().is_ok());
+
+            match compression {
+                CompressionType::NoCompression => assert_eq!(cache.total_charge(), 0),
+                _ => assert!(cache.total_charge() > 0),
+            }
+            fs::remove_file(&path).unwrap();
+        }
+
+        // Files that cannot be mapped are read as usual
+        let mut options = options(CompressionType::NoCompression);
+        options.allow_mmap_reads = true;
+        let file = build_table(&options);
+        let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+        assert!(table.rep.mapping.is_none());
+        assert_eq!(table.get(&ReadOptions::new(), &key(7)).unwrap(), value(7));
+    }
+
+    /// Tables built for a level compress their blocks with that
+    /// level's codec, and read back through it.
+    #[test]
+    fn compression_per_level()
+    {
+        let mut options = options(CompressionType::NoCompression);
+        options.block_size = 1 << 20;
+        options.compression_per_level = vec![CompressionType::NoCompression,
+                                             CompressionType::SnappyCompression,
+                                             CompressionType::Lz4Compression];
+        options.bottommost_compression = Some(CompressionType::ZstdCompression);
+
+        let cases = [(0, false, CompressionType::NoCompression),
+                     (1, false, CompressionType::SnappyCompression),
+                     (2, false, CompressionType::Lz4Compression),
+                     (5, false, CompressionType::Lz4Compression),
+                     (5, true, CompressionType::ZstdCompression)];
+        for &(level, bottommost, compression) in &cases {
+            let mut file = vec![];
+            {
+                let mut builder = TableBuilder::new_for_level(&options, &mut file, level, bottommost);
+                for i in 0..100 {
+                    builder.add(&key(i), &value(i)).unwrap();
+                }
+                builder.finish().unwrap();
+            }
+
+            // The table holds a single data block at the start of the
+            // file.
+            let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+            let properties = table.properties().unwrap();
+            assert_eq!(properties.num_data_blocks, 1);
+            assert_eq!(properties.compression_name, compression.name());
+            let data_end = properties.data_size as usize - BLOCK_TRAILER_SIZE;
+            assert_eq!(file[data_end], compression as u8, "level {}", level);
+
+            let read_options = ReadOptions::new();
+            for i in 0..100 {
+                assert_eq!(table.get(&read_options, &key(i)).unwrap(), value(i));
+            }
+        }
+    }
+
+    #[test]
+    fn properties()
+    {
+        let mut filtered = options(CompressionType::SnappyCompression);
+        filtered.filter_policy = Some(Arc::new(BloomFilterPolicy::new(10)));
+        let file = build_table(&filtered);
+        let table = Table::open(&filtered, Cursor::new(file.clone()), file.len()).unwrap();
+        let properties = table.properties().unwrap();
+
+        assert_eq!(properties.num_entries, NUM_KEYS as u64);
+        assert_eq!(properties.raw_key_size, (0..NUM_KEYS).map(|i| key(i).len() as u64).sum::<u64>());
+        assert_eq!(properties.raw_value_size,
+                   (0..NUM_KEYS).map(|i| value(i).len() as u64).sum::<u64>());
+        assert_eq!(properties.num_deletions, 0);
+        assert_eq!(properties.compression_name, "Snappy");
+        assert_eq!(properties.comparator_name, "leveldb.BytewiseComparator");
+        assert_eq!(properties.filter_policy_name, "leveldb.BuiltinBloomFilter2");
+        assert!(properties.num_data_blocks > 10);
+        assert!(properties.filter_size > 0);
+        assert!(properties.creation_time > 0);
+
+        // The data blocks come first in the file, followed by the
+        // filter block and then the index block.
+        let mut footer = Footer::new();
+        footer.decode_from(&file[file.len() - ENCODED_LENGTH..]).unwrap();
+        let index = footer.index_handle();
+        assert_eq!(properties.index_size, index.size() + BLOCK_TRAILER_SIZE as u64);
+        assert_eq!(properties.data_size + properties.filter_size + BLOCK_TRAILER_SIZE as u64,
+                   index.offset());
+
+        // Without a filter policy no filter is recorded
+        let options = options(CompressionType::NoCompression);
+        let file = build_table(&options);
+        let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+        let properties = table.properties().unwrap();
+        assert_eq!(properties.compression_name, "NoCompression");
+        assert_eq!(properties.filter_policy_name, "");
+        assert_eq!(properties.filter_size, 0);
+    }
+
+    /// Counts the entries added to a table and the bytes of their
+    /// values, and also tries to overwrite a builtin property.
+    struct CountingCollector {
+        entries: u64,
+        value_bytes: u64,
+    }
+
+    impl TablePropertiesCollector for CountingCollector {
+        fn add(&mut self, _key: &[u8], value: &[u8]) -> ::errors::RubbleResult<()>
+        {
+            self.entries += 1;
+            self.value_bytes += value.len() as u64;
+            Ok(())
+        }
+
+        fn finish(&mut self, properties: &mut UserCollectedProperties) -> ::errors::RubbleResult<()>
+        {
+            let mut entries = vec![];
+            coding::put_varint64(&mut entries, self.entries);
+            properties.insert("test.entries".to_string(), entries);
+            let mut value_bytes = vec![];
+            coding::put_varint64(&mut value_bytes, self.value_bytes);
+            properties.insert("test.value.bytes".to_string(), value_bytes);
+            properties.insert(table_properties::NUM_ENTRIES.to_string(), vec![0]);
+            Ok(())
+        }
+
+        fn name(&self) -> &str { "CountingCollector" }
+    }
+
+    struct CountingCollectorFactory;
+
+    impl TablePropertiesCollectorFactory for CountingCollectorFactory {
+        fn create_table_properties_collector(&self) -> Box<dyn TablePropertiesCollector>
+        {
+            Box::new(CountingCollector { entries: 0, value_bytes: 0 })
+        }
+
+        fn name(&self) -> &str { "CountingCollectorFactory" }
+    }
+
+    #[test]
+    fn user_collected_properties()
+    {
+        let mut collecting = options(CompressionType::NoCompression);
+        collecting.table_properties_collector_factories.push(Arc::new(CountingCollectorFactory));
+        let file = build_table(&collecting);
+        let table = Table::open(&collecting, Cursor::new(file.clone()), file.len()).unwrap();
+        let properties = table.properties().unwrap();
+
+        let collected = &properties.user_collected_properties;
+        assert_eq!(collected.len(), 2);
+        assert_eq!(coding::get_varint64(&collected["test.entries"]).unwrap().1,
+                   NUM_KEYS as u64);
+        assert_eq!(coding::get_varint64(&collected["test.value.bytes"]).unwrap().1,
+                   properties.raw_value_size);
+
+        // The builtin property wins over the collector's
+        assert_eq!(properties.num_entries, NUM_KEYS as u64);
+        assert!(!collected.contains_key(table_properties::NUM_ENTRIES));
+
+        // Tables built without collectors have no user properties
+        let plain = options(CompressionType::NoCompression);
+        let file = build_table(&plain);
+        let table = Table::open(&plain, Cursor::new(file.clone()), file.len()).unwrap();
+        assert!(table.properties().unwrap().user_collected_properties.is_empty());
+    }
+
+    #[test]
+    fn approximate_offset_of_plain()
+    {
+        let mut options = options(CompressionType::NoCompression);
+        options.block_size = 1024;
+        let entries: Vec<(&[u8], Vec<u8>)> = vec![
+            (b"k01", b"hello".to_vec()),
+            (b"k02", b"hello2".to_vec()),
+            (b"k03", vec![b'x'; 10000]),
+            (b"k04", vec![b'x'; 200000]),
+            (b"k05", vec![b'x'; 300000]),
+            (b"k06", b"hello3".to_vec()),
+            (b"k07", vec![b'x'; 100000]),
+        ];
+        let mut file = vec![];
+        {
+            let mut builder = TableBuilder::new(&options, &mut file);
+            for (k, v) in &entries {
+                builder.add(k, v).unwrap();
+            }
+            builder.finish().unwrap();
+        }
+        let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+
+        let between = |key: &[u8], low: u64, high: u64| {
+            let offset = table.approximate_offset_of(key);
+            assert!(low <= offset && offset <= high,
+                    "offset of {:?} is {}, expected [{}, {}]", key, offset, low, high);
+        };
+        between(b"abc", 0, 0);
+        between(b"k01", 0, 0);
+        between(b"k01a", 0, 0);
+        between(b"k02", 0, 0);
+        between(b"k03", 0, 0);
+        between(b"k04", 10000, 11000);
+        between(b"k04a", 210000, 211000);
+        between(b"k05", 210000, 211000);
+        between(b"k06", 510000, 511000);
+        between(b"k07", 510000, 511000);
+        // Keys past the last one land on the metaindex block
+        between(b"xyz", 610000, 612000);
+        assert_eq!(table.approximate_offset_of(b"xyz"), table.rep.metaindex_handle.offset());
+
+        assert_eq!(table.approximate_size(b"k04", b"k05"),
+                   table.approximate_offset_of(b"k05") - table.approximate_offset_of(b"k04"));
+        assert_eq!(table.approximate_size(b"k05", b"k04"), 0);
+    }
+
+    #[test]
+    fn approximate_offsets_increase()
+    {
+        let options = options(CompressionType::NoCompression);
+        let file = build_table(&options);
+        let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+
+        let mut last = 0;
+        let mut increases = 0;
+        for i in 0..NUM_KEYS {
+            let offset = table.approximate_offset_of(&key(i));
+            assert!(offset >= last);
+            if offset > last {
+                increases += 1;
+            }
+            last = offset;
+        }
+        assert!(increases > 10);
+        assert!(table.approximate_offset_of(b"z") > last);
+        assert!((table.approximate_offset_of(b"z") as usize) < file.len());
+    }
+
+    #[test]
+    fn legacy_footer()
+    {
+        let file = build_table(&options(CompressionType::NoCompression));
+        let mut footer = Footer::new();
+        footer.decode_from(&file[file.len() - VERSIONED_ENCODED_LENGTH..]).unwrap();
+        assert_eq!(footer.format_version(), format::LEGACY_FORMAT_VERSION);
+
+        let options = partitioned_options(true);
+        let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+        check_contents(&table);
+    }
+
+    #[test]
+    fn partitioned_index()
+    {
+        let options = partitioned_options(false);
+        let file = build_table(&options);
+        let mut footer = Footer::new();
+        footer.decode_from(&file[file.len() - VERSIONED_ENCODED_LENGTH..]).unwrap();
+        assert_eq!(footer.format_version(), format::PARTITIONED_INDEX_FORMAT_VERSION);
+
+        let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+        assert!(table.properties().unwrap().index_partitions > 3);
+        match table.rep.filter {
+            Some(TableFilter::Block(_)) => {},
+            _ => panic!("expected a filter block"),
+        }
+        check_contents(&table);
+
+        let mut offset = 0;
+        for i in (0..NUM_KEYS).step_by(100) {
+            let next = table.approximate_offset_of(&key(i));
+            assert!(next >= offset);
+            offset = next;
+        }
+        assert!(offset > 0);
+
+        // Partitioned indexes are read whatever the options ask for
+        let options = self::options(CompressionType::NoCompression);
+        let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+        check_contents(&table);
+    }
+
+    #[test]
+    fn partitioned_filters()
+    {
+        let options = partitioned_options(true);
+        let file = build_table(&options);
+        let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+        assert!(table.properties().unwrap().filter_size > 0);
+        match table.rep.filter {
+            Some(TableFilter::Partitioned(..)) => {},
+            _ => panic!("expected a partitioned filter"),
+        }
+        check_contents(&table);
+    }
+
+    #[test]
+    fn partitioned_filter_reads_are_cached()
+    {
+        let mut options = partitioned_options(true);
+        options.block_cache = Some(Arc::new(Cache::new(1 << 20)));
+        let file = build_table(&options);
+        let reads = Arc::new(AtomicUsize::new(0));
+        let counting_file = CountingFile {
+            file: Cursor::new(file.clone()),
+            reads: reads.clone(),
+        };
+        let table = Table::open(&options, counting_file, file.len()).unwrap();
+        let read_options = ReadOptions::new();
+
+        for i in 0..NUM_KEYS {
+            let absent = format!("key{:06}", i * 2 + 1).into_bytes();
+            assert!(table.get(&read_options, &absent).is_err());
+            let after_first_get = reads.load(Ordering::SeqCst);
+            assert!(table.get(&read_options, &absent).is_err());
+            assert_eq!(reads.load(Ordering::SeqCst), after_first_get);
+        }
+    }
+
+    #[test]
+    fn partitioned_mmap()
+    {
+        for &compression in &[CompressionType::NoCompression, CompressionType::SnappyCompression] {
+            let mut options = partitioned_options(true);
+            options.compression = compression;
+            options.allow_mmap_reads = true;
+            let file = build_table(&options);
+            let path = temp_file(&format!("partitioned-mmap-{}", compression as u8), &file);
+            let table = Table::open(&options, File::open(&path).unwrap(), file.len()).unwrap();
+            assert!(table.rep.mapping.is_some());
+            check_contents(&table);
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn data_block_hash_index()
+    {
+        for &compression in &[CompressionType::NoCompression, CompressionType::SnappyCompression] {
+            let mut hashed = options(compression);
+            hashed.data_block_index_type = DataBlockIndexType::BinaryAndHash;
+            let file = build_table(&hashed);
+            let table = Table::open(&hashed, Cursor::new(file.clone()), file.len()).unwrap();
+            check_contents(&table);
+
+            // Readers need no option to read the hash index
+            let plain = options(compression);
+            let table = Table::open(&plain, Cursor::new(file.clone()), file.len()).unwrap();
+            check_contents(&table);
+        }
+    }
+
+    /// Tables of internal keys get no hash index, since it is keyed by
+    /// whole keys and lookups carry their own sequence numbers.  Those
+    /// lookups still find their entries by binary search.
+    #[test]
+    fn data_block_hash_index_skipped_for_internal_keys()
+    {
+        let build = |data_block_index_type| {
+            let mut options = options(CompressionType::NoCompression);
+            options.comparator = Arc::new(InternalKeyComparator::new(Arc::new(BytewiseComparator)));
+            options.data_block_index_type = data_block_index_type;
+            let mut file = vec![];
+            {
+                let mut builder = TableBuilder::new(&options, &mut file);
+                for i in 0..NUM_KEYS {
+                    let mut internal_key = vec![];
+                    dbformat::append_internal_key(&mut internal_key, &key(i), i as u64,
+                                                  ValueType::Value);
+                    builder.add(&internal_key, &value(i)).unwrap();
+                }
+                builder.finish().unwrap();
+            }
+            (options, file)
+        };
+        let (options, file) = build(DataBlockIndexType::BinaryAndHash);
+        assert_eq!(file, build(DataBlockIndexType::BinarySearch).1);
+
+        let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+        let read_options = ReadOptions::new();
+        for i in 0..NUM_KEYS {
+            let lookup = LookupKey::new(&key(i), dbformat::MAX_SEQUENCE_NUMBER);
+            let mut found = None;
+            table.internal_get(&read_options, lookup.internal_key(), |found_key, found_value| {
+                found = Some((dbformat::extract_user_key(found_key).to_vec(), found_value.to_vec()));
+            }).unwrap();
+            assert_eq!(found, Some((key(i), value(i))));
+        }
+    }
+
+    /// Options for tables of internal keys filtered by an
+    /// InternalFilterPolicy, with a partitioned filter if "partitioned".
+    fn internal_filter_options(partitioned: bool) -> Options
+    {
+        let mut options = options(CompressionType::NoCompression);
+        options.comparator = Arc::new(InternalKeyComparator::new(Arc::new(BytewiseComparator)));
+        let bloom = Arc::new(BloomFilterPolicy::new(10));
+        options.filter_policy = Some(Arc::new(InternalFilterPolicy::new(bloom)));
+        if partitioned {
+            options.index_type = IndexType::TwoLevelIndexSearch;
+            options.metadata_block_size = 128;
+            options.partition_filters = true;
+        }
+        options
+    }
+
+    /// Build a table holding each of "user_keys" at the sequence number
+    /// of its position, with value(position).
+    fn build_internal_table(options: &Options, user_keys: &[Vec<u8>]) -> Vec<u8>
+    {
+        let mut file = vec![];
+        {
+            let mut builder = TableBuilder::new(options, &mut file);
+            for (i, user_key) in user_keys.iter().enumerate() {
+                let mut internal_key = vec![];
+                dbformat::append_internal_key(&mut internal_key, user_key, i as u64,
+                                              ValueType::Value);
+                builder.add(&internal_key, &value(i)).unwrap();
+            }
+            builder.finish().unwrap();
+        }
+        file
+    }
+
+    /// Look up "user_key" at the latest sequence number, and return its
+    /// value if the table holds it.
+    fn internal_lookup<F: RandomAccessFile>(table: &Table<F>, user_key: &[u8]) -> Option<Vec<u8>>
+    {
+        let lookup = LookupKey::new(user_key, dbformat::MAX_SEQUENCE_NUMBER);
+        let mut found = None;
+        table.internal_get(&ReadOptions::new(), lookup.internal_key(), |found_key, found_value| {
+            if dbformat::extract_user_key(found_key) == user_key {
+                found = Some(found_value.to_vec());
+            }
+        }).unwrap();
+        found
+    }
+
+    /// The filter holds user keys, so lookups at another sequence number
+    /// than the entry's pass it, while absent keys rarely do.
+    #[test]
+    fn internal_filter_policy()
+    {
+        let user_keys: Vec<Vec<u8>> = (0..NUM_KEYS).map(key).collect();
+        for &partitioned in &[false, true] {
+            let options = internal_filter_options(partitioned);
+            let file = build_internal_table(&options, &user_keys);
+            let reads = Arc::new(AtomicUsize::new(0));
+            let counting = CountingFile { file: Cursor::new(file.clone()), reads: reads.clone() };
+            let table = Table::open(&options, counting, file.len()).unwrap();
+            for (i, user_key) in user_keys.iter().enumerate() {
+                assert_eq!(internal_lookup(&table, user_key), Some(value(i)));
+            }
+
+            let before = reads.load(Ordering::SeqCst);
+            for i in 0..NUM_KEYS {
+                let absent = format!("key{:06}", i * 2 + 1).into_bytes();
+                assert_eq!(internal_lookup(&table, &absent), None);
+            }
+
+            // Each lookup that passes the filter reads a data block; the
+            // filter partitions are read too when partitioned.
+            if !partitioned {
+                let passed = reads.load(Ordering::SeqCst) - before;
+                assert!(passed < NUM_KEYS / 20, "{} of {} absent keys passed", passed, NUM_KEYS);
+            }
+        }
+    }
+
+    /// Prefixes of a table of internal keys are extracted from the user
+    /// keys, whether they are shorter or longer than the tag.
+    #[test]
+    fn prefix_filter_on_internal_keys()
+    {
+        // 20 keys for each even tenant
+        let tenant_key = |tenant: usize, i: usize| format!("t{:04}-group/{:04}", tenant, i).into_bytes();
+        let user_keys: Vec<Vec<u8>> = (0..40).step_by(2)
+            .flat_map(|tenant| (0..20).map(move |i| tenant_key(tenant, i)))
+            .collect();
+
+        for &prefix_len in &[5, 10] {
+            for &whole_key_filtering in &[true, false] {
+                for &partitioned in &[false, true] {
+                    let mut options = internal_filter_options(partitioned);
+                    options.prefix_extractor = Some(Arc::new(FixedPrefixTransform::new(prefix_len)));
+                    options.whole_key_filtering = whole_key_filtering;
+                    let file = build_internal_table(&options, &user_keys);
+                    let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+
+                    for (i, user_key) in user_keys.iter().enumerate() {
+                        assert_eq!(internal_lookup(&table, user_key), Some(value(i)));
+                    }
+
+                    let read_options = ReadOptions::new();
+                    let mut filtered = 0;
+                    for tenant in 0..40 {
+                        let target = LookupKey::new(&tenant_key(tenant, 0),
+                                                    dbformat::MAX_SEQUENCE_NUMBER);
+                        let may_match = table.prefix_may_match(&read_options, target.internal_key());
+                        if tenant % 2 == 0 {
+                            assert!(may_match, "tenant {} with prefix length {}", tenant, prefix_len);
+                        } else if !may_match {
+                            filtered += 1;
+                        }
+                    }
+                    assert!(filtered > 15, "{} of 20 absent tenants filtered ({} {} {})", filtered, prefix_len, whole_key_filtering, partitioned);
+                }
+            }
+        }
+    }
+
+    /// Keys of the prefixed tables: 20 keys for each prefix "p000",
+    /// "p002", ..., "p098".  The prefixes with odd numbers are absent.
+    fn prefixed_key(prefix: usize, i: usize) -> Vec<u8>
+    {
+        format!("p{:03}{:04}", prefix, i).into_bytes()
+    }
+
+    fn prefixed_options(whole_key_filtering: bool) -> Options
+    {
+        let mut options = options(CompressionType::NoCompression);
+        options.filter_policy = Some(Arc::new(BloomFilterPolicy::new(10)));
+        options.prefix_extractor = Some(Arc::new(FixedPrefixTransform::new(4)));
+        options.whole_key_filtering = whole_key_filtering;
+        options
+    }
+
+    fn build_prefixed_table(options: &Options) -> Vec<u8>
+    {
+        let mut file = vec![];
+        {
+            let mut builder = TableBuilder::new(options, &mut file);
+            for prefix in (0..100).step_by(2) {
+                for i in 0..20 {
+                    builder.add(&prefixed_key(prefix, i), &value(i)).unwrap();
+                }
+            }
+            builder.finish().unwrap();
+        }
+        file
+    }
+
+    #[test]
+    fn prefix_seek_skips_absent_prefixes()
+    {
+        let options = prefixed_options(true);
+        let file = build_prefixed_table(&options);
+        let reads = Arc::new(AtomicUsize::new(0));
+        let counting = CountingFile { file: Cursor::new(file.clone()), reads: reads.clone() };
+        let table = Table::open(&options, counting, file.len()).unwrap();
+        assert_eq!(table.properties().unwrap().prefix_extractor_name, "rocksdb.FixedPrefix.4");
+
+        let read_options = ReadOptions::new();
+        let mut iter = table.iter(&read_options);
+        let mut skipped = 0;
+        for prefix in (1..100).step_by(2) {
+            let before = reads.load(Ordering::SeqCst);
+            iter.seek(&prefixed_key(prefix, 0));
+            if !iter.is_valid() {
+                // Ruled out by the filter without reading a data block
+                assert_eq!(reads.load(Ordering::SeqCst), before);
+                skipped += 1;
+            }
+            assert!(iter.status().is_ok());
+        }
+        assert!(skipped > 40, "skipped {}", skipped);
+
+        // Present prefixes are found
+        for prefix in (0..100).step_by(2) {
+            iter.seek(&prefixed_key(prefix, 0));
+            assert_eq!(iter.key(), &prefixed_key(prefix, 0)[..]);
+            iter.seek(&prefixed_key(prefix, 5));
+            assert_eq!(iter.key(), &prefixed_key(prefix, 5)[..]);
+        }
+    }
+
+    #[test]
+    fn total_order_seek_ignores_prefixes()
+    {
+        let options = prefixed_options(true);
+        let file = build_prefixed_table(&options);
+        let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+
+        let mut total_order = ReadOptions::new();
+        total_order.total_order_seek = true;
+        let mut iter = table.iter(&total_order);
+        for prefix in (1..99).step_by(2) {
+            iter.seek(&prefixed_key(prefix, 0));
+            assert!(iter.is_valid());
+            assert_eq!(iter.key(), &prefixed_key(prefix + 1, 0)[..]);
+        }
+        iter.seek(&prefixed_key(99, 0));
+        assert!(!iter.is_valid());
+
+        // The filter is not consulted for tables read without the
+        // extractor they were built with
+        let plain = self::options(CompressionType::NoCompression);
+        let table = Table::open(&plain, Cursor::new(file.clone()), file.len()).unwrap();
+        let mut iter = table.iter(&ReadOptions::new());
+        iter.seek(&prefixed_key(1, 0));
+        assert_eq!(iter.key(), &prefixed_key(2, 0)[..]);
+    }
+
+    #[test]
+    fn get_without_whole_key_filtering()
+    {
+        for &partition_filters in &[false, true] {
+            let mut options = prefixed_options(false);
+            if partition_filters {
+                options.index_type = IndexType::TwoLevelIndexSearch;
+                options.metadata_block_size = 128;
+                options.partition_filters = true;
+            }
+            let file = build_prefixed_table(&options);
+            let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+            assert!(!table.properties().unwrap().whole_key_filtering);
+
+            let read_options = ReadOptions::new();
+            for prefix in (0..100).step_by(2) {
+                for i in 0..20 {
+                    assert_eq!(table.get(&read_options, &prefixed_key(prefix, i)).unwrap(),
+                               value(i));
+                }
+                // Absent keys with present prefixes are not filtered,
+                // but still not found
+                assert!(table.get(&read_options, &prefixed_key(prefix, 20)).is_err());
+                assert!(table.get(&read_options, &prefixed_key(prefix + 1, 0)).is_err());
+            }
+            // Keys too short to have a prefix
+            assert!(table.get(&read_options, b"p0").is_err());
+        }
+    }
+
+    /// The keys of the metaindex block of "table"
+    fn meta_keys<F: RandomAccessFile>(table: &Table<F>) -> Vec<Vec<u8>>
+    {
+        let meta = table.read_block(&ReadOptions::new(), &table.rep.metaindex_handle)
+            .and_then(OwnedBlock::new).unwrap();
+        let mut keys = vec![];
+        let mut iter = meta.iter(BytewiseComparator);
+        iter.seek_to_first();
+        while iter.is_valid() {
+            keys.push(iter.key().to_vec());
+            iter.step();
+        }
+        keys
+    }
+
+    /// Filters without whole keys are kept out of LevelDB's sight, which
+    /// would look up whole keys in them.
+    #[test]
+    fn prefix_only_filter_key()
+    {
+        for &whole_key_filtering in &[true, false] {
+            let options = prefixed_options(whole_key_filtering);
+            let file = build_prefixed_table(&options);
+            let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+            assert!(table.rep.filter.is_some());
+
+            let policy = options.filter_policy.as_ref().unwrap();
+            let filter_key = match whole_key_filtering {
+                true => format!("filter.{}", policy.name()),
+                false => format!("prefixfilter.{}", policy.name()),
+            };
+            assert_eq!(meta_keys(&table), vec![filter_key.into_bytes(),
+                                               PROPERTIES_BLOCK.as_bytes().to_vec()]);
+        }
+    }
+
+    #[test]
+    fn index_keys_are_shortened()
+    {
+        // Long keys that differ early: separators can be much shorter
+        let long_key = |i: usize| format!("{:04}{}", i * 2, "x".repeat(100)).into_bytes();
+        let options = options(CompressionType::NoCompression);
+        let mut file = vec![];
+        {
+            let mut builder = TableBuilder::new(&options, &mut file);
+            for i in 0..NUM_KEYS {
+                builder.add(&long_key(i), &value(i)).unwrap();
+            }
+            builder.finish().unwrap();
+        }
+        let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+
+        let mut index_iter = table.index_iter(&ReadOptions::new());
+        index_iter.seek_to_first();
+        let mut blocks = 0;
+        let mut shortened = 0;
+        let mut last_index_key = vec![];
+        while index_iter.is_valid() {
+            // Keys like "0008x..." and "0010x..." first differ in
+            // adjacent bytes, so their separator stays long
+            if index_iter.key().len() <= 4 {
+                shortened += 1;
+            }
+            last_index_key = index_iter.key().to_vec();
+            blocks += 1;
+            index_iter.step();
+        }
+        assert!(blocks > 10);
+        assert!(shortened * 2 > blocks, "{} of {}", shortened, blocks);
+        // The key of the last block is a short successor
+        assert_eq!(last_index_key, b"2");
+
+        let read_options = ReadOptions::new();
+        let mut iter = table.iter(&read_options);
+        for i in 0..NUM_KEYS {
+            iter.seek(&long_key(i));
+            assert_eq!(iter.key(), &long_key(i)[..]);
+            // Seeks between the keys of adjacent blocks land on the
+            // first key of the next block
+            let between = format!("{:04}", i * 2 + 1).into_bytes();
+            iter.seek(&between);
+            if i + 1 < NUM_KEYS {
+                assert_eq!(iter.key(), &long_key(i + 1)[..]);
+            } else {
+                assert!(!iter.is_valid());
+            }
+            assert_eq!(table.get(&read_options, &long_key(i)).unwrap(), value(i));
+        }
+        iter.seek(b"9999");
+        assert!(!iter.is_valid());
+    }
+
+    #[test]
+    fn comparator_mismatch()
+    {
+        let mut options = options(CompressionType::NoCompression);
+        let file = build_table(&options);
+        options.comparator = Arc::new(ReverseBytewiseComparator);
+        assert!(Table::open(&options, Cursor::new(file.clone()), file.len()).is_err());
+    }
+
+    /// Tables of internal keys record their user comparator, so opening
+    /// one with another user comparator fails.
+    #[test]
+    fn internal_key_comparator_mismatch()
+    {
+        let internal_options = |user_comparator: Arc<dyn SliceComparator>| {
+            let mut options = options(CompressionType::NoCompression);
+            options.comparator = Arc::new(InternalKeyComparator::new(user_comparator));
+            options
+        };
+        let options = internal_options(Arc::new(BytewiseComparator));
+        let mut file = vec![];
+        {
+            let mut builder = TableBuilder::new(&options, &mut file);
+            for i in 0..NUM_KEYS {
+                let mut internal_key = vec![];
+                dbformat::append_internal_key(&mut internal_key, &key(i), i as u64,
+                                              ValueType::Value);
+                builder.add(&internal_key, &value(i)).unwrap();
+            }
+            builder.finish().unwrap();
+        }
+
+        let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+        let properties = table.properties().unwrap();
+        assert_eq!(properties.comparator_name,
+                   "leveldb.InternalKeyComparator:leveldb.BytewiseComparator");
+        assert_eq!(properties.largest_seqno, NUM_KEYS as u64 - 1);
+
+        let options = internal_options(Arc::new(ReverseBytewiseComparator));
+        assert!(Table::open(&options, Cursor::new(file.clone()), file.len()).is_err());
+    }
+
+    #[test]
+    fn reverse_bytewise_table()
+    {
+        let mut options = self::options(CompressionType::SnappyCompression);
+        options.comparator = Arc::new(ReverseBytewiseComparator);
+        let mut file = vec![];
+        {
+            let mut builder = TableBuilder::new(&options, &mut file);
+            for i in (0..NUM_KEYS).rev() {
+                builder.add(&key(i), &value(i)).unwrap();
+            }
+            builder.finish().unwrap();
+        }
+        let table = Table::open(&options, Cursor::new(file.clone()), file.len()).unwrap();
+        assert_eq!(table.properties().unwrap().comparator_name,
+                   "rocksdb.ReverseBytewiseComparator");
+
+        let read_options = ReadOptions::new();
+        let mut iter = table.iter(&read_options);
+        iter.seek_to_first();
+        for i in (0..NUM_KEYS).rev() {
+            assert_eq!(iter.key(), &key(i)[..]);
+            iter.step();
+        }
+        assert!(!iter.is_valid());
+        for i in 0..NUM_KEYS {
+            // Seeks land on the next smaller key
+            let between = format!("key{:06}", i * 2 + 1).into_bytes();
+            iter.seek(&between);
+            assert_eq!(iter.key(), &key(i)[..]);
+            assert_eq!(table.get(&read_options, &key(i)).unwrap(), value(i));
+            assert!(table.get(&read_options, &between).is_err());
+        }
+
+        let bytewise = self::options(CompressionType::SnappyCompression);
+        assert!(Table::open(&bytewise, Cursor::new(file.clone()), file.len()).is_err());
+    }
+}
@@ -0,0 +1,547 @@
+//! TableBuilder provides the interface used to build a Table (an
+//! immutable and sorted map from keys to values).
+//!
+//! The file produced has the form:
+//!     <beginning_of_file>
+//!     [data block 1]
+//!     [data block 2]
+//!     ...
+//!     [data block N]
+//!     [filter block]      (if Options::filter_policy is set)
+//!     [index block]
+//!     [properties block]
+//!     [metaindex block]
+//!     [Footer]        (fixed size; starts at file_size - sizeof(Footer))
+//!
+//! The metaindex block maps the names of the meta blocks (the filter
+//! and properties blocks) to their handles.
+//!     <end_of_file>
+//!
+//! With IndexType::TwoLevelIndexSearch, the index block is split into
+//! partitions of about Options::metadata_block_size bytes, written
+//! right before a top-level index that maps the last key of each
+//! partition to its handle; the footer points to the top-level index.
+//! If Options::partition_filters is also set, the filter block is
+//! replaced by one filter partition per index partition, followed by a
+//! top-level filter index built the same way:
+//!     [data blocks]
+//!     [filter partition 1] ... [filter partition M]
+//!     [top-level filter index]
+//!     [index partition 1] ... [index partition M]
+//!     [top-level index]
+//!     [properties block]
+//!     [metaindex block]
+//!     [Footer]
+//!
+//! Every block is followed by a BLOCK_TRAILER_SIZE trailer holding the
+//! compression type of the block and a checksum of its contents.
+
+use ::comparator::BytewiseComparator;
+use ::dbformat::{self, ValueType};
+use ::errors::RubbleResult;
+use ::slice::Slice;
+use ::options::{Options, CompressionType, DataBlockIndexType, IndexType};
+use ::table::block_builder::{self, BlockBuilder};
+use ::table::filter_block::{FilterBlockBuilder, PartitionedFilterBlockBuilder};
+use ::table::format::{
+    BlockHandle,
+    Footer,
+    BLOCK_TRAILER_SIZE,
+    LEGACY_FORMAT_VERSION,
+    PARTITIONED_INDEX_FORMAT_VERSION,
+};
+use ::table::table_properties::{
+    TableProperties,
+    TablePropertiesCollector,
+    PROPERTIES_BLOCK,
+};
+use ::util::{coding, crc32c};
+use ::util::compression::CodecRegistry;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Appends blocks (and their trailers) to the underlying file while
+/// keeping track of the current offset within it.
+struct BlockWriter<W: Write> {
+    file: W,
+    offset: u64,
+    codecs: Arc<CodecRegistry>,
+    compression_level: Option<i32>,
+}
+
+impl<W: Write> BlockWriter<W> {
+    /// Compress "raw" with the requested compression type (if doing
+    /// so saves enough space) and append it to the file.
+    fn write_block(&mut self, raw: Slice, compression: CompressionType)
+                   -> RubbleResult<BlockHandle>
+    {
+        let compressed = match self.codecs.get(compression as u8) {
+            Some(codec) => codec.compress(raw, self.compression_level).ok(),
+            None => None,
+        };
+        match compressed {
+            // Compressed by at least 12.5%, so store the compressed form
+            Some(ref compressed) if compressed.len() < raw.len() - (raw.len() / 8) =>
+                self.write_raw_block(compressed, compression),
+            // Compression not requested or not supported, or compressed
+            // less than 12.5%, so just store uncompressed form
+            _ => self.write_raw_block(raw, CompressionType::NoCompression),
+        }
+    }
+
+    fn write_raw_block(&mut self, contents: Slice, block_type: CompressionType)
+                       -> RubbleResult<BlockHandle>
+    {
+        let handle = BlockHandle {
+            offset: self.offset,
+            size: contents.len() as u64,
+        };
+        self.file.write_all(contents)?;
+
+        let mut trailer = vec![block_type as u8];
+        let crc = crc32c::value(contents);
+        let crc = crc32c::extend(crc, &trailer);  // Extend crc to cover block type
+        coding::put_fixed32(&mut trailer, crc32c::mask(crc));
+        self.file.write_all(&trailer)?;
+
+        self.offset += (contents.len() + trailer.len()) as u64;
+        Ok(handle)
+    }
+}
+
+pub struct TableBuilder<'a, W: Write> {
+    options: &'a Options,
+    /// Compression applied to the blocks of this table
+    compression: CompressionType,
+    writer: BlockWriter<W>,
+    data_block: BlockBuilder,
+    /// The index block, or the current partition of a partitioned index
+    index_block: BlockBuilder,
+    /// The last key and contents of each finished index partition.
+    /// None unless the index is partitioned.
+    index_partitions: Option<Vec<(Vec<u8>, Vec<u8>)>>,
+    filter_block: Option<FilterBlockBuilder>,
+    partitioned_filter: Option<PartitionedFilterBlockBuilder>,
+    last_key: Vec<u8>,
+    num_entries: u64,
+    /// True iff the table is ordered by an InternalKeyComparator, so
+    /// its keys carry sequence numbers and value types.
+    internal_keys: bool,
+
+    /// Either finish() or abandon() has been called.
+    closed: bool,
+
+    /// We do not emit the index entry for a block until we have seen
+    /// the first key for the next data block.  This allows us to use
+    /// shorter keys in the index block.  For example, consider a block
+    /// boundary between the keys "the quick brown fox" and "the who".
+    /// We can use "the r" as the key for the index block entry since it
+    /// is >= all entries in the first block and < all entries in
+    /// subsequent blocks.
+    ///
+    /// Invariant: pending_index_entry is true only if data_block is
+    /// empty.
+    pending_index_entry: bool,
+
+    /// Handle to add to index block
+    pending_handle: BlockHandle,
+
+    /// Properties of the table, stored in its properties block
+    props: TableProperties,
+
+    /// Collectors of user-defined properties
+    collectors: Vec<Box<dyn TablePropertiesCollector>>,
+}
+
+impl<'a, W: Write> TableBuilder<'a, W> {
+    /// Create a builder that will store the contents of the table it is
+    /// building in "file".  Does not close the file.  It is up to the
+    /// caller to close the file after calling finish().
+    pub fn new(options: &'a Options, file: W) -> TableBuilder<'a, W>
+    {
+        Self::with_compression(options, file, options.compression)
+    }
+
+    /// Like new(), but for a table that will be placed in "level",
+    /// which is the bottommost level iff "bottommost".  Blocks are
+    /// compressed as Options::compression_for_level() picks for that
+    /// level.
+    pub fn new_for_level(options: &'a Options, file: W, level: usize, bottommost: bool)
+                         -> TableBuilder<'a, W>
+    {
+        Self::with_compression(options, file, options.compression_for_level(level, bottommost))
+    }
+
+    fn with_compression(options: &'a Options, file: W, compression: CompressionType)
+                        -> TableBuilder<'a, W>
+    {
+        // The hash index is keyed by whole keys, so it cannot find the
+        // entries of internal keys looked up at another sequence number.
+        let internal_keys = options.comparator.name()
+            .starts_with(dbformat::INTERNAL_KEY_COMPARATOR_NAME_PREFIX);
+        let block_options = block_builder::Options {
+            block_restart_interval: options.block_restart_interval as usize,
+            comparator: options.comparator.clone(),
+            hash_index_util_ratio: match options.data_block_index_type {
+                DataBlockIndexType::BinaryAndHash if !internal_keys =>
+                    Some(options.data_block_hash_table_util_ratio),
+                _ => None,
+            },
+        };
+        let index_block_options = block_builder::Options {
+            block_restart_interval: 1,
+            comparator: options.comparator.clone(),
+            hash_index_util_ratio: None,
+        };
+
+        let partition_index = options.index_type == IndexType::TwoLevelIndexSearch;
+        let partition_filters = partition_index && options.partition_filters;
+        let mut filter_block = options.filter_policy.as_ref()
+            .filter(|_| !partition_filters)
+            .map(|policy| FilterBlockBuilder::new(policy.clone()));
+        if let Some(ref mut filter_block) = filter_block {
+            filter_block.start_block(0);
+        }
+        let partitioned_filter = options.filter_policy.as_ref()
+            .filter(|_| partition_filters)
+            .map(|policy| PartitionedFilterBlockBuilder::new(policy.clone()));
+
+        TableBuilder {
+            options,
+            compression,
+            writer: BlockWriter {
+                file,
+                offset: 0,
+                codecs: options.compression_codecs.clone(),
+                compression_level: options.compression_level,
+            },
+            data_block: BlockBuilder::new(block_options),
+            index_block: BlockBuilder::new(index_block_options),
+            index_partitions: if partition_index { Some(vec![]) } else { None },
+            filter_block,
+            partitioned_filter,
+            last_key: vec![],
+            num_entries: 0,
+            internal_keys,
+            closed: false,
+            pending_index_entry: false,
+            pending_handle: BlockHandle::new(),
+            props: TableProperties::new(),
+            collectors: options.table_properties_collector_factories.iter()
+                .map(|factory| factory.create_table_properties_collector())
+                .collect(),
+        }
+    }
+
+    /// Add key,value to the table being constructed.
+    ///
+    /// REQUIRES: key is after any previously added key according to
+    /// comparator.
+    /// REQUIRES: finish(), abandon() have not been called
+    pub fn add(&mut self, key: Slice, value: Slice) -> RubbleResult<()>
+    {
+        assert!(!self.closed);
+        if self.num_entries > 0 {
+            assert!(self.options.comparator.compare(key, &self.last_key) > 0);
+        }
+
+        for collector in &mut self.collectors {
+            collector.add(key, value)?;
+        }
+
+        if self.pending_index_entry {
+            assert!(self.data_block.empty());
+            self.options.comparator.find_shortest_separator(&mut self.last_key, key);
+            self.add_pending_index_entry();
+        }
+
+        self.add_to_filter(key);
+        if self.internal_keys {
+            self.add_internal_key_properties(key);
+        }
+
+        self.last_key = key.to_vec();
+        self.num_entries += 1;
+        self.props.raw_key_size += key.len() as u64;
+        self.props.raw_value_size += value.len() as u64;
+        self.data_block.add(key, value);
+
+        if self.data_block.current_size_estimate() >= self.options.block_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Advanced operation: flush any buffered key/value pairs to file.
+    /// Can be used to ensure that two adjacent entries never live in
+    /// the same data block.  Most clients should not need to use this
+    /// method.
+    ///
+    /// REQUIRES: finish(), abandon() have not been called
+    pub fn flush(&mut self) -> RubbleResult<()>
+    {
+        assert!(!self.closed);
+        if self.data_block.empty() {
+            return Ok(())
+        }
+        assert!(!self.pending_index_entry);
+
+        self.pending_handle = self.writer.write_block(self.data_block.finish(),
+                                                           self.compression)?;
+        self.data_block.reset();
+        self.pending_index_entry = true;
+        self.props.num_data_blocks += 1;
+        self.props.data_size = self.writer.offset;
+        self.writer.file.flush()?;
+        if let Some(ref mut filter_block) = self.filter_block {
+            filter_block.start_block(self.writer.offset);
+        }
+        Ok(())
+    }
+
+    /// Finish building the table.  Stops using the file passed to the
+    /// constructor after this function returns.
+    ///
+    /// REQUIRES: finish(), abandon() have not been called
+    pub fn finish(&mut self) -> RubbleResult<()>
+    {
+        self.flush()?;
+        assert!(!self.closed);
+        self.closed = true;
+
+        if self.pending_index_entry {
+            self.options.comparator.find_short_successor(&mut self.last_key);
+            self.add_pending_index_entry();
+        }
+        if self.index_partitions.is_some() && !self.index_block.empty() {
+            self.cut_index_partition();
+        }
+
+        // Write filter block
+        let filter_block_handle = match self.filter_block {
+            Some(ref mut filter_block) => {
+                let contents = filter_block.finish();
+                self.props.filter_size = contents.len() as u64;
+                Some(self.writer.write_raw_block(contents, CompressionType::NoCompression)?)
+            },
+            None => None,
+        };
+        let filter_block_handle = match self.partitioned_filter {
+            Some(ref partitioned_filter) => {
+                let mut filter_index = BlockBuilder::new(self.top_level_index_options());
+                for (last_key, filter) in partitioned_filter.partitions() {
+                    let handle = self.writer.write_raw_block(filter, CompressionType::NoCompression)?;
+                    let mut handle_encoding = vec![];
+                    handle.encode_to(&mut handle_encoding);
+                    filter_index.add(last_key, &handle_encoding);
+                }
+                let handle = self.writer.write_block(filter_index.finish(), self.compression)?;
+                // Every partition is followed by its own trailer
+                self.props.filter_size = self.writer.offset - self.props.data_size;
+                Some(handle)
+            },
+            None => filter_block_handle,
+        };
+
+        // Write index block, or the index partitions followed by the
+        // top-level index
+        let index_start = self.writer.offset;
+        let index_handle = match self.index_partitions {
+            Some(ref partitions) => {
+                let mut top_level_index = BlockBuilder::new(self.top_level_index_options());
+                for (last_key, contents) in partitions {
+                    let handle = self.writer.write_block(contents, self.compression)?;
+                    let mut handle_encoding = vec![];
+                    handle.encode_to(&mut handle_encoding);
+                    top_level_index.add(last_key, &handle_encoding);
+                }
+                self.props.index_partitions = partitions.len() as u64;
+                self.writer.write_block(top_level_index.finish(), self.compression)?
+            },
+            None => self.writer.write_block(self.index_block.finish(), self.compression)?,
+        };
+
+        // Write properties block
+        for collector in &mut self.collectors {
+            collector.finish(&mut self.props.user_collected_properties)?;
+        }
+        self.props.num_entries = self.num_entries;
+        self.props.index_size = self.writer.offset - index_start;
+        self.props.comparator_name = self.options.comparator.name().to_string();
+        self.props.compression_name = self.compression.name().to_string();
+        if let Some(ref policy) = self.options.filter_policy {
+            self.props.filter_policy_name = policy.name().to_string();
+        }
+        if let Some(ref extractor) = self.options.prefix_extractor {
+            self.props.prefix_extractor_name = extractor.name().to_string();
+        }
+        self.props.whole_key_filtering = self.options.whole_key_filtering;
+        self.props.creation_time = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        let properties_block_handle = self.writer.write_raw_block(
+            &self.props.encode(), CompressionType::NoCompression)?;
+
+        // Write metaindex block.  Its keys are always ordered bytewise,
+        // regardless of the comparator used for the table's own keys.
+        let block_options = block_builder::Options {
+            block_restart_interval: self.options.block_restart_interval as usize,
+            comparator: Arc::new(BytewiseComparator),
+            hash_index_util_ratio: None,
+        };
+        let mut meta_index_block = BlockBuilder::new(block_options);
+        if let (Some(policy), Some(handle)) = (self.options.filter_policy.as_ref(), filter_block_handle) {
+            // Add mapping from "filter.Name" (or "partitionedfilter.Name")
+            // to location of filter data.  A filter without whole keys
+            // goes under "prefixfilter.Name" instead: LevelDB looks up
+            // whole keys in "filter.Name", and would miss them all.
+            let key = match self.partitioned_filter {
+                Some(_) => format!("partitionedfilter.{}", policy.name()),
+                None if self.options.whole_key_filtering => format!("filter.{}", policy.name()),
+                None => format!("prefixfilter.{}", policy.name()),
+            };
+            let mut handle_encoding = vec![];
+            handle.encode_to(&mut handle_encoding);
+            meta_index_block.add(key.as_bytes(), &handle_encoding);
+        }
+        // Add mapping from "rocksdb.properties" to location of the
+        // properties ("filter.", "partitionedfilter." and "prefixfilter."
+        // sort before "rocksdb.")
+        let mut handle_encoding = vec![];
+        properties_block_handle.encode_to(&mut handle_encoding);
+        meta_index_block.add(PROPERTIES_BLOCK.as_bytes(), &handle_encoding);
+        let metaindex_handle = self.writer.write_block(meta_index_block.finish(),
+                                                            self.compression)?;
+
+        // Write footer
+        let mut footer = Footer::new();
+        footer.set_format_version(match self.index_partitions {
+            Some(_) => PARTITIONED_INDEX_FORMAT_VERSION,
+            None => LEGACY_FORMAT_VERSION,
+        });
+        footer.set_metaindex_handle(metaindex_handle);
+        footer.set_index_handle(index_handle);
+        let mut footer_encoding = vec![];
+        footer.encode_to(&mut footer_encoding);
+        self.writer.file.write_all(&footer_encoding)?;
+        self.writer.offset += footer_encoding.len() as u64;
+        self.writer.file.flush()?;
+        Ok(())
+    }
+
+    /// Indicate that the contents of this builder should be abandoned.
+    /// Stops using the file passed to the constructor after this
+    /// function returns.  If the caller is not going to call finish(),
+    /// it must call abandon() before destroying this builder.
+    ///
+    /// REQUIRES: finish(), abandon() have not been called
+    pub fn abandon(&mut self)
+    {
+        assert!(!self.closed);
+        self.closed = true;
+    }
+
+    /// Number of calls to add() so far.
+    pub fn num_entries(&self) -> u64
+    {
+        self.num_entries
+    }
+
+    /// Size of the file generated so far.  If invoked after a successful
+    /// finish() call, returns the size of the final generated file.
+    pub fn file_size(&self) -> u64
+    {
+        self.writer.offset
+    }
+
+    /// Add "key" to the filter: the key itself if whole key filtering
+    /// is on, and its prefix if it has one and the filter does not
+    /// already hold it from the previous key.  The prefix of an internal
+    /// key is the prefix of its user key.
+    fn add_to_filter(&mut self, key: Slice)
+    {
+        let whole_key = self.options.whole_key_filtering;
+        let user_key = match self.internal_keys {
+            true => dbformat::parse_internal_key(key).map(|parsed| parsed.user_key),
+            false => Some(key),
+        };
+        let prefix = match (&self.options.prefix_extractor, user_key) {
+            (Some(extractor), Some(user_key)) if extractor.in_domain(user_key) =>
+                Some(extractor.transform(user_key)),
+            _ => None,
+        };
+        if let Some(ref mut filter_block) = self.filter_block {
+            if whole_key {
+                filter_block.add_key(key);
+            }
+            if let Some(prefix) = prefix {
+                filter_block.add_prefix(prefix);
+            }
+        }
+        if let Some(ref mut partitioned_filter) = self.partitioned_filter {
+            if whole_key {
+                partitioned_filter.add_key(key);
+            }
+            if let Some(prefix) = prefix {
+                partitioned_filter.add_prefix(prefix);
+            }
+        }
+    }
+
+    /// Account for internal key "key" in the sequence number range and
+    /// deletion count of the table.
+    fn add_internal_key_properties(&mut self, key: Slice)
+    {
+        if let Some(parsed) = dbformat::parse_internal_key(key) {
+            if self.num_entries == 0 || parsed.sequence < self.props.smallest_seqno {
+                self.props.smallest_seqno = parsed.sequence;
+            }
+            if self.num_entries == 0 || parsed.sequence > self.props.largest_seqno {
+                self.props.largest_seqno = parsed.sequence;
+            }
+            if parsed.value_type == ValueType::Deletion {
+                self.props.num_deletions += 1;
+            }
+        }
+    }
+
+    fn add_pending_index_entry(&mut self)
+    {
+        let mut handle_encoding = vec![];
+        self.pending_handle.encode_to(&mut handle_encoding);
+        self.index_block.add(&self.last_key, &handle_encoding);
+        self.pending_index_entry = false;
+
+        if self.index_partitions.is_some()
+            && self.index_block.current_size_estimate() >= self.options.metadata_block_size {
+            self.cut_index_partition();
+        }
+    }
+
+    /// Finish the current index partition, along with the filter
+    /// partition for the same data blocks.  The last index key of the
+    /// partition becomes its key in the top-level index.
+    fn cut_index_partition(&mut self)
+    {
+        let contents = self.index_block.finish().to_vec();
+        self.index_block.reset();
+        if let Some(ref mut partitions) = self.index_partitions {
+            partitions.push((self.last_key.clone(), contents));
+        }
+        if let Some(ref mut partitioned_filter) = self.partitioned_filter {
+            partitioned_filter.cut_partition(&self.last_key);
+        }
+    }
+
+    /// Options for the top-level index of a partitioned index or filter,
+    /// which is ordered by the table's comparator like the index itself.
+    fn top_level_index_options(&self) -> block_builder::Options
+    {
+        block_builder::Options {
+            block_restart_interval: 1,
+            comparator: self.options.comparator.clone(),
+            hash_index_util_ratio: None,
+        }
+    }
+}
@@ -0,0 +1,349 @@
+//! TableProperties contains a bunch of read-only properties of a table,
+//! collected while it is built and stored in its properties meta block.
+//!
+//! The properties block is a regular block found through the metaindex
+//! under the key "rocksdb.properties".  Each of its entries maps the
+//! name of a property to its value: a varint64 for numeric properties,
+//! or the raw bytes of string properties.  The property names match
+//! the ones RocksDB uses.
+//!
+//! Alongside the builtin properties, the block holds the properties
+//! added by the TablePropertiesCollectors configured in Options.
+
+use ::comparator::BytewiseComparator;
+use ::errors::RubbleResult;
+use ::slice::Slice;
+use ::status::Status;
+use ::table::block::{Block, OwnedBlock};
+use ::table::block_builder::{self, BlockBuilder};
+use ::util::coding;
+use std::collections::BTreeMap;
+use std::str;
+use std::sync::Arc;
+
+/// Key of the properties block in the metaindex block
+pub const PROPERTIES_BLOCK: &str = "rocksdb.properties";
+
+pub const DATA_SIZE: &str = "rocksdb.data.size";
+pub const INDEX_SIZE: &str = "rocksdb.index.size";
+pub const INDEX_PARTITIONS: &str = "rocksdb.index.partitions";
+pub const FILTER_SIZE: &str = "rocksdb.filter.size";
+pub const RAW_KEY_SIZE: &str = "rocksdb.raw.key.size";
+pub const RAW_VALUE_SIZE: &str = "rocksdb.raw.value.size";
+pub const NUM_DATA_BLOCKS: &str = "rocksdb.num.data.blocks";
+pub const NUM_ENTRIES: &str = "rocksdb.num.entries";
+pub const DELETED_KEYS: &str = "rocksdb.deleted.keys";
+pub const FILTER_POLICY: &str = "rocksdb.filter.policy";
+pub const PREFIX_EXTRACTOR_NAME: &str = "rocksdb.prefix.extractor.name";
+pub const WHOLE_KEY_FILTERING: &str = "rocksdb.block.based.table.whole.key.filtering";
+pub const COMPARATOR: &str = "rocksdb.comparator";
+pub const COMPRESSION: &str = "rocksdb.compression";
+pub const CREATION_TIME: &str = "rocksdb.creation.time";
+pub const SMALLEST_SEQNO: &str = "rocksdb.key.smallest.seqno";
+pub const LARGEST_SEQNO: &str = "rocksdb.key.largest.seqno";
+
+/// Properties added to a table by TablePropertiesCollectors, by name
+pub type UserCollectedProperties = BTreeMap<String, Vec<u8>>;
+
+/// A TablePropertiesCollector is notified of every key/value pair
+/// added to a table while it is built, and adds its own properties to
+/// the table's properties block when the table is finished.  A new
+/// collector is created for every table, so it may keep per-table state.
+pub trait TablePropertiesCollector {
+    /// Called with each key/value added to the table, in order.
+    fn add(&mut self, key: Slice, value: Slice) -> RubbleResult<()>;
+
+    /// Called when the table is finished.  The collector adds the
+    /// properties it has collected to "properties".
+    fn finish(&mut self, properties: &mut UserCollectedProperties) -> RubbleResult<()>;
+
+    /// The name of the collector, for diagnostics.
+    fn name(&self) -> &str;
+}
+
+/// Creates a TablePropertiesCollector for each table that is built.
+pub trait TablePropertiesCollectorFactory: Send + Sync {
+    fn create_table_properties_collector(&self) -> Box<dyn TablePropertiesCollector>;
+
+    /// The name of the factory, for diagnostics.
+    fn name(&self) -> &str;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableProperties {
+    /// Total size of the data blocks, including their trailers
+    pub data_size: u64,
+    /// Size of the index block, including its trailer.  For a
+    /// partitioned index, the total size of the partitions and the
+    /// top-level index.
+    pub index_size: u64,
+    /// Number of index partitions, or zero if the index is not
+    /// partitioned
+    pub index_partitions: u64,
+    /// Size of the filter block.  For a partitioned filter, the total
+    /// size of the partitions and the top-level filter index, including
+    /// their trailers.
+    pub filter_size: u64,
+    /// Total size of the keys added to the table
+    pub raw_key_size: u64,
+    /// Total size of the values added to the table
+    pub raw_value_size: u64,
+    /// Number of data blocks in the table
+    pub num_data_blocks: u64,
+    /// Number of entries in the table
+    pub num_entries: u64,
+    /// Number of deletion markers in the table
+    pub num_deletions: u64,
+    /// Sequence number range of the entries.  Only known for tables of
+    /// internal keys; zero otherwise.
+    pub smallest_seqno: u64,
+    pub largest_seqno: u64,
+    /// Seconds since the epoch at which the table was written
+    pub creation_time: u64,
+    /// Name of the filter policy used in the table, or empty if none
+    pub filter_policy_name: String,
+    /// Name of the prefix extractor whose prefixes the filter holds, or
+    /// empty if none
+    pub prefix_extractor_name: String,
+    /// True iff the filter holds every key of the table.  Tables
+    /// without this property always hold them.
+    pub whole_key_filtering: bool,
+    /// Name of the comparator used to order the keys of the table
+    pub comparator_name: String,
+    /// Name of the compression type the table's blocks were written with
+    pub compression_name: String,
+    /// Properties added by TablePropertiesCollectors.  When reading a
+    /// table, this also holds any properties this version does not know
+    /// about.
+    pub user_collected_properties: UserCollectedProperties,
+}
+
+impl TableProperties {
+    pub fn new() -> TableProperties
+    {
+        TableProperties {
+            data_size: 0,
+            index_size: 0,
+            index_partitions: 0,
+            filter_size: 0,
+            raw_key_size: 0,
+            raw_value_size: 0,
+            num_data_blocks: 0,
+            num_entries: 0,
+            num_deletions: 0,
+            smallest_seqno: 0,
+            largest_seqno: 0,
+            creation_time: 0,
+            filter_policy_name: String::new(),
+            prefix_extractor_name: String::new(),
+            whole_key_filtering: true,
+            comparator_name: String::new(),
+            compression_name: String::new(),
+            user_collected_properties: BTreeMap::new(),
+        }
+    }
+
+    fn numeric_properties(&self) -> [(&'static str, u64); 12]
+    {
+        [(DATA_SIZE, self.data_size),
+         (INDEX_SIZE, self.index_size),
+         (INDEX_PARTITIONS, self.index_partitions),
+         (FILTER_SIZE, self.filter_size),
+         (RAW_KEY_SIZE, self.raw_key_size),
+         (RAW_VALUE_SIZE, self.raw_value_size),
+         (NUM_DATA_BLOCKS, self.num_data_blocks),
+         (NUM_ENTRIES, self.num_entries),
+         (DELETED_KEYS, self.num_deletions),
+         (SMALLEST_SEQNO, self.smallest_seqno),
+         (LARGEST_SEQNO, self.largest_seqno),
+         (CREATION_TIME, self.creation_time)]
+    }
+
+    /// Encode the properties as the contents of a properties block.
+    pub fn encode(&self) -> Vec<u8>
+    {
+        // A BTreeMap keeps the properties in the sorted order the block
+        // requires.  Builtin properties take precedence over collected
+        // properties of the same name.
+        let mut properties = self.user_collected_properties.clone();
+        for &(name, value) in self.numeric_properties().iter() {
+            let mut encoding = vec![];
+            coding::put_varint64(&mut encoding, value);
+            properties.insert(name.to_string(), encoding);
+        }
+        properties.insert(FILTER_POLICY.to_string(), self.filter_policy_name.as_bytes().to_vec());
+        properties.insert(PREFIX_EXTRACTOR_NAME.to_string(), self.prefix_extractor_name.as_bytes().to_vec());
+        properties.insert(WHOLE_KEY_FILTERING.to_string(),
+                          if self.whole_key_filtering { b"1".to_vec() } else { b"0".to_vec() });
+        properties.insert(COMPARATOR.to_string(), self.comparator_name.as_bytes().to_vec());
+        properties.insert(COMPRESSION.to_string(), self.compression_name.as_bytes().to_vec());
+
+        let mut block = BlockBuilder::new(block_builder::Options {
+            block_restart_interval: 1,
+            comparator: Arc::new(BytewiseComparator),
+            hash_index_util_ratio: None,
+        });
+        for (name, value) in &properties {
+            block.add(name.as_bytes(), value);
+        }
+        block.finish().to_vec()
+    }
+
+    /// Parse the properties out of a properties block.  Properties this
+    /// version does not know about are returned as user collected
+    /// properties.
+    pub fn decode(block: &OwnedBlock) -> RubbleResult<TableProperties>
+    {
+        let mut properties = TableProperties::new();
+        let mut iter = block.iter(BytewiseComparator);
+        iter.seek_to_first();
+        while iter.is_valid() {
+            let name = decode_string(iter.key())?;
+            let value = iter.value();
+            match name.as_str() {
+                FILTER_POLICY => properties.filter_policy_name = decode_string(value)?,
+                PREFIX_EXTRACTOR_NAME => properties.prefix_extractor_name = decode_string(value)?,
+                WHOLE_KEY_FILTERING => properties.whole_key_filtering = value != b"0",
+                COMPARATOR => properties.comparator_name = decode_string(value)?,
+                COMPRESSION => properties.compression_name = decode_string(value)?,
+                _ => match properties.numeric_property_mut(&name) {
+                    Some(field) => *field = coding::get_varint64(value)?.1,
+                    None => {
+                        properties.user_collected_properties.insert(name.clone(), value.to_vec());
+                    },
+                },
+            }
+            iter.step();
+        }
+        if !iter.status().is_ok() {
+            return Err(iter.status().clone().into());
+        }
+        Ok(properties)
+    }
+
+    fn numeric_property_mut(&mut self, name: &str) -> Option<&mut u64>
+    {
+        let field = match name {
+            DATA_SIZE => &mut self.data_size,
+            INDEX_SIZE => &mut self.index_size,
+            INDEX_PARTITIONS => &mut self.index_partitions,
+            FILTER_SIZE => &mut self.filter_size,
+            RAW_KEY_SIZE => &mut self.raw_key_size,
+            RAW_VALUE_SIZE => &mut self.raw_value_size,
+            NUM_DATA_BLOCKS => &mut self.num_data_blocks,
+            NUM_ENTRIES => &mut self.num_entries,
+            DELETED_KEYS => &mut self.num_deletions,
+            SMALLEST_SEQNO => &mut self.smallest_seqno,
+            LARGEST_SEQNO => &mut self.largest_seqno,
+            CREATION_TIME => &mut self.creation_time,
+            _ => return None,
+        };
+        Some(field)
+    }
+}
+
+impl Default for TableProperties {
+    fn default() -> TableProperties
+    {
+        TableProperties::new()
+    }
+}
+
+fn decode_string(value: Slice) -> RubbleResult<String>
+{
+    match str::from_utf8(value) {
+        Ok(value) => Ok(value.to_string()),
+        Err(_) => Err(Status::Corruption("bad string property".into()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TableProperties, COMPARATOR, NUM_ENTRIES};
+    use ::comparator::BytewiseComparator;
+    use ::table::block::OwnedBlock;
+    use ::table::block_builder::{self, BlockBuilder};
+    use ::table::format::BlockContents;
+    use ::util::coding;
+    use std::sync::Arc;
+
+    fn block(contents: Vec<u8>) -> OwnedBlock
+    {
+        let mut block_contents = BlockContents::new();
+        block_contents.data = contents.into();
+        OwnedBlock::new(block_contents).unwrap()
+    }
+
+    #[test]
+    fn encode_decode()
+    {
+        let properties = TableProperties {
+            data_size: 1,
+            index_size: 2,
+            index_partitions: 10,
+            filter_size: 3,
+            raw_key_size: 4,
+            raw_value_size: 5,
+            num_data_blocks: 6,
+            num_entries: 7,
+            num_deletions: 8,
+            smallest_seqno: 9,
+            largest_seqno: 1 << 56,
+            creation_time: 1_500_000_000,
+            filter_policy_name: "leveldb.BuiltinBloomFilter2".to_string(),
+            prefix_extractor_name: "rocksdb.FixedPrefix.3".to_string(),
+            whole_key_filtering: false,
+            comparator_name: "leveldb.BytewiseComparator".to_string(),
+            compression_name: "Snappy".to_string(),
+            user_collected_properties: vec![("my.count".to_string(), b"\x03".to_vec()),
+                                            ("my.tag".to_string(), b"\xff\x00".to_vec())]
+                .into_iter().collect(),
+        };
+        let decoded = TableProperties::decode(&block(properties.encode())).unwrap();
+        assert_eq!(decoded, properties);
+
+        let empty = TableProperties::decode(&block(TableProperties::new().encode())).unwrap();
+        assert_eq!(empty, TableProperties::new());
+    }
+
+    #[test]
+    fn unknown_properties_are_user_collected()
+    {
+        let mut num_entries = vec![];
+        coding::put_varint64(&mut num_entries, 42);
+        let mut sorted: Vec<(&[u8], &[u8])> = vec![
+            (NUM_ENTRIES.as_bytes(), &num_entries),
+            (b"rocksdb.some.future.property", b"\x01"),
+            (b"zzz.collected", b"x"),
+        ];
+        sorted.sort();
+        let mut builder = BlockBuilder::new(block_builder::Options {
+            block_restart_interval: 1,
+            comparator: Arc::new(BytewiseComparator),
+            hash_index_util_ratio: None,
+        });
+        for (name, value) in sorted {
+            builder.add(name, value);
+        }
+        let decoded = TableProperties::decode(&block(builder.finish().to_vec())).unwrap();
+        assert_eq!(decoded.num_entries, 42);
+        assert_eq!(decoded.num_data_blocks, 0);
+        assert_eq!(decoded.user_collected_properties.len(), 2);
+        assert_eq!(decoded.user_collected_properties["rocksdb.some.future.property"], b"\x01");
+        assert_eq!(decoded.user_collected_properties["zzz.collected"], b"x");
+    }
+
+    #[test]
+    fn builtin_properties_win()
+    {
+        let mut properties = TableProperties::new();
+        properties.num_entries = 7;
+        properties.comparator_name = "leveldb.BytewiseComparator".to_string();
+        properties.user_collected_properties.insert(NUM_ENTRIES.to_string(), b"\x63".to_vec());
+        properties.user_collected_properties.insert(COMPARATOR.to_string(), b"fake".to_vec());
+        let decoded = TableProperties::decode(&block(properties.encode())).unwrap();
+        assert_eq!(decoded.num_entries, 7);
+        assert_eq!(decoded.comparator_name, "leveldb.BytewiseComparator");
+        assert!(decoded.user_collected_properties.is_empty());
+    }
+}
@@ -0,0 +1,198 @@
+use ::errors::RubbleResult;
+use ::options::ReadOptions;
+use ::slice::Slice;
+use ::status::Status;
+use ::table::iterator::RubbleIterator;
+
+/// Converts an index iterator value (i.e., an encoded BlockHandle)
+/// into an iterator over the contents of the corresponding block.
+pub type BlockFunction<'a, D> = Box<dyn Fn(&ReadOptions, Slice) -> RubbleResult<D> + 'a>;
+
+/// A two-level iterator contains an index iterator whose values point
+/// to a sequence of blocks where each block is itself a sequence of
+/// key,value pairs.  The returned two-level iterator yields the
+/// concatenation of all key/value pairs in the sequence of blocks.
+///
+/// Uses a supplied function to convert an index_iter value into an
+/// iterator over the contents of the corresponding block.
+pub struct TwoLevelIterator<'a, I: RubbleIterator, D: RubbleIterator> {
+    block_function: BlockFunction<'a, D>,
+    options: ReadOptions,
+    status: Status,
+    index_iter: I,
+    /// May be None
+    data_iter: Option<D>,
+    /// If data_iter is not None, then "data_block_handle" holds the
+    /// "index_value" passed to block_function to create the data_iter.
+    data_block_handle: Vec<u8>,
+}
+
+impl<'a, I: RubbleIterator, D: RubbleIterator> TwoLevelIterator<'a, I, D> {
+    pub fn new(index_iter: I, block_function: BlockFunction<'a, D>, options: &ReadOptions)
+               -> TwoLevelIterator<'a, I, D>
+    {
+        TwoLevelIterator {
+            block_function,
+            options: options.clone(),
+            status: Status::Ok,
+            index_iter,
+            data_iter: None,
+            data_block_handle: vec![],
+        }
+    }
+
+    fn save_error(&mut self, status: Status)
+    {
+        if self.status.is_ok() && !status.is_ok() {
+            self.status = status;
+        }
+    }
+
+    fn skip_empty_data_blocks_forward(&mut self)
+    {
+        while !self.is_valid() {
+            // Move to next block
+            if !self.index_iter.is_valid() {
+                self.set_data_iterator(None);
+                return;
+            }
+            self.index_iter.step();
+            self.init_data_block();
+            if let Some(ref mut iter) = self.data_iter {
+                iter.seek_to_first();
+            }
+        }
+    }
+
+    fn skip_empty_data_blocks_backward(&mut self)
+    {
+        while !self.is_valid() {
+            // Move to previous block
+            if !self.index_iter.is_valid() {
+                self.set_data_iterator(None);
+                return;
+            }
+            self.index_iter.prev();
+            self.init_data_block();
+            if let Some(ref mut iter) = self.data_iter {
+                iter.seek_to_last();
+            }
+        }
+    }
+
+    fn set_data_iterator(&mut self, data_iter: Option<D>)
+    {
+        let old_status = self.data_iter.as_ref().map(|iter| iter.status().clone());
+        if let Some(status) = old_status {
+            self.save_error(status);
+        }
+        self.data_iter = data_iter;
+    }
+
+    fn init_data_block(&mut self)
+    {
+        if !self.index_iter.is_valid() {
+            self.set_data_iterator(None);
+            return;
+        }
+
+        if self.data_iter.is_some() && self.index_iter.value() == &self.data_block_handle[..] {
+            // data_iter is already constructed with this iterator, so
+            // no need to change anything
+            return;
+        }
+
+        let handle = self.index_iter.value().to_vec();
+        match (self.block_function)(&self.options, &handle) {
+            Ok(iter) => {
+                self.data_block_handle = handle;
+                self.set_data_iterator(Some(iter));
+            },
+            Err(err) => {
+                self.save_error(err.into());
+                self.set_data_iterator(None);
+            }
+        }
+    }
+}
+
+impl<'a, I: RubbleIterator, D: RubbleIterator> RubbleIterator for TwoLevelIterator<'a, I, D> {
+    fn is_valid(&self) -> bool
+    {
+        self.data_iter.as_ref().is_some_and(|iter| iter.is_valid())
+    }
+
+    fn seek(&mut self, target: Slice)
+    {
+        self.index_iter.seek(target);
+        self.init_data_block();
+        if let Some(ref mut iter) = self.data_iter {
+            iter.seek(target);
+        }
+        self.skip_empty_data_blocks_forward();
+    }
+
+    fn seek_to_first(&mut self)
+    {
+        self.index_iter.seek_to_first();
+        self.init_data_block();
+        if let Some(ref mut iter) = self.data_iter {
+            iter.seek_to_first();
+        }
+        self.skip_empty_data_blocks_forward();
+    }
+
+    fn seek_to_last(&mut self)
+    {
+        self.index_iter.seek_to_last();
+        self.init_data_block();
+        if let Some(ref mut iter) = self.data_iter {
+            iter.seek_to_last();
+        }
+        self.skip_empty_data_blocks_backward();
+    }
+
+    fn step(&mut self)
+    {
+        assert!(self.is_valid());
+        if let Some(ref mut iter) = self.data_iter {
+            iter.step();
+        }
+        self.skip_empty_data_blocks_forward();
+    }
+
+    fn prev(&mut self)
+    {
+        assert!(self.is_valid());
+        if let Some(ref mut iter) = self.data_iter {
+            iter.prev();
+        }
+        self.skip_empty_data_blocks_backward();
+    }
+
+    fn key(&self) -> Slice<'_>
+    {
+        assert!(self.is_valid());
+        self.data_iter.as_ref().unwrap().key()
+    }
+
+    fn value(&self) -> Slice<'_>
+    {
+        assert!(self.is_valid());
+        self.data_iter.as_ref().unwrap().value()
+    }
+
+    fn status(&self) -> &Status
+    {
+        if !self.index_iter.status().is_ok() {
+            self.index_iter.status()
+        } else if let Some(status) = self.data_iter.as_ref()
+            .map(|iter| iter.status())
+            .filter(|status| !status.is_ok())
+        {
+            status
+        } else {
+            &self.status
+        }
+    }
+}
@@ -0,0 +1,378 @@
+//! An Arena hands out memory from large blocks that are all freed at
+//! once when the arena is dropped.  Many small allocations that share
+//! a lifetime, like the entries of a memtable, are cheaper to make
+//! from an arena than one at a time.
+
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+const BLOCK_SIZE: usize = 4096;
+
+/// Alignment of the memory returned by allocate_aligned()
+const ALIGN: usize = mem::size_of::<usize>();
+
+pub struct Arena {
+    /// Allocation state
+    alloc_ptr: *mut u8,
+    alloc_bytes_remaining: usize,
+
+    /// Array of new[] allocated memory blocks.  Blocks are allocated as
+    /// words so that the start of every block is aligned.
+    blocks: Vec<Box<[usize]>>,
+
+    /// Total memory usage of the arena.
+    memory_usage: AtomicUsize,
+}
+
+// The arena only hands out pointers into the blocks it owns; those are
+// heap allocations that do not move when the arena does.
+unsafe impl Send for Arena {}
+unsafe impl Sync for Arena {}
+
+impl Default for Arena {
+    fn default() -> Arena
+    {
+        Arena::new()
+    }
+}
+
+impl Arena {
+    pub fn new() -> Arena
+    {
+        Arena {
+            alloc_ptr: ptr::null_mut(),
+            alloc_bytes_remaining: 0,
+            blocks: vec![],
+            memory_usage: AtomicUsize::new(0),
+        }
+    }
+
+    /// Return a pointer to a newly allocated memory block of "bytes"
+    /// bytes.  The memory stays valid until the arena is dropped.
+    pub fn allocate(&mut self, bytes: usize) -> *mut u8
+    {
+        // The semantics of what to return are a bit messy if we allow
+        // 0-byte allocations, so we disallow them here (we don't need
+        // them for our internal use).
+        assert!(bytes > 0);
+        if bytes <= self.alloc_bytes_remaining {
+            let result = self.alloc_ptr;
+            self.alloc_ptr = self.alloc_ptr.wrapping_add(bytes);
+            self.alloc_bytes_remaining -= bytes;
+            return result;
+        }
+        self.allocate_fallback(bytes)
+    }
+
+    /// Allocate memory with the normal alignment guarantees provided
+    /// by malloc.
+    pub fn allocate_aligned(&mut self, bytes: usize) -> *mut u8
+    {
+        let current_mod = self.alloc_ptr as usize & (ALIGN - 1);
+        let slop = if current_mod == 0 { 0 } else { ALIGN - current_mod };
+        let needed = bytes + slop;
+        let result = if needed <= self.alloc_bytes_remaining {
+            let result = self.alloc_ptr.wrapping_add(slop);
+            self.alloc_ptr = self.alloc_ptr.wrapping_add(needed);
+            self.alloc_bytes_remaining -= needed;
+            result
+        } else {
+            // allocate_fallback always returns aligned memory
+            self.allocate_fallback(bytes)
+        };
+        assert!(result as usize & (ALIGN - 1) == 0);
+        result
+    }
+
+    /// Returns an estimate of the total memory usage of data allocated
+    /// by the arena.
+    pub fn memory_usage(&self) -> usize
+    {
+        self.memory_usage.load(Ordering::Relaxed)
+    }
+
+    fn allocate_fallback(&mut self, bytes: usize) -> *mut u8
+    {
+        if bytes > BLOCK_SIZE / 4 {
+            // Object is more than a quarter of our block size.  Allocate
+            // it separately to avoid wasting too much space in leftover
+            // bytes.
+            return self.allocate_new_block(bytes);
+        }
+
+        // We waste the remaining space in the current block.
+        self.alloc_ptr = self.allocate_new_block(BLOCK_SIZE);
+        self.alloc_bytes_remaining = BLOCK_SIZE;
+
+        let result = self.alloc_ptr;
+        self.alloc_ptr = self.alloc_ptr.wrapping_add(bytes);
+        self.alloc_bytes_remaining -= bytes;
+        result
+    }
+
+    fn allocate_new_block(&mut self, block_bytes: usize) -> *mut u8
+    {
+        let words = block_bytes.div_ceil(ALIGN);
+        let mut block = vec![0usize; words].into_boxed_slice();
+        let result = block.as_mut_ptr() as *mut u8;
+        self.blocks.push(block);
+        self.memory_usage.fetch_add(words * ALIGN + mem::size_of::<Box<[usize]>>(),
+                                    Ordering::Relaxed);
+        result
+    }
+}
+
+/// A block of a ConcurrentArena
+struct SharedBlock {
+    /// Words of memory, allocated as a boxed slice and freed on drop
+    memory: *mut usize,
+    words: usize,
+    /// Bytes handed out from the block.  Allocations that do not fit
+    /// still add to it, so it may exceed the size of the block.
+    used: AtomicUsize,
+    /// The block pushed onto the list of blocks before this one
+    next: AtomicPtr<SharedBlock>,
+}
+
+impl Drop for SharedBlock {
+    fn drop(&mut self)
+    {
+        drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(self.memory, self.words)) });
+    }
+}
+
+/// An arena that several threads may allocate from at once.  Each
+/// allocation bumps the offset of the current block atomically; only
+/// the thread that finds the block full installs a new one, with a
+/// compare-and-swap.  All allocations are aligned like
+/// Arena::allocate_aligned().
+pub struct ConcurrentArena {
+    /// The block small allocations are carved from, or null before the
+    /// first one
+    current: AtomicPtr<SharedBlock>,
+    /// Every block of the arena, most recent first
+    blocks: AtomicPtr<SharedBlock>,
+    /// Total memory usage of the arena.
+    memory_usage: AtomicUsize,
+}
+
+// See Arena: the blocks do not move when the arena does, and they are
+// only handed out in disjoint pieces.
+unsafe impl Send for ConcurrentArena {}
+unsafe impl Sync for ConcurrentArena {}
+
+impl Default for ConcurrentArena {
+    fn default() -> ConcurrentArena
+    {
+        ConcurrentArena::new()
+    }
+}
+
+impl ConcurrentArena {
+    pub fn new() -> ConcurrentArena
+    {
+        ConcurrentArena {
+            current: AtomicPtr::new(ptr::null_mut()),
+            blocks: AtomicPtr::new(ptr::null_mut()),
+            memory_usage: AtomicUsize::new(0),
+        }
+    }
+
+    /// Return a pointer to a newly allocated, aligned memory block of
+    /// "bytes" bytes.  The memory stays valid until the arena is dropped.
+    pub fn allocate(&self, bytes: usize) -> *mut u8
+    {
+        assert!(bytes > 0);
+        let needed = bytes.div_ceil(ALIGN) * ALIGN;
+        if needed > BLOCK_SIZE / 4 {
+            // Allocate large objects separately, like Arena does.
+            return self.push_block(ConcurrentArena::new_block(needed, needed));
+        }
+
+        loop {
+            let block = self.current.load(Ordering::Acquire);
+            if !block.is_null() {
+                let block = unsafe { &*block };
+                let offset = block.used.fetch_add(needed, Ordering::Relaxed);
+                if offset + needed <= BLOCK_SIZE {
+                    return unsafe { (block.memory as *mut u8).add(offset) };
+                }
+            }
+
+            // The block is full.  Replace it, unless another thread did
+            // in the meantime, in which case retry with the new one.
+            let new_block = ConcurrentArena::new_block(BLOCK_SIZE, needed);
+            match self.current.compare_exchange(block, new_block, Ordering::AcqRel,
+                                                Ordering::Acquire) {
+                Ok(_) => return self.push_block(new_block),
+                Err(_) => drop(unsafe { Box::from_raw(new_block) }),
+            }
+        }
+    }
+
+    /// Returns an estimate of the total memory usage of data allocated
+    /// by the arena.
+    pub fn memory_usage(&self) -> usize
+    {
+        self.memory_usage.load(Ordering::Relaxed)
+    }
+
+    /// A block of "block_bytes" bytes whose first "used" bytes are
+    /// handed out.
+    fn new_block(block_bytes: usize, used: usize) -> *mut SharedBlock
+    {
+        let words = block_bytes.div_ceil(ALIGN);
+        let memory = Box::into_raw(vec![0usize; words].into_boxed_slice()) as *mut usize;
+        Box::into_raw(Box::new(SharedBlock {
+            memory,
+            words,
+            used: AtomicUsize::new(used),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }))
+    }
+
+    /// Add "block" to the blocks freed with the arena, and return its
+    /// memory.
+    fn push_block(&self, block: *mut SharedBlock) -> *mut u8
+    {
+        let mut head = self.blocks.load(Ordering::Relaxed);
+        loop {
+            unsafe { (*block).next.store(head, Ordering::Relaxed) };
+            match self.blocks.compare_exchange_weak(head, block, Ordering::Release,
+                                                    Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+        let block = unsafe { &*block };
+        self.memory_usage.fetch_add(block.words * ALIGN + mem::size_of::<SharedBlock>(),
+                                    Ordering::Relaxed);
+        block.memory as *mut u8
+    }
+}
+
+impl Drop for ConcurrentArena {
+    fn drop(&mut self)
+    {
+        let mut block = *self.blocks.get_mut();
+        while !block.is_null() {
+            let owned = unsafe { Box::from_raw(block) };
+            block = owned.next.load(Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Arena, ConcurrentArena, ALIGN, BLOCK_SIZE};
+    use ::util::random::Random;
+    use std::slice;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn empty()
+    {
+        let arena = Arena::new();
+        assert_eq!(arena.memory_usage(), 0);
+    }
+
+    #[test]
+    fn simple()
+    {
+        const N: usize = 100000;
+        let mut allocated: Vec<(usize, *mut u8)> = vec![];
+        let mut arena = Arena::new();
+        let mut bytes = 0;
+        let mut rnd = Random::new(301);
+        for i in 0..N {
+            let mut s = if i % (N / 10) == 0 {
+                i
+            } else if rnd.one_in(4000) {
+                rnd.uniform(6000) as usize
+            } else if rnd.one_in(10) {
+                rnd.uniform(100) as usize
+            } else {
+                rnd.uniform(20) as usize
+            };
+            if s == 0 {
+                // Our arena disallows size 0 allocations.
+                s = 1;
+            }
+            let r = if rnd.one_in(10) {
+                let r = arena.allocate_aligned(s);
+                assert_eq!(r as usize & (ALIGN - 1), 0);
+                r
+            } else {
+                arena.allocate(s)
+            };
+
+            // Fill the "i"th allocation with a known bit pattern
+            let block = unsafe { slice::from_raw_parts_mut(r, s) };
+            for (b, byte) in block.iter_mut().enumerate() {
+                *byte = (i % 256) as u8 ^ (b % 256) as u8;
+            }
+            bytes += s;
+            allocated.push((s, r));
+            assert!(arena.memory_usage() >= bytes);
+            if i > N / 10 {
+                assert!(arena.memory_usage() as f64 <= bytes as f64 * 1.10);
+            }
+        }
+
+        for (i, &(num_bytes, p)) in allocated.iter().enumerate() {
+            let block = unsafe { slice::from_raw_parts(p, num_bytes) };
+            for (b, &byte) in block.iter().enumerate() {
+                // Check the "i"th allocation for the known bit pattern
+                assert_eq!(byte, (i % 256) as u8 ^ (b % 256) as u8);
+            }
+        }
+    }
+
+    /// Threads allocating from a ConcurrentArena at once get disjoint,
+    /// aligned memory.
+    #[test]
+    fn concurrent()
+    {
+        const THREADS: usize = 4;
+        const N: usize = 20000;
+        let arena = Arc::new(ConcurrentArena::new());
+        let threads: Vec<_> = (0..THREADS).map(|t| {
+            let arena = arena.clone();
+            thread::spawn(move || {
+                let mut rnd = Random::new(301 + t as u32);
+                let mut allocated = vec![];
+                let mut bytes = 0;
+                for i in 0..N {
+                    let s = if rnd.one_in(1000) {
+                        BLOCK_SIZE / 4 + rnd.uniform(BLOCK_SIZE as u32) as usize
+                    } else {
+                        1 + rnd.uniform(100) as usize
+                    };
+                    let r = arena.allocate(s);
+                    assert_eq!(r as usize & (ALIGN - 1), 0);
+
+                    // Fill the allocation with a pattern of its thread
+                    // and index
+                    let block = unsafe { slice::from_raw_parts_mut(r, s) };
+                    for byte in block.iter_mut() {
+                        *byte = (t * 64 + i % 64) as u8;
+                    }
+                    bytes += s;
+                    allocated.push((s, r as usize));
+                }
+
+                for (i, &(num_bytes, p)) in allocated.iter().enumerate() {
+                    let block = unsafe { slice::from_raw_parts(p as *const u8, num_bytes) };
+                    assert!(block.iter().all(|&byte| byte == (t * 64 + i % 64) as u8));
+                }
+                bytes
+            })
+        }).collect();
+
+        let bytes: usize = threads.into_iter().map(|thread| thread.join().unwrap()).sum();
+        assert!(arena.memory_usage() >= bytes);
+        assert!(arena.memory_usage() as f64 <= bytes as f64 * 1.5);
+    }
+}
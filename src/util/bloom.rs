@@ -0,0 +1,220 @@
+use ::filter_policy::FilterPolicy;
+use ::slice::Slice;
+use ::util::hash::hash;
+
+fn bloom_hash(key: Slice) -> u32
+{
+    hash(key, 0xbc9f1d34)
+}
+
+/// A filter policy that uses a bloom filter with approximately the
+/// specified number of bits per key.  A good value for bits_per_key
+/// is 10, which yields a filter with ~ 1% false positive rate.
+///
+/// The filters produced are compatible with LevelDB's builtin bloom
+/// filter ("leveldb.BuiltinBloomFilter2").
+///
+/// Note: if you are using a custom comparator that ignores some parts
+/// of the keys being compared, you must not use BloomFilterPolicy and
+/// must provide your own FilterPolicy that also ignores the
+/// corresponding parts of the keys.  For example, if the comparator
+/// ignores trailing spaces, it would be incorrect to use a FilterPolicy
+/// (like BloomFilterPolicy) that does not ignore trailing spaces in keys.
+pub struct BloomFilterPolicy {
+    bits_per_key: usize,
+    k: usize,
+}
+
+impl BloomFilterPolicy {
+    pub fn new(bits_per_key: usize) -> BloomFilterPolicy
+    {
+        // We intentionally round down to reduce probing cost a little bit
+        let k = (bits_per_key as f64 * 0.69) as usize;  // 0.69 =~ ln(2)
+        BloomFilterPolicy {
+            bits_per_key,
+            k: k.clamp(1, 30),
+        }
+    }
+}
+
+impl FilterPolicy for BloomFilterPolicy {
+    fn name(&self) -> &str
+    {
+        "leveldb.BuiltinBloomFilter2"
+    }
+
+    fn create_filter(&self, keys: &[Slice], dst: &mut Vec<u8>)
+    {
+        // Compute bloom filter size (in both bits and bytes)
+        let mut bits = keys.len() * self.bits_per_key;
+
+        // For small n, we can see a very high false positive rate.  Fix it
+        // by enforcing a minimum bloom filter length.
+        if bits < 64 {
+            bits = 64;
+        }
+
+        let bytes = bits.div_ceil(8);
+        bits = bytes * 8;
+
+        let init_size = dst.len();
+        dst.resize(init_size + bytes, 0);
+        dst.push(self.k as u8);  // Remember # of probes in filter
+        let array = &mut dst[init_size..init_size + bytes];
+        for key in keys {
+            // Use double-hashing to generate a sequence of hash values.
+            // See analysis in [Kirsch,Mitzenmacher 2006].
+            let mut h = bloom_hash(key);
+            let delta = h.rotate_right(17);  // Rotate right 17 bits
+            for _ in 0..self.k {
+                let bitpos = h as usize % bits;
+                array[bitpos / 8] |= 1 << (bitpos % 8);
+                h = h.wrapping_add(delta);
+            }
+        }
+    }
+
+    fn key_may_match(&self, key: Slice, filter: Slice) -> bool
+    {
+        let len = filter.len();
+        if len < 2 {
+            return false;
+        }
+
+        let array = &filter[..len - 1];
+        let bits = (len - 1) * 8;
+
+        // Use the encoded k so that we can read filters generated by
+        // bloom filters created using different parameters.
+        let k = filter[len - 1];
+        if k > 30 {
+            // Reserved for potentially new encodings for short bloom filters.
+            // Consider it a match.
+            return true;
+        }
+
+        let mut h = bloom_hash(key);
+        let delta = h.rotate_right(17);  // Rotate right 17 bits
+        for _ in 0..k {
+            let bitpos = h as usize % bits;
+            if array[bitpos / 8] & (1 << (bitpos % 8)) == 0 {
+                return false;
+            }
+            h = h.wrapping_add(delta);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BloomFilterPolicy;
+    use ::filter_policy::FilterPolicy;
+    use ::util::coding;
+
+    fn key(i: u32) -> Vec<u8>
+    {
+        let mut key = vec![];
+        coding::put_fixed32(&mut key, i);
+        key
+    }
+
+    fn build_filter(policy: &BloomFilterPolicy, keys: &[Vec<u8>]) -> Vec<u8>
+    {
+        let keys: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        let mut filter = vec![];
+        policy.create_filter(&keys, &mut filter);
+        filter
+    }
+
+    fn false_positive_rate(policy: &BloomFilterPolicy, filter: &[u8]) -> f64
+    {
+        let mut result = 0;
+        for i in 0..10000 {
+            if policy.key_may_match(&key(i + 1000000000), filter) {
+                result += 1;
+            }
+        }
+        result as f64 / 10000.0
+    }
+
+    fn next_length(length: u32) -> u32
+    {
+        if length < 10 {
+            length + 1
+        } else if length < 100 {
+            length + 10
+        } else if length < 1000 {
+            length + 100
+        } else {
+            length + 1000
+        }
+    }
+
+    #[test]
+    fn empty_filter()
+    {
+        let policy = BloomFilterPolicy::new(10);
+        let filter = build_filter(&policy, &[]);
+        assert!(!policy.key_may_match(b"hello", &filter));
+        assert!(!policy.key_may_match(b"world", &filter));
+    }
+
+    #[test]
+    fn small()
+    {
+        let policy = BloomFilterPolicy::new(10);
+        let filter = build_filter(&policy, &[b"hello".to_vec(), b"world".to_vec()]);
+        assert!(policy.key_may_match(b"hello", &filter));
+        assert!(policy.key_may_match(b"world", &filter));
+        assert!(!policy.key_may_match(b"x", &filter));
+        assert!(!policy.key_may_match(b"foo", &filter));
+    }
+
+    #[test]
+    fn varying_lengths()
+    {
+        let policy = BloomFilterPolicy::new(10);
+
+        // Count number of filters that significantly exceed the false
+        // positive rate
+        let mut mediocre_filters = 0;
+        let mut good_filters = 0;
+
+        let mut length = 1;
+        while length <= 10000 {
+            let keys: Vec<Vec<u8>> = (0..length).map(key).collect();
+            let filter = build_filter(&policy, &keys);
+            assert!(filter.len() <= (length as usize * 10 / 8) + 40, "length {}", length);
+
+            // All added keys must match
+            for key in &keys {
+                assert!(policy.key_may_match(key, &filter), "length {}", length);
+            }
+
+            // Check false positive rate
+            let rate = false_positive_rate(&policy, &filter);
+            assert!(rate <= 0.02, "rate {} at length {}", rate, length);  // Must not be over 2%
+            if rate > 0.0125 {
+                mediocre_filters += 1;  // Allowed, but not too often
+            } else {
+                good_filters += 1;
+            }
+            length = next_length(length);
+        }
+        assert!(mediocre_filters <= good_filters / 5);
+    }
+
+    /// Filters are compatible with LevelDB, so they must keep their
+    /// name and their probe count byte.
+    #[test]
+    fn format()
+    {
+        let policy = BloomFilterPolicy::new(10);
+        assert_eq!(policy.name(), "leveldb.BuiltinBloomFilter2");
+        let filter = build_filter(&policy, &[b"hello".to_vec()]);
+        // 64 bits minimum, then k = 10 * 0.69 rounded down
+        assert_eq!(filter.len(), 8 + 1);
+        assert_eq!(filter[8], 6);
+    }
+}
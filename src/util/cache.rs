@@ -0,0 +1,288 @@
+//! A Cache is an interface that maps keys to values.  It has internal
+//! synchronization and may be safely accessed concurrently from
+//! multiple threads.  It may automatically evict entries to make room
+//! for new entries.  Values have a specified charge against the cache
+//! capacity.  For example, a cache where the values are variable
+//! length strings, may use the length of the string as the charge for
+//! the string.
+//!
+//! The cache is split into shards, each an LRU cache with its own lock
+//! and an equal share of the capacity, so that concurrent users rarely
+//! contend on the same lock.  Values are handed out as Arc<V>: an
+//! entry evicted from the cache stays alive for as long as a caller
+//! still holds it.
+
+use ::slice::Slice;
+use ::util::hash::hash;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const NUM_SHARD_BITS: usize = 4;
+const NUM_SHARDS: usize = 1 << NUM_SHARD_BITS;
+
+struct LRUEntry<V> {
+    value: Arc<V>,
+    charge: usize,
+    /// Position of the entry in the shard's recency order
+    last_use: u64,
+}
+
+/// A single shard of the sharded cache.
+struct LRUCache<V> {
+    /// Initialized before use.
+    capacity: usize,
+    usage: usize,
+    /// Incremented on every insert and lookup to order entries by
+    /// recency of use.
+    clock: u64,
+    table: HashMap<Vec<u8>, LRUEntry<V>>,
+    /// last_use -> key, oldest entry first
+    lru: BTreeMap<u64, Vec<u8>>,
+}
+
+impl<V> LRUCache<V> {
+    fn new(capacity: usize) -> LRUCache<V>
+    {
+        LRUCache {
+            capacity,
+            usage: 0,
+            clock: 0,
+            table: HashMap::new(),
+            lru: BTreeMap::new(),
+        }
+    }
+
+    fn insert(&mut self, key: Slice, value: V, charge: usize) -> Arc<V>
+    {
+        self.erase(key);
+
+        self.clock += 1;
+        let value = Arc::new(value);
+        self.table.insert(key.to_vec(), LRUEntry {
+            value: value.clone(),
+            charge,
+            last_use: self.clock,
+        });
+        self.lru.insert(self.clock, key.to_vec());
+        self.usage += charge;
+
+        while self.usage > self.capacity {
+            let oldest = match self.lru.keys().next() {
+                Some(last_use) => *last_use,
+                None => break,
+            };
+            let oldest_key = self.lru[&oldest].clone();
+            self.erase(&oldest_key);
+        }
+        value
+    }
+
+    fn lookup(&mut self, key: Slice) -> Option<Arc<V>>
+    {
+        self.clock += 1;
+        let clock = self.clock;
+        match self.table.get_mut(key) {
+            Some(entry) => {
+                let key = self.lru.remove(&entry.last_use).unwrap();
+                self.lru.insert(clock, key);
+                entry.last_use = clock;
+                Some(entry.value.clone())
+            },
+            None => None,
+        }
+    }
+
+    fn erase(&mut self, key: Slice)
+    {
+        if let Some(entry) = self.table.remove(key) {
+            self.lru.remove(&entry.last_use);
+            self.usage -= entry.charge;
+        }
+    }
+
+    fn prune(&mut self)
+    {
+        self.table.clear();
+        self.lru.clear();
+        self.usage = 0;
+    }
+}
+
+pub struct Cache<V> {
+    shards: Vec<Mutex<LRUCache<V>>>,
+    last_id: AtomicU64,
+}
+
+impl<V> Cache<V> {
+    /// Create a new cache with a fixed size capacity.  This
+    /// implementation of Cache uses a least-recently-used eviction
+    /// policy.
+    pub fn new(capacity: usize) -> Cache<V>
+    {
+        let per_shard = capacity.div_ceil(NUM_SHARDS);
+        Cache {
+            shards: (0..NUM_SHARDS).map(|_| Mutex::new(LRUCache::new(per_shard))).collect(),
+            last_id: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: Slice) -> &Mutex<LRUCache<V>>
+    {
+        let hash = hash(key, 0);
+        &self.shards[(hash >> (32 - NUM_SHARD_BITS)) as usize]
+    }
+
+    /// Insert a mapping from key->value into the cache and assign it
+    /// the specified charge against the total cache capacity.
+    ///
+    /// Returns the cached value.  It remains usable by the caller even
+    /// if the entry is evicted from the cache.
+    pub fn insert(&self, key: Slice, value: V, charge: usize) -> Arc<V>
+    {
+        self.shard(key).lock().unwrap().insert(key, value, charge)
+    }
+
+    /// If the cache has no mapping for "key", returns None.
+    ///
+    /// Else return the value for the mapping and mark it as the most
+    /// recently used entry.
+    pub fn lookup(&self, key: Slice) -> Option<Arc<V>>
+    {
+        self.shard(key).lock().unwrap().lookup(key)
+    }
+
+    /// If the cache contains entry for key, erase it.  Note that the
+    /// underlying entry will be kept around until all existing holders
+    /// of its value have dropped it.
+    pub fn erase(&self, key: Slice)
+    {
+        self.shard(key).lock().unwrap().erase(key)
+    }
+
+    /// Return a new numeric id.  May be used by multiple clients who are
+    /// sharing the same cache to partition the key space.  Typically the
+    /// client will allocate a new id at startup and prepend the id to
+    /// its cache keys.
+    pub fn new_id(&self) -> u64
+    {
+        self.last_id.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Remove all cache entries.  Memory-constrained applications may
+    /// wish to call this method to reduce memory usage.
+    pub fn prune(&self)
+    {
+        for shard in &self.shards {
+            shard.lock().unwrap().prune();
+        }
+    }
+
+    /// Return an estimate of the combined charges of all elements stored
+    /// in the cache.
+    pub fn total_charge(&self) -> usize
+    {
+        self.shards.iter().map(|shard| shard.lock().unwrap().usage).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cache, LRUCache};
+    use std::collections::HashSet;
+
+    #[test]
+    fn evicts_least_recently_inserted()
+    {
+        let mut cache = LRUCache::new(10);
+        cache.insert(b"a", 1, 4);
+        cache.insert(b"b", 2, 4);
+        assert_eq!(cache.usage, 8);
+
+        // Over capacity: the oldest entry makes room
+        cache.insert(b"c", 3, 4);
+        assert_eq!(cache.usage, 8);
+        assert!(cache.lookup(b"a").is_none());
+        assert_eq!(*cache.lookup(b"b").unwrap(), 2);
+        assert_eq!(*cache.lookup(b"c").unwrap(), 3);
+
+        // A heavy entry evicts as many entries as it needs to
+        cache.insert(b"d", 4, 9);
+        assert_eq!(cache.usage, 9);
+        assert!(cache.lookup(b"b").is_none());
+        assert!(cache.lookup(b"c").is_none());
+        assert_eq!(*cache.lookup(b"d").unwrap(), 4);
+    }
+
+    #[test]
+    fn lookup_refreshes_recency()
+    {
+        let mut cache = LRUCache::new(10);
+        cache.insert(b"a", 1, 4);
+        cache.insert(b"b", 2, 4);
+        assert!(cache.lookup(b"a").is_some());
+
+        cache.insert(b"c", 3, 4);
+        assert_eq!(*cache.lookup(b"a").unwrap(), 1);
+        assert!(cache.lookup(b"b").is_none());
+        assert_eq!(*cache.lookup(b"c").unwrap(), 3);
+    }
+
+    #[test]
+    fn reinsert_replaces_entry()
+    {
+        let mut cache = LRUCache::new(10);
+        cache.insert(b"a", 1, 4);
+        cache.insert(b"a", 2, 3);
+        assert_eq!(cache.usage, 3);
+        assert_eq!(*cache.lookup(b"a").unwrap(), 2);
+    }
+
+    #[test]
+    fn evicted_values_stay_alive()
+    {
+        let cache = Cache::new(0);
+        let value = cache.insert(b"a", vec![1, 2, 3], 3);
+        assert!(cache.lookup(b"a").is_none());
+        assert_eq!(cache.total_charge(), 0);
+        assert_eq!(*value, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn erase_and_prune()
+    {
+        let cache = Cache::new(1 << 20);
+        for i in 0..100u32 {
+            let key = format!("key{}", i);
+            cache.insert(key.as_bytes(), i, 10);
+        }
+        assert_eq!(cache.total_charge(), 1000);
+        for i in 0..100u32 {
+            let key = format!("key{}", i);
+            assert_eq!(*cache.lookup(key.as_bytes()).unwrap(), i);
+        }
+
+        cache.erase(b"key7");
+        assert!(cache.lookup(b"key7").is_none());
+        assert_eq!(cache.total_charge(), 990);
+        // Erasing a missing key is a no-op
+        cache.erase(b"key7");
+        assert_eq!(cache.total_charge(), 990);
+
+        let value = cache.lookup(b"key8").unwrap();
+        cache.prune();
+        assert_eq!(cache.total_charge(), 0);
+        assert!(cache.lookup(b"key8").is_none());
+        assert_eq!(*value, 8);
+    }
+
+    #[test]
+    fn new_id_is_unique()
+    {
+        let cache: Cache<()> = Cache::new(100);
+        let ids: HashSet<u64> = (0..1000).map(|_| cache.new_id()).collect();
+        assert_eq!(ids.len(), 1000);
+        // Zero is left for tables opened without a cache
+        assert!(!ids.contains(&0));
+    }
+}
@@ -3,7 +3,7 @@ use ::errors::RubbleResult;
 use ::status::Status;
 use ::port;
 use regex::Regex;
-use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian, LittleEndian};
+use byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt, BigEndian, LittleEndian};
 use std::io::Cursor;
 
 lazy_static! {
@@ -24,7 +24,7 @@ pub fn parse_u64(text: &str) -> RubbleResult<ParseU64Result>
     match REGEX_U64.captures(text).and_then(|c| c.at(0)) {
         Some(substring) => Ok(
             ParseU64Result{
-                number: try!(substring.parse()),
+                number: substring.parse()?,
                 offset: substring.len(),
             }
         ),
@@ -37,25 +37,36 @@ pub struct FallbackResult<'a> {
     pub value: u32,
 }
 
-/// I don't quite know, we're stepping through every 7 bytes in a
-/// block and doing some fancy shifting of the byte at that offset
-/// masked into a magic number...
+/// Decode a varint32 that did not fit the one-byte fast path.  The
+/// value is stored 7 bits at a time, least significant group first,
+/// with the high bit of each byte set iff more bytes follow.
+/// Returns the decoded value and the slice just past it.
 pub fn get_varint32_ptr_fallback(p: Slice) -> RubbleResult<FallbackResult>
 {
     let mut result = 0;
-    let mut p = p;
-    for shift in (0..5).map(|s| s * 7) {
-        let byte = p[shift];
-        p = &p[1..];
+    for (i, shift) in (0..5).map(|s| s * 7).enumerate() {
+        if i >= p.len() { break }
+        let byte = p[i] as u32;
         if byte & 128 != 0 {
             // More bytes are present
             result |= (byte & 127) << shift;
         } else {
             result |= byte << shift;
-            return Ok(FallbackResult{ slice: p, value: result as u32})
+            return Ok(FallbackResult{ slice: &p[i + 1..], value: result })
         }
     }
-    Err("missed the ptr fallback or something".into())
+    Err(Status::Corruption("bad varint32".into()).into())
+}
+
+#[inline(always)]
+pub fn decode_fixed16(slice: Slice) -> u16
+{
+    match port::ENDIANNESS {
+        port::Endian::Little =>
+            Cursor::new(slice[..2].to_vec()).read_u16::<LittleEndian>().unwrap(),
+        port::Endian::Big =>
+            Cursor::new(slice[..2].to_vec()).read_u16::<BigEndian>().unwrap(),
+    }
 }
 
 #[inline(always)]
@@ -74,12 +85,41 @@ pub fn decode_fixed64(slice: Slice) -> u64
 {
     match port::ENDIANNESS {
         port::Endian::Little =>
-            Cursor::new(slice[..4].to_vec()).read_u64::<LittleEndian>().unwrap(),
+            Cursor::new(slice[..8].to_vec()).read_u64::<LittleEndian>().unwrap(),
         port::Endian::Big =>
-            Cursor::new(slice[..4].to_vec()).read_u64::<BigEndian>().unwrap(),
+            Cursor::new(slice[..8].to_vec()).read_u64::<BigEndian>().unwrap(),
     }
 }
 
+/// Overwrite the first 4 bytes of "dst" with "value"
+#[inline(always)]
+pub fn encode_fixed32(dst: &mut [u8], value: u32)
+{
+    match port::ENDIANNESS {
+        port::Endian::Little => LittleEndian::write_u32(dst, value),
+        port::Endian::Big => BigEndian::write_u32(dst, value),
+    };
+}
+
+/// Overwrite the first 8 bytes of "dst" with "value"
+#[inline(always)]
+pub fn encode_fixed64(dst: &mut [u8], value: u64)
+{
+    match port::ENDIANNESS {
+        port::Endian::Little => LittleEndian::write_u64(dst, value),
+        port::Endian::Big => BigEndian::write_u64(dst, value),
+    };
+}
+
+#[inline(always)]
+pub fn put_fixed16(buff: &mut Vec<u8>, value: u16)
+{
+    match port::ENDIANNESS {
+        port::Endian::Little => buff.write_u16::<LittleEndian>(value).unwrap(),
+        port::Endian::Big => buff.write_u16::<BigEndian>(value).unwrap(),
+    };
+}
+
 #[inline(always)]
 pub fn put_fixed32(buff: &mut Vec<u8>, value: u32)
 {
@@ -113,22 +153,22 @@ pub fn put_varint32(buff: &mut Vec<u8>, v: u32) -> usize
     } else if v < 1 << 21 {
         buff.reserve(3);
         buff.push((v | b) as u8);
-        buff.push((v >> 7) as u8);
+        buff.push(((v >> 7) | b) as u8);
         buff.push((v >> 14) as u8);
         3
     } else if v < 1 << 28 {
         buff.reserve(4);
         buff.push((v | b) as u8);
-        buff.push((v >> 7) as u8);
-        buff.push((v >> 14) as u8);
+        buff.push(((v >> 7) | b) as u8);
+        buff.push(((v >> 14) | b) as u8);
         buff.push((v >> 21) as u8);
         4
     } else {
         buff.reserve(5);
         buff.push((v | b) as u8);
-        buff.push((v >> 7) as u8);
-        buff.push((v >> 14) as u8);
-        buff.push((v >> 21) as u8);
+        buff.push(((v >> 7) | b) as u8);
+        buff.push(((v >> 14) | b) as u8);
+        buff.push(((v >> 21) | b) as u8);
         buff.push((v >> 28) as u8);
         5
     }
@@ -145,74 +185,171 @@ pub fn put_varint64(buff: &mut Vec<u8>, mut v: u64) -> usize
         v >>= 7;
         bytes += 1;
     }
-    bytes
-}
-
-// void PutLengthPrefixedSlice(std::string* dst, const Slice& value) {
-//   PutVarint32(dst, value.size());
-//   dst->append(value.data(), value.size());
-// }
-
-// int VarintLength(uint64_t v) {
-//   int len = 1;
-//   while (v >= 128) {
-//     v >>= 7;
-//     len++;
-//   }
-//   return len;
-// }
-
-// bool GetVarint32(Slice* input, uint32_t* value) {
-//   const char* p = input->data();
-//   const char* limit = p + input->size();
-//   const char* q = GetVarint32Ptr(p, limit, value);
-//   if (q == NULL) {
-//     return false;
-//   } else {
-//     *input = Slice(q, limit - q);
-//     return true;
-//   }
-// }
+    buff.push(v as u8);
+    bytes + 1
+}
+
+pub fn put_length_prefixed_slice(buff: &mut Vec<u8>, value: Slice)
+{
+    put_varint32(buff, value.len() as u32);
+    buff.extend_from_slice(value);
+}
+
+/// Returns the length of the varint32 or varint64 encoding of "v"
+pub fn varint_length(mut v: u64) -> usize
+{
+    let mut len = 1;
+    while v >= 128 {
+        v >>= 7;
+        len += 1;
+    }
+    len
+}
+
+/// Returns (remaining slice, u32 result)
+pub fn get_varint32(slice: Slice) -> RubbleResult<(Slice, u32)>
+{
+    match slice.first() {
+        Some(&byte) if byte & 128 == 0 => Ok((&slice[1..], byte as u32)),
+        _ => {
+            let result = get_varint32_ptr_fallback(slice)?;
+            Ok((result.slice, result.value))
+        },
+    }
+}
 
 /// Returns (remaining slice, u64 result)
 pub fn get_varint64(slice: Slice) -> RubbleResult<(Slice, u64)>
 {
     let mut result: u64 = 0;
-    let mut p = 0;
-    for shift in (0..10).map(|n| n*7) {
+    for (p, shift) in (0..10).map(|n| n*7).enumerate() {
         if p >= slice.len() { break }
         let byte = slice[p] as u64;
-        p += 1;
         if byte & 128 != 0 {
             // More bytes are present
             result |= (byte & 127) << shift;
         } else {
             result |= byte << shift;
-            return Ok((&slice[p..], result));
+            return Ok((&slice[p + 1..], result));
         }
     }
     Err(Status::IOError("Unable to read varin64".into()).into())
 }
 
 
-// const char* GetLengthPrefixedSlice(const char* p, const char* limit,
-//                                    Slice* result) {
-//   uint32_t len;
-//   p = GetVarint32Ptr(p, limit, &len);
-//   if (p == NULL) return NULL;
-//   if (p + len > limit) return NULL;
-//   *result = Slice(p, len);
-//   return p + len;
-// }
-
-// bool GetLengthPrefixedSlice(Slice* input, Slice* result) {
-//   uint32_t len;
-//   if (GetVarint32(input, &len) &&
-//       input->size() >= len) {
-//     *result = Slice(input->data(), len);
-//     input->remove_prefix(len);
-//     return true;
-//   } else {
-//     return false;
-//   }
-// }
+/// Returns (remaining slice, result), where result is the slice of
+/// the length given by the varint32 at the start of "slice".
+pub fn get_length_prefixed_slice(slice: Slice) -> RubbleResult<(Slice, Slice)>
+{
+    let (rest, len) = get_varint32(slice)?;
+    let len = len as usize;
+    if rest.len() < len {
+        return Err(Status::Corruption("bad length prefixed slice".into()).into());
+    }
+    Ok((&rest[len..], &rest[..len]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Values at and around every length boundary of the varint32
+    /// encoding.  Doubling each boundary clears the bit that would
+    /// otherwise set the continuation bit of a byte by accident.
+    fn varint32_boundaries() -> Vec<u32>
+    {
+        let mut values = vec![0, u32::MAX];
+        for shift in &[7, 14, 21, 28] {
+            let boundary = 1u32 << shift;
+            values.extend_from_slice(&[boundary - 1, boundary, boundary + 1, boundary << 1]);
+        }
+        values
+    }
+
+    #[test]
+    fn varint32_round_trip()
+    {
+        for v in varint32_boundaries() {
+            let mut buff = vec![];
+            let len = put_varint32(&mut buff, v);
+            assert_eq!(len, buff.len());
+            assert_eq!(len, varint_length(v as u64));
+            // Every byte but the last has its continuation bit set.
+            for (i, byte) in buff.iter().enumerate() {
+                assert_eq!(byte & 128 != 0, i + 1 < len, "bad continuation bit for {}", v);
+            }
+
+            buff.push(0xab);
+            let (rest, decoded) = get_varint32(&buff).unwrap();
+            assert_eq!(decoded, v);
+            assert_eq!(rest, &[0xab]);
+        }
+    }
+
+    #[test]
+    fn varint32_sequence()
+    {
+        let values = varint32_boundaries();
+        let mut buff = vec![];
+        for &v in &values {
+            put_varint32(&mut buff, v);
+        }
+        let mut input = &buff[..];
+        for &v in &values {
+            let (rest, decoded) = get_varint32(input).unwrap();
+            assert_eq!(decoded, v);
+            input = rest;
+        }
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn varint32_truncated()
+    {
+        let mut buff = vec![];
+        put_varint32(&mut buff, 1 << 21);
+        for len in 0..buff.len() {
+            assert!(get_varint32(&buff[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn varint64_round_trip()
+    {
+        let mut values = vec![0, u64::MAX];
+        for shift in (7..64).step_by(7) {
+            let boundary = 1u64 << shift;
+            values.extend_from_slice(&[boundary - 1, boundary, boundary + 1]);
+        }
+        for v in values {
+            let mut buff = vec![];
+            let len = put_varint64(&mut buff, v);
+            assert_eq!(len, buff.len());
+            assert_eq!(len, varint_length(v));
+            let (rest, decoded) = get_varint64(&buff).unwrap();
+            assert_eq!(decoded, v);
+            assert!(rest.is_empty());
+        }
+    }
+
+    #[test]
+    fn length_prefixed_slice()
+    {
+        let long = vec![b'x'; 1 << 14];
+        let mut buff = vec![];
+        put_length_prefixed_slice(&mut buff, b"");
+        put_length_prefixed_slice(&mut buff, b"foo");
+        put_length_prefixed_slice(&mut buff, &long);
+
+        let (rest, empty) = get_length_prefixed_slice(&buff).unwrap();
+        assert_eq!(empty, b"");
+        let (rest, foo) = get_length_prefixed_slice(rest).unwrap();
+        assert_eq!(foo, b"foo");
+        let (rest, decoded) = get_length_prefixed_slice(rest).unwrap();
+        assert_eq!(decoded, &long[..]);
+        assert!(rest.is_empty());
+
+        // The long slice starts after "" (1 byte) and "foo" (4 bytes).
+        assert!(get_length_prefixed_slice(&buff[5..buff.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,377 @@
+//! Block compression codecs.
+//!
+//! Every block in a table is followed by a trailer whose first byte
+//! records how the block was compressed (see table/format.rs).  A
+//! CodecRegistry maps those type bytes to the codecs that produce and
+//! consume them.  It comes with codecs for the builtin compression
+//! types, and others may be registered under new type bytes.
+//!
+//! LZ4 and Zstd blocks are stored the way RocksDB stores them: the
+//! length of the uncompressed data as a varint32, followed by the
+//! compressed data.
+
+use ::errors::RubbleResult;
+use ::options::CompressionType;
+use ::slice::Slice;
+use ::status::Status;
+use ::util::coding;
+use lz4;
+use snap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use zstd;
+
+/// A Codec compresses and uncompresses the contents of blocks.  A Codec
+/// implementation must be thread-safe since it may be used by several
+/// tables at once.
+pub trait Codec: Send + Sync {
+    /// The type byte stored in the trailer of blocks compressed by this
+    /// codec.  Must not be CompressionType::NoCompression.
+    fn block_type(&self) -> u8;
+
+    /// The name of the codec, for diagnostics.
+    fn name(&self) -> &str;
+
+    /// Compress "input" and return the compressed form.  "level" is the
+    /// codec-specific compression level; None selects the codec's
+    /// default.  Codecs clamp levels to the range they support, and
+    /// codecs without levels ignore it.
+    fn compress(&self, input: Slice, level: Option<i32>) -> RubbleResult<Vec<u8>>;
+
+    /// Uncompress "input", which was produced by compress().
+    fn uncompress(&self, input: Slice) -> RubbleResult<Vec<u8>>;
+}
+
+fn corrupted() -> ::errors::RubbleError
+{
+    Status::Corruption("corrupted compressed block contents".into()).into()
+}
+
+/// Prefix "compressed" with the varint32 length of the uncompressed data.
+fn with_size_prefix(uncompressed_len: usize, compressed: Slice) -> Vec<u8>
+{
+    let mut output = Vec::with_capacity(compressed.len() + 5);
+    coding::put_varint32(&mut output, uncompressed_len as u32);
+    output.extend_from_slice(compressed);
+    output
+}
+
+/// Split off the varint32 length prefix written by with_size_prefix().
+/// The prefix is checked against "max_ratio", the most the codec can
+/// expand a byte of compressed data, so that a corrupt prefix cannot
+/// make us allocate more than the data could ever uncompress to.
+fn split_size_prefix(input: Slice, max_ratio: usize) -> RubbleResult<(usize, Slice)>
+{
+    let (size, compressed) = match coding::get_varint32_ptr_fallback(input) {
+        Ok(result) => (result.value as usize, result.slice),
+        Err(_) => return Err(corrupted()),
+    };
+    if size > compressed.len().saturating_mul(max_ratio) {
+        return Err(corrupted());
+    }
+    Ok((size, compressed))
+}
+
+pub struct SnappyCodec;
+
+/// Snappy spends at least 3 bytes (a copy with a 2-byte offset) per 64
+/// bytes of output
+const SNAPPY_MAX_RATIO: usize = 22;
+
+impl Codec for SnappyCodec {
+    fn block_type(&self) -> u8 { CompressionType::SnappyCompression as u8 }
+    fn name(&self) -> &str { "Snappy" }
+
+    fn compress(&self, input: Slice, level: Option<i32>) -> RubbleResult<Vec<u8>>
+    {
+        snap::raw::Encoder::new().compress_vec(input)
+            .or(Err(Status::NotSupported("snappy compression failed".into()).into()))
+    }
+
+    fn uncompress(&self, input: Slice) -> RubbleResult<Vec<u8>>
+    {
+        // Check the length Snappy records like split_size_prefix() does,
+        // since the decoder allocates that much up front.
+        let size = snap::raw::decompress_len(input).or(Err(corrupted()))?;
+        if size > input.len().saturating_mul(SNAPPY_MAX_RATIO) {
+            return Err(corrupted());
+        }
+        snap::raw::Decoder::new().decompress_vec(input).or(Err(corrupted()))
+    }
+}
+
+/// LZ4 block compression.  With a level, the high compression variant
+/// (LZ4HC) is used at that level, clamped to 1-12.  Both produce the
+/// same format.
+pub struct Lz4Codec;
+
+/// Highest compression level of LZ4HC
+const LZ4HC_MAX_LEVEL: i32 = 12;
+
+/// LZ4 spends at least one byte per 255 bytes of a match or literal run
+const LZ4_MAX_RATIO: usize = 255;
+
+impl Codec for Lz4Codec {
+    fn block_type(&self) -> u8 { CompressionType::Lz4Compression as u8 }
+    fn name(&self) -> &str { "LZ4" }
+
+    fn compress(&self, input: Slice, level: Option<i32>) -> RubbleResult<Vec<u8>>
+    {
+        let mode = match level {
+            // LZ4HC's optimal parser (levels 10 and up) crashes on empty
+            // input.  The fast compressor produces the same format.
+            Some(_) if input.is_empty() => lz4::block::CompressionMode::DEFAULT,
+            Some(level) => {
+                lz4::block::CompressionMode::HIGHCOMPRESSION(level.clamp(1, LZ4HC_MAX_LEVEL))
+            },
+            None => lz4::block::CompressionMode::DEFAULT,
+        };
+        let compressed = lz4::block::compress(input, Some(mode), false)?;
+        Ok(with_size_prefix(input.len(), &compressed))
+    }
+
+    fn uncompress(&self, input: Slice) -> RubbleResult<Vec<u8>>
+    {
+        let (size, compressed) = split_size_prefix(input, LZ4_MAX_RATIO)?;
+        // The LZ4 library takes sizes as an i32
+        if size > i32::MAX as usize {
+            return Err(corrupted());
+        }
+        let output = lz4::block::decompress(compressed, Some(size as i32)).or(Err(corrupted()))?;
+        if output.len() != size {
+            return Err(corrupted());
+        }
+        Ok(output)
+    }
+}
+
+/// Zstandard compression.  Levels range from 1 (fastest) to 22
+/// (smallest output), and negative levels trade even more ratio for
+/// speed; the default is 3.  Levels past either end are clamped.
+pub struct ZstdCodec;
+
+/// Zstd spends at least 4 bytes (an RLE block) per 128KB of output
+const ZSTD_MAX_RATIO: usize = (128 << 10) / 4;
+
+impl Codec for ZstdCodec {
+    fn block_type(&self) -> u8 { CompressionType::ZstdCompression as u8 }
+    fn name(&self) -> &str { "ZSTD" }
+
+    fn compress(&self, input: Slice, level: Option<i32>) -> RubbleResult<Vec<u8>>
+    {
+        let range = zstd::compression_level_range();
+        let level = level.map_or(zstd::DEFAULT_COMPRESSION_LEVEL,
+                                 |level| level.clamp(*range.start(), *range.end()));
+        let compressed = zstd::bulk::compress(input, level)?;
+        Ok(with_size_prefix(input.len(), &compressed))
+    }
+
+    fn uncompress(&self, input: Slice) -> RubbleResult<Vec<u8>>
+    {
+        let (size, compressed) = split_size_prefix(input, ZSTD_MAX_RATIO)?;
+        let output = zstd::bulk::decompress(compressed, size).or(Err(corrupted()))?;
+        if output.len() != size {
+            return Err(corrupted());
+        }
+        Ok(output)
+    }
+}
+
+/// The set of codecs available to read and write tables, keyed by the
+/// block type byte each one handles.
+pub struct CodecRegistry {
+    codecs: HashMap<u8, Arc<dyn Codec>>,
+}
+
+impl CodecRegistry {
+    /// Create a registry holding the codecs for the builtin compression
+    /// types.
+    pub fn new() -> CodecRegistry
+    {
+        let mut registry = CodecRegistry::empty();
+        registry.register(Arc::new(SnappyCodec));
+        registry.register(Arc::new(Lz4Codec));
+        registry.register(Arc::new(ZstdCodec));
+        registry
+    }
+
+    /// Create a registry without any codecs.  Only uncompressed blocks
+    /// can be read or written through it.
+    pub fn empty() -> CodecRegistry
+    {
+        CodecRegistry {
+            codecs: HashMap::new(),
+        }
+    }
+
+    /// Register "codec" for its block type, replacing any codec
+    /// previously registered for that type.
+    pub fn register(&mut self, codec: Arc<dyn Codec>)
+    {
+        assert!(codec.block_type() != CompressionType::NoCompression as u8);
+        self.codecs.insert(codec.block_type(), codec);
+    }
+
+    /// Return the codec for "block_type", if one is registered.
+    pub fn get(&self, block_type: u8) -> Option<&Arc<dyn Codec>>
+    {
+        self.codecs.get(&block_type)
+    }
+
+    /// Uncompress the contents of a block stored with "block_type".
+    pub fn uncompress(&self, block_type: u8, input: Slice) -> RubbleResult<Vec<u8>>
+    {
+        match self.get(block_type) {
+            Some(codec) => codec.uncompress(input),
+            None => Err(Status::Corruption("Bad block type".into()).into()),
+        }
+    }
+}
+
+impl Default for CodecRegistry {
+    fn default() -> CodecRegistry
+    {
+        CodecRegistry::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Codec, CodecRegistry, Lz4Codec, SnappyCodec, ZstdCodec, LZ4_MAX_RATIO,
+                SNAPPY_MAX_RATIO, ZSTD_MAX_RATIO};
+    use ::options::CompressionType;
+    use ::status::Status;
+    use ::util::coding;
+    use ::util::random::Random;
+
+    /// Inputs whose lengths need 1- to 4-byte varint32 size prefixes,
+    /// half random and half repetitive.
+    fn inputs() -> Vec<Vec<u8>>
+    {
+        let mut rnd = Random::new(301);
+        let mut inputs = vec![];
+        for &len in &[0, 1, 100, 1 << 14, (1 << 14) + 1, 1 << 15, 1 << 21] {
+            let random: Vec<u8> = (0..len).map(|_| b' ' + rnd.uniform(95) as u8).collect();
+            let repetitive: Vec<u8> = (0..len).map(|i| b'a' + (i / 100 % 26) as u8).collect();
+            inputs.push(random);
+            inputs.push(repetitive);
+        }
+        inputs
+    }
+
+    fn check_round_trip(codec: &dyn Codec, level: Option<i32>)
+    {
+        // Only the default level is tried on the largest inputs, since
+        // the strongest levels are slow.
+        let inputs = inputs().into_iter().filter(|input| level.is_none() || input.len() < 1 << 21);
+        for input in inputs {
+            let compressed = codec.compress(&input, level).unwrap();
+            assert_eq!(codec.uncompress(&compressed).unwrap(), input,
+                       "{} at level {:?}, {} bytes", codec.name(), level, input.len());
+        }
+    }
+
+    /// LZ4 and Zstd blocks start with the varint32 length of the
+    /// uncompressed data.
+    fn check_size_prefix(codec: &dyn Codec)
+    {
+        for input in inputs() {
+            let compressed = codec.compress(&input, None).unwrap();
+            let (_, size) = coding::get_varint32(&compressed).unwrap();
+            assert_eq!(size as usize, input.len());
+        }
+    }
+
+    fn check_corruption(codec: &dyn Codec)
+    {
+        let input = &inputs()[9];
+        let compressed = codec.compress(input, None).unwrap();
+        assert!(codec.uncompress(&compressed[..compressed.len() / 2]).is_err());
+        assert!(codec.uncompress(b"").is_err());
+
+        // A size prefix that disagrees with the data
+        let mut bad_size = vec![];
+        coding::put_varint32(&mut bad_size, input.len() as u32 + 1);
+        let prefix_len = coding::varint_length(input.len() as u64);
+        bad_size.extend_from_slice(&compressed[prefix_len..]);
+        assert!(codec.uncompress(&bad_size).is_err());
+    }
+
+    /// The most compressible data stays within the size cap, and a size
+    /// prefix past the cap is rejected before anything is allocated.
+    fn check_size_cap(codec: &dyn Codec, max_ratio: usize)
+    {
+        let zeros = vec![0; 1 << 21];
+        let compressed = codec.compress(&zeros, None).unwrap();
+        assert_eq!(codec.uncompress(&compressed).unwrap(), zeros);
+
+        let prefix_len = coding::varint_length(zeros.len() as u64);
+        let data = &compressed[prefix_len..];
+        for &size in &[data.len() * max_ratio + 1, u32::MAX as usize] {
+            let mut bad_size = vec![];
+            coding::put_varint32(&mut bad_size, size as u32);
+            bad_size.extend_from_slice(data);
+            let status: Status = codec.uncompress(&bad_size).unwrap_err().into();
+            assert_eq!(status, Status::Corruption("corrupted compressed block contents".into()));
+        }
+    }
+
+    #[test]
+    fn snappy()
+    {
+        check_round_trip(&SnappyCodec, None);
+        assert!(SnappyCodec.uncompress(b"\xff\xff\xff").is_err());
+        check_size_cap(&SnappyCodec, SNAPPY_MAX_RATIO);
+    }
+
+    #[test]
+    fn lz4()
+    {
+        check_round_trip(&Lz4Codec, None);
+        for &level in &[-5, 0, 1, 9, 12, 100] {
+            check_round_trip(&Lz4Codec, Some(level));
+        }
+        check_size_prefix(&Lz4Codec);
+        check_corruption(&Lz4Codec);
+        check_size_cap(&Lz4Codec, LZ4_MAX_RATIO);
+
+        // Sizes within the cap but past what the LZ4 library takes
+        let size = i32::MAX as usize + 1;
+        let mut too_large = vec![];
+        coding::put_varint32(&mut too_large, size as u32);
+        too_large.resize(too_large.len() + size / LZ4_MAX_RATIO + 1, 0);
+        let status: Status = Lz4Codec.uncompress(&too_large).unwrap_err().into();
+        assert_eq!(status, Status::Corruption("corrupted compressed block contents".into()));
+    }
+
+    #[test]
+    fn zstd()
+    {
+        check_round_trip(&ZstdCodec, None);
+        for &level in &[-1000000, -1, 1, 3, 22, 100] {
+            check_round_trip(&ZstdCodec, Some(level));
+        }
+        check_size_prefix(&ZstdCodec);
+        check_corruption(&ZstdCodec);
+        check_size_cap(&ZstdCodec, ZSTD_MAX_RATIO);
+    }
+
+    #[test]
+    fn registry()
+    {
+        let registry = CodecRegistry::new();
+        for &compression in &[CompressionType::SnappyCompression,
+                              CompressionType::Lz4Compression,
+                              CompressionType::ZstdCompression] {
+            let codec = registry.get(compression as u8).unwrap();
+            assert_eq!(codec.block_type(), compression as u8);
+            let compressed = codec.compress(b"registered codec", None).unwrap();
+            assert_eq!(registry.uncompress(compression as u8, &compressed).unwrap(),
+                       b"registered codec");
+        }
+        assert!(registry.get(CompressionType::NoCompression as u8).is_none());
+        assert!(registry.uncompress(0x7f, b"unknown").is_err());
+        assert!(CodecRegistry::empty().uncompress(CompressionType::SnappyCompression as u8, b"")
+                .is_err());
+    }
+}
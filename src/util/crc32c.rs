@@ -0,0 +1,112 @@
+//! A port of LevelDB's crc32c: the CRC-32C (Castagnoli) checksum used
+//! to protect blocks and log records on disk.
+
+use ::slice::Slice;
+
+/// Reversed representation of the Castagnoli polynomial 0x1EDC6F41
+const POLYNOMIAL: u32 = 0x82f63b78;
+
+const MASK_DELTA: u32 = 0xa282ead8;
+
+lazy_static! {
+    static ref TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut crc = i as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 };
+            }
+            *entry = crc;
+        }
+        table
+    };
+}
+
+/// Return the crc32c of concat(A, data) where init_crc is the
+/// crc32c of some string A.  extend() is often used to maintain the
+/// crc32c of a stream of data.
+pub fn extend(init_crc: u32, data: Slice) -> u32
+{
+    let table = &*TABLE;
+    let mut crc = init_crc ^ 0xffffffff;
+    for byte in data {
+        crc = table[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc ^ 0xffffffff
+}
+
+/// Return the crc32c of data
+pub fn value(data: Slice) -> u32
+{
+    extend(0, data)
+}
+
+/// Return a masked representation of crc.
+///
+/// Motivation: it is problematic to compute the CRC of a string that
+/// contains embedded CRCs.  Therefore we recommend that CRCs stored
+/// somewhere (e.g., in files) should be masked before being stored.
+pub fn mask(crc: u32) -> u32
+{
+    // Rotate right by 15 bits and add a constant.
+    crc.rotate_right(15).wrapping_add(MASK_DELTA)
+}
+
+/// Return the crc whose masked representation is masked_crc.
+pub fn unmask(masked_crc: u32) -> u32
+{
+    masked_crc.wrapping_sub(MASK_DELTA).rotate_left(15)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extend, mask, unmask, value};
+
+    #[test]
+    fn standard_results()
+    {
+        // From rfc3720 section B.4.
+        assert_eq!(value(&[0; 32]), 0x8a9136aa);
+        assert_eq!(value(&[0xff; 32]), 0x62a8ab43);
+
+        let ascending: Vec<u8> = (0..32).collect();
+        assert_eq!(value(&ascending), 0x46dd794e);
+
+        let descending: Vec<u8> = (0..32).rev().collect();
+        assert_eq!(value(&descending), 0x113fdb5c);
+
+        let data = [
+            0x01, 0xc0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00,
+            0x00, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0x18,
+            0x28, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(value(&data), 0xd9963a56);
+
+        assert_eq!(value(b"123456789"), 0xe3069283);
+    }
+
+    #[test]
+    fn values()
+    {
+        assert_ne!(value(b"a"), value(b"foo"));
+    }
+
+    #[test]
+    fn extends()
+    {
+        assert_eq!(value(b"hello world"), extend(value(b"hello "), b"world"));
+    }
+
+    #[test]
+    fn masks()
+    {
+        let crc = value(b"foo");
+        assert_ne!(crc, mask(crc));
+        assert_ne!(crc, mask(mask(crc)));
+        assert_eq!(crc, unmask(mask(crc)));
+        assert_eq!(crc, unmask(unmask(mask(mask(crc)))));
+    }
+}
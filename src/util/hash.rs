@@ -0,0 +1,32 @@
+use ::slice::Slice;
+use ::util::coding;
+
+/// Simple hash function used for internal data structures.  Similar
+/// to murmur hash.  The result is part of the persistent format of
+/// bloom filters, so it must not change.
+pub fn hash(data: Slice, seed: u32) -> u32
+{
+    const M: u32 = 0xc6a4a793;
+    const R: u32 = 24;
+    let mut h = seed ^ (data.len() as u32).wrapping_mul(M);
+
+    // Pick up four bytes at a time
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let w = coding::decode_fixed32(chunk);
+        h = h.wrapping_add(w);
+        h = h.wrapping_mul(M);
+        h ^= h >> 16;
+    }
+
+    // Pick up remaining bytes
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, byte) in rest.iter().enumerate() {
+            h = h.wrapping_add((*byte as u32) << (8 * i));
+        }
+        h = h.wrapping_mul(M);
+        h ^= h >> R;
+    }
+    h
+}
@@ -1 +1,8 @@
+pub mod arena;
+pub mod bloom;
+pub mod cache;
+pub mod compression;
 pub mod coding;
+pub mod crc32c;
+pub mod hash;
+pub mod random;
@@ -0,0 +1,468 @@
+//! WriteBatch holds a collection of updates to apply atomically to a DB.
+//!
+//! The updates are applied in the order in which they are added to the
+//! WriteBatch.  For example, the value of "key" will be "v3" after the
+//! following batch is written:
+//!     batch.put(b"key", b"v1");
+//!     batch.delete(b"key");
+//!     batch.put(b"key", b"v2");
+//!     batch.put(b"key", b"v3");
+//!
+//! WriteBatch::rep :=
+//!    sequence: fixed64
+//!    count: fixed32
+//!    data: record[count]
+//! record :=
+//!    ValueType::Value varstring varstring         |
+//!    ValueType::Deletion varstring
+//! varstring :=
+//!    len: varint32
+//!    data: uint8[len]
+//!
+//! The rep is what gets written to the log, so a batch read back from
+//! the log can be replayed into a memtable with insert_into().
+
+use ::dbformat::{SequenceNumber, ValueType};
+use ::errors::RubbleResult;
+use ::memtable::MemTable;
+use ::slice::Slice;
+use ::status::Status;
+use ::util::coding;
+
+/// WriteBatch header has an 8-byte sequence number followed by a 4-byte
+/// count.
+pub const HEADER: usize = 12;
+
+/// Receives the updates of a WriteBatch from WriteBatch::iterate().
+pub trait Handler {
+    fn put(&mut self, key: Slice, value: Slice);
+    fn delete(&mut self, key: Slice);
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WriteBatch {
+    rep: Vec<u8>,
+}
+
+impl Default for WriteBatch {
+    fn default() -> WriteBatch
+    {
+        WriteBatch::new()
+    }
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch
+    {
+        WriteBatch {
+            rep: vec![0; HEADER],
+        }
+    }
+
+    /// Store the mapping "key->value" in the database.
+    pub fn put(&mut self, key: Slice, value: Slice)
+    {
+        let count = self.count();
+        self.set_count(count + 1);
+        self.rep.push(ValueType::Value as u8);
+        coding::put_length_prefixed_slice(&mut self.rep, key);
+        coding::put_length_prefixed_slice(&mut self.rep, value);
+    }
+
+    /// If the database contains a mapping for "key", erase it.  Else do
+    /// nothing.
+    pub fn delete(&mut self, key: Slice)
+    {
+        let count = self.count();
+        self.set_count(count + 1);
+        self.rep.push(ValueType::Deletion as u8);
+        coding::put_length_prefixed_slice(&mut self.rep, key);
+    }
+
+    /// Clear all updates buffered in this batch.
+    pub fn clear(&mut self)
+    {
+        self.rep.clear();
+        self.rep.resize(HEADER, 0);
+    }
+
+    /// The size of the database changes caused by this batch.
+    ///
+    /// This number is tied to implementation details, and may change
+    /// across releases.  It is intended for usage metrics.
+    pub fn approximate_size(&self) -> usize
+    {
+        self.rep.len()
+    }
+
+    /// Copies the operations in "source" to this batch.
+    ///
+    /// This runs in O(source size) time.  However, the constant factor
+    /// is better than calling iterate() over the source batch with a
+    /// Handler that replicates the operations into this batch.
+    pub fn append(&mut self, source: &WriteBatch)
+    {
+        assert!(source.rep.len() >= HEADER);
+        let count = self.count() + source.count();
+        self.set_count(count);
+        self.rep.extend_from_slice(&source.rep[HEADER..]);
+    }
+
+    /// Support for iterating over the contents of a batch.  Returns
+    /// Status::Corruption if the batch is malformed.
+    pub fn iterate(&self, handler: &mut dyn Handler) -> RubbleResult<()>
+    {
+        if self.rep.len() < HEADER {
+            return Err(Status::Corruption("malformed WriteBatch (too small)".into()).into());
+        }
+
+        let mut input = &self.rep[HEADER..];
+        let mut found = 0;
+        while !input.is_empty() {
+            found += 1;
+            let tag = input[0];
+            input = &input[1..];
+            match ValueType::from_u8(tag) {
+                Some(ValueType::Value) => {
+                    let key_value = coding::get_length_prefixed_slice(input).and_then(|(rest, key)| {
+                        coding::get_length_prefixed_slice(rest).map(|(rest, value)| (rest, key, value))
+                    });
+                    match key_value {
+                        Ok((rest, key, value)) => {
+                            handler.put(key, value);
+                            input = rest;
+                        },
+                        Err(_) => return Err(Status::Corruption("bad WriteBatch Put".into()).into()),
+                    }
+                },
+                Some(ValueType::Deletion) => {
+                    match coding::get_length_prefixed_slice(input) {
+                        Ok((rest, key)) => {
+                            handler.delete(key);
+                            input = rest;
+                        },
+                        Err(_) => return Err(Status::Corruption("bad WriteBatch Delete".into()).into()),
+                    }
+                },
+                None => return Err(Status::Corruption("unknown WriteBatch tag".into()).into()),
+            }
+        }
+        if found != self.count() {
+            Err(Status::Corruption("WriteBatch has wrong count".into()).into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Return the number of entries in the batch.
+    pub fn count(&self) -> u32
+    {
+        coding::decode_fixed32(&self.rep[8..])
+    }
+
+    /// Set the count for the number of entries in the batch.
+    pub fn set_count(&mut self, n: u32)
+    {
+        coding::encode_fixed32(&mut self.rep[8..], n)
+    }
+
+    /// Return the sequence number for the start of this batch.
+    pub fn sequence(&self) -> SequenceNumber
+    {
+        coding::decode_fixed64(&self.rep)
+    }
+
+    /// Store the specified number as the sequence number for the start
+    /// of this batch.
+    pub fn set_sequence(&mut self, seq: SequenceNumber)
+    {
+        coding::encode_fixed64(&mut self.rep, seq)
+    }
+
+    /// The encoded batch, as written to the log.
+    pub fn contents(&self) -> Slice<'_>
+    {
+        &self.rep
+    }
+
+    /// Replace the contents of the batch with "contents", a batch read
+    /// back from the log.  Returns Status::Corruption, leaving the batch
+    /// alone, if "contents" is too small to hold the header; the updates
+    /// are only checked by iterate().
+    pub fn set_contents(&mut self, contents: Slice) -> RubbleResult<()>
+    {
+        if contents.len() < HEADER {
+            return Err(Status::Corruption("malformed WriteBatch (too small)".into()).into());
+        }
+        self.rep.clear();
+        self.rep.extend_from_slice(contents);
+        Ok(())
+    }
+
+    /// Apply the updates of the batch to "memtable", numbering them from
+    /// sequence().
+    pub fn insert_into(&self, memtable: &mut MemTable) -> RubbleResult<()>
+    {
+        let mut inserter = MemTableInserter {
+            sequence: self.sequence(),
+            mem: memtable,
+        };
+        self.iterate(&mut inserter)
+    }
+}
+
+struct MemTableInserter<'a> {
+    sequence: SequenceNumber,
+    mem: &'a mut MemTable,
+}
+
+impl<'a> Handler for MemTableInserter<'a> {
+    fn put(&mut self, key: Slice, value: Slice)
+    {
+        self.mem.add(self.sequence, ValueType::Value, key, value);
+        self.sequence += 1;
+    }
+
+    fn delete(&mut self, key: Slice)
+    {
+        self.mem.add(self.sequence, ValueType::Deletion, key, &[]);
+        self.sequence += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Handler, WriteBatch, HEADER};
+    use ::comparator::BytewiseComparator;
+    use ::dbformat::{self, InternalKeyComparator, ValueType};
+    use ::memtable::MemTable;
+    use ::slice::Slice;
+    use ::status::Status;
+    use ::table::iterator::RubbleIterator;
+    use ::util::coding;
+    use std::str;
+    use std::sync::Arc;
+
+    /// Records the updates of a batch as text
+    #[derive(Default)]
+    struct Recorder {
+        updates: String,
+    }
+
+    impl Handler for Recorder {
+        fn put(&mut self, key: Slice, value: Slice)
+        {
+            self.updates.push_str(&format!("Put({}, {})", str::from_utf8(key).unwrap(),
+                                           str::from_utf8(value).unwrap()));
+        }
+
+        fn delete(&mut self, key: Slice)
+        {
+            self.updates.push_str(&format!("Delete({})", str::from_utf8(key).unwrap()));
+        }
+    }
+
+    /// Apply "batch" to a memtable and print the memtable's contents,
+    /// followed by "ParseError()" if the batch is malformed.
+    fn print_contents(batch: &WriteBatch) -> String
+    {
+        let mut mem = MemTable::new(InternalKeyComparator::new(Arc::new(BytewiseComparator)));
+        let result = batch.insert_into(&mut mem);
+        let mut state = String::new();
+        let mut count = 0;
+        let mut iter = mem.iter();
+        iter.seek_to_first();
+        while iter.is_valid() {
+            let parsed = dbformat::parse_internal_key(iter.key()).unwrap();
+            let user_key = str::from_utf8(parsed.user_key).unwrap();
+            match parsed.value_type {
+                ValueType::Value => state.push_str(&format!(
+                    "Put({}, {})", user_key, str::from_utf8(iter.value()).unwrap())),
+                ValueType::Deletion => state.push_str(&format!("Delete({})", user_key)),
+            }
+            state.push_str(&format!("@{}", parsed.sequence));
+            count += 1;
+            iter.step();
+        }
+        if result.is_err() {
+            state.push_str("ParseError()");
+        } else if count != batch.count() {
+            state.push_str("CountMismatch()");
+        }
+        state
+    }
+
+    #[test]
+    fn empty()
+    {
+        let batch = WriteBatch::new();
+        assert_eq!(print_contents(&batch), "");
+        assert_eq!(batch.count(), 0);
+        assert_eq!(batch.contents(), &[0; HEADER][..]);
+    }
+
+    #[test]
+    fn multiple()
+    {
+        let mut batch = WriteBatch::new();
+        batch.put(b"foo", b"bar");
+        batch.delete(b"box");
+        batch.put(b"baz", b"boo");
+        batch.set_sequence(100);
+        assert_eq!(batch.sequence(), 100);
+        assert_eq!(batch.count(), 3);
+        assert_eq!(print_contents(&batch), "Put(baz, boo)@102Delete(box)@101Put(foo, bar)@100");
+    }
+
+    #[test]
+    fn encoding()
+    {
+        let mut batch = WriteBatch::new();
+        batch.set_sequence(0x0102030405060708);
+        batch.put(b"k", b"vv");
+        batch.delete(b"kk");
+        let mut expected = vec![];
+        coding::put_fixed64(&mut expected, 0x0102030405060708);
+        coding::put_fixed32(&mut expected, 2);  // count
+        expected.extend_from_slice(&[ValueType::Value as u8, 1, b'k', 2, b'v', b'v']);
+        expected.extend_from_slice(&[ValueType::Deletion as u8, 2, b'k', b'k']);
+        assert_eq!(batch.contents(), &expected[..]);
+
+        // The encoding is all there is to a batch
+        let mut decoded = WriteBatch::new();
+        decoded.set_contents(&expected).unwrap();
+        assert_eq!(decoded, batch);
+        assert_eq!(decoded.sequence(), 0x0102030405060708);
+        assert_eq!(decoded.count(), 2);
+    }
+
+    #[test]
+    fn corruption()
+    {
+        let mut batch = WriteBatch::new();
+        batch.put(b"foo", b"bar");
+        batch.delete(b"box");
+        batch.set_sequence(200);
+        let contents = batch.contents().to_vec();
+        batch.set_contents(&contents[..contents.len() - 1]).unwrap();
+        assert_eq!(print_contents(&batch), "Put(foo, bar)@200ParseError()");
+    }
+
+    /// Every malformed batch fails to iterate, after handing over the
+    /// updates that precede the malformed one.
+    #[test]
+    fn malformed()
+    {
+        let mut batch = WriteBatch::new();
+        batch.put(b"foo", b"bar");
+        batch.delete(b"box");
+        let contents = batch.contents().to_vec();
+
+        // Truncated anywhere in the updates
+        let put_len = 1 + 4 + 4;
+        for len in HEADER + 1..contents.len() {
+            let mut truncated = WriteBatch::new();
+            truncated.set_contents(&contents[..len]).unwrap();
+            let mut recorder = Recorder::default();
+            assert!(truncated.iterate(&mut recorder).is_err(), "length {}", len);
+            let expected = if len >= HEADER + put_len { "Put(foo, bar)" } else { "" };
+            assert_eq!(recorder.updates, expected);
+        }
+
+        // Too small to hold the header
+        for len in 0..HEADER {
+            let mut short = batch.clone();
+            let status: Status = short.set_contents(&contents[..len]).unwrap_err().into();
+            assert_eq!(status, Status::Corruption("malformed WriteBatch (too small)".into()));
+            assert_eq!(short, batch);
+        }
+        let mut short = batch.clone();
+        short.rep.truncate(HEADER - 1);
+        assert!(short.iterate(&mut Recorder::default()).is_err());
+
+        // Unknown tag
+        let mut bad_tag = batch.clone();
+        bad_tag.rep[HEADER + put_len] = 0xff;
+        let mut recorder = Recorder::default();
+        assert!(bad_tag.iterate(&mut recorder).is_err());
+        assert_eq!(recorder.updates, "Put(foo, bar)");
+
+        // Count that does not match the updates
+        let mut bad_count = batch.clone();
+        bad_count.set_count(3);
+        let mut recorder = Recorder::default();
+        assert!(bad_count.iterate(&mut recorder).is_err());
+        assert_eq!(recorder.updates, "Put(foo, bar)Delete(box)");
+        assert_eq!(print_contents(&bad_count), "Delete(box)@1Put(foo, bar)@0ParseError()");
+    }
+
+    #[test]
+    fn append()
+    {
+        let mut b1 = WriteBatch::new();
+        let mut b2 = WriteBatch::new();
+        b1.set_sequence(200);
+        b2.set_sequence(300);
+        b1.append(&b2);
+        assert_eq!(print_contents(&b1), "");
+        b2.put(b"a", b"va");
+        b1.append(&b2);
+        assert_eq!(print_contents(&b1), "Put(a, va)@200");
+        b2.clear();
+        b2.put(b"b", b"vb");
+        b1.append(&b2);
+        assert_eq!(print_contents(&b1), "Put(a, va)@200Put(b, vb)@201");
+        b2.delete(b"foo");
+        b1.append(&b2);
+        assert_eq!(print_contents(&b1),
+                   "Put(a, va)@200Put(b, vb)@202Put(b, vb)@201Delete(foo)@203");
+        assert_eq!(b1.sequence(), 200);
+        assert_eq!(b1.count(), 4);
+    }
+
+    #[test]
+    fn approximate_size()
+    {
+        let mut batch = WriteBatch::new();
+        let empty_size = batch.approximate_size();
+
+        batch.put(b"foo", b"bar");
+        let one_key_size = batch.approximate_size();
+        assert!(empty_size < one_key_size);
+
+        batch.put(b"baz", b"boo");
+        let two_keys_size = batch.approximate_size();
+        assert!(one_key_size < two_keys_size);
+
+        batch.delete(b"box");
+        let post_delete_size = batch.approximate_size();
+        assert!(two_keys_size < post_delete_size);
+
+        batch.clear();
+        assert_eq!(batch.approximate_size(), empty_size);
+        assert_eq!(batch.count(), 0);
+    }
+
+    /// Later updates of a key shadow earlier ones in the memtable.
+    #[test]
+    fn insert_into_memtable()
+    {
+        let mut batch = WriteBatch::new();
+        batch.put(b"key", b"v1");
+        batch.delete(b"key");
+        batch.put(b"key", b"v2");
+        batch.put(b"other", b"v3");
+        batch.set_sequence(10);
+
+        let mut mem = MemTable::new(InternalKeyComparator::new(Arc::new(BytewiseComparator)));
+        batch.insert_into(&mut mem).unwrap();
+        let get = |key: &[u8], seq| mem.get(&dbformat::LookupKey::new(key, seq));
+        assert!(get(b"key", 9).is_none());
+        assert_eq!(get(b"key", 10).unwrap().unwrap(), b"v1");
+        assert!(get(b"key", 11).unwrap().is_err());
+        assert_eq!(get(b"key", 12).unwrap().unwrap(), b"v2");
+        assert_eq!(get(b"key", 100).unwrap().unwrap(), b"v2");
+        assert!(get(b"other", 12).is_none());
+        assert_eq!(get(b"other", 13).unwrap().unwrap(), b"v3");
+    }
+}